@@ -1,38 +1,86 @@
 // src/apu.rs
 
 use std::collections::VecDeque;
+use std::io;
 use std::sync::{Arc, Mutex};
 
+use bincode::{Decode, Encode};
+
+use crate::blip::BlipBuf;
+use crate::stretch::TimeStretcher;
+use crate::save_state::{StateReader, StateWriter};
+use crate::wav::AudioRecorder;
+use crate::vgm::{VgmLogger, APU_REGISTER_BASE, APU_REGISTER_COUNT};
+
 const CPU_FREQ: u32 = 4_194_304;
-const FRAME_SEQUENCER_DIVISOR: u32 = CPU_FREQ / 512;
 // ★★★ 変更: バッファサイズを増やし、より長い時間軸の波形を保持 ★★★
 const WAVEFORM_BUFFER_SIZE: usize = 512;
+// 何Tサイクルごとに帯域制限バッファを確定させて出力サンプルを取り出すか (約1ms)
+const BLIP_FRAME_CLOCKS: u32 = 4096;
+// チャンネルを進める粒度 (1 Mサイクル)
+const APU_STEP_CYCLES: u32 = 4;
+// 出力バッファの目標残量 (秒) と、これを超えたらサンプルを破棄する上限の倍率
+const TARGET_BUFFER_SECONDS: f64 = 0.05;
+const MAX_BUFFER_FACTOR: usize = 4;
+// 動的レート制御で許容するリサンプリング比の最大変化量 (±0.5%)
+const MAX_RATE_ADJUST: f64 = 0.005;
+// バッファ残量の移動平均の係数 (コールバック単位の揺れを均す)
+const FILL_SMOOTHING: f64 = 0.01;
 
+/// 出力バッファの残量に応じてリサンプリング比をわずかに調整し、
+/// エミュレーション速度と音声デバイスのクロック差を吸収します。
+pub struct RateController {
+    target_fill: f64,
+    average_fill: f64,
+}
 
-#[derive(Default, Clone, Copy)]
+impl RateController {
+    pub fn new(target_fill: usize) -> Self {
+        Self { target_fill: target_fill as f64, average_fill: target_fill as f64 }
+    }
+
+    /// 現在のバッファ残量から、入力クロックレートに掛ける倍率を返します。
+    /// 残量が目標より多ければ倍率を上げて (出力サンプルを減らして) 消費を待ちます。
+    pub fn update(&mut self, fill: usize) -> f64 {
+        self.average_fill += (fill as f64 - self.average_fill) * FILL_SMOOTHING;
+        let error = ((self.average_fill - self.target_fill) / self.target_fill).clamp(-1.0, 1.0);
+        1.0 + error * MAX_RATE_ADJUST
+    }
+}
+
+
+#[derive(Default, Clone, Copy, Encode, Decode)]
 pub struct LengthCounter { pub counter: u16, pub enabled: bool, pub max_len: u16 }
-impl LengthCounter { fn new(max_len: u16) -> Self { Self { counter: 0, enabled: false, max_len } } fn trigger(&mut self) { if self.counter == 0 { self.counter = self.max_len; } } fn tick(&mut self) -> bool { if self.enabled && self.counter > 0 { self.counter -= 1; if self.counter == 0 { return false; } } self.counter > 0 } fn load(&mut self, length_data: u8) { self.counter = self.max_len - (length_data as u16); } }
+// extra_clock: 次のフレームシーケンサのステップが長さカウンタをクロックしない (奇数ステップ) かどうか
+impl LengthCounter { fn new(max_len: u16) -> Self { Self { counter: 0, enabled: false, max_len } } fn trigger(&mut self, extra_clock: bool) { if self.counter == 0 { self.counter = self.max_len; if self.enabled && extra_clock { self.counter -= 1; } } } fn tick(&mut self) -> bool { if self.enabled && self.counter > 0 { self.counter -= 1; return self.counter == 0; } false } fn load(&mut self, length_data: u8) { self.counter = self.max_len - (length_data as u16); } fn write_enable(&mut self, enable: bool, extra_clock: bool) -> bool { let was_enabled = self.enabled; self.enabled = enable; if extra_clock && !was_enabled && enable && self.counter > 0 { self.counter -= 1; return self.counter == 0; } false } }
 
-#[derive(Default, Clone, Copy)]
-pub struct VolumeEnvelope { pub initial_volume: u8, pub direction: bool, pub period: u8, pub timer: u8, pub volume: u8, pub dac_enabled: bool }
-impl VolumeEnvelope { fn trigger(&mut self) { self.timer = if self.period == 0 { 8 } else { self.period }; self.volume = self.initial_volume; } fn tick(&mut self) { if self.period == 0 { return; } self.timer = self.timer.saturating_sub(1); if self.timer == 0 { self.timer = if self.period == 0 { 8 } else { self.period }; let new_vol = if self.direction { self.volume.saturating_add(1) } else { self.volume.saturating_sub(1) }; if new_vol <= 15 { self.volume = new_vol; } } } }
+#[derive(Default, Clone, Copy, Encode, Decode)]
+pub struct VolumeEnvelope { pub initial_volume: u8, pub direction: bool, pub period: u8, pub timer: u8, pub volume: u8, pub dac_enabled: bool, pub active: bool }
+// write: 再生中のNRx2書き込みで音量が変化する「ゾンビモード」の挙動を含みます
+impl VolumeEnvelope { fn trigger(&mut self) { self.timer = if self.period == 0 { 8 } else { self.period }; self.volume = self.initial_volume; self.active = true; } fn tick(&mut self) { if self.period == 0 { return; } self.timer = self.timer.saturating_sub(1); if self.timer == 0 { self.timer = self.period; if self.direction && self.volume < 15 { self.volume += 1; } else if !self.direction && self.volume > 0 { self.volume -= 1; } else { self.active = false; } } } fn write(&mut self, val: u8, channel_enabled: bool) { let new_direction = (val & 8) != 0; if channel_enabled { if self.period == 0 && self.active { self.volume = self.volume.wrapping_add(1); } else if !self.direction { self.volume = self.volume.wrapping_add(2); } let direction_changed = self.direction != new_direction; if direction_changed { self.volume = 16u8.wrapping_sub(self.volume); } self.volume &= 0x0F; } self.initial_volume = val >> 4; self.direction = new_direction; self.period = val & 7; self.dac_enabled = (val & 0xF8) != 0; } }
 
-#[derive(Default, Clone, Copy)]
-pub struct Sweep { pub period: u8, pub direction: bool, pub shift: u8, pub timer: u8, pub enabled: bool, pub shadow_freq: u16 }
-impl Sweep { fn trigger(&mut self, freq: u16) { self.shadow_freq = freq; self.timer = if self.period > 0 { self.period } else { 8 }; self.enabled = self.period > 0 || self.shift > 0; if self.shift > 0 { if self.calculate_new_freq() >= 2048 { self.enabled = false; } } } fn tick(&mut self, freq_reg: &mut u16, channel_enabled: &mut bool) { if !self.enabled { return; } self.timer = self.timer.saturating_sub(1); if self.timer == 0 { self.timer = if self.period > 0 { self.period } else { 8 }; if self.enabled && self.period > 0 { let new_freq = self.calculate_new_freq(); if new_freq < 2048 && self.shift > 0 { *freq_reg = new_freq; self.shadow_freq = new_freq; if self.calculate_new_freq() >= 2048 { *channel_enabled = false; } } else if new_freq >= 2048 { *channel_enabled = false; } } } } fn calculate_new_freq(&mut self) -> u16 { let offset = self.shadow_freq >> self.shift; if self.direction { self.shadow_freq.wrapping_sub(offset) } else { self.shadow_freq.wrapping_add(offset) } } }
+#[derive(Default, Clone, Copy, Encode, Decode)]
+pub struct Sweep { pub period: u8, pub direction: bool, pub shift: u8, pub timer: u8, pub enabled: bool, pub shadow_freq: u16, pub negate_used: bool }
+// trigger: オーバーフローでチャンネルが無効になる場合は false を返します
+impl Sweep { fn trigger(&mut self, freq: u16) -> bool { self.shadow_freq = freq; self.timer = if self.period > 0 { self.period } else { 8 }; self.enabled = self.period > 0 || self.shift > 0; self.negate_used = false; !(self.shift > 0 && self.calculate_new_freq() >= 2048) } fn tick(&mut self, freq_reg: &mut u16, channel_enabled: &mut bool) { self.timer = self.timer.saturating_sub(1); if self.timer == 0 { self.timer = if self.period > 0 { self.period } else { 8 }; if self.enabled && self.period > 0 { let new_freq = self.calculate_new_freq(); if new_freq < 2048 && self.shift > 0 { *freq_reg = new_freq; self.shadow_freq = new_freq; if self.calculate_new_freq() >= 2048 { *channel_enabled = false; } } else if new_freq >= 2048 { *channel_enabled = false; } } } } fn calculate_new_freq(&mut self) -> u16 { let offset = self.shadow_freq >> self.shift; if self.direction { self.negate_used = true; self.shadow_freq.wrapping_sub(offset) } else { self.shadow_freq.wrapping_add(offset) } } }
+
+fn dac_output(output: u8, dac_enabled: bool) -> f32 { if dac_enabled { (output as f32 / 7.5) - 1.0 } else { 0.0 } }
 
 const DUTY_PATTERNS: [[u8; 8]; 4] = [[0, 0, 0, 0, 0, 0, 0, 1], [1, 0, 0, 0, 0, 0, 0, 1], [1, 0, 0, 0, 0, 1, 1, 1], [0, 1, 1, 1, 1, 1, 1, 0]];
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Clone, Copy, Encode, Decode)]
 pub struct PulseChannel { pub enabled: bool, pub length_counter: LengthCounter, pub envelope: VolumeEnvelope, pub sweep: Sweep, pub freq_timer: u32, pub freq_reg: u16, pub duty_pattern: u8, pub duty_step: u8 }
-impl PulseChannel { fn new(with_sweep: bool) -> Self { Self { length_counter: LengthCounter::new(64), sweep: if with_sweep { Sweep::default() } else { Sweep { enabled: false, ..Default::default() } }, ..Default::default() } } fn tick(&mut self, cycles: u32) { self.freq_timer = self.freq_timer.saturating_sub(cycles); if self.freq_timer == 0 { let period = (2048 - self.freq_reg as u32) * 4; self.freq_timer = if period == 0 { 8192 * 4 } else { period }; self.duty_step = (self.duty_step + 1) % 8; } } fn output(&self) -> u8 { if !self.enabled || !self.envelope.dac_enabled { return 0; } if DUTY_PATTERNS[self.duty_pattern as usize][self.duty_step as usize] == 1 { self.envelope.volume } else { 0 } } }
+impl PulseChannel { fn new(with_sweep: bool) -> Self { Self { length_counter: LengthCounter::new(64), sweep: if with_sweep { Sweep::default() } else { Sweep { enabled: false, ..Default::default() } }, ..Default::default() } } fn period(&self) -> u32 { (2048 - self.freq_reg as u32) * 4 } fn tick(&mut self, cycles: u32) { let mut remaining = cycles; while remaining >= self.freq_timer { remaining -= self.freq_timer; self.freq_timer = self.period(); self.duty_step = (self.duty_step + 1) % 8; } self.freq_timer -= remaining; } fn output(&self) -> u8 { if !self.enabled || !self.envelope.dac_enabled { 0 } else if DUTY_PATTERNS[self.duty_pattern as usize][self.duty_step as usize] == 1 { self.envelope.volume } else { 0 } } }
 
-#[derive(Default, Clone, Copy)]
-pub struct WaveChannel { pub enabled: bool, pub dac_enabled: bool, pub length_counter: LengthCounter, pub volume_level: u8, pub freq_timer: u32, pub freq_reg: u16, pub sample_index: u8, pub wave_ram: [u8; 16], pub sample_buffer: u8 }
-impl WaveChannel { fn new() -> Self { Self { length_counter: LengthCounter::new(256), ..Default::default() } } fn tick(&mut self, cycles: u32) { self.freq_timer = self.freq_timer.saturating_sub(cycles); if self.freq_timer == 0 { let period = (2048 - self.freq_reg as u32) * 2; self.freq_timer = if period == 0 { 4096 * 2 } else { period }; self.sample_index = (self.sample_index + 1) % 32; let ram_byte = self.wave_ram[(self.sample_index / 2) as usize]; self.sample_buffer = if self.sample_index % 2 == 0 { ram_byte >> 4 } else { ram_byte & 0x0F }; } } fn output(&self) -> u8 { if !self.enabled || !self.dac_enabled { return 0; } let shift = match self.volume_level { 1 => 0, 2 => 1, 3 => 2, _ => 4, }; self.sample_buffer >> shift } }
+// トリガーから最初のサンプル読み出しまでの追加遅延 (Tサイクル)
+const WAVE_TRIGGER_DELAY: u32 = 6;
+#[derive(Default, Clone, Copy, Encode, Decode)]
+pub struct WaveChannel { pub enabled: bool, pub dac_enabled: bool, pub length_counter: LengthCounter, pub volume_level: u8, pub freq_timer: u32, pub freq_reg: u16, pub sample_index: u8, pub wave_ram: [u8; 16], pub sample_buffer: u8, pub sample_just_read: bool }
+// sample_just_read: 直前の1 Mサイクル内に波形RAMを読み出したか (DMGでは再生中のCPUアクセスはこの時だけ有効)
+impl WaveChannel { fn new() -> Self { Self { length_counter: LengthCounter::new(256), ..Default::default() } } fn period(&self) -> u32 { (2048 - self.freq_reg as u32) * 2 } fn tick(&mut self, cycles: u32) { self.sample_just_read = false; let mut remaining = cycles; while remaining >= self.freq_timer { remaining -= self.freq_timer; self.freq_timer = self.period(); self.sample_index = (self.sample_index + 1) % 32; let ram_byte = self.wave_ram[(self.sample_index / 2) as usize]; self.sample_buffer = if self.sample_index.is_multiple_of(2) { ram_byte >> 4 } else { ram_byte & 0x0F }; self.sample_just_read = true; } self.freq_timer -= remaining; } fn output(&self) -> u8 { if !self.enabled || !self.dac_enabled { return 0; } let shift = match self.volume_level { 1 => 0, 2 => 1, 3 => 2, _ => 4, }; self.sample_buffer >> shift } fn current_byte(&self) -> usize { (self.sample_index / 2) as usize } fn trigger(&mut self) { if self.enabled && self.sample_just_read { let pos = self.current_byte(); if pos < 4 { self.wave_ram[0] = self.wave_ram[pos]; } else { let aligned = pos & !3; let block = [self.wave_ram[aligned], self.wave_ram[aligned + 1], self.wave_ram[aligned + 2], self.wave_ram[aligned + 3]]; self.wave_ram[..4].copy_from_slice(&block); } } self.sample_index = 0; self.freq_timer = self.period() + WAVE_TRIGGER_DELAY; } }
 
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Clone, Copy, Encode, Decode)]
 pub struct NoiseChannel { pub enabled: bool, pub length_counter: LengthCounter, pub envelope: VolumeEnvelope, pub freq_timer: u32, pub lfsr: u16, pub width_mode: bool, pub clock_shift: u8, pub divisor_code: u8 }
-impl NoiseChannel { fn new() -> Self { Self { length_counter: LengthCounter::new(64), lfsr: 0x7FFF, ..Default::default() } } fn tick(&mut self, cycles: u32) { self.freq_timer = self.freq_timer.saturating_sub(cycles); if self.freq_timer == 0 { let divisor = [8, 16, 32, 48, 64, 80, 96, 112]; let d = divisor[self.divisor_code as usize]; let period = (d as u32) << self.clock_shift; self.freq_timer = period; let xor_res = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1); self.lfsr >>= 1; self.lfsr |= xor_res << 14; if self.width_mode { self.lfsr = (self.lfsr & !(1 << 6)) | (xor_res << 6); } } } fn output(&self) -> u8 { if !self.enabled || !self.envelope.dac_enabled { return 0; } if (self.lfsr & 1) == 0 { self.envelope.volume } else { 0 } } }
+impl NoiseChannel { fn new() -> Self { Self { length_counter: LengthCounter::new(64), lfsr: 0x7FFF, ..Default::default() } } fn period(&self) -> u32 { let divisor = [8, 16, 32, 48, 64, 80, 96, 112]; (divisor[self.divisor_code as usize] as u32) << self.clock_shift } fn tick(&mut self, cycles: u32) { let mut remaining = cycles; while remaining >= self.freq_timer { remaining -= self.freq_timer; self.freq_timer = self.period(); let xor_res = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1); self.lfsr >>= 1; self.lfsr |= xor_res << 14; if self.width_mode { self.lfsr = (self.lfsr & !(1 << 6)) | (xor_res << 6); } } self.freq_timer -= remaining; } fn output(&self) -> u8 { if !self.enabled || !self.envelope.dac_enabled { 0 } else if (self.lfsr & 1) == 0 { self.envelope.volume } else { 0 } } }
 
 #[derive(Default, Clone, Copy)]
 pub struct ApuChannelState {
@@ -48,6 +96,10 @@ pub struct ApuState {
     pub ch3: ApuChannelState,
     pub ch4: ApuChannelState,
     pub wave_ram: [u8; 16],
+    pub channel_audible: [bool; 4],
+    pub channel_muted: [bool; 4],
+    pub solo_channel: Option<usize>,
+    pub channel_gain: [f32; 4],
 }
 
 pub struct Apu {
@@ -59,10 +111,28 @@ pub struct Apu {
     master_vol_left: u8,
     master_vol_right: u8,
     panning: u8,
-    cycle_counter: u32,
-    frame_seq_counter: u32,
     frame_seq_step: u8,
-    cycles_per_output_sample: u32,
+    blips: [BlipBuf; 4],
+    blip_time: u32,
+    last_levels: [f32; 4],
+    blip_out: [Vec<f32>; 4],
+    rate_controller: RateController,
+    target_buffer_fill: usize,
+    sample_rate: u32,
+    recorder: Option<AudioRecorder>,
+    // 録音用の帯域制限バッファ。レート制御を掛けず、常に公称のサンプルレートで合成する
+    record_blips: Option<Box<[BlipBuf; 4]>>,
+    record_out: [Vec<f32>; 4],
+    // 等速以外で出力デバイスへ送る音の時間伸縮 (等速なら None)
+    output_stretch: Option<TimeStretcher>,
+    output_muted: bool,
+    channel_muted: [bool; 4],
+    solo_channel: Option<usize>,
+    channel_gain: [f32; 4],
+    total_cycles: u64,
+    // 最後に書き込まれたレジスタ値 (VGMの初期状態に使う)
+    register_shadow: [u8; APU_REGISTER_COUNT],
+    vgm_log: Option<VgmLogger>,
     sample_buffer: Arc<Mutex<VecDeque<(f32, f32)>>>,
     ch_waveforms: [VecDeque<f32>; 4],
     hpf_cap_l: f32,
@@ -78,6 +148,7 @@ impl Apu {
         let dt = 1.0 / output_sample_rate as f32;
         let rc = 1.0 / (2.0 * std::f32::consts::PI * CUTOFF_FREQ);
         let hpf_alpha = rc / (rc + dt);
+        let target_buffer_fill = (output_sample_rate as f64 * TARGET_BUFFER_SECONDS) as usize;
 
         Self {
             ch1: PulseChannel::new(true),
@@ -88,11 +159,26 @@ impl Apu {
             master_vol_left: 0,
             master_vol_right: 0,
             panning: 0,
-            cycle_counter: 0,
-            frame_seq_counter: 0,
             frame_seq_step: 0,
-            cycles_per_output_sample: CPU_FREQ / output_sample_rate,
-            sample_buffer: Arc::new(Mutex::new(VecDeque::with_capacity(8192))),
+            blips: std::array::from_fn(|_| BlipBuf::new(CPU_FREQ as u64, output_sample_rate as u64)),
+            blip_time: 0,
+            last_levels: [0.0; 4],
+            blip_out: Default::default(),
+            rate_controller: RateController::new(target_buffer_fill),
+            target_buffer_fill,
+            sample_rate: output_sample_rate,
+            recorder: None,
+            record_blips: None,
+            record_out: Default::default(),
+            output_stretch: None,
+            output_muted: false,
+            channel_muted: [false; 4],
+            solo_channel: None,
+            channel_gain: [1.0; 4],
+            total_cycles: 0,
+            register_shadow: [0; APU_REGISTER_COUNT],
+            vgm_log: None,
+            sample_buffer: Arc::new(Mutex::new(VecDeque::with_capacity(target_buffer_fill * MAX_BUFFER_FACTOR))),
             ch_waveforms: [
                 VecDeque::with_capacity(WAVEFORM_BUFFER_SIZE),
                 VecDeque::with_capacity(WAVEFORM_BUFFER_SIZE),
@@ -111,6 +197,160 @@ impl Apu {
         self.sample_buffer.clone()
     }
     
+    /// 出力バッファに溜まっている未再生のサンプル数
+    pub fn buffered_samples(&self) -> usize {
+        self.sample_buffer.lock().unwrap().len()
+    }
+
+    /// 音声同期時に保ちたいバッファ残量 (サンプル数)
+    pub fn target_buffer_fill(&self) -> usize {
+        self.target_buffer_fill
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// 出力バッファの目標残量を秒単位で変更します (音声遅延の調整)。
+    pub fn set_buffer_latency(&mut self, seconds: f64) {
+        self.target_buffer_fill = ((self.sample_rate as f64 * seconds) as usize).max(1);
+        self.rate_controller = RateController::new(self.target_buffer_fill);
+    }
+
+    /// 出力デバイスへ送る音を、エミュレーション速度 `speed` 倍に合わせて音程を変えずに伸縮します。
+    /// `None` で出力を止めます。どちらも録音やVGMには影響しません。
+    pub fn set_output_speed(&mut self, speed: Option<f64>) {
+        self.output_muted = speed.is_none();
+        match speed {
+            Some(speed) if speed != 1.0 => {
+                if self.output_stretch.as_ref().is_none_or(|stretch| stretch.speed() != speed) {
+                    self.output_stretch = Some(TimeStretcher::new(self.sample_rate, speed));
+                }
+            }
+            _ => self.output_stretch = None,
+        }
+    }
+
+    /// 音源の状態を書き出します。出力バッファや録音などのフロントエンド側の状態は含みません。
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.ch1);
+        w.write(&self.ch2);
+        w.write(&self.ch3);
+        w.write(&self.ch4);
+        w.write(&self.master_power);
+        w.write(&self.master_vol_left);
+        w.write(&self.master_vol_right);
+        w.write(&self.panning);
+        w.write(&self.frame_seq_step);
+        w.write(&self.total_cycles);
+        w.write(&self.register_shadow);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.ch1)?;
+        r.read_into(&mut self.ch2)?;
+        r.read_into(&mut self.ch3)?;
+        r.read_into(&mut self.ch4)?;
+        r.read_into(&mut self.master_power)?;
+        r.read_into(&mut self.master_vol_left)?;
+        r.read_into(&mut self.master_vol_right)?;
+        r.read_into(&mut self.panning)?;
+        r.read_into(&mut self.frame_seq_step)?;
+        r.read_into(&mut self.total_cycles)?;
+        r.read_into(&mut self.register_shadow)?;
+        // 出力波形を読み込んだ状態のレベルへつなげる
+        self.update_levels();
+        Ok(())
+    }
+
+    /// 録音を始めます。録音は出力デバイス用とは別に、レート制御のない公称のサンプルレートで合成するので、
+    /// 長時間録音してもWAVヘッダのレートとずれません。
+    pub fn start_recording(&mut self, recorder: AudioRecorder) {
+        let mut blips: Box<[BlipBuf; 4]> = Box::new(std::array::from_fn(|_| BlipBuf::new(CPU_FREQ as u64, self.sample_rate as u64)));
+        // 今の出力レベルから始める
+        for (blip, &level) in blips.iter_mut().zip(self.last_levels.iter()) {
+            if level != 0.0 {
+                blip.add_delta(self.blip_time, level);
+            }
+        }
+        self.record_blips = Some(blips);
+        self.recorder = Some(recorder);
+    }
+
+    /// 録音を停止し、録音中だったレコーダーを返します (ファイルの確定は呼び出し側で行います)。
+    pub fn stop_recording(&mut self) -> Option<AudioRecorder> {
+        self.record_blips = None;
+        self.recorder.take()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// これ以降のレジスタ書き込みをVGMとして記録し始めます。
+    pub fn start_vgm_logging(&mut self) {
+        self.vgm_log = Some(VgmLogger::new(self.total_cycles, &self.register_shadow));
+    }
+
+    /// 記録を終了し、ログを返します (ファイルへの保存は呼び出し側で行います)。
+    pub fn stop_vgm_logging(&mut self) -> Option<VgmLogger> {
+        let mut log = self.vgm_log.take()?;
+        log.set_end_cycle(self.total_cycles);
+        Some(log)
+    }
+
+    pub fn is_vgm_logging(&self) -> bool {
+        self.vgm_log.is_some()
+    }
+
+    // --- ミキサー (ミュート/ソロ/ゲイン) ---
+    // 出力のミックスにのみ作用し、レジスタやチャンネルの状態には影響しません。
+
+    pub fn set_channel_muted(&mut self, channel: usize, muted: bool) {
+        if channel < 4 { self.channel_muted[channel] = muted; }
+    }
+
+    pub fn toggle_channel_mute(&mut self, channel: usize) {
+        if channel < 4 { self.channel_muted[channel] = !self.channel_muted[channel]; }
+    }
+
+    /// 指定したチャンネルだけを鳴らします。`None` でソロを解除します。
+    pub fn set_solo_channel(&mut self, channel: Option<usize>) {
+        self.solo_channel = channel.filter(|&ch| ch < 4);
+    }
+
+    pub fn toggle_channel_solo(&mut self, channel: usize) {
+        self.set_solo_channel(if self.solo_channel == Some(channel) { None } else { Some(channel) });
+    }
+
+    pub fn set_channel_gain(&mut self, channel: usize, gain: f32) {
+        if channel < 4 { self.channel_gain[channel] = gain.max(0.0); }
+    }
+
+    pub fn channel_gain(&self, channel: usize) -> f32 {
+        self.channel_gain.get(channel).copied().unwrap_or(0.0)
+    }
+
+    /// ミュートとソロを反映した、実際にミックスされるチャンネルのマスク (bit0 = パルス1)
+    pub fn channel_enable_mask(&self) -> u8 {
+        (0..4).filter(|&ch| self.is_channel_audible(ch)).fold(0, |mask, ch| mask | (1 << ch))
+    }
+
+    /// マスクのビットが立っていないチャンネルをミュートします。ソロは解除されます。
+    pub fn set_channel_enable_mask(&mut self, mask: u8) {
+        self.solo_channel = None;
+        for (channel, muted) in self.channel_muted.iter_mut().enumerate() {
+            *muted = mask & (1 << channel) == 0;
+        }
+    }
+
+    fn is_channel_audible(&self, channel: usize) -> bool {
+        match self.solo_channel {
+            Some(solo) => solo == channel,
+            None => !self.channel_muted[channel],
+        }
+    }
+
     pub fn get_channel_waveforms(&self) -> [Vec<f32>; 4] {
         [
             self.ch_waveforms[0].iter().cloned().collect(),
@@ -121,34 +361,117 @@ impl Apu {
     }
 
     pub fn tick(&mut self, cycles: u8) {
-        let cycles_u32 = cycles as u32;
+        self.total_cycles += cycles as u64;
+        let mut remaining = cycles as u32;
+        while remaining > 0 {
+            let step = remaining.min(APU_STEP_CYCLES);
+            remaining -= step;
 
-        if self.master_power {
-            self.ch1.tick(cycles_u32);
-            self.ch2.tick(cycles_u32);
-            self.ch3.tick(cycles_u32);
-            self.ch4.tick(cycles_u32);
-
-            self.frame_seq_counter += cycles_u32;
-            while self.frame_seq_counter >= FRAME_SEQUENCER_DIVISOR {
-                self.frame_seq_counter -= FRAME_SEQUENCER_DIVISOR;
-                self.step_frame_sequencer();
+            if self.master_power {
+                self.ch1.tick(step);
+                self.ch2.tick(step);
+                self.ch3.tick(step);
+                self.ch4.tick(step);
             }
+
+            self.blip_time += step;
+            self.update_levels();
         }
-        
-        self.cycle_counter += cycles_u32;
-        while self.cycle_counter >= self.cycles_per_output_sample {
-            self.cycle_counter -= self.cycles_per_output_sample;
-            self.generate_sample();
+
+        if self.blip_time >= BLIP_FRAME_CLOCKS {
+            self.end_blip_frame();
+        }
+    }
+
+    // 各チャンネルのDAC出力 (-1.0〜1.0、DAC無効時は0.0) を求め、変化があれば帯域制限バッファへ記録する
+    fn update_levels(&mut self) {
+        let levels = [
+            dac_output(self.ch1.output(), self.ch1.envelope.dac_enabled),
+            dac_output(self.ch2.output(), self.ch2.envelope.dac_enabled),
+            dac_output(self.ch3.output(), self.ch3.dac_enabled),
+            dac_output(self.ch4.output(), self.ch4.envelope.dac_enabled),
+        ];
+        for (i, level) in levels.iter().enumerate() {
+            let delta = level - self.last_levels[i];
+            if delta != 0.0 {
+                self.blips[i].add_delta(self.blip_time, delta);
+                if let Some(blips) = &mut self.record_blips {
+                    blips[i].add_delta(self.blip_time, delta);
+                }
+                self.last_levels[i] = *level;
+            }
+        }
+    }
+
+    fn end_blip_frame(&mut self) {
+        for blip in self.blips.iter_mut() {
+            blip.end_frame(self.blip_time);
+        }
+        self.record_frame();
+        self.blip_time = 0;
+
+        let count = self.blips[0].samples_avail();
+        for (blip, out) in self.blips.iter_mut().zip(self.blip_out.iter_mut()) {
+            out.resize(count, 0.0);
+            blip.read_samples(out);
+        }
+
+        let handle = self.sample_buffer.clone();
+        let mut buffer = handle.lock().unwrap();
+        let max_fill = self.target_buffer_fill * MAX_BUFFER_FACTOR;
+        for n in 0..count {
+            let ch_outputs = [self.blip_out[0][n], self.blip_out[1][n], self.blip_out[2][n], self.blip_out[3][n]];
+            let sample = self.generate_sample(ch_outputs);
+            if self.output_muted { continue; }
+            match &mut self.output_stretch {
+                Some(stretch) => stretch.push(sample, |sample| if buffer.len() < max_fill { buffer.push_back(sample); }),
+                None => if buffer.len() < max_fill { buffer.push_back(sample); },
+            }
+        }
+
+        let ratio = self.rate_controller.update(buffer.len());
+        let clock_rate = (CPU_FREQ as f64 * ratio).round() as u64;
+        for blip in self.blips.iter_mut() {
+            blip.set_clock_rate(clock_rate);
+        }
+    }
+
+    // 録音用のバッファを確定させ、公称のサンプルレートのままレコーダーへ渡す
+    fn record_frame(&mut self) {
+        let Some(blips) = &mut self.record_blips else { return; };
+        for blip in blips.iter_mut() {
+            blip.end_frame(self.blip_time);
+        }
+        let count = blips[0].samples_avail();
+        for (blip, out) in blips.iter_mut().zip(self.record_out.iter_mut()) {
+            out.resize(count, 0.0);
+            blip.read_samples(out);
+        }
+        for n in 0..count {
+            let ch_outputs = [self.record_out[0][n], self.record_out[1][n], self.record_out[2][n], self.record_out[3][n]];
+            let mix = self.mix_channels(ch_outputs);
+            if let Some(recorder) = &mut self.recorder && let Err(e) = recorder.push(mix, ch_outputs) {
+                eprintln!("Audio recording stopped: {}", e);
+                self.recorder = None;
+                self.record_blips = None;
+                return;
+            }
+        }
+    }
+
+    /// DIV-APU イベント (タイマーのDIVビット4の立ち下がり、512Hz) でフレームシーケンサを1ステップ進めます。
+    pub fn clock_frame_sequencer(&mut self) {
+        if self.master_power {
+            self.step_frame_sequencer();
         }
     }
 
     fn step_frame_sequencer(&mut self) {
-        if self.frame_seq_step % 2 == 0 {
-            if !self.ch1.length_counter.tick() { self.ch1.enabled = false; }
-            if !self.ch2.length_counter.tick() { self.ch2.enabled = false; }
-            if !self.ch3.length_counter.tick() { self.ch3.enabled = false; }
-            if !self.ch4.length_counter.tick() { self.ch4.enabled = false; }
+        if self.frame_seq_step.is_multiple_of(2) {
+            if self.ch1.length_counter.tick() { self.ch1.enabled = false; }
+            if self.ch2.length_counter.tick() { self.ch2.enabled = false; }
+            if self.ch3.length_counter.tick() { self.ch3.enabled = false; }
+            if self.ch4.length_counter.tick() { self.ch4.enabled = false; }
         }
         if self.frame_seq_step == 2 || self.frame_seq_step == 6 {
             self.ch1.sweep.tick(&mut self.ch1.freq_reg, &mut self.ch1.enabled);
@@ -161,25 +484,12 @@ impl Apu {
         self.frame_seq_step = (self.frame_seq_step + 1) % 8;
     }
 
-    fn generate_sample(&mut self) {
-        let mut buffer = self.sample_buffer.lock().unwrap();
-        if buffer.len() >= 4096 { return; }
-
+    // パン・チャンネルのマスクとゲイン・マスターボリュームを掛けたミックス (DC成分の除去前)
+    fn mix_channels(&self, ch_outputs: [f32; 4]) -> (f32, f32) {
         let mut raw_out_l = 0.0;
         let mut raw_out_r = 0.0;
-        
-        let s1 = (self.ch1.output() as f32 / 7.5) - 1.0;
-        let s2 = (self.ch2.output() as f32 / 7.5) - 1.0;
-        let s3 = (self.ch3.output() as f32 / 7.5) - 1.0;
-        let s4 = (self.ch4.output() as f32 / 7.5) - 1.0;
-        
-        let ch_outputs = [s1, s2, s3, s4];
-        for i in 0..4 {
-            if self.ch_waveforms[i].len() >= WAVEFORM_BUFFER_SIZE {
-                self.ch_waveforms[i].pop_front();
-            }
-            self.ch_waveforms[i].push_back(ch_outputs[i]);
-        }
+        let mixed: [f32; 4] = std::array::from_fn(|i| if self.is_channel_audible(i) { ch_outputs[i] * self.channel_gain[i] } else { 0.0 });
+        let [s1, s2, s3, s4] = mixed;
 
         if self.master_power {
             if (self.panning & 0x80) != 0 { raw_out_r += s4; }
@@ -191,10 +501,22 @@ impl Apu {
             if (self.panning & 0x02) != 0 { raw_out_l += s2; }
             if (self.panning & 0x01) != 0 { raw_out_l += s1; }
         }
-        
-        raw_out_l = raw_out_l / 4.0;
-        raw_out_r = raw_out_r / 4.0;
-        
+
+        let left = raw_out_l / 4.0 * (((self.master_vol_left & 7) as f32 + 1.0) / 8.0);
+        let right = raw_out_r / 4.0 * (((self.master_vol_right & 7) as f32 + 1.0) / 8.0);
+        (left, right)
+    }
+
+    fn generate_sample(&mut self, ch_outputs: [f32; 4]) -> (f32, f32) {
+        for (waveform, &output) in self.ch_waveforms.iter_mut().zip(&ch_outputs) {
+            if waveform.len() >= WAVEFORM_BUFFER_SIZE {
+                waveform.pop_front();
+            }
+            waveform.push_back(output);
+        }
+        // オシロスコープにはミュート前の波形を残し、ミックスにだけマスクとゲインを掛ける
+        let (raw_out_l, raw_out_r) = self.mix_channels(ch_outputs);
+
         let filtered_out_l = self.hpf_alpha * (self.hpf_cap_l + raw_out_l - self.last_raw_out_l);
         self.hpf_cap_l = filtered_out_l;
         self.last_raw_out_l = raw_out_l;
@@ -203,10 +525,7 @@ impl Apu {
         self.hpf_cap_r = filtered_out_r;
         self.last_raw_out_r = raw_out_r;
 
-        let final_l = filtered_out_l * (((self.master_vol_left & 7) as f32 + 1.0) / 8.0);
-        let final_r = filtered_out_r * (((self.master_vol_right & 7) as f32 + 1.0) / 8.0);
-
-        buffer.push_back((final_l.clamp(-1.0, 1.0), final_r.clamp(-1.0, 1.0)));
+        (filtered_out_l.clamp(-1.0, 1.0), filtered_out_r.clamp(-1.0, 1.0))
     }
     
     pub fn get_apu_state(&self) -> ApuState {
@@ -216,6 +535,10 @@ impl Apu {
             ch3: ApuChannelState { enabled: self.ch3.enabled, volume: match self.ch3.volume_level { 0 => 0, 1 => 15, 2 => 15/2, 3 => 15/4, _=> 0}, freq_reg: self.ch3.freq_reg },
             ch4: ApuChannelState { enabled: self.ch4.enabled, volume: self.ch4.envelope.volume, freq_reg: (self.ch4.clock_shift as u16) << 8 | self.ch4.divisor_code as u16 },
             wave_ram: self.ch3.wave_ram,
+            channel_audible: std::array::from_fn(|i| self.is_channel_audible(i)),
+            channel_muted: self.channel_muted,
+            solo_channel: self.solo_channel,
+            channel_gain: self.channel_gain,
         }
     }
     pub fn read_reg(&self, addr: u16) -> u8 {
@@ -241,44 +564,275 @@ impl Apu {
             0xFF24 => ((self.master_vol_left & 7) << 4) | (self.master_vol_right & 7),
             0xFF25 => self.panning,
             0xFF26 => { (if self.master_power { 0x80 } else { 0 }) | (if self.ch4.enabled { 0x08 } else { 0 }) | (if self.ch3.enabled { 0x04 } else { 0 }) | (if self.ch2.enabled { 0x02 } else { 0 }) | (if self.ch1.enabled { 0x01 } else { 0 }) | 0x70 },
-            0xFF30..=0xFF3F => if self.ch3.enabled { 0xFF } else { self.ch3.wave_ram[(addr - 0xFF30) as usize] },
+            // DMGでは再生中の波形RAMはチャンネルが読み出した瞬間のみ、そのバイトが見える
+            0xFF30..=0xFF3F => if self.ch3.enabled { if self.ch3.sample_just_read { self.ch3.wave_ram[self.ch3.current_byte()] } else { 0xFF } } else { self.ch3.wave_ram[(addr - 0xFF30) as usize] },
             _ => 0xFF,
         }
     }
+
+    // NR52による電源OFF: NR10〜NR51をクリアする。DMGでは長さカウンタと波形RAMは保持される
+    fn power_off(&mut self) {
+        let lengths = [self.ch1.length_counter.counter, self.ch2.length_counter.counter, self.ch3.length_counter.counter, self.ch4.length_counter.counter];
+        let wave_ram = self.ch3.wave_ram;
+        self.ch1 = PulseChannel::new(true);
+        self.ch2 = PulseChannel::new(false);
+        self.ch3 = WaveChannel::new();
+        self.ch4 = NoiseChannel::new();
+        self.ch1.length_counter.counter = lengths[0];
+        self.ch2.length_counter.counter = lengths[1];
+        self.ch3.length_counter.counter = lengths[2];
+        self.ch4.length_counter.counter = lengths[3];
+        self.ch3.wave_ram = wave_ram;
+        self.master_vol_left = 0;
+        self.master_vol_right = 0;
+        self.panning = 0;
+    }
+
+    fn power_on(&mut self) {
+        self.frame_seq_step = 0;
+        self.ch1.duty_step = 0;
+        self.ch2.duty_step = 0;
+        self.ch3.sample_buffer = 0;
+    }
+
     pub fn write_reg(&mut self, addr: u16, val: u8) {
-        let was_master_power_on = self.master_power;
+        if let Some(index) = addr.checked_sub(APU_REGISTER_BASE).map(|i| i as usize).filter(|&i| i < APU_REGISTER_COUNT) {
+            self.register_shadow[index] = val;
+        }
+        if let Some(log) = &mut self.vgm_log {
+            log.log_write(self.total_cycles, addr, val);
+        }
         if addr == 0xFF26 {
-            self.master_power = (val & 0x80) != 0;
-            if !was_master_power_on && self.master_power { self.frame_seq_step = 0; }
-            else if was_master_power_on && !self.master_power { for i in 0xFF10..=0xFF25 { self.write_reg(i, 0); } }
+            let power = (val & 0x80) != 0;
+            if !self.master_power && power { self.power_on(); }
+            else if self.master_power && !power { self.power_off(); }
+            self.master_power = power;
+            return;
         }
 
-        if !self.master_power { return; }
+        if !self.master_power {
+            // 電源OFF中でも、DMGでは長さカウンタへの書き込みと波形RAMへのアクセスは有効
+            match addr {
+                0xFF11 => self.ch1.length_counter.load(val & 0x3F),
+                0xFF16 => self.ch2.length_counter.load(val & 0x3F),
+                0xFF1B => self.ch3.length_counter.load(val),
+                0xFF20 => self.ch4.length_counter.load(val & 0x3F),
+                0xFF30..=0xFF3F => self.ch3.wave_ram[(addr - 0xFF30) as usize] = val,
+                _ => (),
+            }
+            return;
+        }
 
+        // 次のフレームシーケンサのステップが長さカウンタをクロックしない場合、長さ有効化時に追加のクロックが入る
+        let extra_clock = self.frame_seq_step % 2 == 1;
+        let trigger = (val & 0x80) != 0;
         match addr {
-            0xFF10 => { self.ch1.sweep.period = (val >> 4) & 7; self.ch1.sweep.direction = (val & 8) != 0; self.ch1.sweep.shift = val & 7; },
+            0xFF10 => {
+                let direction = (val & 8) != 0;
+                // 減算モードで計算した後に減算ビットをクリアするとチャンネルが無効になる
+                if self.ch1.sweep.direction && !direction && self.ch1.sweep.negate_used { self.ch1.enabled = false; }
+                self.ch1.sweep.period = (val >> 4) & 7; self.ch1.sweep.direction = direction; self.ch1.sweep.shift = val & 7;
+            },
             0xFF11 => { self.ch1.duty_pattern = val >> 6; self.ch1.length_counter.load(val & 0x3F); },
-            0xFF12 => { self.ch1.envelope.initial_volume = val >> 4; self.ch1.envelope.direction = (val & 8) != 0; self.ch1.envelope.period = val & 7; self.ch1.envelope.dac_enabled = (val & 0xF8) != 0; if !self.ch1.envelope.dac_enabled { self.ch1.enabled = false; } },
+            0xFF12 => { self.ch1.envelope.write(val, self.ch1.enabled); if !self.ch1.envelope.dac_enabled { self.ch1.enabled = false; } },
             0xFF13 => { self.ch1.freq_reg = (self.ch1.freq_reg & 0xFF00) | (val as u16); },
-            0xFF14 => { self.ch1.freq_reg = (self.ch1.freq_reg & 0x00FF) | (((val & 7) as u16) << 8); self.ch1.length_counter.enabled = (val & 0x40) != 0; if (val & 0x80) != 0 { if self.ch1.envelope.dac_enabled { self.ch1.enabled = true; } self.ch1.length_counter.trigger(); self.ch1.envelope.trigger(); self.ch1.sweep.trigger(self.ch1.freq_reg); } },
+            0xFF14 => {
+                self.ch1.freq_reg = (self.ch1.freq_reg & 0x00FF) | (((val & 7) as u16) << 8);
+                if self.ch1.length_counter.write_enable((val & 0x40) != 0, extra_clock) && !trigger { self.ch1.enabled = false; }
+                if trigger {
+                    if self.ch1.envelope.dac_enabled { self.ch1.enabled = true; }
+                    self.ch1.length_counter.trigger(extra_clock); self.ch1.envelope.trigger(); self.ch1.freq_timer = self.ch1.period();
+                    if !self.ch1.sweep.trigger(self.ch1.freq_reg) { self.ch1.enabled = false; }
+                }
+            },
             0xFF16 => { self.ch2.duty_pattern = val >> 6; self.ch2.length_counter.load(val & 0x3F); },
-            0xFF17 => { self.ch2.envelope.initial_volume = val >> 4; self.ch2.envelope.direction = (val & 8) != 0; self.ch2.envelope.period = val & 7; self.ch2.envelope.dac_enabled = (val & 0xF8) != 0; if !self.ch2.envelope.dac_enabled { self.ch2.enabled = false; } },
+            0xFF17 => { self.ch2.envelope.write(val, self.ch2.enabled); if !self.ch2.envelope.dac_enabled { self.ch2.enabled = false; } },
             0xFF18 => { self.ch2.freq_reg = (self.ch2.freq_reg & 0xFF00) | (val as u16); },
-            0xFF19 => { self.ch2.freq_reg = (self.ch2.freq_reg & 0x00FF) | (((val & 7) as u16) << 8); self.ch2.length_counter.enabled = (val & 0x40) != 0; if (val & 0x80) != 0 { if self.ch2.envelope.dac_enabled { self.ch2.enabled = true; } self.ch2.length_counter.trigger(); self.ch2.envelope.trigger(); } },
+            0xFF19 => {
+                self.ch2.freq_reg = (self.ch2.freq_reg & 0x00FF) | (((val & 7) as u16) << 8);
+                if self.ch2.length_counter.write_enable((val & 0x40) != 0, extra_clock) && !trigger { self.ch2.enabled = false; }
+                if trigger {
+                    if self.ch2.envelope.dac_enabled { self.ch2.enabled = true; }
+                    self.ch2.length_counter.trigger(extra_clock); self.ch2.envelope.trigger(); self.ch2.freq_timer = self.ch2.period();
+                }
+            },
             0xFF1A => { self.ch3.dac_enabled = (val & 0x80) != 0; if !self.ch3.dac_enabled { self.ch3.enabled = false; } },
             0xFF1B => { self.ch3.length_counter.load(val); },
             0xFF1C => { self.ch3.volume_level = (val >> 5) & 3; },
             0xFF1D => { self.ch3.freq_reg = (self.ch3.freq_reg & 0xFF00) | (val as u16); },
-            0xFF1E => { self.ch3.freq_reg = (self.ch3.freq_reg & 0x00FF) | (((val & 7) as u16) << 8); self.ch3.length_counter.enabled = (val & 0x40) != 0; if (val & 0x80) != 0 { if self.ch3.dac_enabled { self.ch3.enabled = true; } self.ch3.length_counter.trigger(); self.ch3.sample_index = 0; } },
+            0xFF1E => {
+                self.ch3.freq_reg = (self.ch3.freq_reg & 0x00FF) | (((val & 7) as u16) << 8);
+                if self.ch3.length_counter.write_enable((val & 0x40) != 0, extra_clock) && !trigger { self.ch3.enabled = false; }
+                if trigger {
+                    self.ch3.trigger();
+                    if self.ch3.dac_enabled { self.ch3.enabled = true; }
+                    self.ch3.length_counter.trigger(extra_clock);
+                }
+            },
             0xFF20 => { self.ch4.length_counter.load(val & 0x3F); },
-            0xFF21 => { self.ch4.envelope.initial_volume = val >> 4; self.ch4.envelope.direction = (val & 8) != 0; self.ch4.envelope.period = val & 7; self.ch4.envelope.dac_enabled = (val & 0xF8) != 0; if !self.ch4.envelope.dac_enabled { self.ch4.enabled = false; } },
+            0xFF21 => { self.ch4.envelope.write(val, self.ch4.enabled); if !self.ch4.envelope.dac_enabled { self.ch4.enabled = false; } },
             0xFF22 => { self.ch4.clock_shift = val >> 4; self.ch4.width_mode = (val & 8) != 0; self.ch4.divisor_code = val & 7; },
-            0xFF23 => { self.ch4.length_counter.enabled = (val & 0x40) != 0; if (val & 0x80) != 0 { if self.ch4.envelope.dac_enabled { self.ch4.enabled = true; } self.ch4.length_counter.trigger(); self.ch4.envelope.trigger(); self.ch4.lfsr = 0xFFFF; } },
+            0xFF23 => {
+                if self.ch4.length_counter.write_enable((val & 0x40) != 0, extra_clock) && !trigger { self.ch4.enabled = false; }
+                if trigger {
+                    if self.ch4.envelope.dac_enabled { self.ch4.enabled = true; }
+                    self.ch4.length_counter.trigger(extra_clock); self.ch4.envelope.trigger(); self.ch4.lfsr = 0x7FFF; self.ch4.freq_timer = self.ch4.period();
+                }
+            },
             0xFF24 => { self.master_vol_right = val & 7; self.master_vol_left = (val >> 4) & 7; },
             0xFF25 => { self.panning = val; },
-            0xFF26 => {},
-            0xFF30..=0xFF3F => { if !self.ch3.enabled { self.ch3.wave_ram[(addr - 0xFF30) as usize] = val; } },
+            // DMGでは再生中の波形RAMへの書き込みはチャンネルが読み出した瞬間のみ、そのバイトへ反映される
+            0xFF30..=0xFF3F => {
+                if !self.ch3.enabled { self.ch3.wave_ram[(addr - 0xFF30) as usize] = val; }
+                else if self.ch3.sample_just_read { let pos = self.ch3.current_byte(); self.ch3.wave_ram[pos] = val; }
+            },
             _ => (),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wav::RecordingMode;
+
+    // Tサイクル単位でAPUを進める
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles / 64 {
+            apu.tick(64);
+        }
+    }
+
+    #[test]
+    fn recording_keeps_nominal_rate_under_rate_control() {
+        let sample_rate = 48_000;
+        let dir = std::env::temp_dir().join(format!("apu-recording-test-{}", std::process::id()));
+        let mut apu = Apu::new(sample_rate);
+        apu.start_recording(AudioRecorder::start(&dir, RecordingMode::Mix, sample_rate).unwrap());
+        // 出力バッファを誰も消費しないので、レート制御は出力サンプルを減らす方向へ張り付く
+        run(&mut apu, CPU_FREQ);
+        let paths = apu.stop_recording().unwrap().finish().unwrap();
+        let data_bytes = std::fs::metadata(&paths[0]).unwrap().len() - 44;
+        let _ = std::fs::remove_dir_all(&dir);
+        let recorded = data_bytes as i64 / 4;
+        assert!((recorded - sample_rate as i64).abs() <= 64, "recorded {} samples for 1 second", recorded);
+        assert_ne!(apu.rate_controller.update(apu.buffered_samples()), 1.0);
+    }
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new(44_100);
+        apu.write_reg(0xFF26, 0x80);
+        apu
+    }
+
+    fn channel_on(apu: &Apu, channel: u8) -> bool {
+        apu.read_reg(0xFF26) & (1 << channel) != 0
+    }
+
+    #[test]
+    fn channel_enable_mask_round_trips() {
+        let mut apu = Apu::new(44_100);
+        apu.toggle_channel_solo(2);
+        assert_eq!(apu.channel_enable_mask(), 0b0100);
+        apu.set_channel_enable_mask(0b1010);
+        assert_eq!(apu.channel_enable_mask(), 0b1010);
+        apu.toggle_channel_mute(0);
+        assert_eq!(apu.channel_enable_mask(), 0b1011);
+    }
+
+    #[test]
+    fn length_enable_on_odd_step_clocks_extra() {
+        for (odd_step, expect_on) in [(false, true), (true, false)] {
+            let mut apu = powered_apu();
+            if odd_step {
+                // 次のステップが長さカウンタをクロックしない
+                apu.clock_frame_sequencer();
+            }
+            apu.write_reg(0xFF17, 0xF0);
+            apu.write_reg(0xFF16, 63); // 残り1
+            apu.write_reg(0xFF19, 0x80);
+            assert!(channel_on(&apu, 1));
+            apu.write_reg(0xFF19, 0x40);
+            assert_eq!(channel_on(&apu, 1), expect_on);
+        }
+    }
+
+    #[test]
+    fn trigger_on_odd_step_reloads_length_minus_one() {
+        let mut apu = powered_apu();
+        apu.clock_frame_sequencer();
+        apu.write_reg(0xFF17, 0xF0);
+        apu.write_reg(0xFF19, 0xC0);
+        assert_eq!(apu.ch2.length_counter.counter, 63);
+    }
+
+    #[test]
+    fn clearing_negate_after_negated_calculation_disables_channel() {
+        let mut apu = powered_apu();
+        apu.write_reg(0xFF12, 0xF0);
+        apu.write_reg(0xFF10, 0x19); // 周期1, 減算, シフト1
+        apu.write_reg(0xFF13, 0x00);
+        apu.write_reg(0xFF14, 0x84);
+        assert!(channel_on(&apu, 0));
+        apu.write_reg(0xFF10, 0x11);
+        assert!(!channel_on(&apu, 0));
+    }
+
+    #[test]
+    fn sweep_overflow_on_trigger_disables_channel() {
+        let mut apu = powered_apu();
+        apu.write_reg(0xFF12, 0xF0);
+        apu.write_reg(0xFF10, 0x11); // 加算, シフト1
+        apu.write_reg(0xFF13, 0xFF);
+        apu.write_reg(0xFF14, 0x87);
+        assert!(!channel_on(&apu, 0));
+    }
+
+    #[test]
+    fn frame_sequencer_clocks_length_sweep_and_envelope() {
+        let mut apu = powered_apu();
+        apu.write_reg(0xFF12, 0xF1); // 音量15, 減少, 周期1
+        apu.write_reg(0xFF11, 62); // 残り2
+        apu.write_reg(0xFF14, 0xC0);
+        // ステップ0と2で長さが減る
+        apu.clock_frame_sequencer();
+        assert!(channel_on(&apu, 0));
+        apu.clock_frame_sequencer();
+        apu.clock_frame_sequencer();
+        assert!(!channel_on(&apu, 0));
+        // ステップ7でエンベロープが1回だけ進む
+        for _ in 3..8 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(apu.ch1.envelope.volume, 14);
+    }
+
+    #[test]
+    fn zombie_mode_envelope_writes() {
+        let mut apu = powered_apu();
+        // 周期0で動作中なら +1
+        apu.write_reg(0xFF17, 0x80);
+        apu.write_reg(0xFF19, 0x80);
+        apu.write_reg(0xFF17, 0x80);
+        assert_eq!(apu.ch2.envelope.volume, 9);
+        // 減少モードなら +2
+        apu.write_reg(0xFF17, 0x51);
+        apu.write_reg(0xFF19, 0x80);
+        apu.write_reg(0xFF17, 0x51);
+        assert_eq!(apu.ch2.envelope.volume, 7);
+    }
+
+    #[test]
+    fn power_off_clears_registers_but_keeps_length() {
+        let mut apu = powered_apu();
+        apu.write_reg(0xFF21, 0xF0);
+        apu.write_reg(0xFF20, 10);
+        apu.write_reg(0xFF26, 0x00);
+        assert_eq!(apu.read_reg(0xFF21), 0x00);
+        assert_eq!(apu.read_reg(0xFF26) & 0x80, 0);
+        // 電源オフ中もDMGでは長さカウンタに書き込める
+        apu.write_reg(0xFF20, 20);
+        apu.write_reg(0xFF26, 0x80);
+        assert_eq!(apu.ch4.length_counter.counter, 64 - 20);
+    }
+}
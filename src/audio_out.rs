@@ -0,0 +1,223 @@
+// src/audio_out.rs
+// 音声出力デバイスの選択とストリーム管理
+//
+// 出力デバイスが見つからない環境 (CIやヘッドレスサーバー) では、実時間の速度で
+// サンプルを読み捨てるヌル出力に自動で切り替える。これにより音声同期でも
+// エミュレーションは正しい速度で進む。
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+/// `--audio-device` にこの名前を指定するとヌル出力を使います。
+pub const NULL_DEVICE_NAME: &str = "null";
+// ヌル出力で使うサンプルレート
+const NULL_SAMPLE_RATE: u32 = 44_100;
+// ヌル出力がバッファを消費する間隔
+const NULL_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// 出力デバイスの選択設定。`None` の項目はデバイスの既定値を使います。
+#[derive(Debug, Clone, Default)]
+pub struct AudioOptions {
+    /// デバイス名 (部分一致、大文字小文字を区別しない)。"null" でヌル出力
+    pub device: Option<String>,
+    /// 出力バッファの遅延
+    pub latency: Option<Duration>,
+    pub sample_rate: Option<u32>,
+}
+
+enum Backend {
+    Device { device: cpal::Device, config: cpal::StreamConfig, format: cpal::SampleFormat },
+    Null,
+}
+
+/// 開いた出力先。`start` でサンプルバッファの再生を開始します。
+pub struct AudioOutput {
+    backend: Backend,
+    sample_rate: u32,
+    name: String,
+}
+
+/// 利用可能な出力デバイス名を一覧表示します。
+pub fn print_devices() {
+    let host = cpal::default_host();
+    let default_name = host.default_output_device().and_then(|device| device.name().ok());
+    println!("--- Audio Output Devices ({}) ---", host.id().name());
+    match host.output_devices() {
+        Ok(devices) => {
+            for name in devices.filter_map(|device| device.name().ok()) {
+                let marker = if Some(&name) == default_name.as_ref() { " (default)" } else { "" };
+                println!("  {}{}", name, marker);
+            }
+        }
+        Err(e) => eprintln!("Failed to enumerate audio devices: {}", e),
+    }
+    println!("  {} (no audio output)", NULL_DEVICE_NAME);
+}
+
+fn find_device(host: &cpal::Host, name: Option<&str>) -> Option<cpal::Device> {
+    let Some(name) = name else { return host.default_output_device(); };
+    let wanted = name.to_lowercase();
+    let found = host.output_devices().ok()?.find(|device| device.name().is_ok_and(|n| n.to_lowercase().contains(&wanted)));
+    if found.is_none() {
+        eprintln!("Audio device '{}' not found, using the default device.", name);
+        return host.default_output_device();
+    }
+    found
+}
+
+// 変換して出力できるサンプル形式。先頭ほど優先する
+const SAMPLE_FORMATS: [cpal::SampleFormat; 3] = [cpal::SampleFormat::F32, cpal::SampleFormat::I16, cpal::SampleFormat::U16];
+
+// 指定レートを扱える設定のうち、最も優先度の高いサンプル形式のものを探す
+fn find_supported(device: &cpal::Device, rate: u32) -> Option<cpal::SupportedStreamConfig> {
+    device.supported_output_configs().ok()?
+        .filter(|range| SAMPLE_FORMATS.contains(&range.sample_format()))
+        .filter(|range| range.min_sample_rate().0 <= rate && rate <= range.max_sample_rate().0)
+        .min_by_key(|range| SAMPLE_FORMATS.iter().position(|&format| format == range.sample_format()))
+        .map(|range| range.with_sample_rate(cpal::SampleRate(rate)))
+}
+
+// 対応するサンプル形式で、要求されたサンプルレートを扱える設定を選ぶ
+fn choose_config(device: &cpal::Device, options: &AudioOptions) -> Option<(cpal::StreamConfig, cpal::SampleFormat)> {
+    let default_config = device.default_output_config().ok()?;
+    let default_rate = default_config.sample_rate().0;
+    let requested = options.sample_rate.and_then(|rate| {
+        let found = find_supported(device, rate);
+        if found.is_none() {
+            eprintln!("Sample rate {} Hz is not supported by the device, using {} Hz.", rate, default_rate);
+        }
+        found
+    });
+    // 既定の設定が変換できない形式 (i32など) なら、同じレートで対応形式の設定を探す
+    let supported = requested
+        .or_else(|| SAMPLE_FORMATS.contains(&default_config.sample_format()).then(|| default_config.clone()))
+        .or_else(|| find_supported(device, default_rate))
+        .unwrap_or(default_config);
+
+    let mut config: cpal::StreamConfig = supported.clone().into();
+    if let Some(latency) = options.latency
+        && let cpal::SupportedBufferSize::Range { min, max } = supported.buffer_size() {
+        // デバイス側のバッファは遅延の半分とし、残りはエミュレータ側のキューで吸収する
+        let frames = (config.sample_rate.0 as f64 * latency.as_secs_f64() / 2.0) as u32;
+        config.buffer_size = cpal::BufferSize::Fixed(frames.clamp(*min, *max));
+    }
+    Some((config, supported.sample_format()))
+}
+
+impl AudioOutput {
+    /// 設定に従って出力先を開きます。デバイスが使えない場合はヌル出力になります。
+    pub fn open(options: &AudioOptions) -> Self {
+        let null = || Self { backend: Backend::Null, sample_rate: options.sample_rate.unwrap_or(NULL_SAMPLE_RATE), name: NULL_DEVICE_NAME.to_string() };
+        if options.device.as_deref().is_some_and(|name| name.eq_ignore_ascii_case(NULL_DEVICE_NAME)) {
+            return null();
+        }
+
+        let host = cpal::default_host();
+        let Some(device) = find_device(&host, options.device.as_deref()) else {
+            eprintln!("No audio output device available, audio is disabled.");
+            return null();
+        };
+        let Some((config, format)) = choose_config(&device, options) else {
+            eprintln!("Failed to get an output config for the audio device, audio is disabled.");
+            return null();
+        };
+        let name = device.name().unwrap_or_else(|_| "unknown".to_string());
+        Self { sample_rate: config.sample_rate.0, backend: Backend::Device { device, config, format }, name }
+    }
+
+    pub fn sample_rate(&self) -> u32 { self.sample_rate }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    pub fn is_null(&self) -> bool { matches!(self.backend, Backend::Null) }
+
+    /// `sample_buffer` の再生を開始します。返り値をドロップすると停止します。
+    /// デバイスのストリーム作成に失敗した場合もヌル出力で再生を続けます。
+    pub fn start(self, sample_buffer: Arc<Mutex<VecDeque<(f32, f32)>>>) -> AudioStream {
+        if let Backend::Device { device, config, format } = &self.backend {
+            let stream = match format {
+                cpal::SampleFormat::F32 => build_device_stream::<f32>(device, config, sample_buffer.clone()),
+                cpal::SampleFormat::I16 => build_device_stream::<i16>(device, config, sample_buffer.clone()),
+                cpal::SampleFormat::U16 => build_device_stream::<u16>(device, config, sample_buffer.clone()),
+                other => Err(format!("unsupported sample format {}", other).into()),
+            };
+            match stream {
+                Ok(stream) => return AudioStream::Device(stream),
+                Err(e) => eprintln!("Failed to start audio stream ({}), audio is disabled.", e),
+            }
+        }
+        AudioStream::Null(NullSink::start(sample_buffer, self.sample_rate))
+    }
+}
+
+// エミュレータ側のf32サンプルをデバイスのサンプル形式 `T` に変換して出力する
+fn build_device_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, sample_buffer: Arc<Mutex<VecDeque<(f32, f32)>>>) -> Result<cpal::Stream, Box<dyn std::error::Error>>
+where
+    T: cpal::SizedSample + cpal::FromSample<f32>,
+{
+    let channels = config.channels.max(1) as usize;
+    // アンダーラン時は無音ではなく直前のサンプルを保持してクリックノイズを防ぐ
+    let mut last_sample = (0.0, 0.0);
+    let stream = device.build_output_stream(config, move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+        let mut buffer = sample_buffer.lock().unwrap();
+        for frame in data.chunks_mut(channels) {
+            let (l, r) = buffer.pop_front().unwrap_or(last_sample);
+            last_sample = (l, r);
+            match frame {
+                [mono] => *mono = T::from_sample((l + r) * 0.5),
+                [left, right, rest @ ..] => { *left = T::from_sample(l); *right = T::from_sample(r); rest.fill(T::EQUILIBRIUM); }
+                [] => (),
+            }
+        }
+    }, |err| eprintln!("an error occurred on stream: {}", err), None)?;
+    stream.play()?;
+    Ok(stream)
+}
+
+/// 再生中の出力。ドロップすると再生を停止します。
+pub enum AudioStream {
+    Device(cpal::Stream),
+    Null(NullSink),
+}
+
+/// 実時間に合わせてサンプルバッファを消費するだけの出力
+pub struct NullSink {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NullSink {
+    fn start(sample_buffer: Arc<Mutex<VecDeque<(f32, f32)>>>, sample_rate: u32) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let thread = thread::spawn(move || {
+            let start = Instant::now();
+            let mut consumed: u64 = 0;
+            while thread_running.load(Ordering::Relaxed) {
+                thread::sleep(NULL_POLL_INTERVAL);
+                // 経過時間から求めた消費量との差分だけ取り出すので、スリープの誤差が蓄積しない
+                let due = (start.elapsed().as_secs_f64() * sample_rate as f64) as u64;
+                let count = (due - consumed) as usize;
+                let mut buffer = sample_buffer.lock().unwrap();
+                let available = count.min(buffer.len());
+                buffer.drain(..available);
+                consumed = due;
+            }
+        });
+        Self { running, thread: Some(thread) }
+    }
+}
+
+impl Drop for NullSink {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
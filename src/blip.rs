@@ -0,0 +1,108 @@
+// src/blip.rs
+// 帯域制限ステップ合成 (blip_buf 方式) によるリサンプラ
+//
+// チャンネル出力の「変化量 (デルタ)」を、CPUクロック単位の正確な時刻とともに
+// 窓付きsincインパルスとして出力サンプル列へ書き込み、読み出し時に積分することで
+// エイリアシングのないステップ波形を得る。時刻計算はすべて整数で行うため、
+// CPUクロックと出力サンプルレートの比が割り切れなくても誤差が蓄積しない。
+
+const PHASES: usize = 32;
+const HALF_WIDTH: usize = 8;
+const KERNEL_WIDTH: usize = HALF_WIDTH * 2;
+// 出力サンプルレートに対するカットオフ周波数の比 (ナイキスト周波数 = 0.5)
+const CUTOFF: f64 = 0.45;
+
+pub struct BlipBuf {
+    clock_rate: u64,
+    sample_rate: u64,
+    // 読み出し位置からの経過時間 (単位: 1/clock_rate 出力サンプル)
+    offset: u64,
+    buffer: Vec<f32>,
+    integrator: f32,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+}
+
+fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    let mut kernel = vec![[0.0f32; KERNEL_WIDTH]; PHASES + 1];
+    for (phase, taps) in kernel.iter_mut().enumerate() {
+        let frac = phase as f64 / PHASES as f64;
+        let mut values = [0.0f64; KERNEL_WIDTH];
+        for (k, value) in values.iter_mut().enumerate() {
+            let x = k as f64 - (HALF_WIDTH - 1) as f64 - frac;
+            let sinc = if x == 0.0 { 1.0 } else { (std::f64::consts::PI * 2.0 * CUTOFF * x).sin() / (std::f64::consts::PI * 2.0 * CUTOFF * x) };
+            // Blackman窓
+            let w = x / HALF_WIDTH as f64;
+            let window = if w.abs() >= 1.0 { 0.0 } else { 0.42 + 0.5 * (std::f64::consts::PI * w).cos() + 0.08 * (2.0 * std::f64::consts::PI * w).cos() };
+            *value = sinc * window;
+        }
+        // 各位相の合計を1に正規化し、積分後のステップの高さを正確にする
+        let sum: f64 = values.iter().sum();
+        for (tap, value) in taps.iter_mut().zip(values.iter()) {
+            *tap = (value / sum) as f32;
+        }
+    }
+    kernel
+}
+
+impl BlipBuf {
+    pub fn new(clock_rate: u64, sample_rate: u64) -> Self {
+        Self {
+            clock_rate,
+            sample_rate,
+            offset: 0,
+            buffer: vec![0.0; KERNEL_WIDTH],
+            integrator: 0.0,
+            kernel: build_kernel(),
+        }
+    }
+
+    /// 入力クロックレートを変更します (出力サンプル数の微調整に使用)。
+    pub fn set_clock_rate(&mut self, clock_rate: u64) {
+        if clock_rate == 0 || clock_rate == self.clock_rate { return; }
+        // 端数位置を新しいレートに換算する
+        let samples = self.offset / self.clock_rate;
+        let frac = self.offset % self.clock_rate;
+        self.offset = samples * clock_rate + frac * clock_rate / self.clock_rate;
+        self.clock_rate = clock_rate;
+    }
+
+    pub fn sample_rate(&self) -> u64 { self.sample_rate }
+
+    /// 現在のフレーム先頭から `clock_time` クロック後に振幅が `delta` だけ変化したことを記録します。
+    pub fn add_delta(&mut self, clock_time: u32, delta: f32) {
+        let pos = self.offset + clock_time as u64 * self.sample_rate;
+        let index = (pos / self.clock_rate) as usize;
+        let phase = (((pos % self.clock_rate) * PHASES as u64 + self.clock_rate / 2) / self.clock_rate) as usize;
+        if self.buffer.len() < index + KERNEL_WIDTH {
+            self.buffer.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        for (sample, tap) in self.buffer[index..index + KERNEL_WIDTH].iter_mut().zip(self.kernel[phase].iter()) {
+            *sample += delta * tap;
+        }
+    }
+
+    /// `clocks` クロック分の時間を確定させ、読み出し可能なサンプルを増やします。
+    pub fn end_frame(&mut self, clocks: u32) {
+        self.offset += clocks as u64 * self.sample_rate;
+    }
+
+    pub fn samples_avail(&self) -> usize {
+        (self.offset / self.clock_rate) as usize
+    }
+
+    /// 確定済みのサンプルを `out` に読み出し、読み出した数を返します。
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.samples_avail());
+        if self.buffer.len() < count + KERNEL_WIDTH {
+            self.buffer.resize(count + KERNEL_WIDTH, 0.0);
+        }
+        for (sample, delta) in out.iter_mut().zip(self.buffer.iter()).take(count) {
+            self.integrator += delta;
+            *sample = self.integrator;
+        }
+        self.buffer.drain(..count);
+        self.buffer.resize(self.buffer.len().max(KERNEL_WIDTH), 0.0);
+        self.offset -= count as u64 * self.clock_rate;
+        count
+    }
+}
@@ -1,14 +1,210 @@
 use std::fs::File;
 use std::io::{self, Read};
 use std::path::Path;
+use serde::Serialize;
+
+/// CGBフラグ ($0143) の解釈
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CgbSupport {
+    /// DMG専用 (CGB機能なし)
+    None,
+    /// CGB拡張対応 (DMGでも動作) - $80
+    Enhanced,
+    /// CGB専用 - $C0
+    Only,
+}
+
+/// 仕向地コード ($014A)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Destination {
+    Japan,
+    Overseas,
+    Unknown(u8),
+}
+
+/// カートリッジヘッダ ($0100-$014F) を構造化したもの
+#[derive(Debug, Clone, Serialize)]
+pub struct CartridgeHeader {
+    pub title: String,
+    /// 製造者コード ($013F-$0142)。新しいカートリッジのみ存在する
+    pub manufacturer_code: Option<String>,
+    pub cgb_flag: u8,
+    pub cgb_support: CgbSupport,
+    /// 新ライセンシーコード ($0144-$0145)。旧コードが$33の場合のみ有効
+    pub new_licensee_code: String,
+    pub sgb_flag: u8,
+    pub sgb_support: bool,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub destination_code: u8,
+    pub destination: Destination,
+    pub old_licensee_code: u8,
+    /// 新旧コードを解決したライセンシー名
+    pub licensee: String,
+    pub mask_rom_version: u8,
+    pub header_checksum: u8,
+    pub header_checksum_valid: bool,
+    pub global_checksum: u16,
+    pub global_checksum_valid: bool,
+}
+
+impl CartridgeHeader {
+    /// ROMデータからヘッダを解析します。呼び出し側で$0150バイト以上あることを保証してください。
+    pub fn parse(raw_data: &[u8]) -> Self {
+        let cgb_flag = raw_data[0x0143];
+        let cgb_support = match cgb_flag {
+            0x80 => CgbSupport::Enhanced,
+            0xC0 => CgbSupport::Only,
+            _ => CgbSupport::None,
+        };
+        let old_licensee_code = raw_data[0x014B];
+
+        // CGB世代のカートリッジはタイトル末尾4バイトを製造者コードとして使う場合がある。
+        // 明確なフラグはないため、旧ライセンシーが$33で4バイトが英大文字/数字のときのみ製造者コードとみなす。
+        let manufacturer_bytes = &raw_data[0x013F..=0x0142];
+        let has_manufacturer_code = cgb_support != CgbSupport::None
+            && old_licensee_code == 0x33
+            && manufacturer_bytes.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit());
+        let title_end = if has_manufacturer_code { 0x013F } else if cgb_support != CgbSupport::None { 0x0143 } else { 0x0144 };
+        let title = raw_data[0x0134..title_end].iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        let manufacturer_code = if has_manufacturer_code {
+            Some(manufacturer_bytes.iter().map(|&b| b as char).collect())
+        } else {
+            None
+        };
+
+        let new_licensee_code: String = raw_data[0x0144..=0x0145].iter()
+            .map(|&b| if b.is_ascii_graphic() { b as char } else { '?' })
+            .collect();
+        let licensee = if old_licensee_code == 0x33 {
+            new_licensee_name(&new_licensee_code)
+        } else {
+            old_licensee_name(old_licensee_code)
+        };
+
+        let sgb_flag = raw_data[0x0146];
+        let destination_code = raw_data[0x014A];
+        let destination = match destination_code {
+            0x00 => Destination::Japan,
+            0x01 => Destination::Overseas,
+            code => Destination::Unknown(code),
+        };
+
+        let header_checksum = raw_data[0x014D];
+        let computed_header_checksum = raw_data[0x0134..=0x014C].iter()
+            .fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1));
+        let global_checksum = u16::from_be_bytes([raw_data[0x014E], raw_data[0x014F]]);
+        let computed_global_checksum = raw_data.iter().enumerate()
+            .filter(|&(i, _)| i != 0x014E && i != 0x014F)
+            .fold(0u16, |acc, (_, &b)| acc.wrapping_add(b as u16));
+
+        Self {
+            title,
+            manufacturer_code,
+            cgb_flag,
+            cgb_support,
+            new_licensee_code,
+            sgb_flag,
+            // SGB機能は旧ライセンシーコードが$33のときのみ有効になる
+            sgb_support: sgb_flag == 0x03 && old_licensee_code == 0x33,
+            cartridge_type: raw_data[0x0147],
+            rom_size: raw_data[0x0148],
+            ram_size: raw_data[0x0149],
+            destination_code,
+            destination,
+            old_licensee_code,
+            licensee,
+            mask_rom_version: raw_data[0x014C],
+            header_checksum,
+            header_checksum_valid: header_checksum == computed_header_checksum,
+            global_checksum,
+            global_checksum_valid: global_checksum == computed_global_checksum,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("CartridgeHeader is always serializable")
+    }
+}
+
+fn new_licensee_name(code: &str) -> String {
+    let name = match code {
+        "00" => "None", "01" => "Nintendo R&D1", "08" => "Capcom", "13" => "Electronic Arts",
+        "18" => "Hudson Soft", "19" => "B-AI", "20" => "KSS", "22" => "Planning Office WADA",
+        "24" => "PCM Complete", "25" => "San-X", "28" => "Kemco", "29" => "SETA Corporation",
+        "30" => "Viacom", "31" => "Nintendo", "32" => "Bandai", "33" => "Ocean Software/Acclaim Entertainment",
+        "34" => "Konami", "35" => "HectorSoft", "37" => "Taito", "38" => "Hudson Soft",
+        "39" => "Banpresto", "41" => "Ubi Soft", "42" => "Atlus", "44" => "Malibu Interactive",
+        "46" => "Angel", "47" => "Bullet-Proof Software", "49" => "Irem", "50" => "Absolute",
+        "51" => "Acclaim Entertainment", "52" => "Activision", "53" => "Sammy USA Corporation", "54" => "Konami",
+        "55" => "Hi Tech Expressions", "56" => "LJN", "57" => "Matchbox", "58" => "Mattel",
+        "59" => "Milton Bradley Company", "60" => "Titus Interactive", "61" => "Virgin Games Ltd.", "64" => "Lucasfilm Games",
+        "67" => "Ocean Software", "69" => "Electronic Arts", "70" => "Infogrames", "71" => "Interplay Entertainment",
+        "72" => "Broderbund", "73" => "Sculptured Software", "75" => "The Sales Curve Limited", "78" => "THQ",
+        "79" => "Accolade", "80" => "Misawa Entertainment", "83" => "LOZC G.", "86" => "Tokuma Shoten",
+        "87" => "Tsukuda Original", "91" => "Chunsoft Co.", "92" => "Video System", "93" => "Ocean Software/Acclaim Entertainment",
+        "95" => "Varie", "96" => "Yonezawa/S'Pal", "97" => "Kaneko", "99" => "Pack-In-Video",
+        "9H" => "Bottom Up", "A4" => "Konami (Yu-Gi-Oh!)", "BL" => "MTO", "DK" => "Kodansha",
+        _ => return format!("Unknown ({})", code),
+    };
+    name.to_string()
+}
+
+fn old_licensee_name(code: u8) -> String {
+    let name = match code {
+        0x00 => "None", 0x01 => "Nintendo", 0x08 => "Capcom", 0x09 => "HOT-B",
+        0x0A => "Jaleco", 0x0B => "Coconuts Japan", 0x0C => "Elite Systems", 0x13 => "Electronic Arts",
+        0x18 => "Hudson Soft", 0x19 => "ITC Entertainment", 0x1A => "Yanoman", 0x1D => "Japan Clary",
+        0x1F => "Virgin Games Ltd.", 0x24 => "PCM Complete", 0x25 => "San-X", 0x28 => "Kemco",
+        0x29 => "SETA Corporation", 0x30 => "Infogrames", 0x31 => "Nintendo", 0x32 => "Bandai",
+        0x34 => "Konami", 0x35 => "HectorSoft", 0x38 => "Capcom", 0x39 => "Banpresto",
+        0x3C => "Entertainment Interactive", 0x3E => "Gremlin", 0x41 => "Ubi Soft", 0x42 => "Atlus",
+        0x44 => "Malibu Interactive", 0x46 => "Angel", 0x47 => "Spectrum HoloByte", 0x49 => "Irem",
+        0x4A => "Virgin Games Ltd.", 0x4D => "Malibu Interactive", 0x4F => "U.S. Gold", 0x50 => "Absolute",
+        0x51 => "Acclaim Entertainment", 0x52 => "Activision", 0x53 => "Sammy USA Corporation", 0x54 => "GameTek",
+        0x55 => "Park Place", 0x56 => "LJN", 0x57 => "Matchbox", 0x59 => "Milton Bradley Company",
+        0x5A => "Mindscape", 0x5B => "Romstar", 0x5C => "Naxat Soft", 0x5D => "Tradewest",
+        0x60 => "Titus Interactive", 0x61 => "Virgin Games Ltd.", 0x67 => "Ocean Software", 0x69 => "Electronic Arts",
+        0x6E => "Elite Systems", 0x6F => "Electro Brain", 0x70 => "Infogrames", 0x71 => "Interplay Entertainment",
+        0x72 => "Broderbund", 0x73 => "Sculptured Software", 0x75 => "The Sales Curve Limited", 0x78 => "THQ",
+        0x79 => "Accolade", 0x7A => "Triffix Entertainment", 0x7C => "MicroProse", 0x7F => "Kemco",
+        0x80 => "Misawa Entertainment", 0x83 => "LOZC G.", 0x86 => "Tokuma Shoten", 0x8B => "Bullet-Proof Software",
+        0x8C => "Vic Tokai Corp.", 0x8E => "Ape Inc.", 0x8F => "I'Max", 0x91 => "Chunsoft Co.",
+        0x92 => "Video System", 0x93 => "Tsubaraya Productions", 0x95 => "Varie", 0x96 => "Yonezawa/S'Pal",
+        0x97 => "Kemco", 0x99 => "Arc", 0x9A => "Nihon Bussan", 0x9B => "Tecmo",
+        0x9C => "Imagineer", 0x9D => "Banpresto", 0x9F => "Nova", 0xA1 => "Hori Electric",
+        0xA2 => "Bandai", 0xA4 => "Konami", 0xA6 => "Kawada", 0xA7 => "Takara",
+        0xA9 => "Technos Japan", 0xAA => "Broderbund", 0xAC => "Toei Animation", 0xAD => "Toho",
+        0xAF => "Namco", 0xB0 => "Acclaim Entertainment", 0xB1 => "ASCII Corporation or Nexsoft", 0xB2 => "Bandai",
+        0xB4 => "Square Enix", 0xB6 => "HAL Laboratory", 0xB7 => "SNK", 0xB9 => "Pony Canyon",
+        0xBA => "Culture Brain", 0xBB => "Sunsoft", 0xBD => "Sony Imagesoft", 0xBF => "Sammy Corporation",
+        0xC0 => "Taito", 0xC2 => "Kemco", 0xC3 => "Square", 0xC4 => "Tokuma Shoten",
+        0xC5 => "Data East", 0xC6 => "Tonkin House", 0xC8 => "Koei", 0xC9 => "UFL",
+        0xCA => "Ultra Games", 0xCB => "VAP, Inc.", 0xCC => "Use Corporation", 0xCD => "Meldac",
+        0xCE => "Pony Canyon", 0xCF => "Angel", 0xD0 => "Taito", 0xD1 => "SOFEL",
+        0xD2 => "Quest", 0xD3 => "Sigma Enterprises", 0xD4 => "ASK Kodansha Co.", 0xD6 => "Naxat Soft",
+        0xD7 => "Copya System", 0xD9 => "Banpresto", 0xDA => "Tomy", 0xDB => "LJN",
+        0xDD => "Nippon Computer Systems", 0xDE => "Human Ent.", 0xDF => "Altron", 0xE0 => "Jaleco",
+        0xE1 => "Towa Chiki", 0xE2 => "Yutaka", 0xE3 => "Varie", 0xE5 => "Epoch",
+        0xE7 => "Athena", 0xE8 => "Asmik Ace Entertainment", 0xE9 => "Natsume", 0xEA => "King Records",
+        0xEB => "Atlus", 0xEC => "Epic/Sony Records", 0xEE => "IGS", 0xF0 => "A Wave",
+        0xF3 => "Extreme Entertainment", 0xFF => "LJN",
+        _ => return format!("Unknown ({:#04x})", code),
+    };
+    name.to_string()
+}
 
 #[derive(Debug)]
 pub struct Cartridge {
     pub raw_data: Vec<u8>,
-    pub title: String,
-    pub cartridge_type_code: u8,
-    pub rom_size_code: u8,
-    pub ram_size_code: u8,
+    /// タイトルや種類などの情報はすべてヘッダから読む
+    pub header: CartridgeHeader,
 }
 
 impl Cartridge {
@@ -16,42 +212,39 @@ impl Cartridge {
         let mut file = File::open(path)?;
         let mut raw_data = Vec::new();
         file.read_to_end(&mut raw_data)?;
+        Self::from_bytes(raw_data)
+    }
 
+    /// メモリ上のROMイメージからカートリッジを作成します。
+    pub fn from_bytes(raw_data: Vec<u8>) -> io::Result<Self> {
         if raw_data.len() < 0x014F + 1 {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "ROM file is too small to contain a valid header."));
         }
 
-        let title_bytes = &raw_data[0x0134..=0x0143];
-        let title = title_bytes.iter()
-            .take_while(|&&byte| byte != 0)
-            .map(|&byte| byte as char)
-            .collect::<String>()
-            .trim_end_matches('\0')
-            .to_string();
-
-        let cartridge_type_code = raw_data[0x0147];
-        let rom_size_code = raw_data[0x0148];
-        let ram_size_code = raw_data[0x0149];
-
-        Ok(Self {
-            raw_data,
-            title,
-            cartridge_type_code,
-            rom_size_code,
-            ram_size_code,
-        })
+        let header = CartridgeHeader::parse(&raw_data);
+        Ok(Self { raw_data, header })
     }
 
     // ★ ここから追加 ★
     /// カートリッジがバッテリーバックアップを持っているか判定します。
     pub fn has_battery(&self) -> bool {
-        matches!(self.cartridge_type_code,
+        matches!(self.header.cartridge_type,
             0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF)
     }
     // ★ ここまで追加 ★
 
+    /// カートリッジが振動モーター (MBC5+RUMBLE) を持っているか判定します。
+    pub fn has_rumble(&self) -> bool {
+        matches!(self.header.cartridge_type, 0x1C..=0x1E)
+    }
+
+    /// カートリッジがリアルタイムクロック (MBC3+TIMER) を持っているか判定します。
+    pub fn has_rtc(&self) -> bool {
+        matches!(self.header.cartridge_type, 0x0F | 0x10)
+    }
+
     pub fn cartridge_type_name(&self) -> String {
-        match self.cartridge_type_code {
+        match self.header.cartridge_type {
             0x00 => "ROM ONLY".to_string(),
             0x01 => "MBC1".to_string(),
             0x02 => "MBC1+RAM".to_string(),
@@ -80,35 +273,140 @@ impl Cartridge {
             0xFD => "BANDAI TAMA5".to_string(),
             0xFE => "HuC3".to_string(),
             0xFF => "HuC1+RAM+BATTERY".to_string(),
-            _ => format!("Unknown ({:#04x})", self.cartridge_type_code),
+            _ => format!("Unknown ({:#04x})", self.header.cartridge_type),
         }
     }
 
     pub fn rom_size_str(&self) -> String {
-        if self.rom_size_code <= 0x08 {
-            format!("{} KB", 32 * (1 << self.rom_size_code))
+        if self.header.rom_size <= 0x08 {
+            format!("{} KB", 32 * (1 << self.header.rom_size))
         } else {
-            format!("Unknown code ({:#04x})", self.rom_size_code)
+            format!("Unknown code ({:#04x})", self.header.rom_size)
         }
     }
 
     pub fn ram_size_str(&self) -> String {
-        match self.ram_size_code {
+        match self.header.ram_size {
             0x00 => "None".to_string(),
             0x01 => "2 KB (Unofficial)".to_string(),
             0x02 => "8 KB".to_string(),
             0x03 => "32 KB (4 banks of 8KB)".to_string(),
             0x04 => "128 KB (16 banks of 8KB)".to_string(),
             0x05 => "64 KB (8 banks of 8KB)".to_string(),
-            _ => format!("Unknown code ({:#04x})", self.ram_size_code),
+            _ => format!("Unknown code ({:#04x})", self.header.ram_size),
         }
     }
 
     pub fn print_header_info(&self) {
         println!("--- Cartridge Header Info ---");
-        println!("Title: {}", self.title);
-        println!("Cartridge Type: {} ({:#04x})", self.cartridge_type_name(), self.cartridge_type_code);
-        println!("ROM Size: {} ({:#04x})", self.rom_size_str(), self.rom_size_code);
-        println!("RAM Size: {} ({:#04x})", self.ram_size_str(), self.ram_size_code);
+        println!("Title: {}", self.header.title);
+        println!("Cartridge Type: {} ({:#04x})", self.cartridge_type_name(), self.header.cartridge_type);
+        println!("ROM Size: {} ({:#04x})", self.rom_size_str(), self.header.rom_size);
+        println!("RAM Size: {} ({:#04x})", self.ram_size_str(), self.header.ram_size);
+        let header = &self.header;
+        if let Some(code) = &header.manufacturer_code {
+            println!("Manufacturer Code: {}", code);
+        }
+        println!("CGB Flag: {:?} ({:#04x})", header.cgb_support, header.cgb_flag);
+        println!("SGB Flag: {} ({:#04x})", if header.sgb_support { "Supported" } else { "Not supported" }, header.sgb_flag);
+        if header.old_licensee_code == 0x33 {
+            println!("Licensee: {} (new code \"{}\")", header.licensee, header.new_licensee_code);
+        } else {
+            println!("Licensee: {} (old code {:#04x})", header.licensee, header.old_licensee_code);
+        }
+        println!("Destination: {:?} ({:#04x})", header.destination, header.destination_code);
+        println!("Mask ROM Version: {}", header.mask_rom_version);
+        println!("Header Checksum: {:#04x} ({})", header.header_checksum, if header.header_checksum_valid { "OK" } else { "MISMATCH" });
+        println!("Global Checksum: {:#06x} ({})", header.global_checksum, if header.global_checksum_valid { "OK" } else { "MISMATCH" });
+    }
+
+    pub fn header_json(&self) -> String {
+        self.header.to_json()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// タイトルと種類・サイズのコードを入れ、両方のチェックサムを正しく計算した32KBのROM
+    fn rom_with_header(title: &[u8], cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x0147] = cartridge_type;
+        rom[0x0148] = rom_size;
+        rom[0x0149] = ram_size;
+        rom[0x014D] = rom[0x0134..=0x014C].iter().fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1));
+        let global = rom.iter().fold(0u16, |acc, &b| acc.wrapping_add(b as u16));
+        rom[0x014E..=0x014F].copy_from_slice(&global.to_be_bytes());
+        rom
+    }
+
+    #[test]
+    fn parses_title_and_codes() {
+        let cartridge = Cartridge::from_bytes(rom_with_header(b"TETRIS", 0x13, 0x05, 0x03)).unwrap();
+        assert_eq!(cartridge.header.title, "TETRIS");
+        assert_eq!(cartridge.header.cartridge_type, 0x13);
+        assert_eq!(cartridge.cartridge_type_name(), "MBC3+RAM+BATTERY");
+        assert_eq!(cartridge.rom_size_str(), "1024 KB");
+        assert_eq!(cartridge.ram_size_str(), "32 KB (4 banks of 8KB)");
+        assert!(cartridge.has_battery());
+        assert!(!cartridge.has_rtc());
+    }
+
+    #[test]
+    fn unknown_size_codes_are_reported() {
+        let cartridge = Cartridge::from_bytes(rom_with_header(b"X", 0x00, 0x52, 0x09)).unwrap();
+        assert_eq!(cartridge.rom_size_str(), "Unknown code (0x52)");
+        assert_eq!(cartridge.ram_size_str(), "Unknown code (0x09)");
+    }
+
+    #[test]
+    fn title_stops_before_cgb_flag_and_manufacturer_code() {
+        // DMG専用なら16バイトすべてがタイトル
+        let header = CartridgeHeader::parse(&rom_with_header(b"ABCDEFGHIJKLMNOP", 0x00, 0x00, 0x00));
+        assert_eq!(header.title, "ABCDEFGHIJKLMNOP");
+
+        // CGB対応で旧ライセンシーが$33なら末尾4バイトは製造者コード
+        let mut rom = rom_with_header(b"POKEMON Y", 0x1B, 0x06, 0x03);
+        rom[0x013F..=0x0142].copy_from_slice(b"APSE");
+        rom[0x0143] = 0x80;
+        rom[0x014B] = 0x33;
+        rom[0x0144..=0x0145].copy_from_slice(b"01");
+        let header = CartridgeHeader::parse(&rom);
+        assert_eq!(header.title, "POKEMON Y");
+        assert_eq!(header.manufacturer_code.as_deref(), Some("APSE"));
+        assert_eq!(header.cgb_support, CgbSupport::Enhanced);
+        assert_eq!(header.licensee, "Nintendo R&D1");
+    }
+
+    #[test]
+    fn checksums_are_verified() {
+        let mut rom = rom_with_header(b"CHECK", 0x01, 0x00, 0x00);
+        let header = CartridgeHeader::parse(&rom);
+        assert!(header.header_checksum_valid);
+        assert!(header.global_checksum_valid);
+
+        // ヘッダの範囲のバイトを変えると両方、それ以外を変えるとグローバルだけが合わなくなる
+        rom[0x0200] = 0xAA;
+        let header = CartridgeHeader::parse(&rom);
+        assert!(header.header_checksum_valid);
+        assert!(!header.global_checksum_valid);
+        rom[0x0140] = 0x01;
+        assert!(!CartridgeHeader::parse(&rom).header_checksum_valid);
+    }
+
+    #[test]
+    fn sgb_support_needs_new_licensee_code() {
+        let mut rom = rom_with_header(b"SGB", 0x00, 0x00, 0x00);
+        rom[0x0146] = 0x03;
+        assert!(!CartridgeHeader::parse(&rom).sgb_support);
+        rom[0x014B] = 0x33;
+        assert!(CartridgeHeader::parse(&rom).sgb_support);
+    }
+
+    #[test]
+    fn rejects_truncated_rom() {
+        assert!(Cartridge::from_bytes(vec![0; 0x100]).is_err());
     }
 }
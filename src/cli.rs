@@ -0,0 +1,300 @@
+// src/cli.rs
+// コマンドライン引数の定義 (clap)
+//
+// 旧形式の呼び出し (`<rom>` だけ、`--header-json <rom>` など) は normalize_legacy_args で
+// サブコマンドの形に読み替える。
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+
+use rust_gb_emulator::conformance;
+use rust_gb_emulator::headless::{HeadlessOptions, Model};
+use rust_gb_emulator::ppu;
+use rust_gb_emulator::regression::{self, ScriptRun};
+
+#[derive(Parser)]
+#[command(name = "rust_gb_emulator", version, about = "Game Boy emulator")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run a ROM (or a .gbs music file) in a window
+    Run(RunArgs),
+    /// Print the cartridge header
+    Info {
+        rom: PathBuf,
+        /// Print the header as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run a test ROM headlessly and report the result (Blargg / Mooneye)
+    Test(TestArgs),
+    /// Run a ROM headlessly for some frames and save the screen as PNG
+    Screenshot(ScreenshotArgs),
+    /// Run a ROM headlessly and compare the screen with a golden PNG
+    Compare(CompareArgs),
+    /// Run every test ROM in a directory and print a pass/fail table
+    Conformance(ConformanceArgs),
+    /// Run a ROM headlessly under a Rhai script
+    Script(AutomationArgs),
+    /// List the audio output devices
+    AudioDevices,
+    /// Print the effective configuration as TOML
+    PrintConfig {
+        /// Config file to load instead of the default locations
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ModelArg {
+    Dmg,
+    /// Not emulated yet; rejected with an error
+    Cgb,
+    Sgb,
+}
+
+/// エミュレートする本体の指定 (run / test / screenshot 共通)
+#[derive(Args)]
+pub struct SystemArgs {
+    /// 256-byte DMG/SGB boot ROM to start from
+    #[arg(long, value_name = "FILE")]
+    pub boot_rom: Option<PathBuf>,
+    /// Hardware model (default: SGB if the cartridge supports it, otherwise DMG)
+    #[arg(long, value_enum)]
+    pub model: Option<ModelArg>,
+}
+
+impl SystemArgs {
+    pub fn headless_options(&self) -> io::Result<HeadlessOptions> {
+        let model = match self.model {
+            None => Model::Auto,
+            Some(ModelArg::Dmg) => Model::Dmg,
+            Some(ModelArg::Sgb) => Model::Sgb,
+            Some(ModelArg::Cgb) => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "CGB mode is not emulated yet; use --model dmg or --model sgb"));
+            }
+        };
+        let boot_rom = match &self.boot_rom {
+            Some(path) => Some(fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("failed to read boot ROM {}: {}", path.display(), e)))?),
+            None => None,
+        };
+        Ok(HeadlessOptions { model, boot_rom })
+    }
+}
+
+#[derive(Args)]
+pub struct RunArgs {
+    /// ROM file (.gb) or GBS music file (.gbs)
+    pub rom: PathBuf,
+    #[command(flatten)]
+    pub system: SystemArgs,
+    /// Config file to load instead of the default locations
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Window scale
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub scale: Option<u32>,
+    /// Display palette
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..ppu::PALETTES.len() as i64))]
+    pub palette: Option<u32>,
+    /// Disable audio output (same as --audio-device null)
+    #[arg(long)]
+    pub mute: bool,
+    /// Save state to load at startup; the save/load state hotkeys also use this file
+    #[arg(long, value_name = "FILE")]
+    pub state: Option<PathBuf>,
+    /// Directory for battery saves (.sav)
+    #[arg(long, value_name = "DIR")]
+    pub save_dir: Option<PathBuf>,
+    /// Connect a Game Boy Printer to the link port
+    #[arg(long)]
+    pub printer: bool,
+    /// Frame pacing: audio | video | fixed-rate
+    #[arg(long)]
+    pub sync: Option<String>,
+    /// Display rate in Hz for --sync fixed-rate; set it manually, it is not detected from the monitor
+    #[arg(long, value_name = "HZ")]
+    pub display_rate: Option<f64>,
+    /// Emulation speed, e.g. 0.5, 200%, uncapped
+    #[arg(long)]
+    pub speed: Option<String>,
+    /// Rhai script with frame, exec and memory-write hooks
+    #[arg(long, value_name = "FILE")]
+    pub script: Option<PathBuf>,
+
+    /// Audio device name (substring match) or "null"
+    #[arg(long, value_name = "NAME", help_heading = "Audio")]
+    pub audio_device: Option<String>,
+    /// Audio buffer latency in milliseconds
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u32).range(1..), help_heading = "Audio")]
+    pub audio_latency: Option<u32>,
+    /// Audio sample rate in Hz
+    #[arg(long, value_name = "HZ", value_parser = clap::value_parser!(u32).range(1..), help_heading = "Audio")]
+    pub sample_rate: Option<u32>,
+
+    /// Video recording format
+    #[arg(long, value_enum, default_value_t = VideoFormatArg::Gif, help_heading = "Video recording")]
+    pub video_format: VideoFormatArg,
+    /// Video recording scale
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..=8), help_heading = "Video recording")]
+    pub video_scale: u32,
+    /// Palette for video recording (default: the display colors)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..ppu::PALETTES.len() as i64), help_heading = "Video recording")]
+    pub video_palette: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VideoFormatArg {
+    Gif,
+    Png,
+}
+
+#[derive(Args)]
+pub struct TestArgs {
+    pub rom: PathBuf,
+    #[command(flatten)]
+    pub system: SystemArgs,
+    /// Give up after this many seconds of emulated time
+    #[arg(long, value_name = "SECONDS", default_value_t = 30.0)]
+    pub timeout: f64,
+}
+
+/// ヘッドレス実行の手順 (screenshot / compare 共通)
+#[derive(Args)]
+pub struct ScriptArgs {
+    /// Number of frames to run before taking the screenshot
+    #[arg(long, default_value_t = 60)]
+    pub frames: u64,
+    /// Scripted input, e.g. "Start:2, :60, A+B:5" (buttons:frames; ":N" waits)
+    #[arg(long, value_name = "STEPS")]
+    pub input: Option<String>,
+    /// File with input steps, one or more per line ('#' starts a comment)
+    #[arg(long, value_name = "FILE", conflicts_with = "input")]
+    pub input_file: Option<PathBuf>,
+    /// Stop at the end of the frame in which the ROM executes LD B,B (used by Mooneye, dmg-acid2 and Mealybug)
+    #[arg(long)]
+    pub until_breakpoint: bool,
+    /// Save state to start from
+    #[arg(long, value_name = "FILE")]
+    pub state: Option<PathBuf>,
+    /// Display palette (default: 0 for screenshot, 1 (grayscale) for compare)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..ppu::PALETTES.len() as i64))]
+    pub palette: Option<u32>,
+}
+
+impl ScriptArgs {
+    pub fn script_run(&self) -> io::Result<ScriptRun> {
+        let script = match (&self.input, &self.input_file) {
+            (Some(steps), _) => steps.clone(),
+            (None, Some(path)) => fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("failed to read input file {}: {}", path.display(), e)))?,
+            (None, None) => String::new(),
+        };
+        let input = regression::parse_input_script(&script).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(ScriptRun { frames: self.frames, input, until_breakpoint: self.until_breakpoint })
+    }
+}
+
+#[derive(Args)]
+pub struct ScreenshotArgs {
+    pub rom: PathBuf,
+    #[command(flatten)]
+    pub system: SystemArgs,
+    #[command(flatten)]
+    pub script: ScriptArgs,
+    /// Output PNG (default: the ROM name with .png)
+    #[arg(long, value_name = "FILE")]
+    pub out: Option<PathBuf>,
+    /// Print a hash of the screen instead of saving it
+    #[arg(long)]
+    pub hash: bool,
+}
+
+#[derive(Args)]
+pub struct CompareArgs {
+    pub rom: PathBuf,
+    #[command(flatten)]
+    pub system: SystemArgs,
+    #[command(flatten)]
+    pub script: ScriptArgs,
+    /// Golden PNG to compare against
+    #[arg(long, value_name = "FILE")]
+    pub golden: PathBuf,
+    /// Where to write the diff image on failure (default: diffs/<rom>-diff.png)
+    #[arg(long, value_name = "FILE")]
+    pub diff: Option<PathBuf>,
+    /// Overwrite the golden PNG with the current screen instead of comparing
+    #[arg(long)]
+    pub update: bool,
+}
+
+#[derive(Args)]
+pub struct AutomationArgs {
+    pub rom: PathBuf,
+    /// Rhai script to run; the run ends when it calls quit() or exit()
+    pub script: PathBuf,
+    #[command(flatten)]
+    pub system: SystemArgs,
+    /// Stop after this many frames even if the script has not quit
+    #[arg(long)]
+    pub frames: Option<u64>,
+    /// Save state to start from
+    #[arg(long, value_name = "FILE")]
+    pub state: Option<PathBuf>,
+    /// Display palette
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..ppu::PALETTES.len() as i64))]
+    pub palette: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Text,
+    Junit,
+}
+
+#[derive(Args)]
+pub struct ConformanceArgs {
+    /// Directory searched recursively for .gb test ROMs
+    pub dir: PathBuf,
+    #[command(flatten)]
+    pub system: SystemArgs,
+    /// Give up on a ROM after this many T-cycles
+    #[arg(long, value_name = "CYCLES", default_value_t = conformance::DEFAULT_MAX_CYCLES)]
+    pub max_cycles: u64,
+    /// Number of ROMs to run in parallel (default: number of CPUs)
+    #[arg(long, short = 'j', default_value_t = 0)]
+    pub jobs: usize,
+    /// Report format
+    #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+    pub format: ReportFormat,
+    /// Write the report to a file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+    /// Write diff images for reference-image tests that fail
+    #[arg(long, value_name = "DIR")]
+    pub diff_dir: Option<PathBuf>,
+}
+
+/// 旧形式の引数をサブコマンドの形に読み替えます。
+/// `<rom> [options]` は `run`、`--header-json <rom>` は `info --json`、
+/// `--list-audio-devices` は `audio-devices`、`--print-config` は `print-config` になります。
+pub fn normalize_legacy_args(mut args: Vec<String>) -> Vec<String> {
+    let Some(first) = args.get(1).cloned() else { return args; };
+    match first.as_str() {
+        "--header-json" => { args.splice(1..2, ["info".to_string(), "--json".to_string()]); }
+        "--list-audio-devices" => args[1] = "audio-devices".to_string(),
+        "--print-config" => args[1] = "print-config".to_string(),
+        _ if !first.starts_with('-') && first != "help" && Cli::command().find_subcommand(&first).is_none() => {
+            args.insert(1, "run".to_string());
+        }
+        _ => (),
+    }
+    args
+}
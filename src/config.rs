@@ -0,0 +1,697 @@
+// src/config.rs
+// 設定ファイル (TOML) の読み込みとキー割り当て
+//
+// 設定ファイルは以下の順に探し、最初に見つかったものを使う。
+// 1. --config で指定したパス
+// 2. 実行ファイルと同じフォルダの config.toml (ポータブル運用向け)
+// 3. $XDG_CONFIG_HOME/rust_gb_emulator/config.toml (未設定なら ~/.config/...)
+// 見つからない場合は組み込みの既定値を使う。
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use minifb::Key;
+use serde::{Deserialize, Serialize};
+
+use crate::joypad::GameboyKey;
+
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+const CONFIG_DIR_NAME: &str = "rust_gb_emulator";
+
+/// Game Boyのボタンごとのキー割り当て (minifbのキー名、複数指定可)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JoypadKeys {
+    pub up: Vec<String>,
+    pub down: Vec<String>,
+    pub left: Vec<String>,
+    pub right: Vec<String>,
+    pub a: Vec<String>,
+    pub b: Vec<String>,
+    pub start: Vec<String>,
+    pub select: Vec<String>,
+}
+
+impl Default for JoypadKeys {
+    fn default() -> Self {
+        let keys = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        Self {
+            up: keys(&["Up"]),
+            down: keys(&["Down"]),
+            left: keys(&["Left"]),
+            right: keys(&["Right"]),
+            a: keys(&["Z"]),
+            b: keys(&["X"]),
+            start: keys(&["Enter"]),
+            select: keys(&["Backspace"]),
+        }
+    }
+}
+
+/// エミュレータ機能のホットキー。Shiftとの組み合わせ (ソロ、ステム録音) は各キーに対して働きます。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HotkeyKeys {
+    pub turbo: Vec<String>,
+    pub pause: Vec<String>,
+    pub palette: Vec<String>,
+    pub debug_view: Vec<String>,
+    pub screenshot: Vec<String>,
+    pub vgm_log: Vec<String>,
+    pub record_video: Vec<String>,
+    pub record_audio: Vec<String>,
+    pub channel1: Vec<String>,
+    pub channel2: Vec<String>,
+    pub channel3: Vec<String>,
+    pub channel4: Vec<String>,
+    /// 最後にチャンネルのキーで選んだチャンネルの音量を下げる/上げる
+    pub channel_gain_down: Vec<String>,
+    pub channel_gain_up: Vec<String>,
+    /// 入力マクロの録画開始/終了
+    pub macro_record: Vec<String>,
+    /// 録画したマクロの再生
+    pub macro_play: Vec<String>,
+    /// 早送りのオン/オフ (押している間だけ働く turbo とは別)
+    pub fast_forward: Vec<String>,
+    /// 速度のプリセットを1段階下げる/上げる
+    pub speed_down: Vec<String>,
+    pub speed_up: Vec<String>,
+    /// ポーズ中に1フレームだけ進める (ポーズしていなければポーズする)
+    pub frame_advance: Vec<String>,
+    /// セーブステートの保存/読み込み (--state のファイル、なければセーブデータと同じ場所の .state)
+    pub save_state: Vec<String>,
+    pub load_state: Vec<String>,
+}
+
+impl Default for HotkeyKeys {
+    fn default() -> Self {
+        let key = |name: &str| vec![name.to_string()];
+        Self {
+            turbo: key("Tab"),
+            pause: key("F1"),
+            palette: key("P"),
+            debug_view: key("F2"),
+            screenshot: key("F12"),
+            vgm_log: key("F9"),
+            record_video: key("F10"),
+            record_audio: key("F11"),
+            channel1: key("Key1"),
+            channel2: key("Key2"),
+            channel3: key("Key3"),
+            channel4: key("Key4"),
+            channel_gain_down: key("LeftBracket"),
+            channel_gain_up: key("RightBracket"),
+            macro_record: key("F7"),
+            macro_play: key("F8"),
+            fast_forward: key("Backquote"),
+            speed_down: key("Minus"),
+            speed_up: key("Equal"),
+            frame_advance: key("N"),
+            save_state: key("F3"),
+            load_state: key("F4"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VideoConfig {
+    /// 起動時のパレット番号 (ppu::PALETTES の添字)
+    pub palette: usize,
+    /// ウィンドウの拡大率 (1, 2, 4, 8)。SGBでは枠を含むため半分になります
+    pub scale: u32,
+    /// フレームの進め方: "audio" | "video" | "fixed-rate"
+    pub sync: String,
+    /// sync = "fixed-rate" のときに合わせる表示レート (Hz)。ディスプレイからは検出しないので手動で指定します
+    pub display_rate: f64,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self { palette: 0, scale: 4, sync: "audio".to_string(), display_rate: 60.0 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// 出力デバイス名 (部分一致)。"null" で音声出力なし
+    pub device: Option<String>,
+    pub latency_ms: Option<u32>,
+    pub sample_rate: Option<u32>,
+    /// 起動時に鳴らすチャンネル (1-4)。未指定ならすべて
+    pub channels: Option<Vec<usize>>,
+    /// チャンネルごとの音量の倍率 (パルス1, パルス2, 波形, ノイズ)。未指定なら 1.0
+    pub channel_gain: Option<[f32; 4]>,
+}
+
+impl AudioConfig {
+    /// channels の指定をチャンネルのマスク (bit0 = パルス1) にします。範囲外の番号は警告して無視します。
+    pub fn channel_mask(&self) -> Option<u8> {
+        let channels = self.channels.as_ref()?;
+        Some(channels.iter().fold(0, |mask, &channel| match channel {
+            1..=4 => mask | (1 << (channel - 1)),
+            _ => {
+                eprintln!("Invalid audio channel {} in config (expected 1-4), ignored.", channel);
+                mask
+            }
+        }))
+    }
+}
+
+/// エミュレーション速度の設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeedConfig {
+    /// 起動時の速度 (倍率、例: 0.5)。"uncapped" で制限なし
+    pub initial: String,
+    /// ターボ/早送り中の速度。"uncapped" で制限なし
+    pub fast_forward: String,
+    /// 等速以外での音: "stretch" (音程を保って伸縮) | "mute"
+    pub audio: String,
+}
+
+impl Default for SpeedConfig {
+    fn default() -> Self {
+        Self { initial: "1".to_string(), fast_forward: "4".to_string(), audio: "stretch".to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathConfig {
+    /// セーブデータ (.sav) の保存先。未指定ならROMと同じフォルダ
+    pub save_dir: Option<PathBuf>,
+}
+
+/// ゲームコントローラーの設定。ボタン名はgilrsのボタン名 (South, East, Start, DPadUp など) です。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GamepadConfig {
+    pub enabled: bool,
+    /// 左スティックを十字キーとして扱うときの不感帯 (0.0-1.0)
+    pub deadzone: f32,
+    /// 振動カートリッジのモーターをコントローラーの振動に反映するか
+    pub rumble: bool,
+    pub buttons: GamepadButtons,
+    /// 連射A/Bとして働くボタン
+    pub turbo_a: Vec<String>,
+    pub turbo_b: Vec<String>,
+    /// ホットキーごとの同時押しの組み合わせ (例: pause = ["Select", "Start"])
+    pub hotkeys: GamepadHotkeys,
+}
+
+impl Default for GamepadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            deadzone: 0.4,
+            rumble: true,
+            buttons: GamepadButtons::default(),
+            turbo_a: vec!["North".to_string()],
+            turbo_b: vec!["West".to_string()],
+            hotkeys: GamepadHotkeys::default(),
+        }
+    }
+}
+
+/// Game Boyのボタンごとのコントローラーのボタン割り当て (複数指定可)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GamepadButtons {
+    pub up: Vec<String>,
+    pub down: Vec<String>,
+    pub left: Vec<String>,
+    pub right: Vec<String>,
+    pub a: Vec<String>,
+    pub b: Vec<String>,
+    pub start: Vec<String>,
+    pub select: Vec<String>,
+}
+
+impl Default for GamepadButtons {
+    fn default() -> Self {
+        let buttons = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        // 任天堂配置 (右がA、下がB)
+        Self {
+            up: buttons(&["DPadUp"]),
+            down: buttons(&["DPadDown"]),
+            left: buttons(&["DPadLeft"]),
+            right: buttons(&["DPadRight"]),
+            a: buttons(&["East"]),
+            b: buttons(&["South"]),
+            start: buttons(&["Start"]),
+            select: buttons(&["Select"]),
+        }
+    }
+}
+
+impl GamepadButtons {
+    /// Game Boyの各ボタンと、それに割り当てられたボタン名
+    pub fn mapping(&self) -> [(GameboyKey, &[String]); 8] {
+        [
+            (GameboyKey::Up, &self.up),
+            (GameboyKey::Down, &self.down),
+            (GameboyKey::Left, &self.left),
+            (GameboyKey::Right, &self.right),
+            (GameboyKey::A, &self.a),
+            (GameboyKey::B, &self.b),
+            (GameboyKey::Start, &self.start),
+            (GameboyKey::Select, &self.select),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GamepadHotkeys {
+    pub turbo: Vec<String>,
+    pub pause: Vec<String>,
+    pub palette: Vec<String>,
+    pub screenshot: Vec<String>,
+    pub record_video: Vec<String>,
+    pub record_audio: Vec<String>,
+    pub fast_forward: Vec<String>,
+    pub frame_advance: Vec<String>,
+}
+
+impl Default for GamepadHotkeys {
+    fn default() -> Self {
+        Self {
+            turbo: vec!["RightTrigger2".to_string()],
+            pause: vec!["Select".to_string(), "Start".to_string()],
+            palette: Vec::new(),
+            screenshot: Vec::new(),
+            record_video: Vec::new(),
+            record_audio: Vec::new(),
+            fast_forward: Vec::new(),
+            frame_advance: Vec::new(),
+        }
+    }
+}
+
+impl GamepadHotkeys {
+    /// 組み合わせが設定されているホットキーの一覧
+    pub fn combos(&self) -> Vec<(Hotkey, &[String])> {
+        [
+            (Hotkey::Turbo, &self.turbo),
+            (Hotkey::Pause, &self.pause),
+            (Hotkey::Palette, &self.palette),
+            (Hotkey::Screenshot, &self.screenshot),
+            (Hotkey::RecordVideo, &self.record_video),
+            (Hotkey::RecordAudio, &self.record_audio),
+            (Hotkey::FastForward, &self.fast_forward),
+            (Hotkey::FrameAdvance, &self.frame_advance),
+        ].into_iter().filter(|(_, buttons)| !buttons.is_empty()).map(|(hotkey, buttons)| (hotkey, buttons.as_slice())).collect()
+    }
+}
+
+/// 連射ボタンの設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutofireConfig {
+    /// 連射の周期 (エミュレートしたフレーム数)。前半は押し、後半は離す (4なら約15回/秒)
+    pub period_frames: u32,
+    /// 連射Aとして働くキー
+    pub a: Vec<String>,
+    /// 連射Bとして働くキー
+    pub b: Vec<String>,
+}
+
+impl Default for AutofireConfig {
+    fn default() -> Self {
+        Self { period_frames: 4, a: vec!["A".to_string()], b: vec!["S".to_string()] }
+    }
+}
+
+/// 入力マクロ。`steps` は "A+B+Select+Start:10" (ボタン:フレーム数) の列で、":5" は5フレーム何も押さない
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MacroConfig {
+    pub name: String,
+    /// 再生するキー (省略可)
+    pub key: Option<String>,
+    pub steps: Vec<String>,
+}
+
+impl MacroConfig {
+    /// 設定ファイルにそのまま追加できる `[[macros]]` テーブルの形式にします。
+    pub fn to_toml_string(&self) -> String {
+        #[derive(Serialize)]
+        struct Macros<'a> { macros: [&'a MacroConfig; 1] }
+        toml::to_string_pretty(&Macros { macros: [self] }).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub keys: JoypadKeys,
+    pub hotkeys: HotkeyKeys,
+    pub gamepad: GamepadConfig,
+    pub video: VideoConfig,
+    pub audio: AudioConfig,
+    pub speed: SpeedConfig,
+    pub paths: PathConfig,
+    pub autofire: AutofireConfig,
+    pub macros: Vec<MacroConfig>,
+}
+
+/// コマンドラインで指定された設定。指定されなかった項目は設定ファイルの値のままにします。
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub scale: Option<u32>,
+    pub palette: Option<usize>,
+    pub save_dir: Option<PathBuf>,
+    pub sync: Option<String>,
+    pub speed: Option<String>,
+}
+
+fn candidate_paths() -> Vec<PathBuf> {
+    search_paths(env::current_exe().ok(), env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME"))
+}
+
+// 実行ファイルのパスと環境変数から、設定ファイルを探す順のパスを作る
+fn search_paths(exe: Option<PathBuf>, xdg_config_home: Option<OsString>, home: Option<OsString>) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(exe) = exe && let Some(dir) = exe.parent() {
+        paths.push(dir.join(CONFIG_FILE_NAME));
+    }
+    let config_home = xdg_config_home.filter(|dir| !dir.is_empty()).map(PathBuf::from)
+        .or_else(|| home.map(|home| PathBuf::from(home).join(".config")));
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME));
+    }
+    paths
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// 設定ファイルを探して読み込みます。`explicit` が指定された場合はそのファイルだけを使います。
+    /// 読み込んだファイルのパスも返します (既定値の場合は `None`)。
+    pub fn load_or_default(explicit: Option<&Path>) -> io::Result<(Self, Option<PathBuf>)> {
+        Self::load_first(explicit, candidate_paths())
+    }
+
+    fn load_first(explicit: Option<&Path>, candidates: Vec<PathBuf>) -> io::Result<(Self, Option<PathBuf>)> {
+        if let Some(path) = explicit {
+            return Ok((Self::load(path)?, Some(path.to_path_buf())));
+        }
+        match candidates.into_iter().find(|path| path.is_file()) {
+            Some(path) => Ok((Self::load(&path)?, Some(path))),
+            None => Ok((Self::default(), None)),
+        }
+    }
+
+    /// コマンドラインの指定で上書きします。
+    pub fn apply_overrides(&mut self, overrides: ConfigOverrides) {
+        if let Some(scale) = overrides.scale {
+            self.video.scale = scale;
+        }
+        if let Some(palette) = overrides.palette {
+            self.video.palette = palette;
+        }
+        if let Some(dir) = overrides.save_dir {
+            self.paths.save_dir = Some(dir);
+        }
+        if let Some(sync) = overrides.sync {
+            self.video.sync = sync;
+        }
+        if let Some(speed) = overrides.speed {
+            self.speed.initial = speed;
+        }
+    }
+
+    pub fn to_toml_string(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// キー名を解決してキー割り当てを作ります。解決できないキー名は警告して無視します。
+    pub fn key_bindings(&self) -> KeyBindings {
+        let resolve = |names: &[String]| -> Vec<Key> {
+            names.iter().filter_map(|name| {
+                let key = parse_key(name);
+                if key.is_none() { eprintln!("Unknown key name '{}' in config, ignored.", name); }
+                key
+            }).collect()
+        };
+        let keys = &self.keys;
+        let hotkeys = &self.hotkeys;
+        KeyBindings {
+            joypad: vec![
+                (GameboyKey::Up, resolve(&keys.up)),
+                (GameboyKey::Down, resolve(&keys.down)),
+                (GameboyKey::Left, resolve(&keys.left)),
+                (GameboyKey::Right, resolve(&keys.right)),
+                (GameboyKey::A, resolve(&keys.a)),
+                (GameboyKey::B, resolve(&keys.b)),
+                (GameboyKey::Start, resolve(&keys.start)),
+                (GameboyKey::Select, resolve(&keys.select)),
+            ],
+            hotkeys: [
+                (Hotkey::Turbo, resolve(&hotkeys.turbo)),
+                (Hotkey::Pause, resolve(&hotkeys.pause)),
+                (Hotkey::Palette, resolve(&hotkeys.palette)),
+                (Hotkey::DebugView, resolve(&hotkeys.debug_view)),
+                (Hotkey::Screenshot, resolve(&hotkeys.screenshot)),
+                (Hotkey::VgmLog, resolve(&hotkeys.vgm_log)),
+                (Hotkey::RecordVideo, resolve(&hotkeys.record_video)),
+                (Hotkey::RecordAudio, resolve(&hotkeys.record_audio)),
+                (Hotkey::Channel(0), resolve(&hotkeys.channel1)),
+                (Hotkey::Channel(1), resolve(&hotkeys.channel2)),
+                (Hotkey::Channel(2), resolve(&hotkeys.channel3)),
+                (Hotkey::Channel(3), resolve(&hotkeys.channel4)),
+                (Hotkey::ChannelGainDown, resolve(&hotkeys.channel_gain_down)),
+                (Hotkey::ChannelGainUp, resolve(&hotkeys.channel_gain_up)),
+                (Hotkey::MacroRecord, resolve(&hotkeys.macro_record)),
+                (Hotkey::MacroPlay, resolve(&hotkeys.macro_play)),
+                (Hotkey::FastForward, resolve(&hotkeys.fast_forward)),
+                (Hotkey::SpeedDown, resolve(&hotkeys.speed_down)),
+                (Hotkey::SpeedUp, resolve(&hotkeys.speed_up)),
+                (Hotkey::FrameAdvance, resolve(&hotkeys.frame_advance)),
+                (Hotkey::SaveState, resolve(&hotkeys.save_state)),
+                (Hotkey::LoadState, resolve(&hotkeys.load_state)),
+            ].into_iter().chain(self.macros.iter().enumerate().map(|(i, m)| (Hotkey::Macro(i), resolve(m.key.as_slice())))).collect(),
+            autofire: vec![
+                (GameboyKey::A, resolve(&self.autofire.a)),
+                (GameboyKey::B, resolve(&self.autofire.b)),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    Turbo,
+    Pause,
+    Palette,
+    DebugView,
+    Screenshot,
+    VgmLog,
+    RecordVideo,
+    RecordAudio,
+    /// 音声チャンネル (0-3) のミュート。Shift併用でソロ
+    Channel(usize),
+    ChannelGainDown,
+    ChannelGainUp,
+    MacroRecord,
+    MacroPlay,
+    /// 設定ファイルの macros の添字のマクロを再生
+    Macro(usize),
+    FastForward,
+    SpeedDown,
+    SpeedUp,
+    FrameAdvance,
+    SaveState,
+    LoadState,
+}
+
+/// 解決済みのキー割り当て
+pub struct KeyBindings {
+    joypad: Vec<(GameboyKey, Vec<Key>)>,
+    hotkeys: Vec<(Hotkey, Vec<Key>)>,
+    autofire: Vec<(GameboyKey, Vec<Key>)>,
+}
+
+impl KeyBindings {
+    /// Game Boyの各ボタンと、それに割り当てられたキー
+    pub fn joypad(&self) -> &[(GameboyKey, Vec<Key>)] {
+        &self.joypad
+    }
+
+    /// 連射ボタンとそれに割り当てられたキー
+    pub fn autofire(&self) -> &[(GameboyKey, Vec<Key>)] {
+        &self.autofire
+    }
+
+    /// 押されたキーに対応するホットキー
+    pub fn hotkey_for(&self, key: Key) -> Option<Hotkey> {
+        self.hotkeys.iter().find(|(_, keys)| keys.contains(&key)).map(|(hotkey, _)| *hotkey)
+    }
+
+    /// ホットキーに割り当てられたいずれかのキーが押されているか
+    pub fn is_hotkey_down(&self, hotkey: Hotkey, keys_down: &[Key]) -> bool {
+        self.hotkeys.iter().any(|(h, keys)| *h == hotkey && keys.iter().any(|key| keys_down.contains(key)))
+    }
+
+    /// ホットキーの割り当てを表示用の文字列にします (例: "F1" や "Tab/Q")。
+    pub fn describe(&self, hotkey: Hotkey) -> String {
+        let names: Vec<String> = self.hotkeys.iter().filter(|(h, _)| *h == hotkey).flat_map(|(_, keys)| keys.iter().map(|key| format!("{:?}", key))).collect();
+        if names.is_empty() { "-".to_string() } else { names.join("/") }
+    }
+
+    /// Game Boyのボタンの割り当てを表示用の文字列にします。
+    pub fn describe_button(&self, button: GameboyKey) -> String {
+        let names: Vec<String> = self.joypad.iter().filter(|(b, _)| *b == button).flat_map(|(_, keys)| keys.iter().map(|key| format!("{:?}", key))).collect();
+        if names.is_empty() { "-".to_string() } else { names.join("/") }
+    }
+
+    /// 連射ボタンの割り当てを表示用の文字列にします。
+    pub fn describe_autofire(&self, button: GameboyKey) -> String {
+        let names: Vec<String> = self.autofire.iter().filter(|(b, _)| *b == button).flat_map(|(_, keys)| keys.iter().map(|key| format!("{:?}", key))).collect();
+        if names.is_empty() { "-".to_string() } else { names.join("/") }
+    }
+}
+
+const ALL_KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12, Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal, Key::LeftBracket, Key::Minus, Key::Period, Key::RightBracket, Key::Semicolon, Key::Slash,
+    Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape, Key::Home, Key::Insert, Key::Menu, Key::PageDown, Key::PageUp, Key::Pause, Key::Space, Key::Tab,
+    Key::NumLock, Key::CapsLock, Key::ScrollLock, Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5, Key::NumPad6, Key::NumPad7, Key::NumPad8, Key::NumPad9,
+    Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk, Key::NumPadMinus, Key::NumPadPlus, Key::NumPadEnter,
+    Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+/// minifbのキー名 (例: "Z", "Enter", "Key1", "NumPad4") をキーに変換します。大文字小文字は区別しません。
+/// 1文字の数字 ("1") は "Key1" として扱います。
+pub fn parse_key(name: &str) -> Option<Key> {
+    let name = name.trim();
+    let name = if name.len() == 1 && name.chars().all(|c| c.is_ascii_digit()) { format!("Key{}", name) } else { name.to_string() };
+    ALL_KEYS.iter().copied().find(|key| format!("{:?}", key).eq_ignore_ascii_case(&name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("config-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parses_key_names() {
+        assert_eq!(parse_key("Z"), Some(Key::Z));
+        assert_eq!(parse_key("enter"), Some(Key::Enter));
+        assert_eq!(parse_key(" NUMPAD4 "), Some(Key::NumPad4));
+        assert_eq!(parse_key("1"), Some(Key::Key1));
+        assert_eq!(parse_key("key1"), Some(Key::Key1));
+        assert_eq!(parse_key("LeftShift"), Some(Key::LeftShift));
+        assert_eq!(parse_key("Hyper"), None);
+        assert_eq!(parse_key("12"), None);
+        assert_eq!(parse_key(""), None);
+    }
+
+    #[test]
+    fn partial_files_keep_defaults() {
+        let config: Config = toml::from_str("[keys]\na = [\"K\", \"Bogus\"]\n\n[video]\nscale = 2\n").unwrap();
+        assert_eq!(config.keys.a, vec!["K".to_string(), "Bogus".to_string()]);
+        assert_eq!(config.keys.b, JoypadKeys::default().b);
+        assert_eq!(config.video.scale, 2);
+        assert_eq!(config.video.sync, "audio");
+        assert_eq!(config.speed.fast_forward, SpeedConfig::default().fast_forward);
+        // 解決できないキー名は無視される
+        let bindings = config.key_bindings();
+        assert_eq!(bindings.describe_button(GameboyKey::A), "K");
+        assert_eq!(bindings.describe_button(GameboyKey::Start), "Enter");
+
+        assert!(toml::from_str::<Config>("[video]\nscale = \"big\"\n").is_err());
+    }
+
+    #[test]
+    fn search_paths_prefer_exe_dir_then_xdg_then_home() {
+        let exe = Some(PathBuf::from("/opt/gb/rust_gb_emulator"));
+        let paths = search_paths(exe.clone(), Some("/xdg".into()), Some("/home/user".into()));
+        assert_eq!(paths, vec![PathBuf::from("/opt/gb/config.toml"), PathBuf::from("/xdg/rust_gb_emulator/config.toml")]);
+        // XDG_CONFIG_HOME が空なら ~/.config
+        let paths = search_paths(exe, Some("".into()), Some("/home/user".into()));
+        assert_eq!(paths[1], PathBuf::from("/home/user/.config/rust_gb_emulator/config.toml"));
+        assert!(search_paths(None, None, None).is_empty());
+    }
+
+    #[test]
+    fn loads_the_first_existing_config() {
+        let dir = temp_dir("search");
+        let (exe_config, xdg_config, explicit) = (dir.join("exe.toml"), dir.join("xdg.toml"), dir.join("explicit.toml"));
+        fs::write(&xdg_config, "[video]\nscale = 3\n").unwrap();
+        fs::write(&explicit, "[video]\nscale = 8\n").unwrap();
+        let candidates = || vec![exe_config.clone(), xdg_config.clone()];
+
+        let (config, path) = Config::load_first(None, candidates()).unwrap();
+        assert_eq!((config.video.scale, path), (3, Some(xdg_config.clone())));
+        fs::write(&exe_config, "[video]\nscale = 1\n").unwrap();
+        let (config, path) = Config::load_first(None, candidates()).unwrap();
+        assert_eq!((config.video.scale, path), (1, Some(exe_config.clone())));
+        let (config, path) = Config::load_first(Some(&explicit), candidates()).unwrap();
+        assert_eq!((config.video.scale, path), (8, Some(explicit.clone())));
+
+        // --config のファイルがなければ、他を探さずにエラー
+        assert!(Config::load_first(Some(&dir.join("missing.toml")), candidates()).is_err());
+        let (config, path) = Config::load_first(None, vec![dir.join("missing.toml")]).unwrap();
+        assert_eq!((config.video.scale, path), (4, None));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn command_line_overrides_config_values() {
+        let mut config: Config = toml::from_str("[video]\nscale = 2\npalette = 1\nsync = \"video\"\n\n[speed]\ninitial = \"0.5\"\n").unwrap();
+        config.apply_overrides(ConfigOverrides { scale: Some(8), sync: Some("fixed-rate".to_string()), ..Default::default() });
+        assert_eq!((config.video.scale, config.video.palette), (8, 1));
+        assert_eq!((config.video.sync.as_str(), config.speed.initial.as_str()), ("fixed-rate", "0.5"));
+        assert_eq!(config.paths.save_dir, None);
+
+        config.apply_overrides(ConfigOverrides { palette: Some(3), save_dir: Some(PathBuf::from("saves")), speed: Some("uncapped".to_string()), ..Default::default() });
+        assert_eq!((config.video.scale, config.video.palette), (8, 3));
+        assert_eq!(config.paths.save_dir, Some(PathBuf::from("saves")));
+        assert_eq!(config.speed.initial, "uncapped");
+    }
+
+    #[test]
+    fn audio_mixer_settings() {
+        let config: Config = toml::from_str("[audio]\nchannels = [1, 3, 9]\nchannel_gain = [1.0, 0.5, 2.0, 0.0]\n").unwrap();
+        assert_eq!(config.audio.channel_mask(), Some(0b0101));
+        assert_eq!(config.audio.channel_gain, Some([1.0, 0.5, 2.0, 0.0]));
+        assert_eq!(Config::default().audio.channel_mask(), None);
+    }
+
+    #[test]
+    fn macro_table_round_trips() {
+        let recorded = MacroConfig { name: "recorded".to_string(), key: None, steps: vec!["A+B:3".to_string(), ":10".to_string()] };
+        let config: Config = toml::from_str(&recorded.to_toml_string()).unwrap();
+        assert_eq!(config.macros.len(), 1);
+        assert_eq!(config.macros[0].name, "recorded");
+        assert_eq!(config.macros[0].key, None);
+        assert_eq!(config.macros[0].steps, recorded.steps);
+    }
+
+    #[test]
+    fn channel_gain_hotkeys_are_bound() {
+        let bindings = Config::default().key_bindings();
+        assert_eq!(bindings.hotkey_for(Key::LeftBracket), Some(Hotkey::ChannelGainDown));
+        assert_eq!(bindings.hotkey_for(Key::RightBracket), Some(Hotkey::ChannelGainUp));
+    }
+}
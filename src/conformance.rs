@@ -0,0 +1,350 @@
+// src/conformance.rs
+// テストROMのディレクトリをまとめて実行し、合否の一覧を作る (精度の推移を追うため)
+//
+// ROMごとに合否の判定方法を選ぶ:
+// - 参照画像があるもの (dmg-acid2, Mealybugなど): LD B,B まで実行して画面を参照画像と比べる
+//   参照画像は <名前>.png、同じディレクトリの reference-dmg.png、ROMからルートまでの各ディレクトリの
+//   expected/DMG-blob/<名前>.png の順に探す
+// - それ以外: test_rom の判定 (Mooneyeの結果レジスタ、Blarggのシリアル出力と $A000)
+// Mooneyeのファイル名の末尾でDMG以外のモデルだけを指定しているもの ("-cgb", "-S" など) は飛ばす。
+// expected/ ディレクトリを持つツリー (Mealybug) のROMで、DMGの参照画像がないもの (CGB専用のテスト) も飛ばす。
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cartridge::Cartridge;
+use crate::headless::{Headless, HeadlessOptions};
+use crate::ppu::CYCLES_PER_FRAME;
+use crate::regression::{self, ScriptRun};
+use crate::test_rom::{self, TestStatus, CPU_FREQ};
+
+/// ROM1本あたりの既定の制限 (約20秒分のTサイクル)
+pub const DEFAULT_MAX_CYCLES: u64 = 20 * CPU_FREQ as u64;
+// 実行スレッドのスタック (メインスレッドと同じ大きさにしておく)
+const WORKER_STACK_SIZE: usize = 8 * 1024 * 1024;
+// Mooneyeのファイル名のモデル指定に使われる名前 (長いものから順に照合する)
+const MOONEYE_MODELS: [&str; 15] = ["cgbABCDE", "dmgABC", "dmg0", "sgb2", "cgb0", "dmg", "mgb", "sgb", "cgb", "agb", "ags", "G", "S", "C", "A"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseStatus {
+    Passed,
+    Failed,
+    Timeout,
+    /// DMGでは動かないモデル指定のROM
+    Skipped,
+}
+
+impl CaseStatus {
+    pub fn label(&self) -> &'static str {
+        match self {
+            CaseStatus::Passed => "PASS",
+            CaseStatus::Failed => "FAIL",
+            CaseStatus::Timeout => "TIMEOUT",
+            CaseStatus::Skipped => "SKIP",
+        }
+    }
+}
+
+impl From<TestStatus> for CaseStatus {
+    fn from(status: TestStatus) -> Self {
+        match status {
+            TestStatus::Passed => CaseStatus::Passed,
+            TestStatus::Failed => CaseStatus::Failed,
+            TestStatus::Timeout => CaseStatus::Timeout,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CaseResult {
+    /// ルートからの相対パス
+    pub rom: PathBuf,
+    /// ルート直下のディレクトリ名 (ルートに直接置かれたROMはルートの名前)
+    pub suite: String,
+    pub status: CaseStatus,
+    pub message: String,
+    pub cycles: u64,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConformanceOptions {
+    pub headless: HeadlessOptions,
+    /// ROM1本あたりのTサイクル数の上限
+    pub max_cycles: u64,
+    /// 同時に実行するROMの数 (0で論理CPU数)
+    pub jobs: usize,
+    /// 参照画像と一致しなかったときに差分画像と実際の画面を書き出すディレクトリ
+    pub diff_dir: Option<PathBuf>,
+}
+
+/// ディレクトリ以下の .gb ファイルをパス順に集めます。
+pub fn find_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut roms = Vec::new();
+    collect_roms(dir, &mut roms);
+    roms
+}
+
+fn collect_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else { return; };
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            collect_roms(&path, roms);
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gb")) {
+            roms.push(path);
+        }
+    }
+}
+
+fn rom_name(rom: &Path) -> String {
+    rom.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+/// ROMの参照画像を `root` の下で探します。
+pub fn find_reference(root: &Path, rom: &Path) -> Option<PathBuf> {
+    let name = format!("{}.png", rom_name(rom));
+    let expected = rom.ancestors().skip(1).take_while(|dir| dir.starts_with(root)).map(|dir| dir.join("expected/DMG-blob").join(&name));
+    [rom.with_extension("png"), rom.with_file_name("reference-dmg.png")].into_iter().chain(expected).find(|path| path.is_file())
+}
+
+/// ROMが参照画像のツリー (ROMからルートまでのどこかに expected/ ディレクトリがある) に含まれるか。
+fn in_reference_tree(root: &Path, rom: &Path) -> bool {
+    rom.ancestors().skip(1).take_while(|dir| dir.starts_with(root)).any(|dir| dir.join("expected").is_dir())
+}
+
+/// Mooneyeのファイル名の末尾のモデル指定 ("-dmgABCmgb", "-GS", "-cgb" など) がDMGを含むか。
+/// モデル指定がなければ `true` です。
+pub fn runs_on_dmg(rom: &Path) -> bool {
+    let name = rom_name(rom);
+    let Some((_, mut tag)) = name.rsplit_once('-') else { return true; };
+    let mut models = Vec::new();
+    while !tag.is_empty() {
+        // "01-special" のようにモデル名でなければ、モデル指定ではない
+        let Some(model) = MOONEYE_MODELS.iter().find(|m| tag.starts_with(**m)) else { return true; };
+        models.push(*model);
+        tag = &tag[model.len()..];
+    }
+    models.iter().any(|m| matches!(*m, "dmgABC" | "dmg" | "G"))
+}
+
+/// 参照画像と比べて判定します。
+fn check_reference_image(emulator: &mut Headless, rom: &Path, reference: &Path, options: &ConformanceOptions) -> io::Result<(CaseStatus, String)> {
+    emulator.cpu.mmu.ppu.set_palette(regression::REFERENCE_PALETTE);
+    let run = ScriptRun { frames: options.max_cycles.div_ceil(CYCLES_PER_FRAME as u64), input: Vec::new(), until_breakpoint: true };
+    let (frames, stopped) = regression::run_script(emulator, &run);
+    let diff = regression::compare_with_golden(emulator, reference)?;
+    if diff.matches() {
+        return Ok((CaseStatus::Passed, format!("matches {}", reference.display())));
+    }
+    if let Some(dir) = &options.diff_dir {
+        let name = rom_name(rom);
+        regression::save_png(&diff.diff_image, dir.join(format!("{}-diff.png", name)))?;
+        regression::save_png(&emulator.screen_image(), dir.join(format!("{}-actual.png", name)))?;
+    }
+    if stopped {
+        Ok((CaseStatus::Failed, diff.summary()))
+    } else {
+        Ok((CaseStatus::Timeout, format!("LD B,B not reached after {} frames; {}", frames, diff.summary())))
+    }
+}
+
+/// ROMを1本実行して判定します。
+pub fn run_case(root: &Path, rom: &Path, options: &ConformanceOptions) -> CaseResult {
+    let relative = rom.strip_prefix(root).unwrap_or(rom).to_path_buf();
+    let suite = match relative.components().count() {
+        0 | 1 => root.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_else(|| ".".to_string()),
+        _ => relative.components().next().unwrap().as_os_str().to_string_lossy().to_string(),
+    };
+    let started = Instant::now();
+    let mut result = CaseResult { rom: relative, suite, status: CaseStatus::Skipped, message: String::new(), cycles: 0, duration: Duration::ZERO };
+    if !runs_on_dmg(rom) {
+        result.message = "model not emulated".to_string();
+        return result;
+    }
+    let outcome = Cartridge::load(rom).and_then(|cartridge| {
+        match find_reference(root, rom) {
+            Some(reference) => {
+                let mut emulator = Headless::new(cartridge, &options.headless)?;
+                let (status, message) = check_reference_image(&mut emulator, rom, &reference, options)?;
+                Ok((status, message, emulator.cpu.total_clock_cycles))
+            }
+            None if in_reference_tree(root, rom) => Ok((CaseStatus::Skipped, "no DMG reference image".to_string(), 0)),
+            None => {
+                let report = test_rom::run_test_rom_cycles(cartridge, &options.headless, options.max_cycles)?;
+                Ok((report.status.into(), report.message, report.cycles))
+            }
+        }
+    });
+    match outcome {
+        Ok((status, message, cycles)) => {
+            result.status = status;
+            result.message = message;
+            result.cycles = cycles;
+        }
+        Err(e) => {
+            result.status = CaseStatus::Failed;
+            result.message = format!("could not run: {}", e);
+        }
+    }
+    result.duration = started.elapsed();
+    result
+}
+
+/// ディレクトリ以下のROMをすべて実行します。`progress` は1本終わるごとに (終わった順に) 呼ばれます。
+pub fn run_directory(root: &Path, options: &ConformanceOptions, progress: impl Fn(&CaseResult) + Sync) -> Vec<CaseResult> {
+    let roms = find_roms(root);
+    let jobs = match options.jobs {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }.min(roms.len().max(1));
+    let queue = Mutex::new(roms.iter().enumerate());
+    let results = Mutex::new(vec![None; roms.len()]);
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            thread::Builder::new().stack_size(WORKER_STACK_SIZE).spawn_scoped(scope, || {
+                loop {
+                    let Some((index, rom)) = queue.lock().unwrap().next() else { break; };
+                    let result = run_case(root, rom, options);
+                    progress(&result);
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            }).expect("failed to start a conformance worker");
+        }
+    });
+    results.into_inner().unwrap().into_iter().flatten().collect()
+}
+
+/// 状態ごとの件数 (合格, 不合格, 時間切れ, 飛ばした数)
+pub fn count(results: &[CaseResult]) -> (usize, usize, usize, usize) {
+    let count_of = |status| results.iter().filter(|r| r.status == status).count();
+    (count_of(CaseStatus::Passed), count_of(CaseStatus::Failed), count_of(CaseStatus::Timeout), count_of(CaseStatus::Skipped))
+}
+
+/// 結果を表にします。
+pub fn text_table(results: &[CaseResult]) -> String {
+    let width = results.iter().map(|r| r.rom.display().to_string().len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for r in results {
+        out += &format!("{:<7}  {:<width$}  {:>12}  {}\n", r.status.label(), r.rom.display(), r.cycles, r.message, width = width);
+    }
+    let (passed, failed, timeout, skipped) = count(results);
+    let run = passed + failed + timeout;
+    let percent = if run > 0 { passed as f64 * 100.0 / run as f64 } else { 0.0 };
+    out += &format!("{} passed, {} failed, {} timed out, {} skipped ({:.1}% of {} run)\n", passed, failed, timeout, skipped, percent, run);
+    out
+}
+
+fn xml_escape(text: &str) -> String {
+    text.chars().filter(|&c| c == '\t' || c == '\n' || !c.is_control()).fold(String::new(), |mut out, c| {
+        match c {
+            '&' => out += "&amp;",
+            '<' => out += "&lt;",
+            '>' => out += "&gt;",
+            '"' => out += "&quot;",
+            '\'' => out += "&apos;",
+            _ => out.push(c),
+        }
+        out
+    })
+}
+
+/// 結果をJUnit XMLにします。スイート (ルート直下のディレクトリ) ごとに testsuite を作ります。
+pub fn junit_xml(results: &[CaseResult]) -> String {
+    let mut suites: Vec<&str> = results.iter().map(|r| r.suite.as_str()).collect();
+    suites.dedup();
+    let (_, failed, timeout, skipped) = count(results);
+    let total_time: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out += &format!("<testsuites name=\"conformance\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n", results.len(), failed + timeout, skipped, total_time);
+    for suite in suites {
+        let cases: Vec<&CaseResult> = results.iter().filter(|r| r.suite == suite).collect();
+        let failures = cases.iter().filter(|r| matches!(r.status, CaseStatus::Failed | CaseStatus::Timeout)).count();
+        let skipped = cases.iter().filter(|r| r.status == CaseStatus::Skipped).count();
+        let time: f64 = cases.iter().map(|r| r.duration.as_secs_f64()).sum();
+        out += &format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n", xml_escape(suite), cases.len(), failures, skipped, time);
+        for case in cases {
+            let name = xml_escape(&case.rom.display().to_string());
+            out += &format!("    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\">\n", xml_escape(suite), name, case.duration.as_secs_f64());
+            let message = xml_escape(&case.message);
+            match case.status {
+                CaseStatus::Passed => (),
+                CaseStatus::Failed => out += &format!("      <failure type=\"failed\" message=\"{}\"/>\n", message),
+                CaseStatus::Timeout => out += &format!("      <failure type=\"timeout\" message=\"{}\"/>\n", message),
+                CaseStatus::Skipped => out += &format!("      <skipped message=\"{}\"/>\n", message),
+            }
+            out += &format!("      <system-out>cycles: {}</system-out>\n", case.cycles);
+            out += "    </testcase>\n";
+        }
+        out += "  </testsuite>\n";
+    }
+    out += "</testsuites>\n";
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn case(rom: &str, suite: &str, status: CaseStatus, message: &str) -> CaseResult {
+        CaseResult { rom: PathBuf::from(rom), suite: suite.to_string(), status, message: message.to_string(), cycles: 100, duration: Duration::from_millis(250) }
+    }
+
+    #[test]
+    fn model_suffixes() {
+        assert!(runs_on_dmg(Path::new("acceptance/add_sp_e_timing.gb")));
+        assert!(runs_on_dmg(Path::new("acceptance/boot_regs-dmgABC.gb")));
+        assert!(runs_on_dmg(Path::new("acceptance/boot_hwio-dmgABCmgb.gb")));
+        assert!(runs_on_dmg(Path::new("acceptance/di_timing-GS.gb")));
+        assert!(runs_on_dmg(Path::new("cpu_instrs/01-special.gb")));
+        assert!(!runs_on_dmg(Path::new("acceptance/boot_regs-cgb.gb")));
+        assert!(!runs_on_dmg(Path::new("acceptance/boot_div-S.gb")));
+        assert!(!runs_on_dmg(Path::new("acceptance/boot_regs-dmg0.gb")));
+        assert!(!runs_on_dmg(Path::new("acceptance/boot_regs-mgb.gb")));
+    }
+
+    #[test]
+    fn escapes_xml_special_and_control_characters() {
+        assert_eq!(xml_escape("a<b> & \"c\" 'd'"), "a&lt;b&gt; &amp; &quot;c&quot; &apos;d&apos;");
+        assert_eq!(xml_escape("line\n\tnext\u{1}"), "line\n\tnext");
+    }
+
+    #[test]
+    fn junit_groups_cases_by_suite() {
+        let results = [
+            case("blargg/cpu_instrs.gb", "blargg", CaseStatus::Passed, "Passed"),
+            case("blargg/halt_bug.gb", "blargg", CaseStatus::Timeout, "no result"),
+            case("mooneye/boot_regs-cgb.gb", "mooneye", CaseStatus::Skipped, "model not emulated"),
+            case("mooneye/<odd>.gb", "mooneye", CaseStatus::Failed, "B=0 & C=1"),
+        ];
+        let xml = junit_xml(&results);
+        assert!(xml.contains("<testsuites name=\"conformance\" tests=\"4\" failures=\"2\" skipped=\"1\" time=\"1.000\">"));
+        assert!(xml.contains("<testsuite name=\"blargg\" tests=\"2\" failures=\"1\" skipped=\"0\" time=\"0.500\">"));
+        assert!(xml.contains("<testsuite name=\"mooneye\" tests=\"2\" failures=\"1\" skipped=\"1\" time=\"0.500\">"));
+        assert!(xml.contains("<failure type=\"timeout\" message=\"no result\"/>"));
+        assert!(xml.contains("<skipped message=\"model not emulated\"/>"));
+        assert!(xml.contains("name=\"mooneye/&lt;odd&gt;.gb\""));
+        assert!(xml.contains("<failure type=\"failed\" message=\"B=0 &amp; C=1\"/>"));
+        assert_eq!(xml.matches("<testcase ").count(), 4);
+    }
+
+    #[test]
+    fn skips_reference_tree_roms_without_dmg_image() {
+        let root = std::env::temp_dir().join(format!("conformance-test-{}", std::process::id()));
+        let build = root.join("mealybug/build");
+        fs::create_dir_all(build.join("ppu")).unwrap();
+        fs::create_dir_all(build.join("expected/DMG-blob")).unwrap();
+        let rom = build.join("ppu/m3_cgb_only.gb");
+        fs::write(&rom, vec![0; 0x8000]).unwrap();
+        let result = run_case(&root, &rom, &ConformanceOptions::default());
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(result.status, CaseStatus::Skipped);
+        assert_eq!(result.message, "no DMG reference image");
+        assert_eq!(result.suite, "mealybug");
+    }
+}
@@ -1,9 +1,13 @@
 use crate::mmu::Mmu;
+use crate::ppu::CYCLES_PER_FRAME;
+use crate::save_state::{StateReader, StateWriter};
+use bincode::{Decode, Encode};
 use std::fmt;
+use std::io;
 
 // --- (定数、CpuRegisters 構造体、CpuRegisters impl は変更なし) ---
 pub const VBLANK_INTERRUPT_ADDR: u16 = 0x0040; pub const LCD_STAT_INTERRUPT_ADDR: u16 = 0x0048; pub const TIMER_INTERRUPT_ADDR: u16 = 0x0050; pub const SERIAL_INTERRUPT_ADDR: u16 = 0x0058; pub const JOYPAD_INTERRUPT_ADDR: u16 = 0x0060;
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, Encode, Decode)]
 pub struct CpuRegisters { pub a: u8, pub f: u8, pub b: u8, pub c: u8, pub d: u8, pub e: u8, pub h: u8, pub l: u8, pub sp: u16, pub pc: u16, }
 impl CpuRegisters { pub fn new() -> Self { Self { a: 0x01, f: 0xB0, b: 0x00, c: 0x13, d: 0x00, e: 0xD8, h: 0x01, l: 0x4D, sp: 0xFFFE, pc: 0x0100 } } pub fn af(&self) -> u16 { ((self.a as u16) << 8) | (self.f as u16) } pub fn bc(&self) -> u16 { ((self.b as u16) << 8) | (self.c as u16) } pub fn de(&self) -> u16 { ((self.d as u16) << 8) | (self.e as u16) } pub fn hl(&self) -> u16 { ((self.h as u16) << 8) | (self.l as u16) } pub fn set_af(&mut self, val: u16) { self.a = (val >> 8) as u8; self.f = (val & 0x00F0) as u8; } pub fn set_bc(&mut self, val: u16) { self.b = (val >> 8) as u8; self.c = (val & 0xFF) as u8; } pub fn set_de(&mut self, val: u16) { self.d = (val >> 8) as u8; self.e = (val & 0xFF) as u8; } pub fn set_hl(&mut self, val: u16) { self.h = (val >> 8) as u8; self.l = (val & 0xFF) as u8; } const ZERO_FLAG_POS: u8 = 7; const SUBTRACT_FLAG_POS: u8 = 6; const HALF_CARRY_FLAG_POS: u8 = 5; const CARRY_FLAG_POS: u8 = 4; pub fn f_z(&self) -> bool { (self.f & (1 << Self::ZERO_FLAG_POS)) != 0 } pub fn f_n(&self) -> bool { (self.f & (1 << Self::SUBTRACT_FLAG_POS)) != 0 } pub fn f_h(&self) -> bool { (self.f & (1 << Self::HALF_CARRY_FLAG_POS)) != 0 } pub fn f_c(&self) -> bool { (self.f & (1 << Self::CARRY_FLAG_POS)) != 0 } fn set_flag_value(&mut self, bit: u8, value: bool) { if value { self.f |= 1 << bit; } else { self.f &= !(1 << bit); } self.f &= 0xF0; } pub fn set_f_z(&mut self, val: bool) { self.set_flag_value(7, val); } pub fn set_f_n(&mut self, val: bool) { self.set_flag_value(6, val); } pub fn set_f_h(&mut self, val: bool) { self.set_flag_value(5, val); } pub fn set_f_c(&mut self, val: bool) { self.set_flag_value(4, val); } }
 impl fmt::Display for CpuRegisters { fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} PC:{:04X} Flags[Z:{} N:{} H:{} C:{}]", self.af(), self.bc(), self.de(), self.hl(), self.sp, self.pc, self.f_z() as u8, self.f_n() as u8, self.f_h() as u8, self.f_c() as u8 ) } }
@@ -11,8 +15,20 @@ impl fmt::Display for CpuRegisters { fn fmt(&self, f: &mut fmt::Formatter<'_>) -
 pub struct Cpu { pub registers: CpuRegisters, pub mmu: Mmu, pub ime: bool, halted: bool, current_instruction_cycles: u8, pub total_clock_cycles: u64, }
 impl Cpu {
     pub fn new(mmu: Mmu) -> Self { Self { registers: CpuRegisters::new(), mmu, ime: false, halted: false, current_instruction_cycles: 0, total_clock_cycles: 0, } }
-    fn handle_interrupts(&mut self) -> bool { let ie = self.mmu.read_byte(0xFFFF); let mut if_val = self.mmu.read_io_register_byte(0xFF0F); let pending_and_enabled = if_val & ie & 0x1F; if self.halted && pending_and_enabled != 0 { self.halted = false; } if !self.ime || pending_and_enabled == 0 { return false; } self.ime = false; self.current_instruction_cycles += 20; for bit_num in 0..5 { if (pending_and_enabled & (1 << bit_num)) != 0 { if_val &= !(1 << bit_num); self.mmu.write_io_register_byte(0xFF0F, if_val); self.push_u16(self.registers.pc); self.registers.pc = match bit_num { 0 => VBLANK_INTERRUPT_ADDR, 1 => LCD_STAT_INTERRUPT_ADDR, 2 => TIMER_INTERRUPT_ADDR, 3 => SERIAL_INTERRUPT_ADDR, 4 => JOYPAD_INTERRUPT_ADDR, _ => unreachable!(), }; return true; } } false }
-    pub fn step(&mut self) -> u8 { self.current_instruction_cycles = 0; if self.handle_interrupts() { self.total_clock_cycles += self.current_instruction_cycles as u64; return self.current_instruction_cycles; } if self.halted { self.current_instruction_cycles = 4; self.total_clock_cycles += self.current_instruction_cycles as u64; self.mmu.tick_components(self.current_instruction_cycles); return self.current_instruction_cycles; } let opcode_addr = self.registers.pc; let opcode = self.read_byte(opcode_addr); self.registers.pc = self.registers.pc.wrapping_add(1); self.execute_opcode(opcode); self.total_clock_cycles += self.current_instruction_cycles as u64; self.mmu.tick_components(self.current_instruction_cycles); self.current_instruction_cycles }
+    /// レジスタと割り込み状態を起動直後 (PC=$0100) に戻します。メモリの内容は変更しません。
+    pub fn reset(&mut self) { self.registers = CpuRegisters::new(); self.ime = false; self.halted = false; }
+    /// ブートROMから起動するため、レジスタをすべて0 (PC=$0000) にします。
+    pub fn reset_for_boot_rom(&mut self) { self.registers = CpuRegisters::default(); self.ime = false; self.halted = false; }
+    pub fn save_state(&self, w: &mut StateWriter) { w.write(&self.registers); w.write(&self.ime); w.write(&self.halted); w.write(&self.total_clock_cycles); self.mmu.save_state(w); }
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> { r.read_into(&mut self.registers)?; r.read_into(&mut self.ime)?; r.read_into(&mut self.halted)?; r.read_into(&mut self.total_clock_cycles)?; self.mmu.load_state(r) }
+    pub fn is_halted(&self) -> bool { self.halted }
+    /// 次の `step` でPCの命令を実行するかを返します。割り込みに分岐する場合と、HALTから復帰しない場合は `false` です。
+    pub fn executes_next_step(&self) -> bool { let pending = self.mmu.read_io_register_byte(0xFF0F) & self.mmu.read_byte(0xFFFF) & 0x1F != 0; if self.ime && pending { return false; } !self.halted || pending }
+    /// PPUが次のフレームを完成させる (VBlankに入る) まで実行し、消費したTサイクル数を返します。
+    /// LCDがオフの間は1フレーム分のサイクルで打ち切ります。完成したフレームは `mmu.ppu.frame_ready` で確認できます。
+    pub fn run_until_frame(&mut self) -> u32 { self.mmu.ppu.frame_ready = false; let mut cycles: u32 = 0; while !self.mmu.ppu.frame_ready { cycles += self.step() as u32; if cycles >= CYCLES_PER_FRAME && !self.mmu.ppu.is_lcd_enabled() { break; } } cycles }
+    fn handle_interrupts(&mut self) -> bool { let ie = self.mmu.read_byte(0xFFFF); let mut if_val = self.mmu.read_io_register_byte(0xFF0F); let pending_and_enabled = if_val & ie & 0x1F; self.halted &= pending_and_enabled == 0; if !self.ime || pending_and_enabled == 0 { return false; } self.ime = false; self.current_instruction_cycles += 20; for bit_num in 0..5 { if (pending_and_enabled & (1 << bit_num)) != 0 { if_val &= !(1 << bit_num); self.mmu.write_io_register_byte(0xFF0F, if_val); self.push_u16(self.registers.pc); self.registers.pc = match bit_num { 0 => VBLANK_INTERRUPT_ADDR, 1 => LCD_STAT_INTERRUPT_ADDR, 2 => TIMER_INTERRUPT_ADDR, 3 => SERIAL_INTERRUPT_ADDR, 4 => JOYPAD_INTERRUPT_ADDR, _ => unreachable!(), }; return true; } } false }
+    pub fn step(&mut self) -> u8 { self.current_instruction_cycles = 0; if self.handle_interrupts() { self.total_clock_cycles += self.current_instruction_cycles as u64; return self.current_instruction_cycles; } else if self.halted { self.current_instruction_cycles = 4; self.total_clock_cycles += self.current_instruction_cycles as u64; self.mmu.tick_components(self.current_instruction_cycles); return self.current_instruction_cycles; } let opcode_addr = self.registers.pc; let opcode = self.read_byte(opcode_addr); self.registers.pc = self.registers.pc.wrapping_add(1); self.execute_opcode(opcode); self.total_clock_cycles += self.current_instruction_cycles as u64; self.mmu.tick_components(self.current_instruction_cycles); self.current_instruction_cycles }
     fn read_byte(&mut self, addr: u16) -> u8 { let val = self.mmu.read_byte(addr); self.current_instruction_cycles += 4; val }
     fn write_byte(&mut self, addr: u16, val: u8) { self.mmu.write_byte(addr, val); self.current_instruction_cycles += 4; }
     fn write_word(&mut self, addr: u16, val: u16) { self.write_byte(addr, (val & 0xFF) as u8); self.write_byte(addr.wrapping_add(1), (val >> 8) as u8); }
@@ -35,7 +51,7 @@ impl Cpu {
     fn cb_rr(&mut self, value: u8) -> u8 { let oc = self.registers.f_c() as u8; let nc = value & 1; let res = (value >> 1) | (oc << 7); self.registers.set_f_z(res == 0); self.registers.set_f_n(false); self.registers.set_f_h(false); self.registers.set_f_c(nc == 1); res }
     fn cb_sla(&mut self, value: u8) -> u8 { let c = (value >> 7) & 1; let res = value << 1; self.registers.set_f_z(res == 0); self.registers.set_f_n(false); self.registers.set_f_h(false); self.registers.set_f_c(c == 1); res }
     fn cb_sra(&mut self, value: u8) -> u8 { let c = value & 1; let res = (value >> 1) | (value & 0x80); self.registers.set_f_z(res == 0); self.registers.set_f_n(false); self.registers.set_f_h(false); self.registers.set_f_c(c == 1); res }
-    fn cb_swap(&mut self, value: u8) -> u8 { let res = (value << 4) | (value >> 4); self.registers.set_f_z(res == 0); self.registers.set_f_n(false); self.registers.set_f_h(false); self.registers.set_f_c(false); res }
+    fn cb_swap(&mut self, value: u8) -> u8 { let res = value.rotate_right(4); self.registers.set_f_z(res == 0); self.registers.set_f_n(false); self.registers.set_f_h(false); self.registers.set_f_c(false); res }
     fn cb_srl(&mut self, value: u8) -> u8 { let c = value & 1; let res = value >> 1; self.registers.set_f_z(res == 0); self.registers.set_f_n(false); self.registers.set_f_h(false); self.registers.set_f_c(c == 1); res }
     fn execute_cb_prefixed(&mut self) { let cb_opcode = self.fetch_byte_operand(); let reg_idx = cb_opcode & 0x07; let operation_sub_type = (cb_opcode >> 3) & 0x07; let operation_main_type = cb_opcode >> 6; let (current_value, is_hl) = if reg_idx == 0x06 { (self.read_byte(self.registers.hl()), true) } else { (self.get_reg_by_idx(reg_idx), false) }; let result_value = match operation_main_type { 0x00 => { match operation_sub_type { 0x00 => self.cb_rlc(current_value), 0x01 => self.cb_rrc(current_value), 0x02 => self.cb_rl(current_value), 0x03 => self.cb_rr(current_value), 0x04 => self.cb_sla(current_value), 0x05 => self.cb_sra(current_value), 0x06 => self.cb_swap(current_value), 0x07 => self.cb_srl(current_value), _ => unreachable!(), } } 0x01 => { let bit_to_test = operation_sub_type; self.registers.set_f_z((current_value & (1 << bit_to_test)) == 0); self.registers.set_f_n(false); self.registers.set_f_h(true); current_value } 0x02 => current_value & !(1 << operation_sub_type), 0x03 => current_value | (1 << operation_sub_type), _ => unreachable!(), }; if operation_main_type != 0x01 { if is_hl { self.write_byte(self.registers.hl(), result_value); } else { self.set_reg_by_idx(reg_idx, result_value); } } }
     fn get_reg_by_idx(&self, idx: u8) -> u8 { match idx { 0 => self.registers.b, 1 => self.registers.c, 2 => self.registers.d, 3 => self.registers.e, 4 => self.registers.h, 5 => self.registers.l, 7 => self.registers.a, _ => unreachable!(), } }
@@ -144,10 +160,10 @@ impl Cpu {
             0xD2 => { let addr = self.fetch_word_operand(); if !self.registers.f_c() { self.registers.pc = addr; self.current_instruction_cycles += 4;} }
             0xDA => { let addr = self.fetch_word_operand(); if  self.registers.f_c() { self.registers.pc = addr; self.current_instruction_cycles += 4;} }
             0xCD => { let addr = self.fetch_word_operand(); self.push_u16(self.registers.pc); self.registers.pc = addr; }
-            0xC4 => { let addr = self.fetch_word_operand(); if !self.registers.f_z() { self.push_u16(self.registers.pc); self.registers.pc = addr; } else {} }
-            0xCC => { let addr = self.fetch_word_operand(); if  self.registers.f_z() { self.push_u16(self.registers.pc); self.registers.pc = addr; } else {} }
-            0xD4 => { let addr = self.fetch_word_operand(); if !self.registers.f_c() { self.push_u16(self.registers.pc); self.registers.pc = addr; } else {} }
-            0xDC => { let addr = self.fetch_word_operand(); if  self.registers.f_c() { self.push_u16(self.registers.pc); self.registers.pc = addr; } else {} }
+            0xC4 => { let addr = self.fetch_word_operand(); if !self.registers.f_z() { self.push_u16(self.registers.pc); self.registers.pc = addr; } }
+            0xCC => { let addr = self.fetch_word_operand(); if  self.registers.f_z() { self.push_u16(self.registers.pc); self.registers.pc = addr; } }
+            0xD4 => { let addr = self.fetch_word_operand(); if !self.registers.f_c() { self.push_u16(self.registers.pc); self.registers.pc = addr; } }
+            0xDC => { let addr = self.fetch_word_operand(); if  self.registers.f_c() { self.push_u16(self.registers.pc); self.registers.pc = addr; } }
             0xC9 => { self.registers.pc = self.pop_u16(); self.current_instruction_cycles += 4; }
             0xD9 => { self.registers.pc = self.pop_u16(); self.ime = true; self.current_instruction_cycles += 4; }
             0xC0 => { if !self.registers.f_z() { self.registers.pc = self.pop_u16(); self.current_instruction_cycles += 8; } else { self.current_instruction_cycles +=4; } }
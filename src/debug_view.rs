@@ -7,8 +7,8 @@ use crate::timer::Timer;
 
 pub const DEBUG_WIDTH: usize = 480;
 pub const DEBUG_HEIGHT: usize = 320;
-const CHAR_WIDTH: usize = 8;
-const CHAR_HEIGHT: usize = 8;
+pub const CHAR_WIDTH: usize = 8;
+pub const CHAR_HEIGHT: usize = 8;
 
 // --- カラーテーマ ---
 const COLOR_BG: u32 = 0xFF0A0F1A;
@@ -121,12 +121,16 @@ static FONT_DATA: [[u8; CHAR_HEIGHT]; 95] = [
 ];
 
 
+/// 文字の8x8のビットマップ (各行の最上位ビットが左端)。ASCIIの表示できる文字以外は `None` です。
+pub fn font_glyph(c: char) -> Option<&'static [u8; CHAR_HEIGHT]> {
+    let char_code = c as usize;
+    if !(32..=126).contains(&char_code) { return None; }
+    Some(&FONT_DATA[char_code - 32])
+}
+
 fn draw_char(buffer: &mut [u32], char_to_draw: char, start_x: usize, start_y: usize, color: u32) {
-    let char_code = char_to_draw as usize;
-    if !(32..=126).contains(&char_code) { return; }
-    let glyph = FONT_DATA[char_code - 32];
-    for y in 0..CHAR_HEIGHT {
-        let row = glyph[y];
+    let Some(glyph) = font_glyph(char_to_draw) else { return; };
+    for (y, &row) in glyph.iter().enumerate() {
         for x in 0..CHAR_WIDTH {
             if (row >> (7 - x)) & 1 == 1 {
                 let px = start_x + x;
@@ -154,6 +158,7 @@ fn draw_background(buffer: &mut [u32]) {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn draw_waveform(buffer: &mut [u32], x: usize, y: usize, w: usize, h: usize, waveform: &[f32], color: u32, is_periodic: bool) {
     let h_mid = y + h / 2;
     for i in 1..w { buffer[h_mid * DEBUG_WIDTH + x + i] = COLOR_GRID_LIGHT; }
@@ -163,11 +168,9 @@ fn draw_waveform(buffer: &mut [u32], x: usize, y: usize, w: usize, h: usize, wav
     let samples_to_draw: &[f32] = if is_periodic && waveform.len() > w {
         let mut trigger_offset: Option<usize> = None;
         for i in (0..waveform.len() - 1).rev() {
-            if waveform[i] <= TRIGGER_LEVEL && waveform[i+1] > TRIGGER_LEVEL {
-                if waveform.len() - (i + 1) >= w {
-                    trigger_offset = Some(i + 1);
-                    break;
-                }
+            if waveform[i] <= TRIGGER_LEVEL && waveform[i+1] > TRIGGER_LEVEL && waveform.len() - (i + 1) >= w {
+                trigger_offset = Some(i + 1);
+                break;
             }
         }
         
@@ -253,6 +256,7 @@ fn draw_oam_viewer(buffer: &mut [u32], ppu: &Ppu, start_x: usize, start_y: usize
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn draw(
     buffer: &mut [u32],
     cpu_regs: CpuRegisters,
@@ -313,7 +317,8 @@ pub fn draw(
 
     for i in 0..4 {
         let is_periodic = i < 3;
-        draw_text(buffer, &format!("[{}]", scope_titles[i]), right_panel_x, current_y, WAVEFORM_COLORS[i]);
+        let mix_label = if apu_state.solo_channel == Some(i) { "SOLO".to_string() } else if apu_state.channel_muted[i] { "MUTE".to_string() } else if !apu_state.channel_audible[i] { "-".to_string() } else { format!("{:.0}%", apu_state.channel_gain[i] * 100.0) };
+        draw_text(buffer, &format!("[{}] {} {}", scope_titles[i], i + 1, mix_label), right_panel_x, current_y, WAVEFORM_COLORS[i]);
         draw_waveform(buffer, right_panel_x, current_y + 10, scope_w, scope_h, &waveforms[i], WAVEFORM_COLORS[i], is_periodic);
         current_y += scope_h + 20;
     }
@@ -324,4 +329,28 @@ pub fn draw(
     draw_text(buffer, &wave_ram_str[0..8].join(" "), right_panel_x, current_y, COLOR_VALUE);
     current_y += 10;
     draw_text(buffer, &wave_ram_str[8..16].join(" "), right_panel_x, current_y, COLOR_VALUE);
+}
+/// GBSプレーヤー画面 (曲情報とチャンネルごとのオシロスコープ) を描画します。
+pub fn draw_player(buffer: &mut [u32], info_lines: &[String], apu_state: &ApuState, waveforms: &[Vec<f32>; 4]) {
+    draw_background(buffer);
+
+    let x = 10;
+    let mut current_y = 10;
+    draw_text(buffer, "[GBS PLAYER]", x, current_y, COLOR_TITLE);
+    current_y += 15;
+    for line in info_lines {
+        draw_text(buffer, line, x, current_y, COLOR_VALUE);
+        current_y += 10;
+    }
+    current_y += 5;
+
+    let scope_w = DEBUG_WIDTH - x * 2;
+    let scope_h = (DEBUG_HEIGHT - current_y) / 4 - 15;
+    let scope_titles = ["PULSE 1", "PULSE 2", "WAVE", "NOISE"];
+    for i in 0..4 {
+        let mix_label = if apu_state.solo_channel == Some(i) { "SOLO" } else if apu_state.channel_muted[i] { "MUTE" } else { "" };
+        draw_text(buffer, &format!("[{}] {}", scope_titles[i], mix_label), x, current_y, WAVEFORM_COLORS[i]);
+        draw_waveform(buffer, x, current_y + 10, scope_w, scope_h, &waveforms[i], WAVEFORM_COLORS[i], i < 3);
+        current_y += scope_h + 15;
+    }
 }
\ No newline at end of file
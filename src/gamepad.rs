@@ -0,0 +1,206 @@
+// src/gamepad.rs
+// ゲームコントローラー入力 (gilrs)
+//
+// 接続中のすべてのコントローラーの状態をまとめて1台のGame Boyの入力として扱う。
+// 抜き差しはgilrsのイベントで検出し、振動カートリッジのモーターはフォースフィードバックに反映する。
+
+use gilrs::ff::{BaseEffect, BaseEffectType, Effect, EffectBuilder, Replay, Ticks};
+use gilrs::{Axis, Button, EventType, GamepadId, Gilrs};
+
+use crate::config::{GamepadConfig, Hotkey};
+use crate::joypad::GameboyKey;
+
+// 振動の強さ (0-65535)
+const RUMBLE_MAGNITUDE: u16 = 48_000;
+// 振動の強さを何段階に丸めてコントローラーに送るか (毎フレームの細かな変化で送信しないため)
+const RUMBLE_LEVELS: f32 = 8.0;
+
+const ALL_BUTTONS: [Button; 19] = [
+    Button::South, Button::East, Button::North, Button::West, Button::C, Button::Z,
+    Button::LeftTrigger, Button::LeftTrigger2, Button::RightTrigger, Button::RightTrigger2,
+    Button::Select, Button::Start, Button::Mode, Button::LeftThumb, Button::RightThumb,
+    Button::DPadUp, Button::DPadDown, Button::DPadLeft, Button::DPadRight,
+];
+
+/// gilrsのボタン名 (例: "South", "Start", "DPadUp") をボタンに変換します。大文字小文字は区別しません。
+pub fn parse_button(name: &str) -> Option<Button> {
+    ALL_BUTTONS.iter().copied().find(|button| format!("{:?}", button).eq_ignore_ascii_case(name.trim()))
+}
+
+fn resolve_buttons(names: &[String]) -> Vec<Button> {
+    names.iter().filter_map(|name| {
+        let button = parse_button(name);
+        if button.is_none() { eprintln!("Unknown gamepad button '{}' in config, ignored.", name); }
+        button
+    }).collect()
+}
+
+struct Combo {
+    hotkey: Hotkey,
+    buttons: Vec<Button>,
+    was_active: bool,
+}
+
+/// 1回のポーリングで得られた入力
+#[derive(Debug, Default)]
+pub struct GamepadState {
+    /// 押されているGame Boyのボタン
+    pub buttons: Vec<GameboyKey>,
+    /// 連射ボタンで押されているGame Boyのボタン
+    pub autofire: Vec<GameboyKey>,
+    /// このポーリングで新たに成立したホットキー
+    pub hotkeys_pressed: Vec<Hotkey>,
+    /// 成立中のホットキー (ターボなど押している間だけ働くもの用)
+    pub hotkeys_held: Vec<Hotkey>,
+}
+
+pub struct GamepadInput {
+    gilrs: Gilrs,
+    mapping: Vec<(GameboyKey, Vec<Button>)>,
+    autofire: Vec<(GameboyKey, Vec<Button>)>,
+    combos: Vec<Combo>,
+    deadzone: f32,
+    rumble_enabled: bool,
+    rumble_effect: Option<Effect>,
+    // コントローラーに送った振動の強さ (0.0 で停止)
+    rumble_strength: f32,
+}
+
+impl GamepadInput {
+    /// コントローラー入力を初期化します。無効化されているか初期化に失敗した場合は `None` を返します。
+    pub fn new(config: &GamepadConfig) -> Option<Self> {
+        if !config.enabled { return None; }
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => gilrs,
+            Err(e) => {
+                eprintln!("Gamepad support is unavailable: {}", e);
+                return None;
+            }
+        };
+        for (_, gamepad) in gilrs.gamepads() {
+            println!("Gamepad connected: {}", gamepad.name());
+        }
+        let mapping = config.buttons.mapping().iter().map(|(key, names)| (*key, resolve_buttons(names))).collect();
+        let autofire = vec![(GameboyKey::A, resolve_buttons(&config.turbo_a)), (GameboyKey::B, resolve_buttons(&config.turbo_b))];
+        let combos = config.hotkeys.combos().into_iter()
+            .map(|(hotkey, names)| Combo { hotkey, buttons: resolve_buttons(names), was_active: false })
+            .filter(|combo| !combo.buttons.is_empty())
+            .collect();
+        Some(Self {
+            gilrs,
+            mapping,
+            autofire,
+            combos,
+            deadzone: config.deadzone.clamp(0.0, 0.95),
+            rumble_enabled: config.rumble,
+            rumble_effect: None,
+            rumble_strength: 0.0,
+        })
+    }
+
+    /// イベントを処理して、接続中の全コントローラーの入力をまとめて返します。
+    pub fn poll(&mut self) -> GamepadState {
+        while let Some(event) = self.gilrs.next_event() {
+            match event.event {
+                EventType::Connected => {
+                    println!("Gamepad connected: {}", self.gilrs.gamepad(event.id).name());
+                    self.rebuild_rumble();
+                }
+                EventType::Disconnected => {
+                    println!("Gamepad disconnected: {}", self.gilrs.gamepad(event.id).name());
+                    self.rebuild_rumble();
+                }
+                _ => (),
+            }
+        }
+
+        let mut pressed: Vec<Button> = Vec::new();
+        let mut stick = (0.0f32, 0.0f32);
+        for (_, gamepad) in self.gilrs.gamepads() {
+            pressed.extend(ALL_BUTTONS.iter().copied().filter(|&button| gamepad.is_pressed(button)));
+            let (x, y) = (gamepad.value(Axis::LeftStickX), gamepad.value(Axis::LeftStickY));
+            if x.abs() > stick.0.abs() { stick.0 = x; }
+            if y.abs() > stick.1.abs() { stick.1 = y; }
+        }
+
+        let mut state = GamepadState::default();
+        // 成立中の組み合わせに含まれるボタンはゲームに渡さない (Select+Startでリセットされるのを防ぐ)
+        let mut consumed: Vec<Button> = Vec::new();
+        for combo in &mut self.combos {
+            let active = combo.buttons.iter().all(|button| pressed.contains(button));
+            if active {
+                if !combo.was_active { state.hotkeys_pressed.push(combo.hotkey); }
+                state.hotkeys_held.push(combo.hotkey);
+                if combo.buttons.len() > 1 { consumed.extend(combo.buttons.iter().copied()); }
+            }
+            combo.was_active = active;
+        }
+
+        for (key, buttons) in &self.mapping {
+            if buttons.iter().any(|button| pressed.contains(button) && !consumed.contains(button)) {
+                state.buttons.push(*key);
+            }
+        }
+        for (key, buttons) in &self.autofire {
+            if buttons.iter().any(|button| pressed.contains(button) && !consumed.contains(button)) {
+                state.autofire.push(*key);
+            }
+        }
+        // 左スティック (上が正)
+        let dz = self.deadzone;
+        let stick_keys = [(stick.0 > dz, GameboyKey::Right), (stick.0 < -dz, GameboyKey::Left), (stick.1 > dz, GameboyKey::Up), (stick.1 < -dz, GameboyKey::Down)];
+        for (active, key) in stick_keys {
+            if active && !state.buttons.contains(&key) { state.buttons.push(key); }
+        }
+        state
+    }
+
+    fn rebuild_rumble(&mut self) {
+        self.rumble_effect = None;
+        let strength = self.rumble_strength;
+        if strength > 0.0 {
+            self.rumble_strength = 0.0;
+            self.set_rumble(strength);
+        }
+    }
+
+    fn create_rumble_effect(&mut self) -> Option<Effect> {
+        let ids: Vec<GamepadId> = self.gilrs.gamepads().filter(|(_, gamepad)| gamepad.is_ff_supported()).map(|(id, _)| id).collect();
+        if ids.is_empty() { return None; }
+        let effect = BaseEffect {
+            kind: BaseEffectType::Strong { magnitude: RUMBLE_MAGNITUDE },
+            scheduling: Replay { play_for: Ticks::from_ms(50), ..Default::default() },
+            envelope: Default::default(),
+        };
+        match EffectBuilder::new().add_effect(effect).gamepads(&ids).finish(&mut self.gilrs) {
+            Ok(effect) => Some(effect),
+            Err(e) => {
+                eprintln!("Failed to create rumble effect: {}", e);
+                None
+            }
+        }
+    }
+
+    /// 振動の強さ (0.0-1.0) を設定します。0.0 で停止します。
+    /// 強さは段階に丸め、変わったときだけコントローラーに送ります。
+    pub fn set_rumble(&mut self, strength: f32) {
+        let strength = (strength.clamp(0.0, 1.0) * RUMBLE_LEVELS).round() / RUMBLE_LEVELS;
+        if !self.rumble_enabled || strength == self.rumble_strength { return; }
+        let was_on = self.rumble_strength > 0.0;
+        self.rumble_strength = strength;
+        if self.rumble_effect.is_none() {
+            if strength == 0.0 { return; }
+            self.rumble_effect = self.create_rumble_effect();
+        }
+        if let Some(effect) = &self.rumble_effect {
+            let result = if strength == 0.0 {
+                effect.stop()
+            } else {
+                effect.set_gain(strength).and_then(|()| if was_on { Ok(()) } else { effect.play() })
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to update rumble: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,267 @@
+// src/gbs.rs
+// GBS (Game Boy Sound System) ファイルの読み込みと再生用ROMの合成
+//
+// GBSファイルはゲームのサウンドドライバとデータだけを抜き出したもの。
+// ロードアドレスにコードを配置し、以下のスタブを持つROMイメージを合成して
+// 既存の Cpu/Mmu/Apu でそのまま実行する。
+// - $0000-$0038: RST命令をロードアドレス + n へ転送
+// - $0040/$0050: VBlank/タイマー割り込みで play ルーチンを呼び出して RETI
+// - $0150: 初期化 (SP, タイマー, IE) の後、A = 曲番号で init を呼び出し、以降は HALT で待機
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::cpu::Cpu;
+
+pub const GBS_HEADER_SIZE: usize = 0x70;
+const MIN_LOAD_ADDRESS: u16 = 0x0400;
+const ENTRY_STUB_ADDRESS: usize = 0x0150;
+// エントリスタブ内で曲番号 (LD A,n のオペランド) が置かれる位置
+const TRACK_OPERAND_ADDRESS: usize = ENTRY_STUB_ADDRESS + 24;
+
+#[derive(Debug, Clone)]
+pub struct GbsHeader {
+    pub version: u8,
+    pub song_count: u8,
+    /// 最初に再生する曲 (1始まり)
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+fn read_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if data.len() < GBS_HEADER_SIZE || &data[0..3] != b"GBS" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a GBS file."));
+        }
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let header = Self {
+            version: data[0x03],
+            song_count: data[0x04],
+            first_song: data[0x05],
+            load_address: read_u16(0x06),
+            init_address: read_u16(0x08),
+            play_address: read_u16(0x0A),
+            stack_pointer: read_u16(0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: read_string(&data[0x10..0x30]),
+            author: read_string(&data[0x30..0x50]),
+            copyright: read_string(&data[0x50..0x70]),
+        };
+        if header.load_address < MIN_LOAD_ADDRESS || header.load_address >= 0x8000 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported GBS load address: {:#06x}", header.load_address)));
+        }
+        Ok(header)
+    }
+}
+
+pub struct GbsFile {
+    pub header: GbsHeader,
+    pub code: Vec<u8>,
+}
+
+impl GbsFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Self::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let header = GbsHeader::parse(data)?;
+        Ok(Self { header, code: data[GBS_HEADER_SIZE..].to_vec() })
+    }
+
+    /// 再生ルーチンをタイマー割り込みで呼び出すか (false の場合はVBlank)
+    pub fn uses_timer(&self) -> bool {
+        (self.header.timer_control & 0x04) != 0
+    }
+
+    /// play ルーチンの呼び出し頻度 (Hz)
+    pub fn play_rate(&self) -> f64 {
+        if !self.uses_timer() { return 4_194_304.0 / 70224.0; }
+        let input_clock = match self.header.timer_control & 0x03 { 0 => 4096.0, 1 => 262144.0, 2 => 65536.0, _ => 16384.0 };
+        input_clock / (256.0 - self.header.timer_modulo as f64)
+    }
+
+    pub fn print_info(&self) {
+        let header = &self.header;
+        println!("--- GBS Info ---");
+        println!("Title: {}", header.title);
+        println!("Author: {}", header.author);
+        println!("Copyright: {}", header.copyright);
+        println!("Songs: {} (first: {})", header.song_count, header.first_song);
+        println!("Load/Init/Play: {:#06x} / {:#06x} / {:#06x}", header.load_address, header.init_address, header.play_address);
+        println!("Play rate: {:.2} Hz ({})", self.play_rate(), if self.uses_timer() { "timer" } else { "VBlank" });
+    }
+
+    /// コードをロードアドレスに配置し、割り込みベクタとエントリスタブを持つROMイメージを合成します。
+    /// カートリッジタイプはMBC1 + RAMとしておき、実際のバンク切り替えは Mmu::new_gbs のマッパーが行います。
+    pub fn build_rom(&self) -> Vec<u8> {
+        let header = &self.header;
+        let load = header.load_address as usize;
+        let rom_size = (load + self.code.len()).div_ceil(0x4000).max(2).next_power_of_two() * 0x4000;
+        let mut rom = vec![0xFF; rom_size];
+        rom[load..load + self.code.len()].copy_from_slice(&self.code);
+
+        // RST $00-$38 → ロードアドレス + n
+        for n in (0..0x40).step_by(8) {
+            let target = header.load_address.wrapping_add(n as u16).to_le_bytes();
+            rom[n..n + 3].copy_from_slice(&[0xC3, target[0], target[1]]);
+        }
+        // 割り込みベクタ: VBlank ($40) とタイマー ($50) は CALL play; RETI、それ以外は RETI
+        let play = header.play_address.to_le_bytes();
+        for vector in [0x40, 0x48, 0x50, 0x58, 0x60] {
+            if vector == 0x40 || vector == 0x50 {
+                rom[vector..vector + 4].copy_from_slice(&[0xCD, play[0], play[1], 0xD9]);
+            } else {
+                rom[vector] = 0xD9;
+            }
+        }
+
+        // カートリッジヘッダ
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, ENTRY_STUB_ADDRESS as u8, (ENTRY_STUB_ADDRESS >> 8) as u8]);
+        rom[0x104..0x150].fill(0x00);
+        for (i, b) in header.title.bytes().filter(|b| b.is_ascii()).take(15).enumerate() {
+            rom[0x134 + i] = b;
+        }
+        rom[0x147] = 0x02;
+        rom[0x148] = (rom_size / 0x8000).trailing_zeros() as u8;
+        rom[0x149] = 0x02;
+
+        let sp = header.stack_pointer.to_le_bytes();
+        let init = header.init_address.to_le_bytes();
+        let interrupt_enable = if self.uses_timer() { 0x04 } else { 0x01 };
+        let stub = [
+            0xF3,                               // DI
+            0x31, sp[0], sp[1],                 // LD SP,nn
+            0x3E, 0x80, 0xE0, 0x40,             // LD A,$80 ; LDH (LCDC),A
+            0x3E, header.timer_modulo, 0xE0, 0x06, // LD A,TMA ; LDH (TMA),A
+            0x3E, header.timer_control, 0xE0, 0x07, // LD A,TAC ; LDH (TAC),A
+            0x3E, interrupt_enable, 0xE0, 0xFF, // LD A,IE ; LDH (IE),A
+            0xAF, 0xE0, 0x0F,                   // XOR A ; LDH (IF),A
+            0x3E, 0x00,                         // LD A,曲番号 (start_track で書き換える)
+            0xCD, init[0], init[1],             // CALL init
+            0xFB,                               // EI
+            0x76,                               // HALT
+            0x18, 0xFD,                         // JR -3 (HALTへ戻る)
+        ];
+        rom[ENTRY_STUB_ADDRESS..ENTRY_STUB_ADDRESS + stub.len()].copy_from_slice(&stub);
+        rom
+    }
+}
+
+/// 指定した曲 (0始まり) の再生を最初から開始します。`cpu` は `Mmu::new_gbs` で作成したものを渡してください。
+pub fn start_track(cpu: &mut Cpu, track: u8) {
+    cpu.mmu.cartridge.raw_data[TRACK_OPERAND_ADDRESS] = track;
+    cpu.mmu.reset_for_gbs();
+    cpu.reset();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::Apu;
+    use crate::cartridge::Cartridge;
+    use crate::mmu::Mmu;
+
+    const LOAD_ADDRESS: u16 = 0x0400;
+
+    // init は A (曲番号) を $C000 へ書き、play は $C001 を数える
+    fn tiny_gbs(timer_modulo: u8, timer_control: u8) -> GbsFile {
+        let mut data = vec![0; GBS_HEADER_SIZE];
+        data[0..3].copy_from_slice(b"GBS");
+        data[0x03] = 1;
+        data[0x04] = 3;
+        data[0x05] = 1;
+        data[0x06..0x08].copy_from_slice(&LOAD_ADDRESS.to_le_bytes());
+        data[0x08..0x0A].copy_from_slice(&LOAD_ADDRESS.to_le_bytes());
+        data[0x0A..0x0C].copy_from_slice(&(LOAD_ADDRESS + 4).to_le_bytes());
+        data[0x0C..0x0E].copy_from_slice(&0xFFFEu16.to_le_bytes());
+        data[0x0E] = timer_modulo;
+        data[0x0F] = timer_control;
+        data[0x10..0x14].copy_from_slice(b"Test");
+        data.extend([0xEA, 0x00, 0xC0, 0xC9]); // LD ($C000),A; RET
+        data.extend([0x21, 0x01, 0xC0, 0x34, 0xC9]); // LD HL,$C001; INC (HL); RET
+        GbsFile::from_bytes(&data).unwrap()
+    }
+
+    #[test]
+    fn build_rom_places_code_vectors_and_stub() {
+        let gbs = tiny_gbs(0, 0);
+        let rom = gbs.build_rom();
+        let load = LOAD_ADDRESS as usize;
+        assert_eq!(rom.len(), 0x8000);
+        assert_eq!(&rom[load..load + gbs.code.len()], &gbs.code[..]);
+        for n in (0..0x40).step_by(8) {
+            let target = (LOAD_ADDRESS + n as u16).to_le_bytes();
+            assert_eq!(&rom[n..n + 3], &[0xC3, target[0], target[1]], "RST {:02X}", n);
+        }
+        let play = (LOAD_ADDRESS + 4).to_le_bytes();
+        assert_eq!(&rom[0x40..0x44], &[0xCD, play[0], play[1], 0xD9]);
+        assert_eq!(&rom[0x50..0x54], &[0xCD, play[0], play[1], 0xD9]);
+        assert_eq!(rom[0x48], 0xD9);
+        assert_eq!(&rom[0x100..0x104], &[0x00, 0xC3, 0x50, 0x01]);
+        assert_eq!(&rom[0x134..0x138], b"Test");
+        assert_eq!((rom[0x147], rom[0x149]), (0x02, 0x02));
+
+        // LD A,曲番号 のオペランドの直後が CALL init
+        assert_eq!(rom[TRACK_OPERAND_ADDRESS - 1], 0x3E);
+        let init = LOAD_ADDRESS.to_le_bytes();
+        assert_eq!(&rom[TRACK_OPERAND_ADDRESS + 1..TRACK_OPERAND_ADDRESS + 4], &[0xCD, init[0], init[1]]);
+        // JR は HALT へ戻る
+        let halt = TRACK_OPERAND_ADDRESS + 5;
+        assert_eq!(&rom[halt - 1..halt + 2], &[0xFB, 0x76, 0x18]);
+        assert_eq!(halt as isize + 3 + rom[halt + 2] as i8 as isize, halt as isize);
+    }
+
+    #[test]
+    fn play_rate_follows_vblank_or_timer() {
+        assert!((tiny_gbs(0xBC, 0x00).play_rate() - 59.7275).abs() < 0.001);
+        assert!(!tiny_gbs(0, 0x03).uses_timer());
+        assert_eq!(tiny_gbs(0x00, 0x04).play_rate(), 16.0);
+        assert_eq!(tiny_gbs(0xBC, 0x05).play_rate(), 262144.0 / 68.0);
+        assert_eq!(tiny_gbs(0xC0, 0x06).play_rate(), 1024.0);
+        assert_eq!(tiny_gbs(0xFF, 0x07).play_rate(), 16384.0);
+    }
+
+    #[test]
+    fn start_track_calls_init_then_play() {
+        // VBlank 駆動とタイマー駆動 (4096Hz / 64 = 64Hz)
+        for (timer_control, plays_per_second) in [(0x00, 59.7), (0x04, 64.0)] {
+            let gbs = tiny_gbs(0xC0, timer_control);
+            let mut cpu = Cpu::new(Mmu::new_gbs(Cartridge::from_bytes(gbs.build_rom()).unwrap(), Apu::new(44_100)));
+            for track in [2, 1] {
+                start_track(&mut cpu, track);
+                // TIMA は 0 から数え始めるので、最初の 0.25秒は読み飛ばして次の 0.25秒の回数を数える
+                let mut counts = Vec::new();
+                for _ in 0..2 {
+                    let start = cpu.total_clock_cycles;
+                    while cpu.total_clock_cycles - start < 4_194_304 / 4 {
+                        cpu.step();
+                    }
+                    counts.push(cpu.mmu.read_byte(0xC001) as f64);
+                }
+                assert_eq!(cpu.mmu.read_byte(0xC000), track);
+                assert!(counts[0] > 0.0);
+                let plays = counts[1] - counts[0];
+                assert!((plays - plays_per_second / 4.0).abs() <= 1.0, "TAC {:02X}: {} plays", timer_control, plays);
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+// src/headless.rs
+// ウィンドウや音声出力なしでエミュレータを動かす (スクリプト、テストROM、スクリーンショット用)
+//
+// 音はAPUで生成するが出力デバイスへは送らない。入力はフレームごとのボタンのマスク
+// (input::BUTTONS のビット順) で与える。
+
+use std::io;
+use std::path::Path;
+
+use image::RgbaImage;
+
+use crate::apu::Apu;
+use crate::cartridge::Cartridge;
+use crate::cpu::Cpu;
+use crate::input;
+use crate::mmu::Mmu;
+use crate::ppu::{CYCLES_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::save_state;
+use crate::sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+
+// 出力しない音声のサンプルレート (APUの内部処理用)
+pub const HEADLESS_SAMPLE_RATE: u32 = 44_100;
+// デバッグ用のブレークポイントとしてテストROMが使う命令
+const LD_B_B: u8 = 0x40;
+
+/// エミュレートするハードウェア
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    /// カートリッジのSGB対応フラグから決める
+    #[default]
+    Auto,
+    Dmg,
+    Sgb,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HeadlessOptions {
+    pub model: Model,
+    /// DMG/SGBのブートROM (256バイト)。指定すると $0000 から起動します
+    pub boot_rom: Option<Vec<u8>>,
+}
+
+/// カートリッジからCPUとMMUを組み立てます。ウィンドウ版のフロントエンドと同じ初期化を行います。
+pub fn create_cpu(cartridge: Cartridge, apu: Apu, options: &HeadlessOptions) -> io::Result<Cpu> {
+    let mut mmu = Mmu::new(cartridge, apu);
+    let use_sgb = match options.model {
+        Model::Auto => mmu.cartridge.header.sgb_support,
+        Model::Dmg => false,
+        Model::Sgb => true,
+    };
+    if use_sgb {
+        mmu.enable_sgb();
+    }
+    let has_boot_rom = options.boot_rom.is_some();
+    if let Some(boot_rom) = &options.boot_rom {
+        mmu.load_boot_rom(boot_rom.clone())?;
+    }
+    let mut cpu = Cpu::new(mmu);
+    if has_boot_rom {
+        cpu.reset_for_boot_rom();
+    }
+    Ok(cpu)
+}
+
+/// 色 (0x00RRGGBB) の画面をPNG用の画像にします。
+pub fn to_image(pixels: &[u32], width: usize, height: usize) -> RgbaImage {
+    RgbaImage::from_fn(width as u32, height as u32, |x, y| {
+        let pixel = pixels[y as usize * width + x as usize];
+        image::Rgba([(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8, 255])
+    })
+}
+
+/// 表示される画面 (SGBでは枠と着色を含む) を描き、画素と幅・高さを返します。
+pub fn render_screen(cpu: &Cpu) -> (Vec<u32>, usize, usize) {
+    match &cpu.mmu.sgb {
+        Some(sgb) => {
+            let mut pixels = vec![0; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT];
+            sgb.render(&cpu.mmu.ppu.shade_buffer[..], &mut pixels);
+            (pixels, SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT)
+        }
+        None => (cpu.mmu.ppu.frame_buffer.to_vec(), SCREEN_WIDTH, SCREEN_HEIGHT),
+    }
+}
+
+pub struct Headless {
+    pub cpu: Box<Cpu>,
+    sgb_buffer: Vec<u32>,
+    frame_count: u64,
+}
+
+impl Headless {
+    pub fn new(cartridge: Cartridge, options: &HeadlessOptions) -> io::Result<Self> {
+        let mut apu = Apu::new(HEADLESS_SAMPLE_RATE);
+        apu.set_output_speed(None);
+        let cpu = Box::new(create_cpu(cartridge, apu, options)?);
+        Ok(Self { cpu, sgb_buffer: vec![0; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT], frame_count: 0 })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P, options: &HeadlessOptions) -> io::Result<Self> {
+        Self::new(Cartridge::load(path)?, options)
+    }
+
+    /// フレームの実行を呼び出し側で行う場合 (スクリプトにCPUを貸し出すときなど) の準備として、
+    /// フレームの先頭のサイクル位置に `buttons` の入力を予約します。実行後は `finish_frame` を呼びます。
+    pub fn start_frame(&mut self, buttons: u8) {
+        let frame_start = self.cpu.mmu.joypad.cycles();
+        input::schedule_buttons(&mut self.cpu.mmu.joypad, frame_start, buttons);
+    }
+
+    /// フレームの実行後に、SGBの画面を描いてフレーム数を進めます。
+    pub fn finish_frame(&mut self) {
+        if let Some(sgb) = &self.cpu.mmu.sgb {
+            sgb.render(&self.cpu.mmu.ppu.shade_buffer[..], &mut self.sgb_buffer);
+        }
+        self.frame_count += 1;
+    }
+
+    /// `buttons` を押した状態で1フレーム実行します。LCDが止まっていても1フレーム分のサイクルで戻ります。
+    pub fn run_frame(&mut self, buttons: u8) {
+        self.start_frame(buttons);
+        self.cpu.run_until_frame();
+        self.finish_frame();
+    }
+
+    /// `run_frame` と同じく1フレーム実行し、その間に `LD B,B` (Mooneye, acid2, Mealybugの終了の合図) を
+    /// 実行したかを返します。合図の後もフレームの終わりまで進めるので、画面は描き終わった状態になります。
+    pub fn run_frame_until_breakpoint(&mut self, buttons: u8) -> bool {
+        self.start_frame(buttons);
+        let cpu = &mut self.cpu;
+        cpu.mmu.ppu.frame_ready = false;
+        let mut cycles: u32 = 0;
+        let mut hit = false;
+        while !cpu.mmu.ppu.frame_ready {
+            hit |= cpu.mmu.read_byte(cpu.registers.pc) == LD_B_B;
+            cycles += cpu.step() as u32;
+            if cycles >= CYCLES_PER_FRAME && !cpu.mmu.ppu.is_lcd_enabled() { break; }
+        }
+        self.finish_frame();
+        hit
+    }
+
+    pub fn run_frames(&mut self, count: u64, buttons: u8) {
+        for _ in 0..count {
+            self.run_frame(buttons);
+        }
+    }
+
+    /// 実行したフレーム数
+    pub fn frame_count(&self) -> u64 { self.frame_count }
+
+    /// 画面の大きさ (SGBでは枠を含む)
+    pub fn screen_size(&self) -> (usize, usize) {
+        if self.cpu.mmu.sgb.is_some() { (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT) } else { (SCREEN_WIDTH, SCREEN_HEIGHT) }
+    }
+
+    /// 表示される画面 (0x00RRGGBB)。SGBでは枠と着色を含みます
+    pub fn screen(&self) -> &[u32] {
+        if self.cpu.mmu.sgb.is_some() { &self.sgb_buffer } else { &self.cpu.mmu.ppu.frame_buffer[..] }
+    }
+
+    pub fn screen_image(&self) -> RgbaImage {
+        let (width, height) = self.screen_size();
+        to_image(self.screen(), width, height)
+    }
+
+    pub fn save_screenshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.screen_image().save(path).map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        save_state::save(&self.cpu)
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        save_state::load(&mut self.cpu, data)?;
+        if let Some(sgb) = &self.cpu.mmu.sgb {
+            sgb.render(&self.cpu.mmu.ppu.shade_buffer[..], &mut self.sgb_buffer);
+        }
+        Ok(())
+    }
+}
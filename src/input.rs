@@ -0,0 +1,241 @@
+// src/input.rs
+// フレーム単位の入力の加工 (連射ボタンと入力マクロ)
+//
+// ボタンの状態は GameboyKey ごとに1ビットのマスクで扱い、エミュレートしたフレームごとに
+// 「実際に押されているボタン」「連射ボタン」「再生中のマクロ」を合成してJoypadへ渡す。
+
+use crate::joypad::{GameboyKey, Joypad};
+
+/// マスクのビット順 (ビット0 = Right ... ビット7 = Start)
+pub const BUTTONS: [GameboyKey; 8] = [
+    GameboyKey::Right, GameboyKey::Left, GameboyKey::Up, GameboyKey::Down,
+    GameboyKey::A, GameboyKey::B, GameboyKey::Select, GameboyKey::Start,
+];
+pub const BUTTON_NAMES: [&str; 8] = ["Right", "Left", "Up", "Down", "A", "B", "Select", "Start"];
+
+pub fn button_bit(key: GameboyKey) -> u8 {
+    1 << BUTTONS.iter().position(|&k| k == key).unwrap_or(0)
+}
+
+pub fn mask_from_keys(keys: &[GameboyKey]) -> u8 {
+    keys.iter().fold(0, |mask, &key| mask | button_bit(key))
+}
+
+/// ボタンが `buttons` の状態になるよう、`at_cycle` (`Joypad::cycles` の時刻) に変化するボタンだけを予約します。
+pub fn schedule_buttons(joypad: &mut Joypad, at_cycle: u64, buttons: u8) {
+    for (bit, &key) in BUTTONS.iter().enumerate() {
+        let pressed = buttons & (1 << bit) != 0;
+        if joypad.pending_state(key) != pressed {
+            joypad.schedule_input(at_cycle, key, pressed);
+        }
+    }
+}
+
+/// 1ステップ "A+B+Start:10" を (マスク, フレーム数) に変換します。ボタンなし (":5") は何も押さない待ちです。
+fn parse_step(step: &str) -> Result<(u8, u32), String> {
+    let (buttons, frames) = match step.rsplit_once(':') {
+        Some((buttons, frames)) => (buttons, frames.trim().parse::<u32>().map_err(|_| format!("invalid frame count in '{}'", step))?),
+        None => (step, 1),
+    };
+    let mask = parse_buttons(buttons).map_err(|e| format!("{} in '{}'", e, step))?;
+    Ok((mask, frames))
+}
+
+/// "A+B+Start" をマスクに変換します。空文字列は何も押さない状態です。
+pub fn parse_buttons(buttons: &str) -> Result<u8, String> {
+    let mut mask = 0;
+    for name in buttons.split('+').map(str::trim).filter(|name| !name.is_empty()) {
+        let index = BUTTON_NAMES.iter().position(|n| n.eq_ignore_ascii_case(name)).ok_or_else(|| format!("unknown button '{}'", name))?;
+        mask |= 1 << index;
+    }
+    Ok(mask)
+}
+
+/// フレームごとのボタン状態の列
+#[derive(Debug, Clone, Default)]
+pub struct InputMacro {
+    pub name: String,
+    pub frames: Vec<u8>,
+}
+
+impl InputMacro {
+    /// "A+B+Select+Start:10" のようなステップの列から作ります。
+    pub fn parse(name: &str, steps: &[String]) -> Result<Self, String> {
+        let mut frames = Vec::new();
+        for step in steps {
+            let (mask, count) = parse_step(step)?;
+            frames.extend(std::iter::repeat_n(mask, count as usize));
+        }
+        Ok(Self { name: name.to_string(), frames })
+    }
+
+    /// 同じ状態が続くフレームをまとめて、設定ファイルに書ける形式のステップ列にします。
+    pub fn to_steps(&self) -> Vec<String> {
+        let mut steps: Vec<(u8, u32)> = Vec::new();
+        for &mask in &self.frames {
+            match steps.last_mut() {
+                Some((last, count)) if *last == mask => *count += 1,
+                _ => steps.push((mask, 1)),
+            }
+        }
+        steps.into_iter().map(|(mask, count)| {
+            let names: Vec<&str> = (0..8).filter(|bit| mask & (1 << bit) != 0).map(|bit| BUTTON_NAMES[bit]).collect();
+            format!("{}:{}", names.join("+"), count)
+        }).collect()
+    }
+}
+
+pub struct InputProcessor {
+    // 連射の周期 (フレーム)。前半押し、後半離す
+    autofire_period: u32,
+    // 連射ボタンを押し始めたフレーム (ボタンごと)
+    autofire_start: [Option<u64>; 8],
+    frame: u64,
+    macros: Vec<InputMacro>,
+    // 再生中のマクロ (macros の添字、次に再生するフレーム)
+    playing: Option<(usize, usize)>,
+    recording: Option<Vec<u8>>,
+}
+
+impl InputProcessor {
+    pub fn new(autofire_period: u32, macros: Vec<InputMacro>) -> Self {
+        Self { autofire_period: autofire_period.max(2), autofire_start: [None; 8], frame: 0, macros, playing: None, recording: None }
+    }
+
+    /// 1フレーム分の入力を合成して返します。`held` は押されているボタン、`autofire` は連射ボタンで押されているボタンです。
+    /// マクロの録画中は、連射を反映した後でマクロの再生分を含まない状態を記録します。
+    pub fn next_frame(&mut self, held: u8, autofire: u8) -> u8 {
+        let mut mask = held;
+        for bit in 0..8 {
+            if autofire & (1 << bit) == 0 {
+                self.autofire_start[bit] = None;
+                continue;
+            }
+            let start = *self.autofire_start[bit].get_or_insert(self.frame);
+            let phase = (self.frame - start) % self.autofire_period as u64;
+            if phase * 2 < self.autofire_period as u64 {
+                mask |= 1 << bit;
+            }
+        }
+
+        if let Some(recording) = &mut self.recording {
+            recording.push(mask);
+        }
+
+        if let Some((index, position)) = self.playing {
+            let frames = &self.macros[index].frames;
+            if position < frames.len() {
+                mask |= frames[position];
+                self.playing = Some((index, position + 1));
+            }
+            if position + 1 >= frames.len() {
+                self.playing = None;
+            }
+        }
+        self.frame += 1;
+        mask
+    }
+
+    pub fn macro_name(&self, index: usize) -> Option<&str> {
+        self.macros.get(index).map(|m| m.name.as_str())
+    }
+
+    pub fn find_macro(&self, name: &str) -> Option<usize> {
+        self.macros.iter().position(|m| m.name == name)
+    }
+
+    /// マクロを最初から再生します (再生中のマクロは中断されます)。
+    pub fn play_macro(&mut self, index: usize) {
+        if index < self.macros.len() && !self.macros[index].frames.is_empty() {
+            self.playing = Some((index, 0));
+        }
+    }
+
+    pub fn is_recording(&self) -> bool { self.recording.is_some() }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    /// 録画を終了し、`name` のマクロとして登録 (同名があれば置き換え) してその添字を返します。
+    pub fn finish_recording(&mut self, name: &str) -> Option<usize> {
+        let frames = self.recording.take()?;
+        let recorded = InputMacro { name: name.to_string(), frames };
+        match self.macros.iter().position(|m| m.name == name) {
+            Some(index) => { self.macros[index] = recorded; Some(index) }
+            None => { self.macros.push(recorded); Some(self.macros.len() - 1) }
+        }
+    }
+
+    pub fn get_macro(&self, index: usize) -> Option<&InputMacro> { self.macros.get(index) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: u8 = 1 << 4;
+    const B: u8 = 1 << 5;
+    const START: u8 = 1 << 7;
+
+    fn steps(steps: &[&str]) -> Vec<String> {
+        steps.iter().map(|step| step.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_steps_into_frames() {
+        let parsed = InputMacro::parse("test", &steps(&["a+B:2", ":3", "Start"])).unwrap();
+        assert_eq!(parsed.frames, vec![A | B, A | B, 0, 0, 0, START]);
+        assert!(InputMacro::parse("test", &steps(&["A:x"])).is_err());
+        assert!(InputMacro::parse("test", &steps(&["Turbo:1"])).is_err());
+    }
+
+    #[test]
+    fn to_steps_merges_repeated_frames() {
+        let parsed = InputMacro { name: "test".to_string(), frames: vec![A, A, 0, B | START, B | START, B | START] };
+        let steps = parsed.to_steps();
+        assert_eq!(steps, vec!["A:2", ":1", "B+Start:3"]);
+        assert_eq!(InputMacro::parse("test", &steps).unwrap().frames, parsed.frames);
+    }
+
+    #[test]
+    fn autofire_alternates_every_half_period() {
+        let mut processor = InputProcessor::new(4, Vec::new());
+        let frames: Vec<u8> = (0..8).map(|_| processor.next_frame(START, A)).collect();
+        assert_eq!(frames, vec![A | START, A | START, START, START, A | START, A | START, START, START]);
+        // 離すと周期の途中でもすぐに止まり、次に押したときは押した状態から始まる
+        assert_eq!(processor.next_frame(0, 0), 0);
+        assert_eq!(processor.next_frame(0, A), A);
+    }
+
+    #[test]
+    fn macro_playback_adds_to_held_buttons() {
+        let mut processor = InputProcessor::new(4, vec![InputMacro { name: "jump".to_string(), frames: vec![A, 0, B] }]);
+        processor.play_macro(processor.find_macro("jump").unwrap());
+        let frames: Vec<u8> = (0..4).map(|_| processor.next_frame(START, 0)).collect();
+        assert_eq!(frames, vec![A | START, START, B | START, START]);
+    }
+
+    #[test]
+    fn recording_excludes_macro_playback() {
+        let mut processor = InputProcessor::new(4, vec![InputMacro { name: "jump".to_string(), frames: vec![A, A] }]);
+        processor.start_recording();
+        processor.play_macro(0);
+        processor.next_frame(B, 0);
+        processor.next_frame(0, 0);
+        let index = processor.finish_recording("recorded").unwrap();
+        assert_eq!(processor.get_macro(index).unwrap().frames, vec![B, 0]);
+        assert!(!processor.is_recording());
+    }
+
+    #[test]
+    fn schedule_buttons_queues_only_changes() {
+        let mut joypad = Joypad::new();
+        schedule_buttons(&mut joypad, 8, A | START);
+        schedule_buttons(&mut joypad, 16, A);
+        joypad.tick(8);
+        assert!(joypad.is_pressed(GameboyKey::A) && joypad.is_pressed(GameboyKey::Start));
+        joypad.tick(8);
+        assert!(joypad.is_pressed(GameboyKey::A) && !joypad.is_pressed(GameboyKey::Start));
+    }
+}
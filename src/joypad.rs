@@ -1,4 +1,16 @@
 // 資料 1.6 ジョイパッド入力 (レジスタ $FF00)
+//
+// P10-P13 の入力ラインの状態を保持し、選択ビットやボタンの状態が変わるたびに
+// High→Low の変化を検出してジョイパッド割り込みを要求する (実機と同じく、選択された
+// ラインのボタンが押された時、または押されたままのボタンのラインが選択された時に発生)。
+// フロントエンドからの入力は、エミュレーション時間上のサイクル位置を指定して予約できる。
+
+use std::collections::VecDeque;
+use std::io;
+
+use bincode::{Decode, Encode};
+
+use crate::save_state::{StateReader, StateWriter};
 
 // ボタンのビット表現 (P1レジスタ下位4ビット、押されたら0)
 const BUTTON_A_OR_RIGHT: u8 = 0b0001; // Bit 0
@@ -22,6 +34,19 @@ pub struct Joypad {
     left: bool,
     up: bool,
     down: bool,
+
+    // 直前の入力ライン P10-P13 の状態 (下位4ビット、Lowが0)
+    last_lines: u8,
+    interrupt_request: bool,
+    // tick で進むサイクル数と、予約された入力 (サイクル位置の昇順)
+    cycles: u64,
+    scheduled: VecDeque<(u64, GameboyKey, bool)>,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Joypad {
@@ -30,6 +55,111 @@ impl Joypad {
             p1_register_select: 0xCF, // 初期状態: 何も選択されていない (Bit5=1, Bit4=1), 上位は1
             button_a: false, button_b: false, select: false, start: false,
             right: false, left: false, up: false, down: false,
+            last_lines: 0x0F, interrupt_request: false,
+            cycles: 0, scheduled: VecDeque::new(),
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.p1_register_select);
+        w.write(&self.button_a);
+        w.write(&self.button_b);
+        w.write(&self.select);
+        w.write(&self.start);
+        w.write(&self.right);
+        w.write(&self.left);
+        w.write(&self.up);
+        w.write(&self.down);
+        w.write(&self.last_lines);
+        w.write(&self.interrupt_request);
+        w.write(&self.cycles);
+        w.write(&self.scheduled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.p1_register_select)?;
+        r.read_into(&mut self.button_a)?;
+        r.read_into(&mut self.button_b)?;
+        r.read_into(&mut self.select)?;
+        r.read_into(&mut self.start)?;
+        r.read_into(&mut self.right)?;
+        r.read_into(&mut self.left)?;
+        r.read_into(&mut self.up)?;
+        r.read_into(&mut self.down)?;
+        r.read_into(&mut self.last_lines)?;
+        r.read_into(&mut self.interrupt_request)?;
+        r.read_into(&mut self.cycles)?;
+        r.read_into(&mut self.scheduled)?;
+        Ok(())
+    }
+
+    // 入力ラインの状態が変わった可能性がある時に呼び、High→Lowの変化があれば割り込みを要求する
+    fn update_lines(&mut self) {
+        let lines = self.read_p1() & 0x0F;
+        if (self.last_lines & !lines) != 0 {
+            self.interrupt_request = true;
+        }
+        self.last_lines = lines;
+    }
+
+    /// ジョイパッド割り込みの要求を取り出します (取り出すとクリアされます)。
+    pub fn take_interrupt_request(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt_request)
+    }
+
+    /// エミュレーション時間を進め、到達した予約入力を適用します。
+    pub fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        while let Some(&(at, key, pressed)) = self.scheduled.front() {
+            if at > self.cycles { break; }
+            self.scheduled.pop_front();
+            self.set_button(key, pressed);
+        }
+    }
+
+    /// `tick` で進んだ累計サイクル数 (予約入力の基準時刻)
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// `cycles()` が `at_cycle` に達した時点でボタンの状態を変更するよう予約します。
+    /// 過去の時刻を指定した場合は次の `tick` で適用されます。
+    pub fn schedule_input(&mut self, at_cycle: u64, key: GameboyKey, pressed: bool) {
+        let index = self.scheduled.iter().position(|&(at, _, _)| at > at_cycle).unwrap_or(self.scheduled.len());
+        self.scheduled.insert(index, (at_cycle, key, pressed));
+    }
+
+    /// 予約済みの入力をすべて適用した後のボタンの状態
+    pub fn pending_state(&self, key: GameboyKey) -> bool {
+        self.scheduled.iter().rev().find(|&&(_, k, _)| k == key).map_or(self.is_pressed(key), |&(_, _, pressed)| pressed)
+    }
+
+    /// ボタンの状態を直ちに変更します。
+    pub fn set_button(&mut self, key: GameboyKey, pressed: bool) {
+        let state = match key {
+            GameboyKey::Right  => &mut self.right,
+            GameboyKey::Left   => &mut self.left,
+            GameboyKey::Up     => &mut self.up,
+            GameboyKey::Down   => &mut self.down,
+            GameboyKey::A      => &mut self.button_a,
+            GameboyKey::B      => &mut self.button_b,
+            GameboyKey::Select => &mut self.select,
+            GameboyKey::Start  => &mut self.start,
+        };
+        *state = pressed;
+        self.update_lines();
+    }
+
+    pub fn is_pressed(&self, key: GameboyKey) -> bool {
+        match key {
+            GameboyKey::Right  => self.right,
+            GameboyKey::Left   => self.left,
+            GameboyKey::Up     => self.up,
+            GameboyKey::Down   => self.down,
+            GameboyKey::A      => self.button_a,
+            GameboyKey::B      => self.button_b,
+            GameboyKey::Select => self.select,
+            GameboyKey::Start  => self.start,
         }
     }
 
@@ -39,6 +169,8 @@ impl Joypad {
         // Bit 5 (ボタンキー) と Bit 4 (方向キー) の選択状態のみを更新
         // 他のビットへの書き込みは無視されるか、特定の効果はない
         self.p1_register_select = (self.p1_register_select & 0xCF) | (value & 0x30);
+        // 押されたままのボタンのラインを選択した場合も High→Low になる
+        self.update_lines();
     }
 
     // P1/JOYPレジスタからの読み出し
@@ -59,55 +191,22 @@ impl Joypad {
             if self.left  { result &= !BUTTON_B_OR_LEFT; }
             if self.right { result &= !BUTTON_A_OR_RIGHT; }
         }
-        // 何も選択されていない場合 (Bit5=1, Bit4=1)、または両方選択された場合(ハードウェア的には不定だが、ここではORで両方読む)
-        // 一般的には、何も選択されていない場合は下位4ビットは$F (押されていない状態) を返す
+        // 両方選択された場合は、どちらかのグループで押されているラインがLowになる (上の2つの処理の合成)
+        // 何も選択されていない場合 (Bit5=1, Bit4=1) は、下位4ビットは$F (押されていない状態) を返す
         if (self.p1_register_select & 0x30) == 0x30 {
             result |= 0x0F;
         }
         result
     }
 
-    // ボタンが押されたことを通知 (true = 押された)
-    pub fn button_down(&mut self, key: GameboyKey) -> bool { // 割り込み要求のために状態変化を返す
-        let mut changed = false;
-        match key {
-            GameboyKey::Right  => { if !self.right { self.right = true; changed = true; } }
-            GameboyKey::Left   => { if !self.left  { self.left  = true; changed = true; } }
-            GameboyKey::Up     => { if !self.up    { self.up    = true; changed = true; } }
-            GameboyKey::Down   => { if !self.down  { self.down  = true; changed = true; } }
-            GameboyKey::A      => { if !self.button_a { self.button_a = true; changed = true; } }
-            GameboyKey::B      => { if !self.button_b { self.button_b = true; changed = true; } }
-            GameboyKey::Select => { if !self.select   { self.select   = true; changed = true; } }
-            GameboyKey::Start  => { if !self.start    { self.start    = true; changed = true; } }
-        }
-        // ジョイパッド割り込みは、選択されているラインのボタンが押された (High->Low遷移) 時に発生
-        // ここでは単純化のため、いずれかのボタンが新たに押されたらtrueを返す
-        changed && self.is_selected_line_active(key)
+    // ボタンが押されたことを通知
+    pub fn button_down(&mut self, key: GameboyKey) {
+        self.set_button(key, true);
     }
 
     // ボタンが離されたことを通知
     pub fn button_up(&mut self, key: GameboyKey) {
-        match key {
-            GameboyKey::Right  => self.right = false,
-            GameboyKey::Left   => self.left  = false,
-            GameboyKey::Up     => self.up    = false,
-            GameboyKey::Down   => self.down  = false,
-            GameboyKey::A      => self.button_a = false,
-            GameboyKey::B      => self.button_b = false,
-            GameboyKey::Select => self.select = false,
-            GameboyKey::Start  => self.start  = false,
-        }
-    }
-    
-    // 押されたキーが現在P1レジスタで選択されているラインに属するか
-    fn is_selected_line_active(&self, key: GameboyKey) -> bool {
-        let action_keys_selected = (self.p1_register_select & 0b0010_0000) == 0;
-        let direction_keys_selected = (self.p1_register_select & 0b0001_0000) == 0;
-
-        match key {
-            GameboyKey::A | GameboyKey::B | GameboyKey::Select | GameboyKey::Start => action_keys_selected,
-            GameboyKey::Right | GameboyKey::Left | GameboyKey::Up | GameboyKey::Down => direction_keys_selected,
-        }
+        self.set_button(key, false);
     }
 
     pub fn get_input_state_debug(&self) -> String {
@@ -118,5 +217,50 @@ impl Joypad {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum GameboyKey { Right, Left, Up, Down, A, B, Select, Start }
\ No newline at end of file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum GameboyKey { Right, Left, Up, Down, A, B, Select, Start }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheduled_press_takes_effect_at_its_cycle() {
+        let mut joypad = Joypad::new();
+        joypad.write_p1(0x10); // ボタンキーを選択
+        joypad.schedule_input(100, GameboyKey::A, true);
+        assert!(joypad.pending_state(GameboyKey::A));
+        for _ in 0..24 {
+            joypad.tick(4);
+        }
+        assert_eq!(joypad.cycles(), 96);
+        assert!(!joypad.is_pressed(GameboyKey::A));
+        assert!(!joypad.take_interrupt_request());
+        joypad.tick(4);
+        assert!(joypad.is_pressed(GameboyKey::A));
+        assert_eq!(joypad.read_p1() & 0x0F, 0x0E);
+        assert!(joypad.take_interrupt_request());
+    }
+
+    #[test]
+    fn inputs_apply_in_cycle_order() {
+        let mut joypad = Joypad::new();
+        joypad.schedule_input(20, GameboyKey::Start, false);
+        joypad.schedule_input(10, GameboyKey::Start, true);
+        assert!(!joypad.pending_state(GameboyKey::Start));
+        joypad.tick(12);
+        assert!(joypad.is_pressed(GameboyKey::Start));
+        joypad.tick(8);
+        assert!(!joypad.is_pressed(GameboyKey::Start));
+    }
+
+    #[test]
+    fn selecting_a_held_line_requests_interrupt() {
+        let mut joypad = Joypad::new();
+        joypad.write_p1(0x30); // どちらも選択しない
+        joypad.set_button(GameboyKey::Down, true);
+        assert!(!joypad.take_interrupt_request());
+        joypad.write_p1(0x20); // 方向キーを選択
+        assert!(joypad.take_interrupt_request());
+    }
+}
\ No newline at end of file
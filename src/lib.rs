@@ -5,4 +5,29 @@ pub mod ppu;
 pub mod timer;
 pub mod joypad;
 pub mod apu;
-pub mod debug_view; // 追加
\ No newline at end of file
+pub mod blip;
+pub mod stretch;
+pub mod debug_view; // 追加
+pub mod save_file;
+pub mod sgb;
+pub mod serial;
+pub mod printer;
+pub mod wav;
+pub mod gbs;
+pub mod vgm;
+#[cfg(feature = "frontend")]
+pub mod audio_out;
+pub mod video;
+#[cfg(feature = "frontend")]
+pub mod config;
+#[cfg(feature = "frontend")]
+pub mod gamepad;
+pub mod input;
+pub mod save_state;
+pub mod headless;
+pub mod test_rom;
+pub mod regression;
+pub mod conformance;
+pub mod script;
+#[cfg(feature = "python")]
+pub mod python;
\ No newline at end of file
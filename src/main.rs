@@ -1,10 +1,12 @@
 // src/main.rs
 
+mod cli;
+
 use std::env;
+use std::process::ExitCode;
 use std::time::{Duration, Instant};
 use std::fs;
-use std::path::Path;
-use std::io::{Write, Read}; // ★★★ 変更点: Readを追加 ★★★
+use std::path::{Path, PathBuf};
 use image::{ImageBuffer, Rgba};
 use chrono::Local;
 
@@ -15,19 +17,254 @@ use rust_gb_emulator::ppu;
 use rust_gb_emulator::apu;
 use rust_gb_emulator::joypad::GameboyKey;
 use rust_gb_emulator::debug_view;
+use rust_gb_emulator::sgb;
+use rust_gb_emulator::printer::GbPrinter;
+use rust_gb_emulator::save_file::{BatterySaver, DEFAULT_FLUSH_INTERVAL};
+use rust_gb_emulator::wav::{AudioRecorder, RecordingMode};
+use rust_gb_emulator::gbs::{self, GbsFile};
+use rust_gb_emulator::audio_out::{self, AudioOptions, AudioOutput};
+use rust_gb_emulator::video::{self, VideoFormat, VideoOptions, VideoRecorder};
+use rust_gb_emulator::config::{AudioConfig, Config, ConfigOverrides, Hotkey, KeyBindings, MacroConfig};
+use rust_gb_emulator::gamepad::GamepadInput;
+use rust_gb_emulator::input::{self, InputMacro, InputProcessor};
+
+use rust_gb_emulator::headless::{self, Headless};
+use rust_gb_emulator::save_state;
+use rust_gb_emulator::test_rom::{self, TestStatus};
+use rust_gb_emulator::regression;
+use rust_gb_emulator::script::ScriptHost;
+use rust_gb_emulator::conformance::{self, CaseStatus, ConformanceOptions};
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use clap::Parser;
 use minifb::{Key, Window, WindowOptions, Scale, ScaleMode, KeyRepeat};
 
-const TARGET_FPS: u64 = 60;
-const CYCLES_PER_FRAME: u64 = 4_194_304 / TARGET_FPS;
-const FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / TARGET_FPS);
-const TURBO_MULTIPLIER: u64 = 4;
+use cli::{AutomationArgs, Cli, Command, CompareArgs, ConformanceArgs, ReportFormat, RunArgs, ScreenshotArgs, ScriptArgs, SystemArgs, TestArgs, VideoFormatArg};
+
+// 1フレームの実時間 (70224 / 4194304 秒 ≒ 16.74ms, 約59.73Hz)
+const FRAME_SECONDS: f64 = ppu::CYCLES_PER_FRAME as f64 / 4_194_304.0;
+const PAUSED_FRAME_DURATION: Duration = Duration::from_millis(16);
+// 速度の切り替えで選べる段階
+const SPEED_PRESETS: [Speed; 5] = [Speed::Multiplier(0.25), Speed::Multiplier(0.5), Speed::Multiplier(1.0), Speed::Multiplier(2.0), Speed::Uncapped];
+// 期限からこれ以上遅れたら追いつこうとせず、基準時刻を取り直す
+const MAX_FRAME_LAG: Duration = Duration::from_millis(100);
+// フレームの期限の直前はスリープせずに待つ時間
+const SPIN_MARGIN: Duration = Duration::from_millis(2);
+// 表示レートに合わせるときに許容する速度のずれ (音声のレート制御で吸収できる範囲)
+const MAX_DISPLAY_RATE_SPEED_ERROR: f64 = 0.005;
+// 音声同期時、バッファが空くのを待つ最大時間 (デバイスが止まった場合の保険)
+const MAX_AUDIO_WAIT: Duration = Duration::from_millis(100);
+// チャンネルの音量の調整幅と上限
+const CHANNEL_GAIN_STEP: f32 = 0.25;
+const MAX_CHANNEL_GAIN: f32 = 2.0;
+
+/// フレームの進め方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SyncMode {
+    /// 音声バッファの残量に合わせてエミュレーションを進める (音切れしにくい)
+    Audio,
+    /// 実機のフレームレート (約59.73Hz) の時刻に合わせて進め、音声側はレート制御で追従させる
+    Video,
+    /// 手動で指定した表示レート (`--display-rate`) の周期の整数倍ごとに1フレーム進める。
+    /// 表示の垂直同期は取らず、ディスプレイのレートも検出しない。
+    /// 実機との速度差はわずかなので、音声側はレート制御で追従させる
+    FixedRate,
+}
+
+/// エミュレーション速度
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Speed {
+    /// 実機に対する倍率
+    Multiplier(f64),
+    /// 待たずにできるだけ速く進める (音は出さない)
+    Uncapped,
+}
 
+impl Speed {
+    /// "0.5", "50%", "2x", "uncapped" のような指定を解釈します。
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        if value == "uncapped" || value == "max" { return Some(Speed::Uncapped); }
+        let multiplier = match value.strip_suffix('%') {
+            Some(percent) => percent.trim().parse::<f64>().ok()? / 100.0,
+            None => value.trim_end_matches('x').trim().parse::<f64>().ok()?,
+        };
+        (multiplier.is_finite() && multiplier >= 0.01).then_some(Speed::Multiplier(multiplier))
+    }
 
-fn get_save_path(rom_path: &str) -> String {
+    fn label(&self) -> String {
+        match self {
+            Speed::Multiplier(multiplier) => format!("{}%", (multiplier * 100.0).round()),
+            Speed::Uncapped => "uncapped".to_string(),
+        }
+    }
+
+    fn is_normal(&self) -> bool { *self == Speed::Multiplier(1.0) }
+
+    /// プリセットの中で、今の速度より1段階遅い/速いもの
+    fn step(&self, faster: bool) -> Self {
+        let key = |speed: &Speed| match speed { Speed::Multiplier(m) => *m, Speed::Uncapped => f64::INFINITY };
+        let current = key(self);
+        if faster {
+            SPEED_PRESETS.iter().copied().find(|preset| key(preset) > current).unwrap_or(*self)
+        } else {
+            SPEED_PRESETS.iter().rev().copied().find(|preset| key(preset) < current).unwrap_or(*self)
+        }
+    }
+}
+
+fn parse_speed_setting(value: &str, default: Speed) -> Speed {
+    Speed::parse(value).unwrap_or_else(|| {
+        eprintln!("Invalid speed '{}', using {}.", value, default.label());
+        default
+    })
+}
+
+/// 端数を持つフレーム間隔で次の表示期限を決めます。期限は基準時刻からのフレーム数で
+/// 計算するので、スリープの誤差やナノ秒への丸めが蓄積しません。
+struct FramePacer {
+    frame_seconds: f64,
+    origin: Instant,
+    frames: u64,
+}
+
+impl FramePacer {
+    fn new(frame_seconds: f64) -> Self {
+        Self { frame_seconds, origin: Instant::now(), frames: 0 }
+    }
+
+    /// 指定した表示レートの周期の整数倍で進むペーサーを作ります。
+    fn for_display_rate(display_rate: f64) -> Self {
+        let refreshes_per_frame = (display_rate * FRAME_SECONDS).round().max(1.0);
+        let frame_seconds = refreshes_per_frame / display_rate;
+        let speed = FRAME_SECONDS / frame_seconds;
+        println!("Display rate {:.2} Hz: 1 frame every {} refresh(es), speed {:.2}%", display_rate, refreshes_per_frame, speed * 100.0);
+        if (speed - 1.0).abs() > MAX_DISPLAY_RATE_SPEED_ERROR {
+            eprintln!("Display rate {:.2} Hz is too far from {:.2} Hz; audio may crackle. Consider --sync video.", display_rate, 1.0 / FRAME_SECONDS);
+        }
+        Self::new(frame_seconds)
+    }
+
+    /// 次のフレームの期限まで待ちます。大きく遅れている場合は基準時刻を取り直します。
+    fn wait(&mut self) {
+        self.frames += 1;
+        let deadline = self.origin + Duration::from_secs_f64(self.frames as f64 * self.frame_seconds);
+        let now = Instant::now();
+        if deadline > now {
+            // OSのスリープは1ms以上遅れることがあるので、最後は時刻を見ながら待つ
+            if deadline - now > SPIN_MARGIN {
+                std::thread::sleep(deadline - now - SPIN_MARGIN);
+            }
+            while Instant::now() < deadline {
+                std::thread::yield_now();
+            }
+        } else if now - deadline > MAX_FRAME_LAG {
+            self.reset();
+        }
+    }
+
+    /// ポーズやターボの後に、現在時刻を基準にし直します。
+    fn reset(&mut self) {
+        self.origin = Instant::now();
+        self.frames = 0;
+    }
+}
+
+fn parse_sync_mode(value: Option<&str>) -> SyncMode {
+    match value {
+        Some("video") => SyncMode::Video,
+        Some("fixed-rate") => SyncMode::FixedRate,
+        Some("audio") | None => SyncMode::Audio,
+        Some(other) => {
+            eprintln!("Unknown sync mode '{}', using audio.", other);
+            SyncMode::Audio
+        }
+    }
+}
+
+fn create_frame_pacer(sync_mode: SyncMode, display_rate: Option<f64>, default_display_rate: f64) -> FramePacer {
+    if sync_mode != SyncMode::FixedRate {
+        return FramePacer::new(FRAME_SECONDS);
+    }
+    let display_rate = match display_rate {
+        Some(hz) if hz >= 1.0 => hz,
+        Some(hz) => {
+            eprintln!("Invalid display rate '{}', using {} Hz.", hz, default_display_rate);
+            default_display_rate
+        }
+        None => {
+            println!("Pacing to a {} Hz display; pass --display-rate if yours differs.", default_display_rate);
+            default_display_rate
+        }
+    };
+    FramePacer::for_display_rate(display_rate)
+}
+
+/// 設定ファイルの音声設定に、コマンドラインの指定を上書きします。
+fn parse_audio_options(args: &RunArgs, config: &Config) -> AudioOptions {
+    let device = if args.mute { Some(audio_out::NULL_DEVICE_NAME.to_string()) } else { args.audio_device.clone().or_else(|| config.audio.device.clone()) };
+    AudioOptions {
+        device,
+        latency: args.audio_latency.or(config.audio.latency_ms).map(|ms| Duration::from_millis(ms as u64)),
+        sample_rate: args.sample_rate.or(config.audio.sample_rate),
+    }
+}
+
+fn parse_video_options(args: &RunArgs) -> VideoOptions {
+    VideoOptions {
+        format: match args.video_format {
+            VideoFormatArg::Gif => VideoFormat::Gif,
+            VideoFormatArg::Png => VideoFormat::PngSequence,
+        },
+        scale: args.video_scale,
+        palette: args.video_palette.map(|index| ppu::PALETTES[index as usize]),
+    }
+}
+
+fn get_save_path(rom_path: &str, save_dir: Option<&Path>) -> String {
     let rom_path_obj = Path::new(rom_path);
-    rom_path_obj.with_extension("sav").to_string_lossy().to_string()
+    let save_path = match (save_dir, rom_path_obj.file_name()) {
+        (Some(dir), Some(file_name)) => dir.join(file_name).with_extension("sav"),
+        _ => rom_path_obj.with_extension("sav"),
+    };
+    save_path.to_string_lossy().to_string()
+}
+
+fn load_config(explicit: Option<&Path>) -> std::io::Result<Config> {
+    let (config, path) = Config::load_or_default(explicit)?;
+    match path {
+        // print-config の出力をそのまま設定ファイルとして保存できるよう、標準エラーに出す
+        Some(path) => eprintln!("Loaded config from {}", path.display()),
+        None => eprintln!("No config file found, using defaults."),
+    }
+    Ok(config)
+}
+
+/// 設定ファイルを読み込み、コマンドラインの指定 (--scale, --palette, --save-dir, --sync, --speed) を上書きします。
+fn load_run_config(args: &RunArgs) -> std::io::Result<Config> {
+    let mut config = load_config(args.config.as_deref())?;
+    config.apply_overrides(ConfigOverrides {
+        scale: args.scale,
+        palette: args.palette.map(|index| index as usize),
+        save_dir: args.save_dir.clone(),
+        sync: args.sync.clone(),
+        speed: args.speed.clone(),
+    });
+    Ok(config)
+}
+
+fn window_scale(scale: u32) -> Scale {
+    match scale {
+        0 | 1 => Scale::X1,
+        2 | 3 => Scale::X2,
+        4..=7 => Scale::X4,
+        8..=15 => Scale::X8,
+        16..=31 => Scale::X16,
+        _ => Scale::X32,
+    }
+}
+
+fn is_shift_down(window: &Window) -> bool {
+    window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift)
 }
 
 fn save_screenshot(frame_buffer: &[u32], width: usize, height: usize) {
@@ -54,57 +291,515 @@ fn save_screenshot(frame_buffer: &[u32], width: usize, height: usize) {
     }
 }
 
+fn open_audio_output(options: &AudioOptions, mixer: &AudioConfig) -> (AudioOutput, apu::Apu) {
+    let output = AudioOutput::open(options);
+    println!("Audio output: {} ({} Hz)", output.name(), output.sample_rate());
+    let mut apu = apu::Apu::new(output.sample_rate());
+    if let Some(latency) = options.latency {
+        apu.set_buffer_latency(latency.as_secs_f64());
+    }
+    if let Some(mask) = mixer.channel_mask() {
+        apu.set_channel_enable_mask(mask);
+    }
+    if let Some(gains) = mixer.channel_gain {
+        for (channel, gain) in gains.into_iter().enumerate() {
+            apu.set_channel_gain(channel, gain.min(MAX_CHANNEL_GAIN));
+        }
+    }
+    (output, apu)
+}
+
+fn wait_for_next_frame(sync_mode: SyncMode, apu: &apu::Apu, pacer: &mut FramePacer, frame_start_time: Instant) {
+    match sync_mode {
+        SyncMode::Audio => {
+            let target_fill = apu.target_buffer_fill();
+            while apu.buffered_samples() > target_fill && frame_start_time.elapsed() < MAX_AUDIO_WAIT {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        SyncMode::Video | SyncMode::FixedRate => pacer.wait(),
+    }
+}
+
+/// チャンネルのキーでミュート (Shiftでソロ) を切り替え、音量調整の対象に選びます。
+fn handle_channel_key(apu: &mut apu::Apu, channel: usize, shift: bool, selected_channel: &mut usize) {
+    if shift { apu.toggle_channel_solo(channel); } else { apu.toggle_channel_mute(channel); }
+    *selected_channel = channel;
+    println!("Audio channel mask: {:04b}", apu.channel_enable_mask());
+}
+
+fn adjust_channel_gain(apu: &mut apu::Apu, channel: usize, up: bool) {
+    let step = if up { CHANNEL_GAIN_STEP } else { -CHANNEL_GAIN_STEP };
+    let gain = (apu.channel_gain(channel) + step).clamp(0.0, MAX_CHANNEL_GAIN);
+    apu.set_channel_gain(channel, gain);
+    println!("Audio channel {} gain: {:.2}", channel + 1, gain);
+}
+
+fn run_gbs_player(path: &str, sync_mode: SyncMode, mut pacer: FramePacer, audio_options: &AudioOptions, config: &Config, bindings: &KeyBindings) -> std::io::Result<()> {
+    let gbs_file = GbsFile::load(path)?;
+    gbs_file.print_info();
+
+    let (audio_output, apu) = open_audio_output(audio_options, &config.audio);
+    let _stream = audio_output.start(apu.get_sample_buffer_handle());
+
+    let cartridge = Cartridge::from_bytes(gbs_file.build_rom())?;
+    let mut cpu = Cpu::new(Mmu::new_gbs(cartridge, apu));
+    let song_count = gbs_file.header.song_count.max(1);
+    let mut track = gbs_file.header.first_song.saturating_sub(1).min(song_count - 1);
+    gbs::start_track(&mut cpu, track);
+    let mut track_cycles: u64 = 0;
+
+    let mut window = Window::new("Rust Game Boy Emulator - GBS Player", debug_view::DEBUG_WIDTH, debug_view::DEBUG_HEIGHT, WindowOptions::default())
+        .map_err(std::io::Error::other)?;
+    let mut buffer: Vec<u32> = vec![0; debug_view::DEBUG_WIDTH * debug_view::DEBUG_HEIGHT];
+    let mut is_paused = false;
+
+    println!("\n--- Starting GBS Player ---");
+    println!("  - Left/Right (Previous/Next Track), Space (Pause), Channel keys (Mute), Shift+Channel keys (Solo)");
+    println!("  - {}/{} (Selected Channel Volume Down/Up)", bindings.describe(Hotkey::ChannelGainDown), bindings.describe(Hotkey::ChannelGainUp));
+    println!("  - {} (Log VGM), {} (Record WAV), Shift+{} (Record Channel Stems)", bindings.describe(Hotkey::VgmLog), bindings.describe(Hotkey::RecordAudio), bindings.describe(Hotkey::RecordAudio));
+    let mut selected_channel = 0;
+
+    while window.is_open() {
+        let frame_start_time = Instant::now();
+        let shift = is_shift_down(&window);
+        for key in window.get_keys_pressed(KeyRepeat::No) {
+            match key {
+                Key::Right | Key::Left => {
+                    track = if key == Key::Right { (track + 1) % song_count } else { (track + song_count - 1) % song_count };
+                    gbs::start_track(&mut cpu, track);
+                    track_cycles = 0;
+                    println!("Track {}/{}", track + 1, song_count);
+                },
+                Key::Space => is_paused = !is_paused,
+                _ => match bindings.hotkey_for(key) {
+                    Some(Hotkey::Channel(channel)) => handle_channel_key(&mut cpu.mmu.apu, channel, shift, &mut selected_channel),
+                    Some(Hotkey::ChannelGainDown) => adjust_channel_gain(&mut cpu.mmu.apu, selected_channel, false),
+                    Some(Hotkey::ChannelGainUp) => adjust_channel_gain(&mut cpu.mmu.apu, selected_channel, true),
+                    Some(Hotkey::VgmLog) => toggle_vgm_logging(&mut cpu.mmu.apu),
+                    Some(Hotkey::RecordAudio) => toggle_recording(&mut cpu.mmu.apu, if shift { RecordingMode::Stems } else { RecordingMode::Mix }),
+                    _ => (),
+                },
+            }
+        }
+
+        if !is_paused {
+            track_cycles += cpu.run_until_frame() as u64;
+        }
+
+        let seconds = track_cycles / 4_194_304;
+        let header = &gbs_file.header;
+        let info_lines = [
+            header.title.clone(),
+            header.author.clone(),
+            header.copyright.clone(),
+            format!("TRACK {:>3}/{}   {:02}:{:02}{}", track + 1, song_count, seconds / 60, seconds % 60, if is_paused { "  [PAUSED]" } else { "" }),
+            format!("PLAY {:.2} Hz ({})", gbs_file.play_rate(), if gbs_file.uses_timer() { "TIMER" } else { "VBLANK" }),
+        ];
+        debug_view::draw_player(&mut buffer, &info_lines, &cpu.mmu.apu.get_apu_state(), &cpu.mmu.apu.get_channel_waveforms());
+        window.update_with_buffer(&buffer, debug_view::DEBUG_WIDTH, debug_view::DEBUG_HEIGHT).unwrap();
+
+        if is_paused {
+            std::thread::sleep(PAUSED_FRAME_DURATION);
+            pacer.reset();
+        } else {
+            wait_for_next_frame(sync_mode, &cpu.mmu.apu, &mut pacer, frame_start_time);
+        }
+    }
+
+    finish_vgm_logging(&mut cpu.mmu.apu);
+    finish_recording(&mut cpu.mmu.apu);
+    Ok(())
+}
+
+fn finish_vgm_logging(apu: &mut apu::Apu) {
+    let Some(log) = apu.stop_vgm_logging() else { return; };
+    if fs::create_dir_all("recordings").is_err() {
+        eprintln!("Failed to create recordings directory.");
+        return;
+    }
+    let path = format!("recordings/session-{}.vgm", Local::now().format("%Y%m%d-%H%M%S"));
+    match log.save(&path) {
+        Ok(_) => println!("VGM saved to {} ({} register writes)", path, log.write_count()),
+        Err(e) => eprintln!("Failed to save VGM: {}", e),
+    }
+}
+
+fn toggle_vgm_logging(apu: &mut apu::Apu) {
+    if apu.is_vgm_logging() {
+        finish_vgm_logging(apu);
+    } else {
+        apu.start_vgm_logging();
+        println!("VGM logging started");
+    }
+}
+
+// 録画したマクロの名前と保存先
+const RECORDED_MACRO_NAME: &str = "recorded";
+const MACRO_DIR: &str = "macros";
+
+fn create_input_processor(config: &Config) -> InputProcessor {
+    let macros = config.macros.iter().map(|m| InputMacro::parse(&m.name, &m.steps).unwrap_or_else(|e| {
+        eprintln!("Invalid macro '{}': {}", m.name, e);
+        InputMacro { name: m.name.clone(), frames: Vec::new() }
+    })).collect();
+    InputProcessor::new(config.autofire.period_frames, macros)
+}
+
+fn toggle_macro_recording(processor: &mut InputProcessor) {
+    if !processor.is_recording() {
+        processor.start_recording();
+        println!("Macro recording started");
+        return;
+    }
+    let Some(index) = processor.finish_recording(RECORDED_MACRO_NAME) else { return; };
+    let Some(recorded) = processor.get_macro(index) else { return; };
+    println!("Macro recorded ({} frames)", recorded.frames.len());
+    // 次回以降も使えるよう、設定ファイルに追加できる形式でファイルに保存する
+    let timestamp = Local::now().format("%Y%m%d-%H%M%S");
+    let saved = MacroConfig { name: format!("{}-{}", RECORDED_MACRO_NAME, timestamp), key: None, steps: recorded.to_steps() };
+    let path = Path::new(MACRO_DIR).join(format!("macro-{}.toml", timestamp));
+    match fs::create_dir_all(MACRO_DIR).and_then(|()| fs::write(&path, saved.to_toml_string())) {
+        Ok(()) => println!("Macro saved to {}; add it to the config file (with a key) to keep it.", path.display()),
+        Err(e) => eprintln!("Failed to save macro: {}", e),
+    }
+}
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <rom_file_path>", args[0]);
+/// 入力を反映して1フレーム (PPUのフレーム完了まで) 実行し、SGBの描画と録画を行います。
+/// スクリプトがあれば、その入力を重ねてフックを呼びながら実行します (その間CPUはスクリプトに貸し出すので、所有権を受け取って返します)。
+/// LCDが止まっていてフレームが完了しなかった場合は、SGBの描画と録画を行いません。
+fn run_frame(mut cpu: Box<Cpu>, input_processor: &mut InputProcessor, held: u8, autofire: u8, sgb_buffer: &mut [u32], video_recorder: &mut Option<VideoRecorder>, script: &mut Option<ScriptHost>) -> Box<Cpu> {
+    // 入力はフレームの先頭 (VBlank開始直後) のサイクル位置に予約し、エミュレーション時間の中で反映する。
+    // 割り込みはJoypad側でライン変化から発生する
+    let buttons = input_processor.next_frame(held, autofire) | script.as_mut().map_or(0, |host| host.next_input());
+    let frame_start = cpu.mmu.joypad.cycles();
+    input::schedule_buttons(&mut cpu.mmu.joypad, frame_start, buttons);
+    match script {
+        Some(host) => cpu = host.run_frame(cpu),
+        None => { cpu.run_until_frame(); }
+    }
+    if !cpu.mmu.ppu.frame_ready { return cpu; }
+    if let Some(sgb) = &cpu.mmu.sgb {
+        sgb.render(&cpu.mmu.ppu.shade_buffer[..], sgb_buffer);
+    }
+    if let Some(recorder) = video_recorder {
+        if cpu.mmu.sgb.is_some() {
+            recorder.push_frame(sgb_buffer, None);
+        } else {
+            recorder.push_frame(&cpu.mmu.ppu.frame_buffer[..], Some(&cpu.mmu.ppu.shade_buffer[..]));
+        }
+    }
+    cpu
+}
+
+/// 動画録画を終了します。`owns_audio` は連番PNGと一緒に始めたWAV録音も止めるかどうかです。
+fn finish_video_recording(recorder: &mut Option<VideoRecorder>, apu: &mut apu::Apu, owns_audio: bool) {
+    let Some(recorder) = recorder.take() else { return; };
+    let frames = recorder.frame_count();
+    let format = recorder.options().format;
+    match recorder.finish() {
+        Ok(path) => {
+            println!("Video saved to {} ({} frames)", path.display(), frames);
+            if format == VideoFormat::PngSequence {
+                println!("  Mux with: ffmpeg -framerate {:.4} -i {}/frame-%06d.png -i <wav> -pix_fmt yuv420p out.mp4", video::FRAME_RATE, path.display());
+            }
+        }
+        Err(e) => eprintln!("Failed to finish video recording: {}", e),
+    }
+    if owns_audio {
+        finish_recording(apu);
+    }
+}
+
+/// 動画録画を開始/終了します。戻り値は録画に合わせてWAV録音を開始したかどうかです。
+fn toggle_video_recording(recorder: &mut Option<VideoRecorder>, apu: &mut apu::Apu, options: VideoOptions, width: usize, height: usize, owns_audio: bool) -> bool {
+    if recorder.is_some() {
+        finish_video_recording(recorder, apu, owns_audio);
+        return false;
+    }
+    match VideoRecorder::start("recordings", options, width, height) {
+        Ok(started) => {
+            println!("Video recording started ({:?}, x{}) -> {}", options.format, options.scale, started.path().display());
+            let dir = started.path().to_path_buf();
+            *recorder = Some(started);
+            if options.format == VideoFormat::PngSequence && !apu.is_recording() {
+                match AudioRecorder::start(&dir, RecordingMode::Mix, apu.sample_rate()) {
+                    Ok(audio) => { apu.start_recording(audio); return true; }
+                    Err(e) => eprintln!("Failed to start audio recording: {}", e),
+                }
+            }
+        }
+        Err(e) => eprintln!("Failed to start video recording: {}", e),
+    }
+    false
+}
+
+fn finish_recording(apu: &mut apu::Apu) {
+    if let Some(recorder) = apu.stop_recording() {
+        match recorder.finish() {
+            Ok(paths) => for path in paths { println!("Recording saved to {}", path.display()); },
+            Err(e) => eprintln!("Failed to finish recording: {}", e),
+        }
+    }
+}
+
+fn toggle_recording(apu: &mut apu::Apu, mode: RecordingMode) {
+    if apu.is_recording() {
+        finish_recording(apu);
+        return;
+    }
+    match AudioRecorder::start("recordings", mode, apu.sample_rate()) {
+        Ok(recorder) => {
+            println!("Recording started ({:?})", recorder.mode());
+            apu.start_recording(recorder);
+        }
+        Err(e) => eprintln!("Failed to start recording: {}", e),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse_from(cli::normalize_legacy_args(env::args().collect()));
+    // 各コマンドは結果を終了コードで返し、エラーはここでまとめて表示する
+    let result = match cli.command {
+        Command::Run(args) => run_rom(&args),
+        Command::Info { rom, json } => Cartridge::load(&rom).map(|cartridge| {
+            if json { println!("{}", cartridge.header_json()); } else { cartridge.print_header_info(); }
+            ExitCode::SUCCESS
+        }),
+        Command::Test(args) => run_test_rom(&args),
+        Command::Screenshot(args) => take_screenshot(&args).map(|()| ExitCode::SUCCESS),
+        Command::Compare(args) => compare_screen(&args),
+        Command::Conformance(args) => run_conformance(&args),
+        Command::Script(args) => run_script(&args),
+        Command::AudioDevices => {
+            audio_out::print_devices();
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::PrintConfig { config } => load_config(config.as_deref()).map(|config| {
+            print!("{}", config.to_toml_string());
+            ExitCode::SUCCESS
+        }),
+    };
+    result.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        ExitCode::FAILURE
+    })
+}
+
+/// テストROMをヘッドレスで実行し、結果を終了コードで返します (0: 合格, 1: 不合格, 2: 時間切れ)。
+fn run_test_rom(args: &TestArgs) -> std::io::Result<ExitCode> {
+    let cartridge = Cartridge::load(&args.rom)?;
+    let report = test_rom::run_test_rom(cartridge, &args.system.headless_options()?, args.timeout)?;
+    if !report.serial_output.is_empty() {
+        println!("{}", report.serial_output.trim_end());
+    }
+    let (label, code) = match report.status {
+        TestStatus::Passed => ("PASSED", 0),
+        TestStatus::Failed => ("FAILED", 1),
+        TestStatus::Timeout => ("TIMEOUT", 2),
+    };
+    println!("{}: {} ({} cycles, {:.2} s)", label, report.message, report.cycles, report.cycles as f64 / 4_194_304.0);
+    Ok(ExitCode::from(code))
+}
+
+/// screenshot / compare 用にROMを読み込み、スクリプトに沿って実行します。
+fn run_headless_script(rom: &Path, system: &SystemArgs, script: &ScriptArgs, default_palette: usize) -> std::io::Result<Headless> {
+    let run = script.script_run()?;
+    let mut emulator = Headless::from_file(rom, &system.headless_options()?)?;
+    emulator.cpu.mmu.ppu.set_palette(script.palette.map_or(default_palette, |p| p as usize));
+    if let Some(path) = &script.state {
+        emulator.load_state(&fs::read(path)?)?;
+    }
+    let (frames, stopped) = regression::run_script(&mut emulator, &run);
+    if run.until_breakpoint && !stopped {
+        eprintln!("Warning: LD B,B was not executed within {} frames.", run.frames);
+    }
+    println!("Ran {} frames", frames);
+    Ok(emulator)
+}
+
+fn take_screenshot(args: &ScreenshotArgs) -> std::io::Result<()> {
+    let emulator = run_headless_script(&args.rom, &args.system, &args.script, 0)?;
+    if args.hash {
+        println!("{:016x}", regression::frame_hash(emulator.screen()));
         return Ok(());
     }
-    let rom_path = &args[1];
+    let out = args.out.clone().unwrap_or_else(|| args.rom.with_extension("png"));
+    emulator.save_screenshot(&out)?;
+    println!("Saved {}", out.display());
+    Ok(())
+}
+
+/// 画面を基準画像と比べます。一致しなければ差分画像を書き出して終了コード1で終わります。
+fn compare_screen(args: &CompareArgs) -> std::io::Result<ExitCode> {
+    let emulator = run_headless_script(&args.rom, &args.system, &args.script, regression::REFERENCE_PALETTE)?;
+    if args.update {
+        regression::save_png(&emulator.screen_image(), &args.golden)?;
+        println!("Updated {}", args.golden.display());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let diff = regression::compare_with_golden(&emulator, &args.golden)?;
+    if diff.matches() {
+        println!("MATCH: {}", args.golden.display());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let stem = args.rom.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_else(|| "screen".to_string());
+    let diff_path = args.diff.clone().unwrap_or_else(|| Path::new("diffs").join(format!("{}-diff.png", stem)));
+    let actual_path = diff_path.with_file_name(format!("{}-actual.png", stem));
+    regression::save_png(&diff.diff_image, &diff_path)?;
+    regression::save_png(&emulator.screen_image(), &actual_path)?;
+    println!("MISMATCH: {} ({})", args.golden.display(), diff.summary());
+    println!("Wrote {} and {}", diff_path.display(), actual_path.display());
+    Ok(ExitCode::FAILURE)
+}
+
+/// スクリプトのオーバーレイがあれば、画面に重ねた複製を `overlay_buffer` に作って返します。
+fn with_script_overlay<'a>(screen: &'a [u32], script: &Option<ScriptHost>, overlay_buffer: &'a mut Vec<u32>, width: usize, height: usize) -> &'a [u32] {
+    match script {
+        Some(host) if host.has_overlay() => {
+            overlay_buffer.clear();
+            overlay_buffer.extend_from_slice(screen);
+            host.draw_overlay(overlay_buffer, width, height);
+            overlay_buffer
+        }
+        _ => screen,
+    }
+}
+
+/// ROMをヘッドレスでスクリプトに沿って実行します。スクリプトの終了コードで終わります。
+fn run_script(args: &AutomationArgs) -> std::io::Result<ExitCode> {
+    let mut emulator = Headless::from_file(&args.rom, &args.system.headless_options()?)?;
+    emulator.cpu.mmu.ppu.set_palette(args.palette as usize);
+    if let Some(path) = &args.state {
+        emulator.load_state(&fs::read(path)?)?;
+    }
+    let (host, cpu) = ScriptHost::load(&args.script, emulator.cpu);
+    emulator.cpu = cpu;
+    let mut host = host?;
+    while host.exit_code().is_none() && args.frames.is_none_or(|frames| emulator.frame_count() < frames) {
+        let buttons = host.next_input();
+        emulator.start_frame(buttons);
+        emulator.cpu = host.run_frame(emulator.cpu);
+        emulator.finish_frame();
+    }
+    let code = host.exit_code().unwrap_or(0);
+    println!("Script finished after {} frames (exit code {})", emulator.frame_count(), code);
+    Ok(ExitCode::from(code))
+}
+
+/// ディレクトリ内のテストROMをすべて実行して結果の表を出力します。すべて合格 (または対象外) なら終了コード0です。
+fn run_conformance(args: &ConformanceArgs) -> std::io::Result<ExitCode> {
+    let options = ConformanceOptions {
+        headless: args.system.headless_options()?,
+        max_cycles: args.max_cycles,
+        jobs: args.jobs,
+        diff_dir: args.diff_dir.clone(),
+    };
+    let started = Instant::now();
+    let results = conformance::run_directory(&args.dir, &options, |result| {
+        eprintln!("{:<7} {}", result.status.label(), result.rom.display());
+    });
+    if results.is_empty() {
+        eprintln!("No .gb files found in {}", args.dir.display());
+    }
+    let report = match args.format {
+        ReportFormat::Text => conformance::text_table(&results),
+        ReportFormat::Junit => conformance::junit_xml(&results),
+    };
+    match &args.output {
+        Some(path) => {
+            fs::write(path, report)?;
+            eprintln!("Wrote {}", path.display());
+        }
+        None => print!("{}", report),
+    }
+    eprintln!("Finished in {:.1} s", started.elapsed().as_secs_f64());
+    if results.iter().any(|r| matches!(r.status, CaseStatus::Failed | CaseStatus::Timeout)) {
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// セーブステートの保存先。--state の指定がなければセーブデータと同じ場所の .state です。
+fn get_state_path(args: &RunArgs, save_path: &str) -> PathBuf {
+    args.state.clone().unwrap_or_else(|| Path::new(save_path).with_extension("state"))
+}
+
+fn run_rom(args: &RunArgs) -> std::io::Result<ExitCode> {
+    let rom_path = &args.rom.to_string_lossy().to_string();
+    let config = load_run_config(args)?;
+    let bindings = config.key_bindings();
+    let sync_mode = parse_sync_mode(Some(&config.video.sync));
+    let audio_options = parse_audio_options(args, &config);
+    let video_options = parse_video_options(args);
+    println!("Sync mode: {:?}", sync_mode);
+    let mut pacer = create_frame_pacer(sync_mode, args.display_rate, config.video.display_rate);
+    if Path::new(rom_path).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gbs")) {
+        return run_gbs_player(rom_path, sync_mode, pacer, &audio_options, &config, &bindings).map(|()| ExitCode::SUCCESS);
+    }
 
     println!("Loading ROM from: {}", rom_path);
-    let cartridge = Cartridge::load(rom_path).expect("Failed to load ROM");
+    let cartridge = Cartridge::load(rom_path)?;
     cartridge.print_header_info();
 
-    let host = cpal::default_host();
-    let device = host.default_output_device().expect("no output device available");
-    let config = device.default_output_config().expect("Failed to get default output config");
-    let sample_rate = config.sample_rate().0;
-    println!("Audio device sample rate: {} Hz", sample_rate);
-    let stream_config = config.into();
-
-    let apu = apu::Apu::new(sample_rate);
+    let (audio_output, apu) = open_audio_output(&audio_options, &config.audio);
     let sample_buffer_handle = apu.get_sample_buffer_handle();
     
-    let mut mmu = Mmu::new(cartridge, apu);
-    let save_path = get_save_path(rom_path);
+    let mut cpu = Box::new(headless::create_cpu(cartridge, apu, &args.system.headless_options()?)?);
+    if cpu.mmu.sgb.is_some() {
+        println!("Super Game Boy mode enabled.");
+    }
+    if cpu.mmu.is_boot_rom_mapped() {
+        println!("Starting from boot ROM.");
+    }
+    if args.printer {
+        cpu.mmu.serial.connect(Box::new(GbPrinter::new("printouts")));
+        println!("Game Boy Printer connected. Prints are saved to printouts/");
+    }
+    cpu.mmu.ppu.set_palette(config.video.palette);
+    let save_dir = config.paths.save_dir.as_deref();
+    if let Some(dir) = save_dir && let Err(e) = fs::create_dir_all(dir) {
+        eprintln!("Failed to create save directory {}: {}", dir.display(), e);
+    }
+    let save_path = get_save_path(rom_path, save_dir);
     // ★★★ 変更点: セーブデータロード処理をMMUの専用関数に置き換え ★★★
-    if mmu.cartridge.has_battery() {
-        if let Ok(save_data) = fs::read(&save_path) {
-            mmu.load_ram_and_rtc(&save_data);
-            println!("Loaded save data from {}", save_path);
-        }
+    if cpu.mmu.cartridge.has_battery() && let Ok(save_data) = fs::read(&save_path) {
+        cpu.mmu.load_ram_and_rtc(&save_data);
+        println!("Loaded save data from {}", save_path);
+    }
+    let mut battery_saver = if cpu.mmu.has_persistent_data() { Some(BatterySaver::new(&save_path, DEFAULT_FLUSH_INTERVAL)) } else { None };
+    let state_path = get_state_path(args, &save_path);
+    if let Some(path) = &args.state {
+        save_state::load_from_file(&mut cpu, path).map_err(|e| std::io::Error::new(e.kind(), format!("failed to load state {}: {}", path.display(), e)))?;
+        println!("Loaded state from {}", path.display());
     }
-    let mut cpu = Cpu::new(mmu);
+    let mut script = match &args.script {
+        Some(path) => {
+            let (host, lent) = ScriptHost::load(path, cpu);
+            cpu = lent;
+            let host = host?;
+            println!("Loaded script {}", path.display());
+            Some(host)
+        }
+        None => None,
+    };
+    let mut overlay_buffer: Vec<u32> = Vec::new();
 
-    let stream = device.build_output_stream(&stream_config, move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-         let mut buffer = sample_buffer_handle.lock().unwrap();
-         for frame in data.chunks_mut(2) {
-             let (l, r) = buffer.pop_front().unwrap_or((0.0, 0.0));
-             frame[0] = l;
-             frame[1] = r;
-         }
-     }, |err| eprintln!("an error occurred on stream: {}", err), None).unwrap();
-    stream.play().unwrap();
+    let _stream = audio_output.start(sample_buffer_handle);
 
 
+    let (screen_width, screen_height, window_scale) = if cpu.mmu.sgb.is_some() {
+        (sgb::SGB_SCREEN_WIDTH, sgb::SGB_SCREEN_HEIGHT, window_scale(config.video.scale / 2))
+    } else {
+        (ppu::SCREEN_WIDTH, ppu::SCREEN_HEIGHT, window_scale(config.video.scale))
+    };
+    let mut sgb_buffer: Vec<u32> = vec![0; sgb::SGB_SCREEN_WIDTH * sgb::SGB_SCREEN_HEIGHT];
     let mut game_window = Window::new(
         "Rust Game Boy Emulator",
-        ppu::SCREEN_WIDTH,
-        ppu::SCREEN_HEIGHT,
-        WindowOptions { resize: true, scale: Scale::X4, scale_mode: ScaleMode::AspectRatioStretch, ..WindowOptions::default() }
-    ).expect("Failed to create game window");
+        screen_width,
+        screen_height,
+        WindowOptions { resize: true, scale: window_scale, scale_mode: ScaleMode::AspectRatioStretch, ..WindowOptions::default() }
+    ).map_err(std::io::Error::other)?;
 
     let mut debug_window: Option<Window> = None;
     let mut debug_buffer: Vec<u32> = vec![0; debug_view::DEBUG_WIDTH * debug_view::DEBUG_HEIGHT];
@@ -112,72 +807,199 @@ fn main() -> std::io::Result<()> {
     let mut is_paused = false;
     let mut frame_skip_enabled = false;
     let mut frame_counter = 0u64;
-    
-    let mut f2_key_was_pressed = false;
+    let mut speed = parse_speed_setting(&config.speed.initial, Speed::Multiplier(1.0));
+    let fast_forward_speed = parse_speed_setting(&config.speed.fast_forward, Speed::Multiplier(4.0));
+    let mute_at_speed = config.speed.audio.eq_ignore_ascii_case("mute");
+    let mut fast_forward_on = false;
+    let mut last_speed = speed;
+    // 等速以外で、まだ実行していないフレームの端数
+    let mut frame_credit = 0.0;
+    let mut emulated_frames = 0u64;
+    let mut script_exit = 0;
 
     println!("\n--- Starting Emulation Loop ---");
     println!("================================ Controls ================================");
-    println!("  - Gamepad:  Arrow Keys, Z (A), X (B), Enter (Start), Backspace (Select)");
-    println!("  - Features: Tab (Turbo), P (Palette), F1 (Pause), F2 (Toggle Debug View)");
-    println!("  -           F12 (Screenshot)");
+    println!("  - Gamepad:  {} (Up), {} (Down), {} (Left), {} (Right), {} (A), {} (B), {} (Start), {} (Select)",
+        bindings.describe_button(GameboyKey::Up), bindings.describe_button(GameboyKey::Down), bindings.describe_button(GameboyKey::Left), bindings.describe_button(GameboyKey::Right),
+        bindings.describe_button(GameboyKey::A), bindings.describe_button(GameboyKey::B), bindings.describe_button(GameboyKey::Start), bindings.describe_button(GameboyKey::Select));
+    println!("  - Features: {} (Turbo), {} (Palette), {} (Pause), {} (Toggle Debug View)",
+        bindings.describe(Hotkey::Turbo), bindings.describe(Hotkey::Palette), bindings.describe(Hotkey::Pause), bindings.describe(Hotkey::DebugView));
+    println!("  - Speed:    {} (Toggle Fast-Forward), {}/{} (Slower/Faster), {} (Frame Advance)",
+        bindings.describe(Hotkey::FastForward), bindings.describe(Hotkey::SpeedDown), bindings.describe(Hotkey::SpeedUp), bindings.describe(Hotkey::FrameAdvance));
+    println!("  - State:    {} (Save State), {} (Load State) -> {}",
+        bindings.describe(Hotkey::SaveState), bindings.describe(Hotkey::LoadState), state_path.display());
+    println!("  -           {} (Log VGM), {} (Record Video), {} (Record WAV, Shift: Channel Stems), {} (Screenshot)",
+        bindings.describe(Hotkey::VgmLog), bindings.describe(Hotkey::RecordVideo), bindings.describe(Hotkey::RecordAudio), bindings.describe(Hotkey::Screenshot));
+    println!("  - Audio:    {} (Mute Channel 1-4), with Shift (Solo Channel), {}/{} (Selected Channel Volume Down/Up)",
+        (0..4).map(|channel| bindings.describe(Hotkey::Channel(channel))).collect::<Vec<_>>().join(", "),
+        bindings.describe(Hotkey::ChannelGainDown), bindings.describe(Hotkey::ChannelGainUp));
+    println!("  - Input:    {} (Turbo A), {} (Turbo B), {} (Record Macro), {} (Play Recorded Macro)",
+        bindings.describe_autofire(GameboyKey::A), bindings.describe_autofire(GameboyKey::B), bindings.describe(Hotkey::MacroRecord), bindings.describe(Hotkey::MacroPlay));
     println!("==========================================================================");
     
     let mut fps = 0.0;
+    // 音量調整の対象 (最後にチャンネルのキーを押したチャンネル)
+    let mut selected_channel = 0;
+    let mut gamepad = GamepadInput::new(&config.gamepad);
+    let mut input_processor = create_input_processor(&config);
+    let has_rumble = cpu.mmu.cartridge.has_rumble();
+    let mut video_recorder: Option<VideoRecorder> = None;
+    let mut video_owns_audio = false;
     
+    if !speed.is_normal() {
+        println!("Speed: {}", speed.label());
+    }
     while game_window.is_open() {
         let frame_start_time = Instant::now();
+        let mut frame_advance = false;
         
-        let is_f2_down = game_window.is_key_down(Key::F2);
-        if is_f2_down && !f2_key_was_pressed {
-            if debug_window.is_some() {
-                debug_window = None;
-                println!("Debug View: OFF");
-            } else {
-                debug_window = Some(Window::new("Debug View", debug_view::DEBUG_WIDTH, debug_view::DEBUG_HEIGHT, WindowOptions::default()).unwrap());
-                frame_skip_enabled = false; 
-                println!("Debug View: ON");
-            }
-        }
-        f2_key_was_pressed = is_f2_down;
-
-        let keys_just_pressed = game_window.get_keys_pressed(KeyRepeat::No);
-        if !keys_just_pressed.is_empty() {
-            for key in keys_just_pressed {
-                match key {
-                    Key::F1 => {
-                        is_paused = !is_paused;
-                        println!("Game {}", if is_paused { "Paused" } else { "Resumed" });
+        let shift = is_shift_down(&game_window);
+        let pad_state = gamepad.as_mut().map(|pad| pad.poll()).unwrap_or_default();
+        let mut hotkeys_pressed: Vec<Hotkey> = game_window.get_keys_pressed(KeyRepeat::No).into_iter().filter_map(|key| bindings.hotkey_for(key)).collect();
+        hotkeys_pressed.extend(pad_state.hotkeys_pressed.iter().copied());
+        for hotkey in hotkeys_pressed {
+            match hotkey {
+                Hotkey::Pause => {
+                    is_paused = !is_paused;
+                    println!("Game {}", if is_paused { "Paused" } else { "Resumed" });
+                    if is_paused && let Some(pad) = &mut gamepad { pad.set_rumble(0.0); }
+                    if is_paused && let Some(saver) = &mut battery_saver
+                        && let Err(e) = saver.flush(&mut cpu.mmu, false) {
+                        eprintln!("Failed to write save data: {}", e);
+                    }
+                },
+                Hotkey::DebugView => {
+                    if debug_window.is_some() {
+                        debug_window = None;
+                        println!("Debug View: OFF");
+                    } else {
+                        match Window::new("Debug View", debug_view::DEBUG_WIDTH, debug_view::DEBUG_HEIGHT, WindowOptions::default()) {
+                            Ok(window) => {
+                                debug_window = Some(window);
+                                frame_skip_enabled = false;
+                                println!("Debug View: ON");
+                            }
+                            Err(e) => eprintln!("Failed to open debug view: {}", e),
+                        }
+                    }
+                },
+                Hotkey::Palette => cpu.mmu.ppu.cycle_palette(),
+                Hotkey::Channel(channel) => handle_channel_key(&mut cpu.mmu.apu, channel, shift, &mut selected_channel),
+                Hotkey::ChannelGainDown => adjust_channel_gain(&mut cpu.mmu.apu, selected_channel, false),
+                Hotkey::ChannelGainUp => adjust_channel_gain(&mut cpu.mmu.apu, selected_channel, true),
+                Hotkey::VgmLog => toggle_vgm_logging(&mut cpu.mmu.apu),
+                Hotkey::RecordVideo => video_owns_audio = toggle_video_recording(&mut video_recorder, &mut cpu.mmu.apu, video_options, screen_width, screen_height, video_owns_audio),
+                Hotkey::RecordAudio => toggle_recording(&mut cpu.mmu.apu, if shift { RecordingMode::Stems } else { RecordingMode::Mix }),
+                Hotkey::Screenshot => {
+                    if cpu.mmu.sgb.is_some() {
+                        save_screenshot(&sgb_buffer, screen_width, screen_height);
+                    } else {
+                        save_screenshot(&cpu.mmu.ppu.frame_buffer[..], screen_width, screen_height);
+                    }
+                },
+                Hotkey::MacroRecord => toggle_macro_recording(&mut input_processor),
+                Hotkey::MacroPlay => match input_processor.find_macro(RECORDED_MACRO_NAME) {
+                    Some(index) => input_processor.play_macro(index),
+                    None => println!("No macro recorded yet"),
+                },
+                Hotkey::Macro(index) => {
+                    input_processor.play_macro(index);
+                    println!("Playing macro '{}'", input_processor.macro_name(index).unwrap_or("?"));
+                },
+                Hotkey::FastForward => {
+                    fast_forward_on = !fast_forward_on;
+                    println!("Fast-forward: {}", if fast_forward_on { "ON" } else { "OFF" });
+                },
+                Hotkey::SpeedDown | Hotkey::SpeedUp => {
+                    speed = speed.step(hotkey == Hotkey::SpeedUp);
+                    println!("Speed: {}", speed.label());
+                },
+                Hotkey::FrameAdvance => {
+                    if !is_paused {
+                        is_paused = true;
+                        println!("Game Paused");
+                        if let Some(pad) = &mut gamepad { pad.set_rumble(0.0); }
+                    }
+                    frame_advance = true;
+                },
+                Hotkey::SaveState => match save_state::save_to_file(&cpu, &state_path) {
+                    Ok(()) => println!("State saved to {}", state_path.display()),
+                    Err(e) => eprintln!("Failed to save state: {}", e),
+                },
+                Hotkey::LoadState => match save_state::load_from_file(&mut cpu, &state_path) {
+                    Ok(()) => {
+                        println!("State loaded from {}", state_path.display());
+                        if let Some(sgb) = &cpu.mmu.sgb {
+                            sgb.render(&cpu.mmu.ppu.shade_buffer[..], &mut sgb_buffer);
+                        }
+                        // ポーズ中でも読み込んだ画面を表示する
+                        cpu.mmu.ppu.frame_ready = true;
                     },
-                    Key::P => cpu.mmu.ppu.cycle_palette(),
-                    Key::F12 => save_screenshot(&cpu.mmu.ppu.frame_buffer, ppu::SCREEN_WIDTH, ppu::SCREEN_HEIGHT),
-                    _ => (),
-                }
+                    Err(e) => eprintln!("Failed to load state {}: {}", state_path.display(), e),
+                },
+                Hotkey::Turbo => (),
             }
         }
-        
-        if !is_paused {
-            let is_turbo = game_window.is_key_down(Key::Tab);
 
+        if !is_paused || frame_advance {
             let keys_down = game_window.get_keys();
-            let mut joypad_interrupt_requested = false;
-            if keys_down.contains(&Key::Right) { if cpu.mmu.joypad.button_down(GameboyKey::Right) { joypad_interrupt_requested = true; } } else { cpu.mmu.joypad.button_up(GameboyKey::Right); }
-            if keys_down.contains(&Key::Left)  { if cpu.mmu.joypad.button_down(GameboyKey::Left)  { joypad_interrupt_requested = true; } } else { cpu.mmu.joypad.button_up(GameboyKey::Left); }
-            if keys_down.contains(&Key::Up)    { if cpu.mmu.joypad.button_down(GameboyKey::Up)    { joypad_interrupt_requested = true; } } else { cpu.mmu.joypad.button_up(GameboyKey::Up); }
-            if keys_down.contains(&Key::Down)  { if cpu.mmu.joypad.button_down(GameboyKey::Down)  { joypad_interrupt_requested = true; } } else { cpu.mmu.joypad.button_up(GameboyKey::Down); }
-            if keys_down.contains(&Key::Z)     { if cpu.mmu.joypad.button_down(GameboyKey::A)      { joypad_interrupt_requested = true; } } else { cpu.mmu.joypad.button_up(GameboyKey::A); }
-            if keys_down.contains(&Key::X)     { if cpu.mmu.joypad.button_down(GameboyKey::B)      { joypad_interrupt_requested = true; } } else { cpu.mmu.joypad.button_up(GameboyKey::B); }
-            if keys_down.contains(&Key::Backspace) { if cpu.mmu.joypad.button_down(GameboyKey::Select) { joypad_interrupt_requested = true; } } else { cpu.mmu.joypad.button_up(GameboyKey::Select); }
-            if keys_down.contains(&Key::Enter) { if cpu.mmu.joypad.button_down(GameboyKey::Start)  { joypad_interrupt_requested = true; } } else { cpu.mmu.joypad.button_up(GameboyKey::Start); }
-            if joypad_interrupt_requested { cpu.mmu.request_interrupt(4); }
-            
-            let mut cycles_this_frame: u64 = 0;
-            let target_cycles = if is_turbo { CYCLES_PER_FRAME * TURBO_MULTIPLIER } else { CYCLES_PER_FRAME };
-            while cycles_this_frame < target_cycles {
-                cycles_this_frame += cpu.step() as u64;
+            let is_turbo = fast_forward_on || bindings.is_hotkey_down(Hotkey::Turbo, &keys_down) || pad_state.hotkeys_held.contains(&Hotkey::Turbo);
+            let current_speed = if frame_advance { Speed::Multiplier(1.0) } else if is_turbo { fast_forward_speed } else { speed };
+            if current_speed != last_speed {
+                // 音声同期とペーサーの切り替えで基準がずれるので取り直す
+                pacer.reset();
+                frame_credit = 0.0;
+                last_speed = current_speed;
+            }
+            let output_speed = match current_speed {
+                Speed::Multiplier(multiplier) if multiplier == 1.0 || !mute_at_speed => Some(multiplier),
+                _ => None,
+            };
+            cpu.mmu.apu.set_output_speed(output_speed);
+
+            let is_down = |keys: &[Key]| keys.iter().any(|key| keys_down.contains(key));
+            let held = bindings.joypad().iter().filter(|(button, keys)| is_down(keys) || pad_state.buttons.contains(button)).fold(0, |mask, (button, _)| mask | input::button_bit(*button));
+            let autofire = bindings.autofire().iter().filter(|(button, keys)| is_down(keys) || pad_state.autofire.contains(button)).fold(0, |mask, (button, _)| mask | input::button_bit(*button));
+
+            // 表示1回あたりに実行するフレーム数を速度から決め、最後のフレームだけを表示する。
+            // 遅いときは表示を何回かに1回だけ進める
+            match current_speed {
+                Speed::Multiplier(multiplier) => {
+                    frame_credit += if frame_advance { 1.0 } else { multiplier };
+                    while frame_credit >= 1.0 {
+                        frame_credit -= 1.0;
+                        cpu = run_frame(cpu, &mut input_processor, held, autofire, &mut sgb_buffer, &mut video_recorder, &mut script);
+                        emulated_frames += 1;
+                    }
+                }
+                Speed::Uncapped => {
+                    // 1表示期間ぶんの時間を使えるだけフレームを実行する
+                    loop {
+                        cpu = run_frame(cpu, &mut input_processor, held, autofire, &mut sgb_buffer, &mut video_recorder, &mut script);
+                        emulated_frames += 1;
+                        if frame_start_time.elapsed().as_secs_f64() >= FRAME_SECONDS { break; }
+                    }
+                }
+            }
+            if frame_advance {
+                println!("Frame advance: frame {} (cycle {}, PC={:04X})", emulated_frames, cpu.total_clock_cycles, cpu.registers.pc);
+            }
+            if let Some(code) = script.as_ref().and_then(|host| host.exit_code()) {
+                println!("Script finished with exit code {}", code);
+                script_exit = code;
+                break;
             }
             frame_counter += 1;
+            if has_rumble && !is_paused && let Some(pad) = &mut gamepad {
+                // PWMで駆動されるモーターは、フレーム内でオンだった割合を強さにする
+                pad.set_rumble(cpu.mmu.take_rumble_duty());
+            }
+
+            if let Some(saver) = &mut battery_saver && let Err(e) = saver.maybe_flush(&mut cpu.mmu) {
+                eprintln!("Failed to write save data: {}", e);
+            }
 
-            let should_draw_frame = !frame_skip_enabled || frame_counter % 2 == 0;
+            let should_draw_frame = frame_advance || !frame_skip_enabled || frame_counter.is_multiple_of(2);
             if should_draw_frame {
                 if let Some(win) = &mut debug_window {
                     if win.is_open() {
@@ -193,41 +1015,59 @@ fn main() -> std::io::Result<()> {
                 }
                 
                 if cpu.mmu.ppu.frame_ready {
-                    game_window.update_with_buffer(&cpu.mmu.ppu.frame_buffer, ppu::SCREEN_WIDTH, ppu::SCREEN_HEIGHT).unwrap();
+                    let screen: &[u32] = if cpu.mmu.sgb.is_some() { &sgb_buffer } else { &cpu.mmu.ppu.frame_buffer[..] };
+                    let buffer = with_script_overlay(screen, &script, &mut overlay_buffer, screen_width, screen_height);
+                    game_window.update_with_buffer(buffer, screen_width, screen_height).unwrap();
                     cpu.mmu.ppu.frame_ready = false;
                 } else {
                     game_window.update();
                 }
 
                 fps = 1.0 / frame_start_time.elapsed().as_secs_f64();
-                let title = if is_paused { "Rust Game Boy Emulator - [PAUSED]".to_string() } else { format!("Rust Game Boy Emulator - FPS: {:.1}", fps) };
+                let title = if is_paused {
+                    format!("Rust Game Boy Emulator - [PAUSED] Frame {}", emulated_frames)
+                } else if current_speed.is_normal() {
+                    format!("Rust Game Boy Emulator - FPS: {:.1}", fps)
+                } else {
+                    format!("Rust Game Boy Emulator - FPS: {:.1} - Speed: {}", fps, current_speed.label())
+                };
                 game_window.set_title(&title);
             }
             
-            if !is_turbo {
-                let elapsed = frame_start_time.elapsed();
-                if elapsed < FRAME_DURATION {
-                    std::thread::sleep(FRAME_DURATION - elapsed);
-                }
+            match current_speed {
+                _ if is_paused => pacer.reset(),
+                Speed::Uncapped => pacer.reset(),
+                // 等速以外では音声の残量が実時間と対応しないので、時刻で進める
+                Speed::Multiplier(_) if !current_speed.is_normal() => pacer.wait(),
+                Speed::Multiplier(_) => wait_for_next_frame(sync_mode, &cpu.mmu.apu, &mut pacer, frame_start_time),
             }
 
         } else {
-            game_window.update();
-            std::thread::sleep(Duration::from_millis(16));
-        }
-    }
-    
-    // ★★★ 変更点: セーブデータ書き出し処理をMMUの専用関数に置き換え ★★★
-    if cpu.mmu.cartridge.has_battery() && !cpu.mmu.external_ram.is_empty() {
-        let save_data = cpu.mmu.get_ram_and_rtc_data();
-        if let Ok(mut file) = fs::File::create(&save_path) {
-            if let Err(e) = file.write_all(&save_data) {
-                eprintln!("Failed to write save data: {}", e);
+            if cpu.mmu.ppu.frame_ready {
+                let screen: &[u32] = if cpu.mmu.sgb.is_some() { &sgb_buffer } else { &cpu.mmu.ppu.frame_buffer[..] };
+                let buffer = with_script_overlay(screen, &script, &mut overlay_buffer, screen_width, screen_height);
+                game_window.update_with_buffer(buffer, screen_width, screen_height).unwrap();
+                cpu.mmu.ppu.frame_ready = false;
             } else {
-                println!("Saved data to {}", save_path);
+                game_window.update();
             }
+            std::thread::sleep(PAUSED_FRAME_DURATION);
+            pacer.reset();
         }
     }
     
-    Ok(())
+    if let Some(pad) = &mut gamepad {
+        pad.set_rumble(0.0);
+    }
+    finish_video_recording(&mut video_recorder, &mut cpu.mmu.apu, video_owns_audio);
+    finish_vgm_logging(&mut cpu.mmu.apu);
+    finish_recording(&mut cpu.mmu.apu);
+    cpu.mmu.serial.disconnect();
+    if let Some(saver) = &mut battery_saver {
+        match saver.flush(&mut cpu.mmu, true) {
+            Ok(_) => println!("Saved data to {}", saver.path().display()),
+            Err(e) => eprintln!("Failed to write save data: {}", e),
+        }
+    }
+    Ok(ExitCode::from(script_exit))
 }
\ No newline at end of file
@@ -5,12 +5,19 @@ use crate::ppu::{Ppu, PpuMode};
 use crate::timer::Timer;
 use crate::joypad::Joypad;
 use crate::apu::Apu;
+use crate::sgb::Sgb;
+use crate::serial::Serial;
+use crate::save_state::{StateReader, StateWriter};
 use chrono::Utc;
+use std::io;
 
 const WRAM_SIZE: usize = 8192;
 const HRAM_SIZE: usize = 127;
 const IO_REG_SIZE: usize = 128;
 const OAM_SIZE: usize = 160;
+const LEGACY_RTC_FOOTER_SIZE: usize = 8 + 5;
+/// DMG/SGBのブートROMの大きさ
+pub const BOOT_ROM_SIZE: usize = 256;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum Mbc {
@@ -19,6 +26,8 @@ enum Mbc {
     Mbc2,
     Mbc3,
     Mbc5,
+    // GBS再生用: $2000-$3FFF への書き込みで $4000-$7FFF のバンクを切り替えるだけの最小マッパー
+    Gbs,
 }
 
 pub struct Mmu {
@@ -27,6 +36,8 @@ pub struct Mmu {
     pub timer: Timer,
     pub joypad: Joypad,
     pub apu: Apu,
+    pub sgb: Option<Sgb>,
+    pub serial: Serial,
     wram: [u8; WRAM_SIZE],
     hram: [u8; HRAM_SIZE],
     io_registers: [u8; IO_REG_SIZE],
@@ -42,13 +53,24 @@ pub struct Mmu {
     latched_rtc_registers: [u8; 5],
     rtc_latch_written_00: bool,
     rtc_last_timestamp: i64,
+    sram_dirty: bool,
+    rumble_active: bool,
+    // 前回 take_rumble_duty を呼んでからのサイクル数と、そのうちモーターがオンだったサイクル数。
+    // ゲームはPWMで強さを表現するので、フレーム単位の平均で振動の強さを求める
+    rumble_cycles: u64,
+    rumble_on_cycles: u64,
+    // $FF50 に書き込まれるまで $0000-$00FF に重ねて見えるブートROM
+    boot_rom: Option<Vec<u8>>,
+    // 書き込みを記録するアドレスの範囲 (スクリプトのフック用)。セーブステートには含めない
+    write_watches: Vec<(u16, u16)>,
+    watched_writes: Vec<(u16, u8)>,
 }
 
 
 impl Mmu {
     pub fn new(cartridge: Cartridge, apu: Apu) -> Self {
-        let ram_size_code = cartridge.ram_size_code;
-        let external_ram_size = if (0x05..=0x06).contains(&cartridge.cartridge_type_code) {
+        let ram_size_code = cartridge.header.ram_size;
+        let external_ram_size = if (0x05..=0x06).contains(&cartridge.header.cartridge_type) {
             0
         } else {
             match ram_size_code {
@@ -56,13 +78,13 @@ impl Mmu {
                 0x04 => 128 * 1024, 0x05 => 64 * 1024, _ => 0,
             }
         };
-        let mbc_type = match cartridge.cartridge_type_code {
+        let mbc_type = match cartridge.header.cartridge_type {
             0x00 | 0x08 | 0x09 => Mbc::RomOnly,
             0x01..=0x03 => Mbc::Mbc1,
             0x05..=0x06 => Mbc::Mbc2,
             0x0F..=0x13 => Mbc::Mbc3,
             0x19..=0x1E => Mbc::Mbc5,
-            _ => panic!("Unsupported cartridge type: {:#04x}", cartridge.cartridge_type_code),
+            _ => panic!("Unsupported cartridge type: {:#04x}", cartridge.header.cartridge_type),
         };
         println!("MBC type detected: {:?}", mbc_type);
         let mut mmu = Self {
@@ -71,6 +93,8 @@ impl Mmu {
             timer: Timer::new(),
             joypad: Joypad::new(),
             apu,
+            sgb: None,
+            serial: Serial::new(),
             wram: [0; WRAM_SIZE],
             hram: [0; HRAM_SIZE],
             io_registers: [0; IO_REG_SIZE],
@@ -86,11 +110,128 @@ impl Mmu {
             latched_rtc_registers: [0; 5],
             rtc_latch_written_00: false,
             rtc_last_timestamp: Utc::now().timestamp(),
+            sram_dirty: false,
+            rumble_active: false,
+            rumble_cycles: 0,
+            rumble_on_cycles: 0,
+            boot_rom: None,
+            write_watches: Vec::new(),
+            watched_writes: Vec::new(),
         };
         mmu.io_registers[0x0F] = 0xE1;
         mmu
     }
 
+    /// GBSファイルから合成したROM (gbs::GbsFile::build_rom) で動作するMMUを作成します。
+    /// 拡張RAM ($A000-$BFFF) は常に有効な8KBとして扱います。
+    pub fn new_gbs(cartridge: Cartridge, apu: Apu) -> Self {
+        let mut mmu = Self::new(cartridge, apu);
+        mmu.mbc = Mbc::Gbs;
+        mmu.external_ram = vec![0; 0x2000];
+        mmu.ram_and_rtc_enabled = true;
+        mmu
+    }
+
+    /// GBSの曲を切り替える前に、RAMとI/Oを再生開始時の状態に戻します。
+    pub fn reset_for_gbs(&mut self) {
+        self.wram.fill(0);
+        self.hram.fill(0);
+        self.external_ram.fill(0);
+        self.interrupt_enable_register = 0x00;
+        self.io_registers[0x0F] = 0xE1;
+        self.current_rom_bank = 1;
+        self.timer.write_div();
+        self.apu.write_reg(0xFF26, 0x00);
+        self.apu.write_reg(0xFF26, 0x80);
+        self.apu.write_reg(0xFF24, 0x77);
+        self.apu.write_reg(0xFF25, 0xFF);
+    }
+
+    /// ブートROMを $0000-$00FF に割り当てます。CPUは `Cpu::reset_for_boot_rom` で $0000 から起動してください。
+    pub fn load_boot_rom(&mut self, data: Vec<u8>) -> io::Result<()> {
+        if data.len() != BOOT_ROM_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("boot ROM must be {} bytes (got {})", BOOT_ROM_SIZE, data.len())));
+        }
+        self.boot_rom = Some(data);
+        Ok(())
+    }
+
+    /// ブートROMがまだ割り当てられているか
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    /// メモリとI/Oの状態を書き出します (ROMの内容は含みません)。
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.wram);
+        w.write(&self.hram);
+        w.write(&self.io_registers);
+        w.write(&self.interrupt_enable_register);
+        w.write(&self.external_ram);
+        w.write(&self.current_rom_bank);
+        w.write(&self.current_ram_bank);
+        w.write(&self.ram_and_rtc_enabled);
+        w.write(&self.mbc1_banking_mode);
+        w.write(&self.mbc2_ram);
+        w.write(&self.rtc_registers);
+        w.write(&self.latched_rtc_registers);
+        w.write(&self.rtc_latch_written_00);
+        w.write(&self.rtc_last_timestamp);
+        w.write(&self.rumble_active);
+        w.write(&self.boot_rom);
+        self.ppu.save_state(w);
+        self.timer.save_state(w);
+        self.joypad.save_state(w);
+        self.apu.save_state(w);
+        self.serial.save_state(w);
+        w.write(&self.sgb.is_some());
+        if let Some(sgb) = &self.sgb { sgb.save_state(w); }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.wram)?;
+        r.read_into(&mut self.hram)?;
+        r.read_into(&mut self.io_registers)?;
+        r.read_into(&mut self.interrupt_enable_register)?;
+        let external_ram: Vec<u8> = r.read()?;
+        if external_ram.len() != self.external_ram.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "save state has a different cartridge RAM size"));
+        }
+        self.external_ram = external_ram;
+        r.read_into(&mut self.current_rom_bank)?;
+        r.read_into(&mut self.current_ram_bank)?;
+        r.read_into(&mut self.ram_and_rtc_enabled)?;
+        r.read_into(&mut self.mbc1_banking_mode)?;
+        r.read_into(&mut self.mbc2_ram)?;
+        r.read_into(&mut self.rtc_registers)?;
+        r.read_into(&mut self.latched_rtc_registers)?;
+        r.read_into(&mut self.rtc_latch_written_00)?;
+        r.read_into(&mut self.rtc_last_timestamp)?;
+        r.read_into(&mut self.rumble_active)?;
+        r.read_into(&mut self.boot_rom)?;
+        if self.boot_rom.as_ref().is_some_and(|boot| boot.len() != BOOT_ROM_SIZE) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "save state has an invalid boot ROM"));
+        }
+        self.ppu.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.serial.load_state(r)?;
+        let has_sgb: bool = r.read()?;
+        if has_sgb != self.sgb.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "save state was made in a different model (DMG/SGB)"));
+        }
+        if let Some(sgb) = &mut self.sgb { sgb.load_state(r)?; }
+        // 読み込んだRAMを次の保存でバッテリーセーブにも反映する
+        self.sram_dirty = true;
+        Ok(())
+    }
+
+    /// スーパーゲームボーイとして動作させます (コマンドパケット受信とボーダー描画を有効化)。
+    pub fn enable_sgb(&mut self) {
+        self.sgb = Some(Sgb::new());
+    }
+
     pub fn load_ram_and_rtc(&mut self, data: &[u8]) {
         if self.mbc == Mbc::Mbc2 {
             if data.len() >= 512 {
@@ -99,34 +240,95 @@ impl Mmu {
             }
             return;
         }
-        if self.external_ram.is_empty() { return; }
         let ram_size = self.external_ram.len();
-        if data.len() >= ram_size {
+        if ram_size > 0 && data.len() >= ram_size {
             self.external_ram.copy_from_slice(&data[0..ram_size]);
         }
-        if self.mbc == Mbc::Mbc3 && data.len() >= ram_size + std::mem::size_of::<i64>() + 5 {
-             let rtc_data_start = ram_size;
-             let timestamp_bytes: [u8; 8] = data[rtc_data_start..rtc_data_start+8].try_into().unwrap();
-             self.rtc_last_timestamp = i64::from_le_bytes(timestamp_bytes);
-             self.rtc_registers.copy_from_slice(&data[rtc_data_start+8..rtc_data_start+8+5]);
-             self.update_rtc();
-             println!("RTC data loaded.");
+        if self.cartridge.has_rtc() && data.len() > ram_size {
+            self.load_rtc_footer(&data[ram_size..]);
+        } else if data.len() > ram_size {
+            eprintln!("Save data has {} extra bytes (an RTC footer?) but this cartridge has no RTC; they will be dropped on next save.", data.len() - ram_size);
         }
     }
-    
+
+    // RTCフッタの読み込み。以下の形式を受け付ける:
+    // - 48バイト: BGB / VBA-M / SameBoy / mGBA 共通形式 (u32 x5 現在値, u32 x5 ラッチ値, u64 UNIX時刻)
+    // - 44バイト: 旧VBA形式 (UNIX時刻がu32)
+    // - 13バイト: 本エミュレータの旧形式 (i64 UNIX時刻, u8 x5 現在値)。次回保存時に標準形式へ移行する
+    fn load_rtc_footer(&mut self, footer: &[u8]) {
+        let read_u32 = |offset: usize| u32::from_le_bytes(footer[offset..offset + 4].try_into().unwrap());
+        match footer.len() {
+            44 | 48 => {
+                for i in 0..5 {
+                    self.rtc_registers[i] = read_u32(i * 4) as u8;
+                    self.latched_rtc_registers[i] = read_u32(20 + i * 4) as u8;
+                }
+                self.rtc_last_timestamp = if footer.len() == 48 {
+                    i64::from_le_bytes(footer[40..48].try_into().unwrap())
+                } else {
+                    read_u32(40) as i64
+                };
+                println!("RTC data loaded.");
+            }
+            LEGACY_RTC_FOOTER_SIZE => {
+                self.rtc_last_timestamp = i64::from_le_bytes(footer[0..8].try_into().unwrap());
+                self.rtc_registers.copy_from_slice(&footer[8..13]);
+                self.latched_rtc_registers.copy_from_slice(&self.rtc_registers);
+                println!("Legacy RTC data loaded; it will be migrated to the standard format on next save.");
+            }
+            len => {
+                eprintln!("Unrecognized RTC footer ({} bytes); RTC state was not restored.", len);
+                return;
+            }
+        }
+        self.update_rtc();
+    }
+
     pub fn get_ram_and_rtc_data(&mut self) -> Vec<u8> {
         if self.mbc == Mbc::Mbc2 {
             return self.mbc2_ram.to_vec();
         }
         let mut data = self.external_ram.clone();
-        if self.mbc == Mbc::Mbc3 {
+        if self.cartridge.has_rtc() {
             self.update_rtc();
+            for reg in self.rtc_registers.iter().chain(self.latched_rtc_registers.iter()) {
+                data.extend_from_slice(&(*reg as u32).to_le_bytes());
+            }
             data.extend_from_slice(&self.rtc_last_timestamp.to_le_bytes());
-            data.extend_from_slice(&self.rtc_registers);
         }
         data
     }
-    
+
+    /// 前回の呼び出し以降にバッテリーバックアップ領域 (RAM/RTC) へ書き込みがあったかを返し、フラグをクリアします。
+    pub fn take_sram_dirty(&mut self) -> bool {
+        let dirty = self.sram_dirty;
+        self.sram_dirty = false;
+        dirty
+    }
+
+    /// 振動カートリッジのモーターが現在オンになっているか
+    pub fn rumble_active(&self) -> bool {
+        self.rumble_active
+    }
+
+    /// 前回の呼び出し以降にモーターがオンだった時間の割合 (0.0-1.0) を返し、計測をやり直します。
+    pub fn take_rumble_duty(&mut self) -> f32 {
+        let duty = if self.rumble_cycles == 0 {
+            if self.rumble_active { 1.0 } else { 0.0 }
+        } else {
+            self.rumble_on_cycles as f32 / self.rumble_cycles as f32
+        };
+        self.rumble_cycles = 0;
+        self.rumble_on_cycles = 0;
+        duty
+    }
+
+    /// バッテリーバックアップで保存すべきデータ (RAMまたはRTC) を持っているか判定します。
+    pub fn has_persistent_data(&self) -> bool {
+        self.cartridge.has_battery()
+            && (self.mbc == Mbc::Mbc2 || !self.external_ram.is_empty() || self.cartridge.has_rtc())
+    }
+
     fn update_rtc(&mut self) {
         let now = Utc::now().timestamp();
         let elapsed_secs = now - self.rtc_last_timestamp;
@@ -158,7 +360,10 @@ impl Mmu {
     pub fn tick_components(&mut self, cpu_t_cycles: u8) {
         let ppu_interrupt = self.ppu.step(cpu_t_cycles);
         match ppu_interrupt {
-            crate::ppu::PpuInterruptType::VBlank => self.request_interrupt(0),
+            crate::ppu::PpuInterruptType::VBlank => {
+                self.request_interrupt(0);
+                if let Some(sgb) = &mut self.sgb { sgb.on_vblank(&self.ppu.shade_buffer[..]); }
+            }
             crate::ppu::PpuInterruptType::LcdStat => self.request_interrupt(1),
             crate::ppu::PpuInterruptType::None => {}
         }
@@ -166,7 +371,22 @@ impl Mmu {
         if self.timer.take_interrupt_request() {
             self.request_interrupt(2);
         }
+        for _ in 0..self.timer.take_apu_frame_clocks() {
+            self.apu.clock_frame_sequencer();
+        }
+        self.serial.tick(cpu_t_cycles);
+        if self.serial.take_interrupt_request() {
+            self.request_interrupt(3);
+        }
+        self.joypad.tick(cpu_t_cycles);
+        if self.joypad.take_interrupt_request() {
+            self.request_interrupt(4);
+        }
         self.apu.tick(cpu_t_cycles);
+        self.rumble_cycles += cpu_t_cycles as u64;
+        if self.rumble_active {
+            self.rumble_on_cycles += cpu_t_cycles as u64;
+        }
     }
     
     pub fn request_interrupt(&mut self, interrupt_bit: u8) {
@@ -179,11 +399,9 @@ impl Mmu {
         match address {
             0x0000..=0x7FFF => self.cartridge.raw_data[address as usize],
             0x8000..=0x9FFF => self.ppu.vram[(address - 0x8000) as usize],
-            0xA000..=0xBFFF => {
-                 if self.ram_and_rtc_enabled && !self.external_ram.is_empty() {
-                    let ram_addr = (self.current_ram_bank * 0x2000) + (address - 0xA000) as usize;
-                    if ram_addr < self.external_ram.len() { self.external_ram[ram_addr] } else { 0xFF }
-                } else { 0xFF }
+            0xA000..=0xBFFF if self.ram_and_rtc_enabled && !self.external_ram.is_empty() => {
+                let ram_addr = (self.current_ram_bank * 0x2000) + (address - 0xA000) as usize;
+                if ram_addr < self.external_ram.len() { self.external_ram[ram_addr] } else { 0xFF }
             }
             0xC000..=0xDFFF => self.wram[(address - 0xC000) as usize],
             0xE000..=0xFDFF => self.wram[(address - 0xE000) as usize],
@@ -194,6 +412,7 @@ impl Mmu {
     pub fn read_byte(&self, address: u16) -> u8 {
         match address {
             // ★★★ 変更点: このブロックのロジックを大幅に簡略化 ★★★
+            0x0000..=0x00FF if let Some(boot) = &self.boot_rom => boot[address as usize],
             0x0000..=0x3FFF => {
                 // この領域は常にROMの先頭16KB (バンク0) を指す固定領域。
                 self.cartridge.raw_data[address as usize]
@@ -231,21 +450,42 @@ impl Mmu {
         }
     }
 
+    /// 書き込みを記録するアドレスの範囲 (両端を含む) を設定します。空なら記録しません。
+    pub fn set_write_watches(&mut self, ranges: Vec<(u16, u16)>) {
+        self.write_watches = ranges;
+        self.watched_writes.clear();
+    }
+
+    /// 前回から記録された書き込み (アドレス, 値) を取り出します。
+    pub fn take_watched_writes(&mut self) -> Vec<(u16, u8)> {
+        std::mem::take(&mut self.watched_writes)
+    }
+
     pub fn write_byte(&mut self, address: u16, value: u8) {
+        if !self.write_watches.is_empty() && self.write_watches.iter().any(|&(start, end)| (start..=end).contains(&address)) {
+            self.watched_writes.push((address, value));
+        }
         match address {
             0x0000..=0x7FFF => self.handle_mbc_write(address, value),
             0x8000..=0x9FFF => { if self.ppu.is_lcd_enabled() && self.ppu.current_mode == PpuMode::Drawing { return; } self.ppu.vram[(address - 0x8000) as usize] = value; },
             0xA000..=0xBFFF => {
                 if self.mbc == Mbc::Mbc2 {
                     if self.ram_and_rtc_enabled {
-                        self.mbc2_ram[(address & 0x01FF) as usize] = value & 0x0F;
+                        let cell = &mut self.mbc2_ram[(address & 0x01FF) as usize];
+                        if *cell != value & 0x0F {
+                            *cell = value & 0x0F;
+                            self.sram_dirty = true;
+                        }
                     }
                     return;
                 }
                 if !self.ram_and_rtc_enabled { return; }
                  if self.mbc == Mbc::Mbc3 && self.current_ram_bank >= 0x08 {
                     let rtc_reg_idx = self.current_ram_bank - 0x08;
-                    self.rtc_registers[rtc_reg_idx] = value;
+                    if self.rtc_registers[rtc_reg_idx] != value {
+                        self.rtc_registers[rtc_reg_idx] = value;
+                        self.sram_dirty = true;
+                    }
                     if rtc_reg_idx == 4 && (value & 0x40) == 0 {
                         self.update_rtc();
                     }
@@ -253,7 +493,10 @@ impl Mmu {
                 }
                 if !self.external_ram.is_empty() {
                     let ram_addr = (self.current_ram_bank * 0x2000) + (address - 0xA000) as usize;
-                    if ram_addr < self.external_ram.len() { self.external_ram[ram_addr] = value; }
+                    if ram_addr < self.external_ram.len() && self.external_ram[ram_addr] != value {
+                        self.external_ram[ram_addr] = value;
+                        self.sram_dirty = true;
+                    }
                 }
             },
             0xC000..=0xDFFF => self.wram[(address - 0xC000) as usize] = value,
@@ -273,6 +516,7 @@ impl Mmu {
             Mbc::Mbc2 => self.handle_mbc2_write(address, value),
             Mbc::Mbc3 => self.handle_mbc3_write(address, value),
             Mbc::Mbc5 => self.handle_mbc5_write(address, value),
+            Mbc::Gbs => self.handle_gbs_write(address, value),
         }
     }
     
@@ -289,11 +533,20 @@ impl Mmu {
         }
     }
 
+    fn handle_gbs_write(&mut self, address: u16, value: u8) {
+        if let 0x2000..=0x3FFF = address {
+            let num_rom_banks_actual = self.cartridge.raw_data.len() / 0x4000;
+            self.current_rom_bank = if value == 0 { 1 } else { value as usize % num_rom_banks_actual.max(1) };
+        }
+    }
+
     fn handle_mbc5_write(&mut self, address: u16, value: u8) {
         match address {
             0x0000..=0x1FFF => { self.ram_and_rtc_enabled = (value & 0x0F) == 0x0A; },
             0x2000..=0x2FFF => { self.current_rom_bank = (self.current_rom_bank & 0x100) | (value as usize); },
             0x3000..=0x3FFF => { self.current_rom_bank = (self.current_rom_bank & 0x0FF) | (((value & 0x01) as usize) << 8); },
+            // 振動カートリッジではビット3がモーター制御で、RAMバンクは下位3ビットのみ
+            0x4000..=0x5FFF if self.cartridge.has_rumble() => { self.current_ram_bank = (value & 0x07) as usize; self.rumble_active = (value & 0x08) != 0; },
             0x4000..=0x5FFF => { self.current_ram_bank = (value & 0x0F) as usize; },
             _ => { }
         }
@@ -350,33 +603,41 @@ impl Mmu {
 
     pub fn read_io_register_byte(&self, address: u16) -> u8 {
         match address {
-            0xFF00 => self.joypad.read_p1(),
+            0xFF00 => match &self.sgb {
+                Some(sgb) => sgb.read_p1(self.joypad.read_p1()),
+                None => self.joypad.read_p1(),
+            },
+            0xFF01 => self.serial.read_sb(),
+            0xFF02 => self.serial.read_sc(),
             0xFF04 => self.timer.read_div(),
             0xFF05 => self.timer.read_tima(),
             0xFF06 => self.timer.read_tma(),
             0xFF07 => self.timer.read_tac(),
             0xFF0F => self.io_registers[0x0F],
-            0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.apu.read_reg(address),
+            0xFF10..=0xFF3F => self.apu.read_reg(address),
             0xFF40 => self.ppu.lcdc, 0xFF41 => self.ppu.stat,
             0xFF42 => self.ppu.scy, 0xFF43 => self.ppu.scx,
             0xFF44 => self.ppu.ly, 0xFF45 => self.ppu.lyc,
             0xFF47 => self.ppu.bgp, 0xFF48 => self.ppu.obp0,
             0xFF49 => self.ppu.obp1, 0xFF4A => self.ppu.wy,
             0xFF4B => self.ppu.wx,
-            0xFF01..=0xFF02 | 0xFF4C..=0xFF7F => self.io_registers[(address - 0xFF00) as usize],
+            0xFF4C..=0xFF7F => self.io_registers[(address - 0xFF00) as usize],
             _ => 0xFF,
         }
     }
     
     pub fn write_io_register_byte(&mut self, address: u16, value: u8) {
         match address {
-            0xFF00 => self.joypad.write_p1(value),
+            0xFF00 => {
+                self.joypad.write_p1(value);
+                if let Some(sgb) = &mut self.sgb { sgb.write_p1(value); }
+            }
             0xFF04 => self.timer.write_div(),
             0xFF05 => self.timer.write_tima(value),
             0xFF06 => self.timer.write_tma(value),
             0xFF07 => self.timer.write_tac(value),
             0xFF0F => self.io_registers[0x0F] = (value & 0x1F) | 0xE0,
-            0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.apu.write_reg(address, value),
+            0xFF10..=0xFF3F => self.apu.write_reg(address, value),
             0xFF40 => self.ppu.lcdc = value,
             0xFF41 => self.ppu.stat = (value & 0x78) | (self.ppu.stat & 0x87),
             0xFF42 => self.ppu.scy = value, 0xFF43 => self.ppu.scx = value,
@@ -385,11 +646,14 @@ impl Mmu {
             0xFF47 => self.ppu.bgp = value, 0xFF48 => self.ppu.obp0 = value,
             0xFF49 => self.ppu.obp1 = value, 0xFF4A => self.ppu.wy = value,
             0xFF4B => self.ppu.wx = value,
-            0xFF01..=0xFF02 | 0xFF4C..=0xFF7F => {
-                if address == 0xFF01 { self.io_registers[0x01] = value; }
-                else if address == 0xFF02 { self.io_registers[0x02] = value & 0x81; if value == 0x81 { print!("{}", self.io_registers[0x01] as char); } }
-                else { self.io_registers[(address - 0xFF00) as usize] = value; }
-            },
+            0xFF01 => self.serial.write_sb(value),
+            0xFF02 => self.serial.write_sc(value),
+            // 0以外を書き込むとブートROMが切り離される (元に戻すことはできない)
+            0xFF50 => {
+                if value != 0 { self.boot_rom = None; }
+                self.io_registers[0x50] = value | 0xFE;
+            }
+            0xFF4C..=0xFF7F => self.io_registers[(address - 0xFF00) as usize] = value,
             _ => {}
         }
     }
@@ -424,4 +688,145 @@ impl Mmu {
         }
         println!("\n-----------------------------");
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MBC3+TIMER+RAM+BATTERY (8KB RAM) か、RTCなしの MBC3+RAM+BATTERY のMMU
+    fn mbc3_mmu(with_rtc: bool) -> Mmu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = if with_rtc { 0x10 } else { 0x13 };
+        rom[0x0149] = 0x02;
+        Mmu::new(Cartridge::from_bytes(rom).unwrap(), Apu::new(44_100))
+    }
+
+    /// 止めたRTC (DHのビット6) は時間が経っても進まないので、読み書きの結果を比べられる
+    fn set_halted_rtc(mmu: &mut Mmu) {
+        mmu.rtc_registers = [12, 34, 5, 0x2A, 0x41];
+        mmu.latched_rtc_registers = [1, 2, 3, 4, 0x40];
+        mmu.rtc_last_timestamp = 1_600_000_000;
+    }
+
+    #[test]
+    fn rtc_footer_round_trip() {
+        let mut mmu = mbc3_mmu(true);
+        mmu.external_ram[0] = 0x5A;
+        set_halted_rtc(&mut mmu);
+        let data = mmu.get_ram_and_rtc_data();
+        assert_eq!(data.len(), 0x2000 + 48);
+
+        let mut loaded = mbc3_mmu(true);
+        loaded.load_ram_and_rtc(&data);
+        assert_eq!(loaded.external_ram[0], 0x5A);
+        assert_eq!(loaded.rtc_registers, [12, 34, 5, 0x2A, 0x41]);
+        assert_eq!(loaded.latched_rtc_registers, [1, 2, 3, 4, 0x40]);
+        assert_eq!(loaded.get_ram_and_rtc_data(), data);
+    }
+
+    #[test]
+    fn reads_44_byte_footer() {
+        let mut mmu = mbc3_mmu(true);
+        set_halted_rtc(&mut mmu);
+        let mut data = mmu.get_ram_and_rtc_data();
+        // 旧VBA形式はUNIX時刻がu32
+        data.truncate(0x2000 + 44);
+        let mut loaded = mbc3_mmu(true);
+        loaded.load_ram_and_rtc(&data);
+        assert_eq!(loaded.rtc_registers, [12, 34, 5, 0x2A, 0x41]);
+    }
+
+    #[test]
+    fn migrates_legacy_footer() {
+        let mut data = vec![0; 0x2000];
+        data.extend_from_slice(&1_600_000_000i64.to_le_bytes());
+        data.extend_from_slice(&[7, 8, 9, 10, 0x40]);
+        let mut mmu = mbc3_mmu(true);
+        mmu.load_ram_and_rtc(&data);
+        assert_eq!(mmu.rtc_registers, [7, 8, 9, 10, 0x40]);
+        assert_eq!(mmu.latched_rtc_registers, [7, 8, 9, 10, 0x40]);
+        assert_eq!(mmu.get_ram_and_rtc_data().len(), 0x2000 + 48);
+    }
+
+    #[test]
+    fn mbc2_marks_dirty_only_on_change() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x06;
+        let mut mmu = Mmu::new(Cartridge::from_bytes(rom).unwrap(), Apu::new(44_100));
+        mmu.write_byte(0x0000, 0x0A);
+        mmu.write_byte(0xA010, 0x03);
+        assert!(mmu.take_sram_dirty());
+        // 同じ値 (上位4ビットは保存されない) の書き込みではセーブしない
+        mmu.write_byte(0xA010, 0xF3);
+        assert!(!mmu.take_sram_dirty());
+        mmu.write_byte(0xA010, 0x04);
+        assert!(mmu.take_sram_dirty());
+    }
+
+    #[test]
+    fn footer_is_dropped_without_rtc() {
+        let mut source = mbc3_mmu(true);
+        source.external_ram[1] = 0x77;
+        let data = source.get_ram_and_rtc_data();
+        let mut mmu = mbc3_mmu(false);
+        mmu.load_ram_and_rtc(&data);
+        assert_eq!(mmu.external_ram[1], 0x77);
+        assert_eq!(mmu.get_ram_and_rtc_data().len(), 0x2000);
+    }
+
+    #[test]
+    fn rumble_duty_averages_motor_pwm() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x1C;
+        let mut mmu = Mmu::new(Cartridge::from_bytes(rom).unwrap(), Apu::new(44_100));
+        for _ in 0..100 {
+            mmu.write_byte(0x4000, 0x08);
+            mmu.tick_components(4);
+            mmu.write_byte(0x4000, 0x00);
+            mmu.tick_components(4);
+            mmu.tick_components(4);
+            mmu.tick_components(4);
+        }
+        assert_eq!(mmu.take_rumble_duty(), 0.25);
+        // 計測し直した後、サイクルが進んでいなければ現在の状態をそのまま返す
+        mmu.write_byte(0x4000, 0x08);
+        assert_eq!(mmu.take_rumble_duty(), 1.0);
+    }
+
+    #[test]
+    fn scheduled_joypad_press_interrupts_at_its_cycle() {
+        let mut mmu = mbc3_mmu(false);
+        mmu.write_byte(0xFF00, 0x10); // ボタンキーを選択
+        mmu.io_registers[0x0F] &= !0x10;
+        let at = mmu.joypad.cycles() + 400;
+        mmu.joypad.schedule_input(at, crate::joypad::GameboyKey::Start, true);
+        while mmu.joypad.cycles() < at {
+            assert_eq!(mmu.io_registers[0x0F] & 0x10, 0);
+            assert_eq!(mmu.read_byte(0xFF00) & 0x08, 0x08);
+            mmu.tick_components(4);
+        }
+        assert_eq!(mmu.io_registers[0x0F] & 0x10, 0x10);
+        assert_eq!(mmu.read_byte(0xFF00) & 0x08, 0);
+    }
+
+    #[test]
+    fn load_state_rejects_a_boot_rom_of_the_wrong_size() {
+        let mut mmu = mbc3_mmu(false);
+        mmu.load_boot_rom(vec![0x31; BOOT_ROM_SIZE]).unwrap();
+        let mut w = StateWriter::new();
+        mmu.save_state(&mut w);
+        let state = w.into_bytes();
+        let mut loaded = mbc3_mmu(false);
+        loaded.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(loaded.read_byte(0x0000), 0x31);
+
+        // 壊れたセーブステートのブートROMは割り当てない
+        mmu.boot_rom = Some(vec![0x31; 16]);
+        let mut w = StateWriter::new();
+        mmu.save_state(&mut w);
+        let state = w.into_bytes();
+        let error = mbc3_mmu(false).load_state(&mut StateReader::new(&state)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
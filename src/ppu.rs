@@ -1,12 +1,20 @@
 // src/ppu.rs
 
+use std::io;
+
+use bincode::{Decode, Encode};
+
+use crate::save_state::{StateReader, StateWriter};
+
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
+/// 1フレーム (154ライン × 456サイクル) のTサイクル数
+pub const CYCLES_PER_FRAME: u32 = 70224;
 const VRAM_SIZE: usize = 8192;
 const OAM_SIZE: usize = 160;
 pub const PUB_OAM_SIZE: usize = OAM_SIZE;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
 pub enum PpuMode { HBlank = 0, VBlank = 1, OamScan = 2, Drawing = 3 }
 impl PpuMode { fn to_stat_bits(self) -> u8 { self as u8 } }
 
@@ -15,7 +23,7 @@ pub enum PpuInterruptType { None, VBlank, LcdStat }
 
 // ★ ここから追加 ★
 // 事前にカラーパレットを定義しておく
-const PALETTES: [[u32; 4]; 4] = [
+pub const PALETTES: [[u32; 4]; 4] = [
     [0x00E0F8D0, 0x0088C070, 0x00346856, 0x00081820], // デフォルト (薄緑)
     [0x00FFFFFF, 0x00AAAAAA, 0x00555555, 0x00000000], // グレースケール
     [0x00f8e4a0, 0x00d0a068, 0x00a06030, 0x00302010], // セピア
@@ -39,7 +47,10 @@ pub struct Ppu {
     pub wx: u8,
     pub current_mode: PpuMode,
     cycles_in_current_mode: u32,
-    pub frame_buffer: [u32; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // 画面のバッファはヒープに置く (CPU全体をスタックで受け渡すときに大きくなりすぎないように)
+    pub frame_buffer: Box<[u32; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    // パレット (BGP/OBP) 適用後の色番号 (0-3)。SGBの着色などに使う
+    pub shade_buffer: Box<[u8; SCREEN_WIDTH * SCREEN_HEIGHT]>,
     pub frame_ready: bool,
     scanline_sprites: Vec<(usize, u8)>, // (oam_address_base, x_pos)
     // ★ ここから追加 ★
@@ -48,13 +59,20 @@ pub struct Ppu {
     // ★ ここまで追加 ★
 }
 
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Ppu {
     pub fn new() -> Self {
         Self {
             vram: [0; VRAM_SIZE], oam: [0; OAM_SIZE], lcdc: 0x91, stat: 0x80, scy: 0, scx: 0,
             ly: 0, lyc: 0, bgp: 0xFC, obp0: 0xFF, obp1: 0xFF, wy: 0, wx: 0,
             current_mode: PpuMode::OamScan, cycles_in_current_mode: 0,
-            frame_buffer: [PALETTES[0][0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_buffer: Box::new([PALETTES[0][0]; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            shade_buffer: Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT]),
             frame_ready: false,
             scanline_sprites: Vec::with_capacity(10),
             colors: PALETTES[0], // ★ 変更: デフォルトパレットで初期化
@@ -62,6 +80,53 @@ impl Ppu {
         }
     }
 
+    /// 画面の状態を書き出します。表示パレットは含まず、画面は色番号から現在のパレットで復元します。
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.vram);
+        w.write(&self.oam);
+        w.write(&self.lcdc);
+        w.write(&self.stat);
+        w.write(&self.scy);
+        w.write(&self.scx);
+        w.write(&self.ly);
+        w.write(&self.lyc);
+        w.write(&self.bgp);
+        w.write(&self.obp0);
+        w.write(&self.obp1);
+        w.write(&self.wy);
+        w.write(&self.wx);
+        w.write(&self.current_mode);
+        w.write(&self.cycles_in_current_mode);
+        w.write(&self.shade_buffer);
+        w.write(&self.frame_ready);
+        w.write(&self.scanline_sprites);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.vram)?;
+        r.read_into(&mut self.oam)?;
+        r.read_into(&mut self.lcdc)?;
+        r.read_into(&mut self.stat)?;
+        r.read_into(&mut self.scy)?;
+        r.read_into(&mut self.scx)?;
+        r.read_into(&mut self.ly)?;
+        r.read_into(&mut self.lyc)?;
+        r.read_into(&mut self.bgp)?;
+        r.read_into(&mut self.obp0)?;
+        r.read_into(&mut self.obp1)?;
+        r.read_into(&mut self.wy)?;
+        r.read_into(&mut self.wx)?;
+        r.read_into(&mut self.current_mode)?;
+        r.read_into(&mut self.cycles_in_current_mode)?;
+        r.read_into(&mut self.shade_buffer)?;
+        r.read_into(&mut self.frame_ready)?;
+        r.read_into(&mut self.scanline_sprites)?;
+        for (pixel, &shade) in self.frame_buffer.iter_mut().zip(self.shade_buffer.iter()) {
+            *pixel = self.colors[(shade & 3) as usize];
+        }
+        Ok(())
+    }
+
     pub fn get_colors(&self) -> &[u32; 4] {
         &self.colors
     }
@@ -73,6 +138,14 @@ impl Ppu {
         self.colors = PALETTES[self.palette_index];
         println!("Palette changed to index {}", self.palette_index);
     }
+
+    /// カラーパレットをプリセット番号で設定します (範囲外の番号は無視します)。
+    pub fn set_palette(&mut self, index: usize) {
+        if let Some(colors) = PALETTES.get(index) {
+            self.palette_index = index;
+            self.colors = *colors;
+        }
+    }
     // ★ ここまで追加 ★
 
     pub fn step(&mut self, cycles: u8) -> PpuInterruptType {
@@ -121,8 +194,8 @@ impl Ppu {
                             // STAT割り込みも同時に発生させる
                         }
                     } else {
-                        if let PpuInterruptType::LcdStat = self.change_mode(PpuMode::OamScan) {
-                           if interrupt == PpuInterruptType::None { interrupt = PpuInterruptType::LcdStat; }
+                        if let PpuInterruptType::LcdStat = self.change_mode(PpuMode::OamScan) && interrupt == PpuInterruptType::None {
+                            interrupt = PpuInterruptType::LcdStat;
                         }
                     }
                 }
@@ -133,12 +206,12 @@ impl Ppu {
                     self.ly += 1;
                     if self.ly > 153 {
                         self.ly = 0;
-                        if let PpuInterruptType::LcdStat = self.change_mode(PpuMode::OamScan) {
-                            if interrupt == PpuInterruptType::None { interrupt = PpuInterruptType::LcdStat; }
+                        if let PpuInterruptType::LcdStat = self.change_mode(PpuMode::OamScan) && interrupt == PpuInterruptType::None {
+                            interrupt = PpuInterruptType::LcdStat;
                         }
                     }
-                    if self.check_lyc_coincidence() {
-                       if interrupt == PpuInterruptType::None { interrupt = PpuInterruptType::LcdStat; }
+                    if self.check_lyc_coincidence() && interrupt == PpuInterruptType::None {
+                        interrupt = PpuInterruptType::LcdStat;
                     }
                 }
             }
@@ -211,7 +284,7 @@ impl Ppu {
         let tile_data_base_addr: u16 = if (self.lcdc & 0b00010000) != 0 { 0x8000 } else { 0x9000 };
         let signed_addressing = (self.lcdc & 0b00010000) == 0;
         let frame_buffer_line_start_idx = self.ly as usize * SCREEN_WIDTH;
-        for screen_x in 0..SCREEN_WIDTH {
+        for (screen_x, bg_color_id) in bg_pixel_color_ids.iter_mut().enumerate() {
             let on_window = window_display_enabled && (screen_x as u8) >= self.wx.saturating_sub(7);
             let (map_base, map_y, map_x) = if on_window {
                 let win_map_base = if (self.lcdc & 0b01000000) != 0 { 0x9C00 } else { 0x9800 };
@@ -231,9 +304,10 @@ impl Ppu {
                 let x_in_tile = 7 - (map_x % 8); let color_bit_1 = (byte1 >> x_in_tile) & 1; let color_bit_2 = (byte2 >> x_in_tile) & 1;
                 final_color_id = (color_bit_2 << 1) | color_bit_1;
             }
-            bg_pixel_color_ids[screen_x] = final_color_id;
+            *bg_color_id = final_color_id;
             let shade_index = (self.bgp >> (final_color_id * 2)) & 0b11;
             self.frame_buffer[frame_buffer_line_start_idx + screen_x] = self.colors[shade_index as usize]; // ★ 変更: DMG_COLORS -> self.colors
+            self.shade_buffer[frame_buffer_line_start_idx + screen_x] = shade_index;
         }
     }
 
@@ -288,6 +362,7 @@ impl Ppu {
                 let shade_index = (palette >> (color_id * 2)) & 0b11;
                 let fb_idx = self.ly as usize * SCREEN_WIDTH + screen_x as usize;
                 self.frame_buffer[fb_idx] = self.colors[shade_index as usize]; // ★ 変更: DMG_COLORS -> self.colors
+                self.shade_buffer[fb_idx] = shade_index;
                 drawn_pixels[screen_x as usize] = true;
             }
         }
@@ -0,0 +1,326 @@
+// src/printer.rs
+// ポケットプリンタ (Game Boy Printer) のエミュレーション
+
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::Local;
+use image::{GrayImage, Luma};
+
+use crate::serial::SerialDevice;
+
+const PRINTER_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PRINTER_WIDTH / 8;
+const BYTES_PER_TILE_ROW: usize = TILES_PER_ROW * 16; // 8ピクセル行ぶん (320バイト)
+const IMAGE_BUFFER_SIZE: usize = 0x2000;
+// 余白1単位あたりのピクセル行数 (実機の紙送り量の近似)
+const MARGIN_UNIT_PIXELS: usize = 8;
+// PRINT後、何回のステータス問い合わせの間「印刷中」を返すか
+const PRINT_BUSY_POLLS: u8 = 4;
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketState { Magic1, Magic2, Command, Compression, LengthLow, LengthHigh, Data, ChecksumLow, ChecksumHigh, Alive, Status }
+
+pub struct GbPrinter {
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    packet_data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    busy_polls: u8,
+    image_buffer: Vec<u8>,
+    // 紙送りで切り離されるまでの印刷済みピクセル (色番号適用後の濃淡値)
+    paper: Vec<u8>,
+    output_dir: PathBuf,
+    printed_count: usize,
+}
+
+impl GbPrinter {
+    pub fn new<P: Into<PathBuf>>(output_dir: P) -> Self {
+        Self {
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet_data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            busy_polls: 0,
+            image_buffer: Vec::with_capacity(IMAGE_BUFFER_SIZE),
+            paper: Vec::new(),
+            output_dir: output_dir.into(),
+            printed_count: 0,
+        }
+    }
+
+    fn execute_packet(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            COMMAND_INIT => {
+                self.image_buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            COMMAND_DATA => {
+                if self.packet_data.is_empty() {
+                    // 長さ0のDATAパケットは転送終了の合図
+                    self.status |= STATUS_IMAGE_FULL;
+                    return;
+                }
+                let data = if self.compressed { decompress(&self.packet_data) } else { self.packet_data.clone() };
+                let room = IMAGE_BUFFER_SIZE - self.image_buffer.len();
+                self.image_buffer.extend_from_slice(&data[..data.len().min(room)]);
+                self.status |= STATUS_UNPROCESSED;
+            }
+            COMMAND_PRINT if self.packet_data.len() >= 4 => {
+                let margins = self.packet_data[1];
+                let palette = if self.packet_data[2] == 0 { 0xE4 } else { self.packet_data[2] };
+                self.print_image(margins >> 4, margins & 0x0F, palette);
+                self.image_buffer.clear();
+                self.status = (self.status & !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL)) | STATUS_PRINTING;
+                self.busy_polls = PRINT_BUSY_POLLS;
+            }
+            COMMAND_STATUS if self.busy_polls > 0 => {
+                self.busy_polls -= 1;
+                if self.busy_polls == 0 { self.status &= !STATUS_PRINTING; }
+            }
+            _ => {}
+        }
+    }
+
+    fn print_image(&mut self, margin_before: u8, margin_after: u8, palette: u8) {
+        self.paper.extend(std::iter::repeat_n(SHADES[0], margin_before as usize * MARGIN_UNIT_PIXELS * PRINTER_WIDTH));
+        let tile_rows = self.image_buffer.len() / BYTES_PER_TILE_ROW;
+        for tile_row in 0..tile_rows {
+            for y in 0..8 {
+                for x in 0..PRINTER_WIDTH {
+                    let tile_addr = tile_row * BYTES_PER_TILE_ROW + (x / 8) * 16 + y * 2;
+                    let bit = 7 - (x % 8);
+                    let color_id = (((self.image_buffer[tile_addr + 1] >> bit) & 1) << 1) | ((self.image_buffer[tile_addr] >> bit) & 1);
+                    let shade = (palette >> (color_id * 2)) & 0x03;
+                    self.paper.push(SHADES[shade as usize]);
+                }
+            }
+        }
+        // 後ろ余白 (紙送り) が指定されたら用紙を切り離して保存する
+        if margin_after > 0 {
+            self.paper.extend(std::iter::repeat_n(SHADES[0], margin_after as usize * MARGIN_UNIT_PIXELS * PRINTER_WIDTH));
+            self.save_paper();
+        }
+    }
+
+    fn save_paper(&mut self) {
+        let height = self.paper.len() / PRINTER_WIDTH;
+        if height == 0 { return; }
+        let mut image = GrayImage::new(PRINTER_WIDTH as u32, height as u32);
+        for (i, shade) in self.paper.iter().enumerate() {
+            image.put_pixel((i % PRINTER_WIDTH) as u32, (i / PRINTER_WIDTH) as u32, Luma([*shade]));
+        }
+        self.paper.clear();
+
+        if fs::create_dir_all(&self.output_dir).is_err() {
+            eprintln!("Failed to create {} directory.", self.output_dir.display());
+            return;
+        }
+        self.printed_count += 1;
+        let timestamp = Local::now().format("%Y%m%d-%H%M%S");
+        let path = self.output_dir.join(format!("print-{}-{}.png", timestamp, self.printed_count));
+        match image.save(&path) {
+            Ok(_) => println!("Printed image saved to {}", path.display()),
+            Err(e) => eprintln!("Failed to save printed image: {}", e),
+        }
+    }
+}
+
+impl SerialDevice for GbPrinter {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut response = 0x00;
+        self.state = match self.state {
+            PacketState::Magic1 => if byte == 0x88 { PacketState::Magic2 } else { PacketState::Magic1 },
+            PacketState::Magic2 => if byte == 0x33 { PacketState::Command } else { PacketState::Magic1 },
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                self.packet_data.clear();
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = (byte & 0x01) != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = byte as usize;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.length > 0 { PacketState::Data } else { PacketState::ChecksumLow }
+            }
+            PacketState::Data => {
+                self.packet_data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.packet_data.len() >= self.length { PacketState::ChecksumLow } else { PacketState::Data }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                PacketState::Alive
+            }
+            PacketState::Alive => {
+                // 接続確認バイト: プリンタは $81 を返す
+                response = 0x81;
+                self.execute_packet();
+                PacketState::Status
+            }
+            PacketState::Status => {
+                response = self.status;
+                PacketState::Magic1
+            }
+        };
+        response
+    }
+
+    fn disconnect(&mut self) {
+        self.save_paper();
+    }
+}
+
+// 印刷データのRLE展開
+// 制御バイトのBit 7が1なら、続く1バイトを (下位7ビット + 2) 回繰り返す。
+// 0なら、続く (制御バイト + 1) バイトをそのままコピーする。
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(IMAGE_BUFFER_SIZE);
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if (control & 0x80) != 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(&value) = data.get(i) {
+                output.extend(std::iter::repeat_n(value, count));
+            }
+            i += 1;
+        } else {
+            let count = control as usize + 1;
+            let end = (i + count).min(data.len());
+            output.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // マジック〜ステータスまでの1パケットを送り、最後の2バイト (接続確認, ステータス) の応答を返す
+    fn send_packet(printer: &mut GbPrinter, command: u8, compressed: bool, data: &[u8], checksum_error: bool) -> (u8, u8) {
+        let header = [command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        let mut checksum = header.iter().chain(data).fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        if checksum_error { checksum = checksum.wrapping_add(1); }
+        let bytes: Vec<u8> = [0x88, 0x33].iter().chain(&header).chain(data).chain(&checksum.to_le_bytes()).copied().collect();
+        for byte in bytes {
+            assert_eq!(printer.exchange(byte), 0x00);
+        }
+        (printer.exchange(0x00), printer.exchange(0x00))
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("printer-test-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn decompresses_runs_and_literals() {
+        assert_eq!(decompress(&[0x81, 0xAB, 0x02, 1, 2, 3, 0x80, 0x00]), vec![0xAB, 0xAB, 0xAB, 1, 2, 3, 0x00, 0x00]);
+        // 途中で切れたデータは読めた分だけ
+        assert_eq!(decompress(&[0x03, 7, 8]), vec![7, 8]);
+        assert_eq!(decompress(&[0x85]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn ignores_bytes_until_the_magic() {
+        let mut printer = GbPrinter::new(temp_dir("magic"));
+        for byte in [0x00, 0x33, 0x88, 0x00] {
+            printer.exchange(byte);
+        }
+        assert_eq!(printer.state, PacketState::Magic1);
+        assert_eq!(send_packet(&mut printer, COMMAND_STATUS, false, &[], false), (0x81, 0x00));
+    }
+
+    #[test]
+    fn reports_checksum_errors_in_the_status() {
+        let mut printer = GbPrinter::new(temp_dir("checksum"));
+        let (alive, status) = send_packet(&mut printer, COMMAND_DATA, false, &[0xFF; 16], true);
+        assert_eq!((alive, status), (0x81, STATUS_CHECKSUM_ERROR));
+        assert!(printer.image_buffer.is_empty());
+        // 正しいパケットでエラーは消える
+        assert_eq!(send_packet(&mut printer, COMMAND_DATA, false, &[0xFF; 16], false).1, STATUS_UNPROCESSED);
+        assert_eq!(printer.image_buffer.len(), 16);
+    }
+
+    #[test]
+    fn data_packets_expand_compressed_data() {
+        let mut printer = GbPrinter::new(temp_dir("rle"));
+        send_packet(&mut printer, COMMAND_INIT, false, &[], false);
+        send_packet(&mut printer, COMMAND_DATA, true, &[0x80 | 14, 0x55, 0x01, 0x12, 0x34], false);
+        let mut expected = vec![0x55; 16];
+        expected.extend([0x12, 0x34]);
+        assert_eq!(printer.image_buffer, expected);
+        // 長さ0のDATAで転送終了
+        assert_eq!(send_packet(&mut printer, COMMAND_DATA, false, &[], false).1, STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+    }
+
+    #[test]
+    fn init_data_print_saves_a_160_pixel_wide_image() {
+        let dir = temp_dir("print");
+        let mut printer = GbPrinter::new(&dir);
+        send_packet(&mut printer, COMMAND_INIT, false, &[], false);
+        // 2タイル行: 1行目は色3 (黒)、2行目は色0 (白)
+        let mut data = vec![0xFF; BYTES_PER_TILE_ROW];
+        data.extend(vec![0x00; BYTES_PER_TILE_ROW]);
+        send_packet(&mut printer, COMMAND_DATA, false, &data, false);
+        send_packet(&mut printer, COMMAND_DATA, false, &[], false);
+        // 1枚, 前余白0, 後余白1, パレット E4, 濃度
+        let (_, status) = send_packet(&mut printer, COMMAND_PRINT, false, &[0x01, 0x01, 0xE4, 0x40], false);
+        assert_eq!(status, STATUS_PRINTING);
+        for _ in 0..PRINT_BUSY_POLLS {
+            send_packet(&mut printer, COMMAND_STATUS, false, &[], false);
+        }
+        assert_eq!(send_packet(&mut printer, COMMAND_STATUS, false, &[], false).1, 0x00);
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        let image = image::open(&files[0]).unwrap().into_luma8();
+        assert_eq!(image.dimensions(), (PRINTER_WIDTH as u32, (16 + MARGIN_UNIT_PIXELS) as u32));
+        assert_eq!(image.get_pixel(0, 0).0, [0x00]);
+        assert_eq!(image.get_pixel(159, 7).0, [0x00]);
+        assert_eq!(image.get_pixel(0, 8).0, [0xFF]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
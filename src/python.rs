@@ -0,0 +1,394 @@
+// src/python.rs
+// Pythonの拡張モジュール (強化学習やボットから使う。`python` フィーチャーでビルドし、maturinでパッケージにする)
+//
+// Gymに近いインターフェースを持つ GameBoy クラスを公開する。
+//
+//   gb = rust_gb_emulator.GameBoy("game.gb", frame_skip=4, observation="shades",
+//                                 reward=lambda gb: gb.read_u8(0xC0A0), max_steps=10000)
+//   observation, info = gb.reset()
+//   observation, reward, terminated, truncated, info = gb.step("A+Right")
+//
+// 観測は bytes で返す (numpyでは np.frombuffer(obs, np.uint8).reshape(gb.observation_shape))。
+//   "screen"  表示される画面のRGB (高さ, 幅, 3)。SGBでは枠を含む
+//   "shades"  PPUの色番号 0〜3 (144, 160)。パレットによらない
+//   "tiles"   BGのタイルマップのタイル番号 (32, 32)。LCDCで選ばれている方
+// 行動はボタンのマスク (BUTTONS のビット順)、"A+B" のような文字列、ボタン名のリストのどれか。
+// reward / done は GameBoy を受け取る関数で、step のたびに呼ばれる。
+
+use std::fs;
+
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList};
+
+use crate::cartridge::Cartridge;
+use crate::headless::{Headless, HeadlessOptions, Model};
+use crate::input::{self, BUTTON_NAMES};
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const TILE_MAP_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Observation {
+    Screen,
+    Shades,
+    Tiles,
+}
+
+impl Observation {
+    fn parse(name: &str) -> PyResult<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "screen" => Ok(Self::Screen),
+            "shades" => Ok(Self::Shades),
+            "tiles" => Ok(Self::Tiles),
+            _ => Err(PyValueError::new_err(format!("unknown observation '{}' (expected screen, shades or tiles)", name))),
+        }
+    }
+}
+
+fn parse_model(name: &str) -> PyResult<Model> {
+    match name.to_ascii_lowercase().as_str() {
+        "auto" => Ok(Model::Auto),
+        "dmg" => Ok(Model::Dmg),
+        "sgb" => Ok(Model::Sgb),
+        _ => Err(PyValueError::new_err(format!("unknown model '{}' (expected auto, dmg or sgb)", name))),
+    }
+}
+
+/// 行動 (マスク、"A+B"、["A", "B"]) をボタンのマスクにします。
+fn action_mask(action: &Bound<'_, PyAny>) -> PyResult<u8> {
+    if action.is_none() {
+        return Ok(0);
+    }
+    if let Ok(mask) = action.extract::<u8>() {
+        return Ok(mask);
+    }
+    let buttons = match action.extract::<String>() {
+        Ok(buttons) => buttons,
+        Err(_) => action.extract::<Vec<String>>()
+            .map_err(|_| PyValueError::new_err("action must be a button mask, a string like 'A+B' or a list of button names"))?
+            .join("+"),
+    };
+    input::parse_buttons(&buttons).map_err(PyValueError::new_err)
+}
+
+/// Game Boyのエミュレータ (ウィンドウと音声出力なし)
+#[pyclass(name = "GameBoy", unsendable)]
+pub struct GameBoy {
+    emulator: Headless,
+    // reset で戻す起動直後の状態
+    initial_state: Vec<u8>,
+    frame_skip: u32,
+    observation: Observation,
+    reward: Option<Py<PyAny>>,
+    done: Option<Py<PyAny>>,
+    max_steps: Option<u64>,
+    steps: u64,
+    // reset してから実行したフレーム数
+    frames: u64,
+}
+
+impl GameBoy {
+    fn observe_as(&self, kind: Observation) -> Vec<u8> {
+        let ppu = &self.emulator.cpu.mmu.ppu;
+        match kind {
+            Observation::Screen => self.emulator.screen().iter()
+                .flat_map(|pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])
+                .collect(),
+            Observation::Shades => ppu.shade_buffer.to_vec(),
+            Observation::Tiles => {
+                let base = if ppu.lcdc & 0b00001000 != 0 { 0x1C00 } else { 0x1800 };
+                ppu.vram[base..base + TILE_MAP_SIZE * TILE_MAP_SIZE].to_vec()
+            }
+        }
+    }
+
+    fn shape_of(&self, kind: Observation) -> Vec<usize> {
+        match kind {
+            Observation::Screen => {
+                let (width, height) = self.emulator.screen_size();
+                vec![height, width, 3]
+            }
+            Observation::Shades => vec![SCREEN_HEIGHT, SCREEN_WIDTH],
+            Observation::Tiles => vec![TILE_MAP_SIZE, TILE_MAP_SIZE],
+        }
+    }
+
+    fn info<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let info = PyDict::new(py);
+        info.set_item("frame", self.frames)?;
+        info.set_item("steps", self.steps)?;
+        Ok(info)
+    }
+}
+
+#[pymethods]
+impl GameBoy {
+    #[new]
+    #[pyo3(signature = (rom, model = "auto", boot_rom = None, frame_skip = 1, observation = "screen", palette = 0, reward = None, done = None, max_steps = None))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        rom: &str,
+        model: &str,
+        boot_rom: Option<&str>,
+        frame_skip: u32,
+        observation: &str,
+        palette: usize,
+        reward: Option<Py<PyAny>>,
+        done: Option<Py<PyAny>>,
+        max_steps: Option<u64>,
+    ) -> PyResult<Self> {
+        if frame_skip == 0 {
+            return Err(PyValueError::new_err("frame_skip must be at least 1"));
+        }
+        let boot_rom = match boot_rom {
+            Some(path) => Some(fs::read(path).map_err(|e| PyIOError::new_err(format!("failed to read boot ROM {}: {}", path, e)))?),
+            None => None,
+        };
+        let options = HeadlessOptions { model: parse_model(model)?, boot_rom };
+        let cartridge = Cartridge::load(rom).map_err(|e| PyIOError::new_err(format!("failed to load ROM {}: {}", rom, e)))?;
+        let mut emulator = Headless::new(cartridge, &options)?;
+        emulator.cpu.mmu.ppu.set_palette(palette);
+        let initial_state = emulator.save_state();
+        Ok(Self {
+            emulator,
+            initial_state,
+            frame_skip,
+            observation: Observation::parse(observation)?,
+            reward,
+            done,
+            max_steps,
+            steps: 0,
+            frames: 0,
+        })
+    }
+
+    /// 起動直後の状態に戻し、(観測, info) を返します。
+    fn reset<'py>(&mut self, py: Python<'py>) -> PyResult<(Bound<'py, PyBytes>, Bound<'py, PyDict>)> {
+        self.emulator.load_state(&self.initial_state)?;
+        self.steps = 0;
+        self.frames = 0;
+        Ok((PyBytes::new(py, &self.observe_as(self.observation)), self.info(py)?))
+    }
+
+    /// 行動のボタンを押したまま frame_skip フレーム進め、(観測, 報酬, 終了, 打ち切り, info) を返します。
+    #[allow(clippy::type_complexity)]
+    fn step<'py>(slf: &Bound<'py, Self>, action: &Bound<'py, PyAny>) -> PyResult<(Bound<'py, PyBytes>, f64, bool, bool, Bound<'py, PyDict>)> {
+        let py = slf.py();
+        let buttons = action_mask(action)?;
+        let (observation, truncated, reward, done) = {
+            let mut gb = slf.borrow_mut();
+            let frames = gb.frame_skip as u64;
+            gb.emulator.run_frames(frames, buttons);
+            gb.frames += frames;
+            gb.steps += 1;
+            let truncated = gb.max_steps.is_some_and(|max| gb.steps >= max);
+            let reward = gb.reward.as_ref().map(|f| f.clone_ref(py));
+            let done = gb.done.as_ref().map(|f| f.clone_ref(py));
+            (PyBytes::new(py, &gb.observe_as(gb.observation)), truncated, reward, done)
+        };
+        // コールバックから GameBoy のメソッドを呼べるように、借用を返してから呼ぶ
+        let reward = match reward {
+            Some(f) => f.call1(py, (slf,))?.extract::<f64>(py)?,
+            None => 0.0,
+        };
+        let terminated = match done {
+            Some(f) => f.call1(py, (slf,))?.is_truthy(py)?,
+            None => false,
+        };
+        let info = slf.borrow().info(py)?;
+        Ok((observation, reward, terminated, truncated, info))
+    }
+
+    /// step を数えずに `frames` フレーム進めます (タイトル画面を飛ばすときなど)。
+    #[pyo3(signature = (frames = 1, action = None))]
+    fn tick(&mut self, frames: u64, action: Option<&Bound<'_, PyAny>>) -> PyResult<()> {
+        let buttons = match action {
+            Some(action) => action_mask(action)?,
+            None => 0,
+        };
+        self.emulator.run_frames(frames, buttons);
+        self.frames += frames;
+        Ok(())
+    }
+
+    /// 今の観測。`kind` を省略するとコンストラクタで指定した種類です。
+    #[pyo3(signature = (kind = None))]
+    fn observe<'py>(&self, py: Python<'py>, kind: Option<&str>) -> PyResult<Bound<'py, PyBytes>> {
+        let kind = kind.map_or(Ok(self.observation), Observation::parse)?;
+        Ok(PyBytes::new(py, &self.observe_as(kind)))
+    }
+
+    /// 観測の形 (numpyの reshape 用)
+    #[getter]
+    fn observation_shape(&self) -> Vec<usize> {
+        self.shape_of(self.observation)
+    }
+
+    /// reset してから実行したフレーム数
+    #[getter]
+    fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    #[getter]
+    fn frame_skip(&self) -> u32 {
+        self.frame_skip
+    }
+
+    #[setter]
+    fn set_frame_skip(&mut self, frame_skip: u32) -> PyResult<()> {
+        if frame_skip == 0 {
+            return Err(PyValueError::new_err("frame_skip must be at least 1"));
+        }
+        self.frame_skip = frame_skip;
+        Ok(())
+    }
+
+    fn read_u8(&self, address: u16) -> u8 {
+        self.emulator.cpu.mmu.read_byte(address)
+    }
+
+    /// リトルエンディアンの16ビット値
+    fn read_u16(&self, address: u16) -> u16 {
+        self.emulator.cpu.mmu.read_u16(address)
+    }
+
+    /// `address` から `length` バイト (アドレスは $FFFF の次は $0000 に戻る)
+    fn read_range<'py>(&self, py: Python<'py>, address: u16, length: usize) -> Bound<'py, PyBytes> {
+        let mmu = &self.emulator.cpu.mmu;
+        let bytes: Vec<u8> = (0..length).map(|i| mmu.read_byte(address.wrapping_add(i as u16))).collect();
+        PyBytes::new(py, &bytes)
+    }
+
+    fn write_u8(&mut self, address: u16, value: u8) {
+        self.emulator.cpu.mmu.write_byte(address, value);
+    }
+
+    /// レジスタの値 (a f b c d e h l sp pc)
+    fn registers<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let r = &self.emulator.cpu.registers;
+        let registers = PyDict::new(py);
+        for (name, value) in [("a", r.a), ("f", r.f), ("b", r.b), ("c", r.c), ("d", r.d), ("e", r.e), ("h", r.h), ("l", r.l)] {
+            registers.set_item(name, value)?;
+        }
+        registers.set_item("sp", r.sp)?;
+        registers.set_item("pc", r.pc)?;
+        Ok(registers)
+    }
+
+    fn save_state<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.emulator.save_state())
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> PyResult<()> {
+        Ok(self.emulator.load_state(data)?)
+    }
+
+    /// 今の画面をPNGで保存します。
+    fn screenshot(&self, path: &str) -> PyResult<()> {
+        Ok(self.emulator.save_screenshot(path)?)
+    }
+}
+
+#[pymodule]
+fn rust_gb_emulator(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<GameBoy>()?;
+    // ボタンのマスクのビット順
+    m.add("BUTTONS", PyList::new(m.py(), BUTTON_NAMES)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::GameboyKey;
+
+    /// $0100 から $C000 の値を増やし続けるだけのROMを一時ファイルに書き、GameBoy を作ります。
+    fn counting_game_boy(name: &str, frame_skip: u32, observation: &str, reward: Option<Py<PyAny>>, done: Option<Py<PyAny>>, max_steps: Option<u64>) -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]); // LD HL,$C000; INC (HL); JR $0103
+        let path = std::env::temp_dir().join(format!("python-test-{}-{}.gb", name, std::process::id()));
+        fs::write(&path, rom).unwrap();
+        let gb = GameBoy::new(path.to_str().unwrap(), "dmg", None, frame_skip, observation, 0, reward, done, max_steps);
+        fs::remove_file(&path).unwrap();
+        gb.unwrap()
+    }
+
+    fn lambda(py: Python<'_>, code: &std::ffi::CStr) -> Py<PyAny> {
+        py.eval(code, None, None).unwrap().unbind()
+    }
+
+    #[test]
+    fn reset_restores_the_initial_state() {
+        Python::initialize();
+        Python::attach(|py| {
+            let mut gb = counting_game_boy("reset", 1, "screen", None, None, None);
+            let initial = gb.read_u8(0xC100);
+            gb.tick(3, None).unwrap();
+            gb.write_u8(0xC100, initial.wrapping_add(1));
+            assert_eq!(gb.frame_count(), 3);
+
+            let (observation, info) = gb.reset(py).unwrap();
+            assert_eq!(gb.read_u8(0xC100), initial);
+            assert_eq!(gb.frame_count(), 0);
+            assert_eq!(observation.as_bytes().len(), SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+            assert_eq!(info.get_item("frame").unwrap().unwrap().extract::<u64>().unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn step_reports_reward_termination_and_truncation() {
+        Python::initialize();
+        Python::attach(|py| {
+            let reward = lambda(py, c"lambda gb: gb.read_u8(0xC100) * 0.5");
+            let done = lambda(py, c"lambda gb: gb.frame_count >= 8");
+            let gb = Bound::new(py, counting_game_boy("step", 4, "shades", Some(reward), Some(done), Some(3))).unwrap();
+            gb.borrow_mut().write_u8(0xC100, 6);
+
+            let (_, reward, terminated, truncated, info) = GameBoy::step(&gb, &"A+Right".into_pyobject(py).unwrap()).unwrap();
+            assert_eq!((reward, terminated, truncated), (3.0, false, false));
+            assert_eq!(info.get_item("frame").unwrap().unwrap().extract::<u64>().unwrap(), 4);
+            assert!(gb.borrow().emulator.cpu.mmu.joypad.pending_state(GameboyKey::A));
+
+            let (_, _, terminated, truncated, info) = GameBoy::step(&gb, &py.None().into_bound(py)).unwrap();
+            assert_eq!((terminated, truncated), (true, false));
+            assert_eq!(info.get_item("steps").unwrap().unwrap().extract::<u64>().unwrap(), 2);
+
+            let (_, _, _, truncated, _) = GameBoy::step(&gb, &0u8.into_pyobject(py).unwrap()).unwrap();
+            assert!(truncated);
+            assert!(GameBoy::step(&gb, &"Turbo".into_pyobject(py).unwrap()).is_err());
+        });
+    }
+
+    #[test]
+    fn observations_match_their_shapes() {
+        Python::initialize();
+        Python::attach(|py| {
+            let mut gb = counting_game_boy("observe", 1, "tiles", None, None, None);
+            gb.tick(1, None).unwrap();
+            assert_eq!(gb.observation_shape(), vec![TILE_MAP_SIZE, TILE_MAP_SIZE]);
+            for kind in ["screen", "shades", "tiles"] {
+                let observation = gb.observe(py, Some(kind)).unwrap();
+                assert_eq!(observation.as_bytes().len(), gb.shape_of(Observation::parse(kind).unwrap()).iter().product::<usize>(), "{}", kind);
+            }
+            assert!(gb.observe(py, Some("pixels")).is_err());
+        });
+    }
+
+    #[test]
+    fn reads_memory_little_endian() {
+        Python::initialize();
+        Python::attach(|py| {
+            let mut gb = counting_game_boy("memory", 1, "screen", None, None, None);
+            gb.write_u8(0xC100, 0x34);
+            gb.write_u8(0xC101, 0x12);
+            assert_eq!(gb.read_u16(0xC100), 0x1234);
+            assert_eq!(gb.read_range(py, 0xC100, 2).as_bytes(), &[0x34, 0x12]);
+            // カウンタはROMのループで増え続ける
+            let counter = gb.read_u8(0xC000);
+            gb.tick(1, None).unwrap();
+            assert_ne!(gb.read_u8(0xC000), counter);
+        });
+    }
+}
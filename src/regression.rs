@@ -0,0 +1,142 @@
+// src/regression.rs
+// 画面の回帰テスト: 入力スクリプトに沿ってヘッドレスで実行し、画面を基準画像 (PNG) と比べる
+//
+// 入力スクリプトは入力マクロと同じ "ボタン+ボタン:フレーム数" のステップを、カンマ・空白・改行で
+// 区切って並べたもの ("Start:2, :60, A:1")。'#' から行末まではコメント。
+// 基準画像との比較は色そのもので行うので、テストROMに付属する参照画像 (dmg-acid2, Mealybug) と
+// 比べるときはグレースケールのパレット (REFERENCE_PALETTE) で実行する。
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use image::{Rgba, RgbaImage};
+
+use crate::headless::Headless;
+use crate::input::InputMacro;
+
+/// テストROMの参照画像と同じ色 (白, $AA, $55, 黒) になる ppu::PALETTES の番号
+pub const REFERENCE_PALETTE: usize = 1;
+
+// 差分画像で一致した画素を薄く表示するときの元の色の割合
+const DIFF_FADE: u32 = 4;
+const DIFF_COLOR: Rgba<u8> = Rgba([255, 0, 0, 255]);
+
+/// 入力スクリプトをフレームごとのボタンのマスク (input::BUTTONS のビット順) にします。
+pub fn parse_input_script(script: &str) -> Result<Vec<u8>, String> {
+    let steps: Vec<String> = script.lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|step| !step.is_empty())
+        .map(str::to_string)
+        .collect();
+    Ok(InputMacro::parse("script", &steps)?.frames)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScriptRun {
+    /// 実行する最大フレーム数
+    pub frames: u64,
+    /// フレームごとのボタン。足りない分は何も押さない
+    pub input: Vec<u8>,
+    /// `LD B,B` を実行したフレームで止める (Mooneye, dmg-acid2, Mealybugの終了の合図)
+    pub until_breakpoint: bool,
+}
+
+/// スクリプトに沿って実行し、実行したフレーム数と、`LD B,B` で止まったかを返します。
+pub fn run_script(emulator: &mut Headless, run: &ScriptRun) -> (u64, bool) {
+    for frame in 0..run.frames {
+        let buttons = run.input.get(frame as usize).copied().unwrap_or(0);
+        if run.until_breakpoint {
+            if emulator.run_frame_until_breakpoint(buttons) {
+                return (frame + 1, true);
+            }
+        } else {
+            emulator.run_frame(buttons);
+        }
+    }
+    (run.frames, false)
+}
+
+/// 画面 (0x00RRGGBB) のハッシュ (FNV-1a 64ビット)。実行環境やRustのバージョンによらず同じ値になります。
+pub fn frame_hash(pixels: &[u32]) -> u64 {
+    pixels.iter().flat_map(|pixel| pixel.to_le_bytes()).fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+/// 画像の比較結果
+#[derive(Debug, Clone)]
+pub struct ImageDiff {
+    pub actual_size: (u32, u32),
+    pub expected_size: (u32, u32),
+    /// 色が違う画素の数 (大きさが違うときは重なる範囲だけを数える)
+    pub differing_pixels: usize,
+    /// 最初に見つかった違う画素 (x, y)
+    pub first_difference: Option<(u32, u32)>,
+    /// 違う画素を赤、一致した画素を薄く描いた画像
+    pub diff_image: RgbaImage,
+}
+
+impl ImageDiff {
+    pub fn matches(&self) -> bool {
+        self.actual_size == self.expected_size && self.differing_pixels == 0
+    }
+
+    /// 結果を1行で説明します。
+    pub fn summary(&self) -> String {
+        if self.actual_size != self.expected_size {
+            return format!("size mismatch: got {}x{}, expected {}x{}", self.actual_size.0, self.actual_size.1, self.expected_size.0, self.expected_size.1);
+        }
+        let total = self.actual_size.0 as usize * self.actual_size.1 as usize;
+        match self.first_difference {
+            Some((x, y)) => format!("{} of {} pixels differ (first at {},{})", self.differing_pixels, total, x, y),
+            None => "images match".to_string(),
+        }
+    }
+}
+
+/// 2つの画像を画素ごとに比べます。アルファは無視します。
+pub fn compare_images(actual: &RgbaImage, expected: &RgbaImage) -> ImageDiff {
+    let width = actual.width().min(expected.width());
+    let height = actual.height().min(expected.height());
+    let mut diff_image = RgbaImage::new(actual.width().max(expected.width()), actual.height().max(expected.height()));
+    let mut differing_pixels = 0;
+    let mut first_difference = None;
+    for (x, y, pixel) in diff_image.enumerate_pixels_mut() {
+        if x >= width || y >= height {
+            *pixel = DIFF_COLOR;
+            continue;
+        }
+        let a = actual.get_pixel(x, y);
+        let e = expected.get_pixel(x, y);
+        if a.0[..3] == e.0[..3] {
+            // 一致した画素は白に寄せて薄くする
+            let fade = |c: u8| (255 - (255 - c as u32) / DIFF_FADE) as u8;
+            *pixel = Rgba([fade(a[0]), fade(a[1]), fade(a[2]), 255]);
+        } else {
+            *pixel = DIFF_COLOR;
+            differing_pixels += 1;
+            first_difference.get_or_insert((x, y));
+        }
+    }
+    ImageDiff { actual_size: actual.dimensions(), expected_size: expected.dimensions(), differing_pixels, first_difference, diff_image }
+}
+
+pub fn load_png<P: AsRef<Path>>(path: P) -> io::Result<RgbaImage> {
+    let path = path.as_ref();
+    image::open(path).map(|image| image.to_rgba8())
+        .map_err(|e| io::Error::other(format!("failed to read {}: {}", path.display(), e)))
+}
+
+pub fn save_png<P: AsRef<Path>>(image: &RgbaImage, path: P) -> io::Result<()> {
+    if let Some(dir) = path.as_ref().parent() && !dir.as_os_str().is_empty() {
+        fs::create_dir_all(dir)?;
+    }
+    image.save(path).map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// 今の画面を基準画像と比べます。
+pub fn compare_with_golden<P: AsRef<Path>>(emulator: &Headless, golden: P) -> io::Result<ImageDiff> {
+    Ok(compare_images(&emulator.screen_image(), &load_png(golden)?))
+}
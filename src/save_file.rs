@@ -0,0 +1,200 @@
+// src/save_file.rs
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::mmu::Mmu;
+
+/// 保持するバックアップの世代数 (.sav.bak1 が最新)。
+/// バックアップを作るのはセッションで最初に書き出すときだけなので、世代はセッション単位になる
+/// (定期的な書き出しのたびにずらすと、数秒おきの同じような内容で古い世代が押し出されてしまう)。
+pub const BACKUP_GENERATIONS: usize = 3;
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut os_string = path.as_os_str().to_owned();
+    os_string.push(suffix);
+    PathBuf::from(os_string)
+}
+
+/// 一時ファイルに書き込んでから rename することで、書き込み途中でプロセスが落ちても
+/// 既存のセーブファイルが壊れないようにします。
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path_with_suffix(path, ".tmp");
+    {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+/// 既存のセーブファイルを .bak1 に退避し、古いバックアップを1世代ずつずらします。
+pub fn rotate_backups(path: &Path, generations: usize) -> io::Result<()> {
+    if generations == 0 || !path.exists() {
+        return Ok(());
+    }
+    for i in (1..generations).rev() {
+        let from = path_with_suffix(path, &format!(".bak{}", i));
+        if from.exists() {
+            fs::rename(&from, path_with_suffix(path, &format!(".bak{}", i + 1)))?;
+        }
+    }
+    fs::copy(path, path_with_suffix(path, ".bak1"))?;
+    Ok(())
+}
+
+/// バッテリーバックアップRAMを定期的にディスクへ書き出します。
+/// 書き出しはSRAMへの書き込みがあった場合のみ行い、セッション最初の書き出し前に
+/// 前回までのセーブファイルをバックアップとして退避します。
+pub struct BatterySaver {
+    path: PathBuf,
+    interval: Duration,
+    last_flush: Instant,
+    pending: bool,
+    backed_up: bool,
+}
+
+impl BatterySaver {
+    pub fn new<P: AsRef<Path>>(path: P, interval: Duration) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            interval,
+            last_flush: Instant::now(),
+            pending: false,
+            backed_up: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 前回の書き出しから一定時間が経過していれば flush します。毎フレーム呼び出してください。
+    pub fn maybe_flush(&mut self, mmu: &mut Mmu) -> io::Result<bool> {
+        if self.last_flush.elapsed() < self.interval {
+            return Ok(false);
+        }
+        self.flush(mmu, false)
+    }
+
+    /// SRAMが変更されていれば (force の場合は常に) セーブファイルへ書き出します。
+    /// 書き出しを行った場合は true を返します。
+    pub fn flush(&mut self, mmu: &mut Mmu, force: bool) -> io::Result<bool> {
+        self.last_flush = Instant::now();
+        self.pending |= mmu.take_sram_dirty();
+        if !self.pending && !force {
+            return Ok(false);
+        }
+        if !self.backed_up {
+            rotate_backups(&self.path, BACKUP_GENERATIONS)?;
+            self.backed_up = true;
+        }
+        write_atomic(&self.path, &mmu.get_ram_and_rtc_data())?;
+        self.pending = false;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::Apu;
+    use crate::cartridge::Cartridge;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("save-file-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// MBC1+RAM+BATTERY (8KB RAM) のMMU
+    fn battery_mmu() -> Mmu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        Mmu::new(Cartridge::from_bytes(rom).unwrap(), Apu::new(44_100))
+    }
+
+    fn write_sram(mmu: &mut Mmu, value: u8) {
+        mmu.write_byte(0x0000, 0x0A);
+        mmu.write_byte(0xA000, value);
+    }
+
+    #[test]
+    fn write_atomic_replaces_the_file_through_a_temporary() {
+        let dir = temp_dir("atomic");
+        let path = dir.join("game.sav");
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert!(!path_with_suffix(&path, ".tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_backups_keeps_the_newest_generations() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("game.sav");
+        rotate_backups(&path, BACKUP_GENERATIONS).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        for version in ["v1", "v2", "v3", "v4", "v5"] {
+            fs::write(&path, version).unwrap();
+            rotate_backups(&path, BACKUP_GENERATIONS).unwrap();
+        }
+        let backup = |i: usize| fs::read_to_string(path_with_suffix(&path, &format!(".bak{}", i))).unwrap();
+        assert_eq!((backup(1), backup(2), backup(3)), ("v5".to_string(), "v4".to_string(), "v3".to_string()));
+        assert!(!path_with_suffix(&path, ".bak4").exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "v5");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn maybe_flush_waits_for_the_interval_and_changes() {
+        let dir = temp_dir("interval");
+        let path = dir.join("game.sav");
+        let mut mmu = battery_mmu();
+        mmu.take_sram_dirty();
+
+        // 間隔が経っていなければ、変更があっても書き出さない
+        let mut saver = BatterySaver::new(&path, Duration::from_secs(3600));
+        write_sram(&mut mmu, 0x42);
+        assert!(!saver.maybe_flush(&mut mmu).unwrap());
+        assert!(!path.exists());
+
+        // 変更がなければ間隔が経っていても書き出さない
+        let mut saver = BatterySaver::new(&path, Duration::ZERO);
+        mmu.take_sram_dirty();
+        assert!(!saver.maybe_flush(&mut mmu).unwrap());
+        assert!(!path.exists());
+
+        write_sram(&mut mmu, 0x43);
+        assert!(saver.maybe_flush(&mut mmu).unwrap());
+        assert_eq!(fs::read(&path).unwrap()[0], 0x43);
+        assert!(!saver.maybe_flush(&mut mmu).unwrap());
+        // force なら変更がなくても書き出す
+        assert!(saver.flush(&mut mmu, true).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backs_up_only_on_the_first_flush_of_a_session() {
+        let dir = temp_dir("session");
+        let path = dir.join("game.sav");
+        fs::write(&path, b"previous session").unwrap();
+        let mut mmu = battery_mmu();
+        let mut saver = BatterySaver::new(&path, Duration::ZERO);
+        for value in [1, 2, 3] {
+            write_sram(&mut mmu, value);
+            assert!(saver.maybe_flush(&mut mmu).unwrap());
+        }
+        assert_eq!(fs::read(path_with_suffix(&path, ".bak1")).unwrap(), b"previous session");
+        assert!(!path_with_suffix(&path, ".bak2").exists());
+        assert_eq!(fs::read(&path).unwrap()[0], 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
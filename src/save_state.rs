@@ -0,0 +1,177 @@
+// src/save_state.rs
+// セーブステート (エミュレータ全体の状態のスナップショット)
+//
+// 各コンポーネントが save_state / load_state で自分の状態を決まった順に書き出し・読み込む。
+// 値の符号化には bincode を使う。ROMの内容やフロントエンド側の設定 (表示パレット、音声出力、
+// 録音・録画、接続中のシリアル機器) は含めない。
+//
+// ファイル形式: マジック "RGBSTATE", バージョン, ROMのグローバルチェックサム, 本体の長さ, 本体
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use bincode::{Decode, Encode};
+
+use crate::cpu::Cpu;
+
+const MAGIC: &[u8; 8] = b"RGBSTATE";
+const VERSION: u32 = 1;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// コンポーネントの状態を順に書き出すバッファ
+#[derive(Default)]
+pub struct StateWriter {
+    buffer: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn write<T: Encode>(&mut self, value: &T) {
+        // Vec への書き込みは失敗しない
+        bincode::encode_into_std_write(value, &mut self.buffer, bincode::config::standard()).expect("encoding into a Vec cannot fail");
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> { self.buffer }
+}
+
+/// StateWriter で書き出した状態を同じ順に読み込みます。
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn read<T: Decode<()>>(&mut self) -> io::Result<T> {
+        let (value, length) = bincode::decode_from_slice(&self.data[self.position..], bincode::config::standard())
+            .map_err(|e| invalid_data(format!("corrupted save state: {}", e)))?;
+        self.position += length;
+        Ok(value)
+    }
+
+    /// 固定長配列などを、既存の値に上書きする形で読み込みます。
+    pub fn read_into<T: Decode<()>>(&mut self, target: &mut T) -> io::Result<()> {
+        *target = self.read()?;
+        Ok(())
+    }
+}
+
+// ヘッダを除いた本体 (各コンポーネントの状態) を書き出す
+fn save_body(cpu: &Cpu) -> Vec<u8> {
+    let mut body = StateWriter::new();
+    cpu.save_state(&mut body);
+    body.into_bytes()
+}
+
+/// エミュレータの状態をセーブステートのバイト列にします。
+pub fn save(cpu: &Cpu) -> Vec<u8> {
+    let body = save_body(cpu);
+
+    let mut header = StateWriter::new();
+    header.write(&VERSION);
+    header.write(&cpu.mmu.cartridge.header.global_checksum);
+    header.write(&(body.len() as u64));
+    let mut data = MAGIC.to_vec();
+    data.extend(header.into_bytes());
+    data.extend(body);
+    data
+}
+
+/// セーブステートを読み込みます。別のROMのステートや壊れたファイルはエラーになります。
+/// エラーの場合、状態は呼び出し前のまま変わりません。
+pub fn load(cpu: &mut Cpu, data: &[u8]) -> io::Result<()> {
+    if !data.starts_with(MAGIC) {
+        return Err(invalid_data("not a save state file".to_string()));
+    }
+    let mut reader = StateReader::new(&data[MAGIC.len()..]);
+    let version: u32 = reader.read()?;
+    if version != VERSION {
+        return Err(invalid_data(format!("unsupported save state version {} (expected {})", version, VERSION)));
+    }
+    let checksum: u16 = reader.read()?;
+    if checksum != cpu.mmu.cartridge.header.global_checksum {
+        return Err(invalid_data(format!("save state is for another ROM (checksum {:04X}, loaded ROM {:04X})", checksum, cpu.mmu.cartridge.header.global_checksum)));
+    }
+    let body_length: u64 = reader.read()?;
+    let body_start = MAGIC.len() + reader.position;
+    if (data.len() - body_start) as u64 != body_length {
+        return Err(invalid_data("save state is truncated".to_string()));
+    }
+    // SGBの有無やカートリッジRAMの大きさの違いは本体の途中でわかるので、
+    // 失敗したら読み込み前のスナップショットに戻す
+    let snapshot = save_body(cpu);
+    let result = cpu.load_state(&mut StateReader::new(&data[body_start..]));
+    if result.is_err() {
+        cpu.load_state(&mut StateReader::new(&snapshot)).expect("restoring a snapshot of the same emulator cannot fail");
+    }
+    result
+}
+
+pub fn save_to_file<P: AsRef<Path>>(cpu: &Cpu, path: P) -> io::Result<()> {
+    if let Some(dir) = path.as_ref().parent() && !dir.as_os_str().is_empty() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, save(cpu))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(cpu: &mut Cpu, path: P) -> io::Result<()> {
+    load(cpu, &fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::Apu;
+    use crate::cartridge::Cartridge;
+    use crate::mmu::Mmu;
+
+    fn cpu(sgb: bool) -> Cpu {
+        let mut mmu = Mmu::new(Cartridge::from_bytes(vec![0; 0x8000]).unwrap(), Apu::new(44_100));
+        if sgb { mmu.enable_sgb(); }
+        Cpu::new(mmu)
+    }
+
+    #[test]
+    fn round_trips_cpu_and_memory() {
+        let mut original = cpu(false);
+        original.registers.a = 0x42;
+        original.mmu.write_byte(0xC123, 0x99);
+        let data = save(&original);
+
+        let mut loaded = cpu(false);
+        load(&mut loaded, &data).unwrap();
+        assert_eq!(loaded.registers.a, 0x42);
+        assert_eq!(loaded.mmu.read_byte(0xC123), 0x99);
+    }
+
+    #[test]
+    fn failed_load_leaves_state_unchanged() {
+        let mut sgb = cpu(true);
+        sgb.mmu.write_byte(0xC000, 0x11);
+        let data = save(&sgb);
+
+        let mut dmg = cpu(false);
+        dmg.registers.a = 0x42;
+        dmg.mmu.write_byte(0xC000, 0x77);
+        let before = save(&dmg);
+        assert!(load(&mut dmg, &data).is_err());
+        assert_eq!(save(&dmg), before);
+        assert_eq!(dmg.mmu.read_byte(0xC000), 0x77);
+    }
+
+    #[test]
+    fn rejects_foreign_and_truncated_states() {
+        let mut target = cpu(false);
+        let data = save(&target);
+        assert!(load(&mut target, b"not a state").is_err());
+        assert!(load(&mut target, &data[..data.len() - 1]).is_err());
+    }
+}
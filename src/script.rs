@@ -0,0 +1,569 @@
+// src/script.rs
+// Rhaiスクリプトによる自動操作 (QAの自動化、TASのルート検証など)
+//
+// スクリプトは読み込み時に最上位の文を実行し、on_frame / on_exec / on_write でフックを登録する。
+// スクリプトの実行中はCPUをスクリプト側の共有状態に貸し出し (所有権を移す)、登録した関数から
+// メモリ・レジスタ・セーブステートを操作できるようにする。
+//
+// スクリプトから使える関数:
+//   read_u8(addr) read_u16(addr) write_u8(addr, value) write_u16(addr, value)
+//   reg(name) set_reg(name, value) registers()      name: a f b c d e h l af bc de hl sp pc
+//   frame() cycles()
+//   press(buttons, frames) input(steps) hold(buttons) buttons: "A+B"、steps: "Start:2, :30, A:1"
+//   save_state(slot) load_state(slot) save_state_file(path) load_state_file(path)
+//   draw_pixel(x, y, color) draw_rect(x, y, w, h, color) fill_rect(x, y, w, h, color)
+//   draw_text(x, y, text, color) clear_overlay() screenshot(path)
+//   on_frame(fn) on_exec(addr, fn) on_write(addr, fn) on_write(start, end, fn) remove_hook(id)
+//   quit() quit(code) (exit と同じ。スクリプトをその場で止め、実行を終える。0〜255 以外の code は 1 になる)
+//
+// on_exec はその命令を実行する直前、on_write は書き込んだ命令の実行後に呼ばれる (引数は addr, value)。
+// スクリプト自身の書き込みではフックは呼ばれない。
+// 色は 0xRRGGBB (不透明) か 0xAARRGGBB (AAで半透明)。オーバーレイは表示される画面 (SGBでは枠を含む)
+// の座標で描き、フレームを実行する前に消える。
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use rhai::{Engine, EvalAltResult, FnPtr, FuncArgs, Map, Position, AST};
+
+use crate::cpu::Cpu;
+use crate::debug_view::{font_glyph, CHAR_HEIGHT, CHAR_WIDTH};
+use crate::headless;
+use crate::ppu::CYCLES_PER_FRAME;
+use crate::regression;
+use crate::save_state;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+enum DrawCommand {
+    Pixel { x: i64, y: i64, color: u32 },
+    Rect { x: i64, y: i64, width: i64, height: i64, color: u32, fill: bool },
+    Text { x: i64, y: i64, text: String, color: u32 },
+}
+
+/// スクリプトが画面に重ねて描く図形
+#[derive(Default)]
+pub struct Overlay {
+    commands: Vec<DrawCommand>,
+}
+
+/// 0xAARRGGBB の色を重ねます。AAが0なら不透明として扱います。
+fn blend(dst: u32, color: u32) -> u32 {
+    let alpha = color >> 24;
+    if alpha == 0 || alpha == 0xFF {
+        return color & 0x00FF_FFFF;
+    }
+    let mix = |shift: u32| {
+        let (d, s) = ((dst >> shift) & 0xFF, (color >> shift) & 0xFF);
+        ((s * alpha + d * (255 - alpha)) / 255) << shift
+    };
+    mix(16) | mix(8) | mix(0)
+}
+
+impl Overlay {
+    pub fn is_empty(&self) -> bool { self.commands.is_empty() }
+
+    pub fn clear(&mut self) { self.commands.clear(); }
+
+    /// 画面 (0x00RRGGBB、幅 `width`) に重ねて描きます。画面の外にはみ出た部分は描きません。
+    pub fn draw(&self, buffer: &mut [u32], width: usize, height: usize) {
+        let mut plot = |x: i64, y: i64, color: u32| {
+            if x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height {
+                let pixel = &mut buffer[y as usize * width + x as usize];
+                *pixel = blend(*pixel, color);
+            }
+        };
+        for command in &self.commands {
+            match command {
+                DrawCommand::Pixel { x, y, color } => plot(*x, *y, *color),
+                DrawCommand::Rect { x, y, width: w, height: h, color, fill } => {
+                    // 画面内に切り詰めてから回すので、巨大な矩形でも画面の大きさ分しか描かない
+                    let (right, bottom) = (x.saturating_add(*w), y.saturating_add(*h));
+                    for py in (*y).max(0)..bottom.min(height as i64) {
+                        for px in (*x).max(0)..right.min(width as i64) {
+                            if *fill || px == *x || py == *y || px == right - 1 || py == bottom - 1 {
+                                plot(px, py, *color);
+                            }
+                        }
+                    }
+                }
+                DrawCommand::Text { x, y, text, color } => {
+                    for (i, c) in text.chars().enumerate() {
+                        let Some(glyph) = font_glyph(c) else { continue; };
+                        let left = x + (i * CHAR_WIDTH) as i64;
+                        for (row, bits) in glyph.iter().enumerate().take(CHAR_HEIGHT) {
+                            for column in 0..CHAR_WIDTH {
+                                if (bits >> (7 - column)) & 1 == 1 {
+                                    plot(left + column as i64, y + row as i64, *color);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum HookKind {
+    Frame,
+    Exec(u16),
+    Write(u16, u16),
+}
+
+#[derive(Clone)]
+struct Hook {
+    id: i64,
+    kind: HookKind,
+    func: FnPtr,
+}
+
+/// スクリプトに登録した関数とフックの間で共有する状態
+struct ScriptContext {
+    // スクリプトの実行中だけ貸し出されるエミュレータのCPU
+    cpu: Option<Box<Cpu>>,
+    hooks: Vec<Hook>,
+    next_hook_id: i64,
+    hooks_changed: bool,
+    input_queue: VecDeque<u8>,
+    held_input: u8,
+    states: HashMap<String, Vec<u8>>,
+    overlay: Overlay,
+    frame: u64,
+    exit_code: Option<u8>,
+}
+
+impl ScriptContext {
+    fn cpu(&self) -> ScriptResult<&Cpu> {
+        self.cpu.as_deref().ok_or_else(|| "the emulator is not available here".into())
+    }
+
+    fn cpu_mut(&mut self) -> ScriptResult<&mut Cpu> {
+        self.cpu.as_deref_mut().ok_or_else(|| "the emulator is not available here".into())
+    }
+
+    fn add_hook(&mut self, kind: HookKind, func: FnPtr) -> i64 {
+        let id = self.next_hook_id;
+        self.next_hook_id += 1;
+        self.hooks.push(Hook { id, kind, func });
+        self.hooks_changed = true;
+        id
+    }
+}
+
+fn address(value: i64) -> ScriptResult<u16> {
+    u16::try_from(value).map_err(|_| format!("address out of range: {}", value).into())
+}
+
+fn color(value: i64) -> u32 {
+    value as u32
+}
+
+fn parse_buttons(buttons: &str) -> ScriptResult<u8> {
+    // ボタン名だけのステップは1フレーム分になる
+    let frames = regression::parse_input_script(&buttons.replace(' ', ""))?;
+    Ok(frames.first().copied().unwrap_or(0))
+}
+
+fn get_register(cpu: &Cpu, name: &str) -> ScriptResult<i64> {
+    let r = &cpu.registers;
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "a" => r.a as i64, "f" => r.f as i64, "b" => r.b as i64, "c" => r.c as i64,
+        "d" => r.d as i64, "e" => r.e as i64, "h" => r.h as i64, "l" => r.l as i64,
+        "af" => r.af() as i64, "bc" => r.bc() as i64, "de" => r.de() as i64, "hl" => r.hl() as i64,
+        "sp" => r.sp as i64, "pc" => r.pc as i64,
+        _ => return Err(format!("unknown register '{}'", name).into()),
+    })
+}
+
+fn set_register(cpu: &mut Cpu, name: &str, value: i64) -> ScriptResult<()> {
+    let r = &mut cpu.registers;
+    let (byte, word) = (value as u8, value as u16);
+    match name.to_ascii_lowercase().as_str() {
+        "a" => r.a = byte, "f" => r.f = byte & 0xF0, "b" => r.b = byte, "c" => r.c = byte,
+        "d" => r.d = byte, "e" => r.e = byte, "h" => r.h = byte, "l" => r.l = byte,
+        "af" => r.set_af(word), "bc" => r.set_bc(word), "de" => r.set_de(word), "hl" => r.set_hl(word),
+        "sp" => r.sp = word, "pc" => r.pc = word,
+        _ => return Err(format!("unknown register '{}'", name).into()),
+    }
+    Ok(())
+}
+
+fn io_error(e: io::Error) -> Box<EvalAltResult> {
+    e.to_string().into()
+}
+
+/// 関数をエンジンに登録します。
+fn register_api(engine: &mut Engine, context: &Rc<RefCell<ScriptContext>>) {
+    let ctx = context.clone();
+    engine.register_fn("read_u8", move |addr: i64| -> ScriptResult<i64> { Ok(ctx.borrow().cpu()?.mmu.read_byte(address(addr)?) as i64) });
+    let ctx = context.clone();
+    engine.register_fn("read_u16", move |addr: i64| -> ScriptResult<i64> { Ok(ctx.borrow().cpu()?.mmu.read_u16(address(addr)?) as i64) });
+    let ctx = context.clone();
+    engine.register_fn("write_u8", move |addr: i64, value: i64| -> ScriptResult<()> {
+        ctx.borrow_mut().cpu_mut()?.mmu.write_byte(address(addr)?, value as u8);
+        Ok(())
+    });
+    let ctx = context.clone();
+    engine.register_fn("write_u16", move |addr: i64, value: i64| -> ScriptResult<()> {
+        ctx.borrow_mut().cpu_mut()?.mmu.write_u16(address(addr)?, value as u16);
+        Ok(())
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("reg", move |name: &str| get_register(ctx.borrow().cpu()?, name));
+    let ctx = context.clone();
+    engine.register_fn("set_reg", move |name: &str, value: i64| set_register(ctx.borrow_mut().cpu_mut()?, name, value));
+    let ctx = context.clone();
+    engine.register_fn("registers", move || -> ScriptResult<Map> {
+        let ctx = ctx.borrow();
+        let cpu = ctx.cpu()?;
+        Ok(["a", "f", "b", "c", "d", "e", "h", "l", "sp", "pc"].into_iter()
+            .map(|name| (name.into(), get_register(cpu, name).unwrap_or(0).into()))
+            .collect())
+    });
+    let ctx = context.clone();
+    engine.register_fn("frame", move || ctx.borrow().frame as i64);
+    let ctx = context.clone();
+    engine.register_fn("cycles", move || -> ScriptResult<i64> { Ok(ctx.borrow().cpu()?.total_clock_cycles as i64) });
+
+    let ctx = context.clone();
+    engine.register_fn("press", move |buttons: &str, frames: i64| -> ScriptResult<()> {
+        let mask = parse_buttons(buttons)?;
+        ctx.borrow_mut().input_queue.extend(std::iter::repeat_n(mask, frames.max(0) as usize));
+        Ok(())
+    });
+    let ctx = context.clone();
+    engine.register_fn("input", move |steps: &str| -> ScriptResult<()> {
+        ctx.borrow_mut().input_queue.extend(regression::parse_input_script(steps)?);
+        Ok(())
+    });
+    let ctx = context.clone();
+    engine.register_fn("hold", move |buttons: &str| -> ScriptResult<()> {
+        ctx.borrow_mut().held_input = parse_buttons(buttons)?;
+        Ok(())
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("save_state", move |slot: &str| -> ScriptResult<()> {
+        let mut ctx = ctx.borrow_mut();
+        let data = save_state::save(ctx.cpu()?);
+        ctx.states.insert(slot.to_string(), data);
+        Ok(())
+    });
+    let ctx = context.clone();
+    engine.register_fn("load_state", move |slot: &str| -> ScriptResult<()> {
+        let mut ctx = ctx.borrow_mut();
+        let data = ctx.states.get(slot).cloned().ok_or_else(|| format!("no save state in slot '{}'", slot))?;
+        save_state::load(ctx.cpu_mut()?, &data).map_err(io_error)
+    });
+    let ctx = context.clone();
+    engine.register_fn("save_state_file", move |path: &str| -> ScriptResult<()> {
+        save_state::save_to_file(ctx.borrow().cpu()?, path).map_err(io_error)
+    });
+    let ctx = context.clone();
+    engine.register_fn("load_state_file", move |path: &str| -> ScriptResult<()> {
+        save_state::load_from_file(ctx.borrow_mut().cpu_mut()?, path).map_err(io_error)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("draw_pixel", move |x: i64, y: i64, c: i64| {
+        ctx.borrow_mut().overlay.commands.push(DrawCommand::Pixel { x, y, color: color(c) });
+    });
+    let ctx = context.clone();
+    engine.register_fn("draw_rect", move |x: i64, y: i64, width: i64, height: i64, c: i64| {
+        ctx.borrow_mut().overlay.commands.push(DrawCommand::Rect { x, y, width, height, color: color(c), fill: false });
+    });
+    let ctx = context.clone();
+    engine.register_fn("fill_rect", move |x: i64, y: i64, width: i64, height: i64, c: i64| {
+        ctx.borrow_mut().overlay.commands.push(DrawCommand::Rect { x, y, width, height, color: color(c), fill: true });
+    });
+    let ctx = context.clone();
+    engine.register_fn("draw_text", move |x: i64, y: i64, text: &str, c: i64| {
+        ctx.borrow_mut().overlay.commands.push(DrawCommand::Text { x, y, text: text.to_string(), color: color(c) });
+    });
+    let ctx = context.clone();
+    engine.register_fn("clear_overlay", move || ctx.borrow_mut().overlay.clear());
+    let ctx = context.clone();
+    engine.register_fn("screenshot", move |path: &str| -> ScriptResult<()> {
+        let ctx = ctx.borrow();
+        let (mut pixels, width, height) = headless::render_screen(ctx.cpu()?);
+        ctx.overlay.draw(&mut pixels, width, height);
+        regression::save_png(&headless::to_image(&pixels, width, height), path).map_err(io_error)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("on_frame", move |func: FnPtr| ctx.borrow_mut().add_hook(HookKind::Frame, func));
+    let ctx = context.clone();
+    engine.register_fn("on_exec", move |addr: i64, func: FnPtr| -> ScriptResult<i64> {
+        Ok(ctx.borrow_mut().add_hook(HookKind::Exec(address(addr)?), func))
+    });
+    let ctx = context.clone();
+    engine.register_fn("on_write", move |addr: i64, func: FnPtr| -> ScriptResult<i64> {
+        let addr = address(addr)?;
+        Ok(ctx.borrow_mut().add_hook(HookKind::Write(addr, addr), func))
+    });
+    let ctx = context.clone();
+    engine.register_fn("on_write", move |start: i64, end: i64, func: FnPtr| -> ScriptResult<i64> {
+        Ok(ctx.borrow_mut().add_hook(HookKind::Write(address(start)?, address(end)?), func))
+    });
+    let ctx = context.clone();
+    engine.register_fn("remove_hook", move |id: i64| {
+        let mut ctx = ctx.borrow_mut();
+        ctx.hooks.retain(|hook| hook.id != id);
+        ctx.hooks_changed = true;
+    });
+
+    // Rhaiの exit は最上位では普通の終了になってしまうので、終了コードを記録してから止める
+    for name in ["quit", "exit"] {
+        let ctx = context.clone();
+        engine.register_fn(name, move || -> ScriptResult<()> { stop_script(&ctx, 0) });
+        let ctx = context.clone();
+        engine.register_fn(name, move |code: i64| -> ScriptResult<()> { stop_script(&ctx, code) });
+    }
+}
+
+fn stop_script(context: &Rc<RefCell<ScriptContext>>, code: i64) -> ScriptResult<()> {
+    // プロセスの終了コードは 0〜255 なので、範囲外 (256 や負の値) は切り詰めずに失敗扱いにする
+    context.borrow_mut().exit_code = Some(u8::try_from(code).unwrap_or(1));
+    Err(Box::new(EvalAltResult::Exit(code.into(), Position::NONE)))
+}
+
+pub struct ScriptHost {
+    engine: Engine,
+    ast: AST,
+    context: Rc<RefCell<ScriptContext>>,
+    // on_exec を登録したアドレス (命令ごとに調べるので共有状態とは別に持つ)
+    exec_addresses: HashSet<u16>,
+    watching_writes: bool,
+}
+
+impl ScriptHost {
+    /// スクリプトを読み込み、`cpu` を貸し出して最上位の文を実行します。`cpu` は成否にかかわらず返します。
+    pub fn load<P: AsRef<Path>>(path: P, cpu: Box<Cpu>) -> (io::Result<Self>, Box<Cpu>) {
+        let path = path.as_ref();
+        let context = Rc::new(RefCell::new(ScriptContext {
+            cpu: None,
+            hooks: Vec::new(),
+            next_hook_id: 1,
+            hooks_changed: false,
+            input_queue: VecDeque::new(),
+            held_input: 0,
+            states: HashMap::new(),
+            overlay: Overlay::default(),
+            frame: 0,
+            exit_code: None,
+        }));
+        let mut engine = Engine::new();
+        register_api(&mut engine, &context);
+        let ast = match engine.compile_file(path.to_path_buf()) {
+            Ok(ast) => ast,
+            Err(e) => return (Err(io::Error::new(io::ErrorKind::InvalidData, format!("failed to load script {}: {}", path.display(), e))), cpu),
+        };
+        let mut host = Self { engine, ast, context, exec_addresses: HashSet::new(), watching_writes: false };
+
+        host.lend(cpu);
+        let result = host.engine.run_ast(&host.ast);
+        let cpu = host.give_back();
+        if let Err(e) = result
+            && let Err(message) = host.handle_error(*e, "script") {
+            return (Err(io::Error::other(format!("{}: {}", path.display(), message))), cpu);
+        }
+        (Ok(host), cpu)
+    }
+
+    fn lend(&self, cpu: Box<Cpu>) {
+        self.context.borrow_mut().cpu = Some(cpu);
+    }
+
+    /// CPUを返してもらい、フックの変更をMMUの書き込みの記録に反映します。
+    fn give_back(&mut self) -> Box<Cpu> {
+        let mut ctx = self.context.borrow_mut();
+        let mut cpu = ctx.cpu.take().expect("the CPU is lent while a script runs");
+        if ctx.hooks_changed {
+            ctx.hooks_changed = false;
+            self.exec_addresses = ctx.hooks.iter().filter_map(|hook| match hook.kind { HookKind::Exec(addr) => Some(addr), _ => None }).collect();
+            let ranges: Vec<(u16, u16)> = ctx.hooks.iter().filter_map(|hook| match hook.kind { HookKind::Write(start, end) => Some((start, end)), _ => None }).collect();
+            self.watching_writes = !ranges.is_empty();
+            cpu.mmu.set_write_watches(ranges);
+        } else {
+            // スクリプト自身の書き込みではフックを呼ばない
+            cpu.mmu.take_watched_writes();
+        }
+        cpu
+    }
+
+    /// quit / exit による停止はエラーとしないで、それ以外はエラーの説明を返します。
+    fn handle_error(&self, error: EvalAltResult, place: &str) -> Result<(), String> {
+        match error {
+            EvalAltResult::Exit(..) => Ok(()),
+            e => Err(format!("error in {}: {}", place, e)),
+        }
+    }
+
+    /// 条件に合うフックを順に呼びます。エラーになったフックは取り除きます。
+    fn call_hooks<A: FuncArgs + Clone>(&mut self, cpu: Box<Cpu>, matches: impl Fn(HookKind) -> bool, args: A, place: &str) -> Box<Cpu> {
+        let hooks: Vec<Hook> = self.context.borrow().hooks.iter().filter(|hook| matches(hook.kind)).cloned().collect();
+        if hooks.is_empty() {
+            return cpu;
+        }
+        self.lend(cpu);
+        for hook in hooks {
+            if let Err(e) = hook.func.call::<rhai::Dynamic>(&self.engine, &self.ast, args.clone())
+                && let Err(message) = self.handle_error(*e, place) {
+                eprintln!("Script {}; hook removed", message);
+                let mut ctx = self.context.borrow_mut();
+                ctx.hooks.retain(|h| h.id != hook.id);
+                ctx.hooks_changed = true;
+            }
+        }
+        self.give_back()
+    }
+
+    /// 次のフレームで押すボタン (input::BUTTONS のビット順)。1フレームに1回呼びます。
+    pub fn next_input(&mut self) -> u8 {
+        let mut ctx = self.context.borrow_mut();
+        ctx.input_queue.pop_front().unwrap_or(0) | ctx.held_input
+    }
+
+    /// フックを呼びながら1フレーム実行します (`Cpu::run_until_frame` の代わり)。
+    pub fn run_frame(&mut self, mut cpu: Box<Cpu>) -> Box<Cpu> {
+        self.context.borrow_mut().overlay.clear();
+        if self.exec_addresses.is_empty() && !self.watching_writes {
+            cpu.run_until_frame();
+        } else {
+            cpu.mmu.ppu.frame_ready = false;
+            let mut cycles: u32 = 0;
+            while !cpu.mmu.ppu.frame_ready {
+                // 割り込みへの分岐やHALT中のステップでは命令を実行しないので、フックも呼ばない
+                let pc = cpu.registers.pc;
+                if self.exec_addresses.contains(&pc) && cpu.executes_next_step() {
+                    cpu = self.call_hooks(cpu, |kind| matches!(kind, HookKind::Exec(addr) if addr == pc), (pc as i64,), "exec hook");
+                }
+                cycles += cpu.step() as u32;
+                if self.watching_writes {
+                    for (addr, value) in cpu.mmu.take_watched_writes() {
+                        let matches = |kind| matches!(kind, HookKind::Write(start, end) if (start..=end).contains(&addr));
+                        cpu = self.call_hooks(cpu, matches, (addr as i64, value as i64), "write hook");
+                    }
+                }
+                if cycles >= CYCLES_PER_FRAME && !cpu.mmu.ppu.is_lcd_enabled() { break; }
+            }
+        }
+        self.context.borrow_mut().frame += 1;
+        self.call_hooks(cpu, |kind| matches!(kind, HookKind::Frame), (), "frame hook")
+    }
+
+    /// スクリプトが quit か exit を呼んでいれば、その終了コード
+    pub fn exit_code(&self) -> Option<u8> { self.context.borrow().exit_code }
+
+    /// スクリプトのオーバーレイを画面に重ねます。
+    pub fn draw_overlay(&self, buffer: &mut [u32], width: usize, height: usize) {
+        self.context.borrow().overlay.draw(buffer, width, height);
+    }
+
+    pub fn has_overlay(&self) -> bool { !self.context.borrow().overlay.is_empty() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::Apu;
+    use crate::cartridge::Cartridge;
+    use crate::mmu::Mmu;
+
+    /// $0100 で BC を増やし続け、VBlank割り込み ($0040) はすぐ RETI で戻るROMのCPU
+    fn looping_cpu() -> Box<Cpu> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0040] = 0xD9; // RETI
+        rom[0x0100..0x0103].copy_from_slice(&[0x03, 0x18, 0xFD]); // INC BC; JR $0100
+        let mut cpu = Box::new(Cpu::new(Mmu::new(Cartridge::from_bytes(rom).unwrap(), Apu::new(headless::HEADLESS_SAMPLE_RATE))));
+        cpu.registers.set_bc(0);
+        cpu.mmu.write_byte(0xFFFF, 0x01);
+        cpu.ime = true;
+        cpu
+    }
+
+    fn load_script(name: &str, source: &str, cpu: Box<Cpu>) -> (ScriptHost, Box<Cpu>) {
+        let path = std::env::temp_dir().join(format!("script-test-{}-{}.rhai", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let (host, cpu) = ScriptHost::load(&path, cpu);
+        std::fs::remove_file(&path).unwrap();
+        (host.unwrap(), cpu)
+    }
+
+    #[test]
+    fn blends_with_alpha() {
+        assert_eq!(blend(0x123456, 0x00FF_0000), 0xFF0000);
+        assert_eq!(blend(0x123456, 0xFF00_FF00), 0x00FF00);
+        assert_eq!(blend(0x000000, 0x80FF_FFFF), 0x808080);
+        assert_eq!(blend(0xFFFFFF, 0x8000_0000), 0x7F7F7F);
+    }
+
+    #[test]
+    fn parses_button_names() {
+        assert_eq!(parse_buttons("A").unwrap(), 1 << 4);
+        assert_eq!(parse_buttons("a + start").unwrap(), 1 << 4 | 1 << 7);
+        assert_eq!(parse_buttons("").unwrap(), 0);
+        assert!(parse_buttons("Turbo").is_err());
+    }
+
+    #[test]
+    fn overlay_clips_rectangles_to_screen() {
+        let overlay = Overlay { commands: vec![
+            DrawCommand::Rect { x: -5, y: 2, width: 8, height: i64::MAX, color: 0xFFFFFF, fill: false },
+            DrawCommand::Rect { x: 3, y: -1, width: 2, height: 2, color: 0x00FF00, fill: true },
+        ] };
+        let mut buffer = vec![0; 4 * 4];
+        overlay.draw(&mut buffer, 4, 4);
+        // 左辺は画面外なので描かれず、右辺 (x=2) と上辺 (y=2) だけが見える
+        assert_eq!(buffer, vec![
+            0, 0, 0, 0x00FF00,
+            0, 0, 0, 0,
+            0xFFFFFF, 0xFFFFFF, 0xFFFFFF, 0,
+            0, 0, 0xFFFFFF, 0,
+        ]);
+    }
+
+    #[test]
+    fn exec_hook_fires_once_per_executed_instruction() {
+        let source = "on_exec(0x100, |pc| write_u16(0xC000, read_u16(0xC000) + 1));\non_frame(|| write_u8(0xC002, frame()));";
+        let (mut host, mut cpu) = load_script("exec", source, looping_cpu());
+        for _ in 0..3 {
+            cpu = host.run_frame(cpu);
+        }
+        let executed = cpu.registers.bc();
+        assert!(executed > 0);
+        // 割り込みに分岐したステップでは、戻ってきて実際に実行するときだけ呼ばれる
+        assert_eq!(cpu.mmu.read_u16(0xC000), executed);
+        assert_eq!(cpu.mmu.read_byte(0xC002), 3);
+    }
+
+    #[test]
+    fn write_hook_sees_cpu_writes_only() {
+        let mut rom = vec![0; 0x8000];
+        // LD A,$42; LD ($C010),A; JR -2
+        rom[0x0100..0x0107].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x10, 0xC0, 0x18, 0xFE]);
+        let cpu = Box::new(Cpu::new(Mmu::new(Cartridge::from_bytes(rom).unwrap(), Apu::new(headless::HEADLESS_SAMPLE_RATE))));
+        let source = "write_u8(0xC010, 1);\non_write(0xC010, |addr, value| write_u8(0xC011, read_u8(0xC011) + value));";
+        let (mut host, cpu) = load_script("write", source, cpu);
+        let cpu = host.run_frame(cpu);
+        assert_eq!(cpu.mmu.read_byte(0xC010), 0x42);
+        assert_eq!(cpu.mmu.read_byte(0xC011), 0x42);
+    }
+
+    #[test]
+    fn exit_codes_outside_a_byte_become_failure() {
+        for (code, expected) in [("", 0), ("7", 7), ("255", 255), ("256", 1), ("-1", 1), ("4294967296", 1)] {
+            let source = format!("on_frame(|| quit({}));", code);
+            let (mut host, cpu) = load_script("quit", &source, looping_cpu());
+            assert_eq!(host.exit_code(), None);
+            host.run_frame(cpu);
+            assert_eq!(host.exit_code(), Some(expected), "quit({})", code);
+        }
+    }
+}
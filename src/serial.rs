@@ -0,0 +1,164 @@
+// src/serial.rs
+// シリアル通信ポート (SB: $FF01, SC: $FF02)
+
+use std::io;
+
+use crate::save_state::{StateReader, StateWriter};
+
+// 内部クロック (8192Hz) で1バイト (8ビット) を転送するのに要するTサイクル数
+const TRANSFER_CYCLES: u32 = 8 * 512;
+
+/// リンクケーブルの先に接続される周辺機器
+pub trait SerialDevice {
+    /// GB側が送信したバイトを受け取り、同時にデバイス側から送り返すバイトを返します。
+    fn exchange(&mut self, byte: u8) -> u8;
+    /// 切断時 (エミュレータ終了時など) に呼ばれます。未出力のデータがあれば書き出してください。
+    fn disconnect(&mut self) {}
+}
+
+pub struct Serial {
+    pub sb: u8,
+    pub sc: u8,
+    transfer_cycles_remaining: u32,
+    device: Option<Box<dyn SerialDevice>>,
+    interrupt_request: bool,
+}
+
+impl Default for Serial {
+    fn default() -> Self { Self::new() }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self { sb: 0, sc: 0, transfer_cycles_remaining: 0, device: None, interrupt_request: false }
+    }
+
+    /// 転送の状態を書き出します。接続中の機器は含みません。
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.sb);
+        w.write(&self.sc);
+        w.write(&self.transfer_cycles_remaining);
+        w.write(&self.interrupt_request);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.sb)?;
+        r.read_into(&mut self.sc)?;
+        r.read_into(&mut self.transfer_cycles_remaining)?;
+        r.read_into(&mut self.interrupt_request)?;
+        Ok(())
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.disconnect();
+        self.device = Some(device);
+    }
+
+    pub fn disconnect(&mut self) {
+        if let Some(mut device) = self.device.take() {
+            device.disconnect();
+        }
+    }
+
+    pub fn read_sb(&self) -> u8 { self.sb }
+    pub fn write_sb(&mut self, value: u8) { self.sb = value; }
+    pub fn read_sc(&self) -> u8 { self.sc | 0x7E }
+
+    pub fn write_sc(&mut self, value: u8) {
+        self.sc = value & 0x81;
+        // 内部クロックでの転送開始
+        if value == 0x81 {
+            // 何も接続されていない場合はテストROM等の出力として標準出力へ表示する
+            if self.device.is_none() { print!("{}", self.sb as char); }
+            self.transfer_cycles_remaining = TRANSFER_CYCLES;
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        if self.transfer_cycles_remaining == 0 { return; }
+        self.transfer_cycles_remaining = self.transfer_cycles_remaining.saturating_sub(cycles as u32);
+        if self.transfer_cycles_remaining == 0 {
+            // 未接続時はラインがプルアップされているため $FF を受信する
+            self.sb = match &mut self.device {
+                Some(device) => device.exchange(self.sb),
+                None => 0xFF,
+            };
+            self.sc &= 0x7F;
+            self.interrupt_request = true;
+        }
+    }
+
+    pub fn take_interrupt_request(&mut self) -> bool { let req = self.interrupt_request; self.interrupt_request = false; req }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::apu::Apu;
+    use crate::cartridge::Cartridge;
+    use crate::mmu::Mmu;
+
+    // 受け取ったバイトを記録し、反転して返す機器
+    struct Recorder(Rc<RefCell<Vec<u8>>>);
+
+    impl SerialDevice for Recorder {
+        fn exchange(&mut self, byte: u8) -> u8 {
+            self.0.borrow_mut().push(byte);
+            !byte
+        }
+    }
+
+    #[test]
+    fn internal_clock_transfer_completes_after_eight_bits() {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let mut serial = Serial::new();
+        serial.connect(Box::new(Recorder(received.clone())));
+        serial.write_sb(0x5A);
+        serial.write_sc(0x81);
+        assert_eq!(serial.read_sc(), 0xFF);
+        for _ in 0..TRANSFER_CYCLES / 4 - 1 {
+            serial.tick(4);
+        }
+        assert!(received.borrow().is_empty());
+        assert!(!serial.take_interrupt_request());
+        serial.tick(4);
+        assert_eq!(*received.borrow(), vec![0x5A]);
+        assert_eq!(serial.read_sb(), 0xA5);
+        assert_eq!(serial.read_sc(), 0x7F);
+        assert!(serial.take_interrupt_request());
+        assert!(!serial.take_interrupt_request());
+    }
+
+    #[test]
+    fn external_clock_waits_for_the_partner() {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(Recorder(Rc::new(RefCell::new(Vec::new())))));
+        serial.write_sb(0x12);
+        serial.write_sc(0x80);
+        for _ in 0..TRANSFER_CYCLES {
+            serial.tick(4);
+        }
+        assert_eq!(serial.read_sb(), 0x12);
+        assert_eq!(serial.read_sc(), 0xFE);
+        assert!(!serial.take_interrupt_request());
+    }
+
+    #[test]
+    fn finished_transfer_raises_the_serial_interrupt() {
+        let mut mmu = Mmu::new(Cartridge::from_bytes(vec![0; 0x8000]).unwrap(), Apu::new(44_100));
+        mmu.serial.connect(Box::new(Recorder(Rc::new(RefCell::new(Vec::new())))));
+        mmu.write_byte(0xFF0F, 0x00);
+        mmu.write_byte(0xFF01, 0x01);
+        mmu.write_byte(0xFF02, 0x81);
+        let mut cycles = 0;
+        while mmu.read_byte(0xFF0F) & 0x08 == 0 {
+            mmu.tick_components(4);
+            cycles += 4;
+            assert!(cycles <= TRANSFER_CYCLES, "no serial interrupt");
+        }
+        assert_eq!(cycles, TRANSFER_CYCLES);
+        assert_eq!(mmu.read_byte(0xFF01), 0xFE);
+    }
+}
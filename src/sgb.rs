@@ -0,0 +1,601 @@
+// src/sgb.rs
+// スーパーゲームボーイ (SGB) のコマンドパケット受信とボーダー/パレット描画
+
+use std::io;
+
+use bincode::{Decode, Encode};
+
+use crate::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::save_state::{StateReader, StateWriter};
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+// 256x224 の中でゲーム画面 (160x144) が置かれる位置
+const GB_SCREEN_X: usize = 48;
+const GB_SCREEN_Y: usize = 40;
+
+const TILES_X: usize = SCREEN_WIDTH / 8;  // 20
+const TILES_Y: usize = SCREEN_HEIGHT / 8; // 18
+const PACKET_SIZE: usize = 16;
+const MAX_PACKETS: usize = 7;
+const TRANSFER_SIZE: usize = 4096;
+const ATTRIBUTE_FILE_SIZE: usize = 90;
+const ATTRIBUTE_FILE_COUNT: usize = 45;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_HEIGHT: usize = 28;
+
+// SGB起動直後のパレット (システムパレット 1-A 相当)
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum MaskMode { None, Freeze, Black, Color0 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+enum Transfer { Palettes, BorderTiles(usize), BorderMap, Attributes }
+
+pub struct Sgb {
+    // --- パケット受信 ---
+    packets: [u8; PACKET_SIZE * MAX_PACKETS],
+    packet_count: usize,   // 受信済みパケット数
+    expected_packets: usize,
+    bit_index: usize,      // 現在のパケット内で受信したビット数 (128ビット + ストップビット)
+    receiving: bool,
+    ready_for_pulse: bool,
+    // --- マルチプレイヤー (MLT_REQ) ---
+    player_count: u8,
+    current_player: u8,
+    mlt_lock: bool,
+    // --- パレット/属性 ---
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attribute_map: [u8; TILES_X * TILES_Y],
+    attribute_files: Vec<u8>,
+    // --- ボーダー ---
+    border_tiles: Vec<u8>,
+    border_map: [u16; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT],
+    border_palettes: [[u16; 16]; 4],
+    pending_transfer: Option<Transfer>,
+    mask: MaskMode,
+    frozen_screen: Vec<u8>,
+}
+
+fn rgb555_to_u32(color: u16) -> u32 {
+    let expand = |c: u16| -> u32 { let c = (c & 0x1F) as u32; (c << 3) | (c >> 2) };
+    (expand(color) << 16) | (expand(color >> 5) << 8) | expand(color >> 10)
+}
+
+impl Default for Sgb {
+    fn default() -> Self { Self::new() }
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            packets: [0; PACKET_SIZE * MAX_PACKETS],
+            packet_count: 0,
+            expected_packets: 0,
+            bit_index: 0,
+            receiving: false,
+            ready_for_pulse: false,
+            player_count: 1,
+            current_player: 0,
+            mlt_lock: false,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![[0; 4]; 512],
+            attribute_map: [0; TILES_X * TILES_Y],
+            attribute_files: vec![0; ATTRIBUTE_FILE_SIZE * ATTRIBUTE_FILE_COUNT],
+            border_tiles: vec![0; 256 * 32],
+            border_map: [0; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT],
+            border_palettes: [[0; 16]; 4],
+            pending_transfer: None,
+            mask: MaskMode::None,
+            frozen_screen: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.packets);
+        w.write(&self.packet_count);
+        w.write(&self.expected_packets);
+        w.write(&self.bit_index);
+        w.write(&self.receiving);
+        w.write(&self.ready_for_pulse);
+        w.write(&self.player_count);
+        w.write(&self.current_player);
+        w.write(&self.mlt_lock);
+        w.write(&self.palettes);
+        w.write(&self.system_palettes);
+        w.write(&self.attribute_map);
+        w.write(&self.attribute_files);
+        w.write(&self.border_tiles);
+        w.write(&self.border_map);
+        w.write(&self.border_palettes);
+        w.write(&self.pending_transfer);
+        w.write(&self.mask);
+        w.write(&self.frozen_screen);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.packets)?;
+        r.read_into(&mut self.packet_count)?;
+        r.read_into(&mut self.expected_packets)?;
+        r.read_into(&mut self.bit_index)?;
+        r.read_into(&mut self.receiving)?;
+        r.read_into(&mut self.ready_for_pulse)?;
+        r.read_into(&mut self.player_count)?;
+        r.read_into(&mut self.current_player)?;
+        r.read_into(&mut self.mlt_lock)?;
+        r.read_into(&mut self.palettes)?;
+        r.read_into(&mut self.system_palettes)?;
+        r.read_into(&mut self.attribute_map)?;
+        r.read_into(&mut self.attribute_files)?;
+        r.read_into(&mut self.border_tiles)?;
+        r.read_into(&mut self.border_map)?;
+        r.read_into(&mut self.border_palettes)?;
+        r.read_into(&mut self.pending_transfer)?;
+        r.read_into(&mut self.mask)?;
+        r.read_into(&mut self.frozen_screen)?;
+        Ok(())
+    }
+
+    pub fn player_count(&self) -> u8 { self.player_count }
+    pub fn current_player(&self) -> u8 { self.current_player }
+    pub fn mask_mode(&self) -> MaskMode { self.mask }
+
+    // P1 ($FF00) への書き込み。P14/P15 のパルスとしてパケットのビットを受信する
+    // - P14=0,P15=0: リセット (パケット開始)
+    // - P14=0,P15=1: ビット "0"
+    // - P14=1,P15=0: ビット "1"
+    // - P14=1,P15=1: 次のパルス待ち
+    pub fn write_p1(&mut self, value: u8) {
+        match (value >> 4) & 0x03 {
+            0b00 => {
+                self.receiving = true;
+                self.ready_for_pulse = false;
+                self.bit_index = 0;
+                let start = self.packet_count * PACKET_SIZE;
+                if start < self.packets.len() {
+                    self.packets[start..start + PACKET_SIZE].fill(0);
+                }
+            }
+            0b11 => {
+                self.ready_for_pulse = true;
+                // P15 の立ち上がりで読み出し対象のコントローラを切り替える
+                if self.player_count > 1 && !self.mlt_lock {
+                    self.current_player = (self.current_player + 1) % self.player_count;
+                    self.mlt_lock = true;
+                }
+            }
+            bits => {
+                if bits == 0b01 { self.mlt_lock = false; }
+                if !self.receiving || !self.ready_for_pulse { return; }
+                self.ready_for_pulse = false;
+                let bit = bits == 0b01;
+                if self.bit_index < PACKET_SIZE * 8 {
+                    if bit {
+                        let byte = self.packet_count * PACKET_SIZE + self.bit_index / 8;
+                        self.packets[byte] |= 1 << (self.bit_index % 8);
+                    }
+                    self.bit_index += 1;
+                } else {
+                    // ストップビット (0) でパケット完了
+                    self.receiving = false;
+                    if !bit { self.finish_packet(); }
+                }
+            }
+        }
+    }
+
+    /// マルチプレイヤー有効時の P1 読み出し値を返します。
+    /// `joypad_value` は通常のジョイパッド読み出し値 (Bit 5-4 に選択状態を含む) です。
+    pub fn read_p1(&self, joypad_value: u8) -> u8 {
+        if self.player_count <= 1 {
+            return joypad_value;
+        }
+        if (joypad_value & 0x30) == 0x30 {
+            // 両ラインとも非選択のときは下位ニブルにコントローラ番号 (反転) が見える
+            (joypad_value & 0xF0) | (0x0F - self.current_player)
+        } else if self.current_player != 0 {
+            // 2P以降のコントローラは未接続扱い (何も押されていない)
+            joypad_value | 0x0F
+        } else {
+            joypad_value
+        }
+    }
+
+    fn finish_packet(&mut self) {
+        if self.packet_count == 0 {
+            self.expected_packets = (self.packets[0] & 0x07) as usize;
+            if self.expected_packets == 0 { return; }
+        }
+        self.packet_count += 1;
+        if self.packet_count >= self.expected_packets {
+            self.execute_command();
+            self.packet_count = 0;
+            self.expected_packets = 0;
+        }
+    }
+
+    fn execute_command(&mut self) {
+        let data = self.packets;
+        let command = data[0] >> 3;
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        match command {
+            // PAL01, PAL23, PAL03, PAL12
+            0x00..=0x03 => {
+                let (a, b) = match command { 0x00 => (0, 1), 0x01 => (2, 3), 0x02 => (0, 3), _ => (1, 2) };
+                let color0 = read_u16(1);
+                for palette in self.palettes.iter_mut() { palette[0] = color0; }
+                for i in 0..3 {
+                    self.palettes[a][i + 1] = read_u16(3 + i * 2);
+                    self.palettes[b][i + 1] = read_u16(9 + i * 2);
+                }
+            }
+            0x04 => self.attr_blk(&data),
+            0x05 => self.attr_lin(&data),
+            0x06 => self.attr_div(&data),
+            0x07 => self.attr_chr(&data),
+            // PAL_SET
+            0x0A => {
+                for i in 0..4 {
+                    let index = (read_u16(1 + i * 2) & 0x01FF) as usize;
+                    self.palettes[i] = self.system_palettes[index];
+                }
+                let flags = data[9];
+                if (flags & 0x80) != 0 { self.apply_attribute_file((flags & 0x3F) as usize); }
+                if (flags & 0x40) != 0 { self.mask = MaskMode::None; }
+            }
+            0x0B => self.pending_transfer = Some(Transfer::Palettes),
+            // MLT_REQ
+            0x11 => {
+                self.player_count = match data[1] & 0x03 { 1 => 2, 3 => 4, _ => 1 };
+                self.current_player = 0;
+            }
+            0x13 => self.pending_transfer = Some(Transfer::BorderTiles(((data[1] & 0x01) as usize) * 128)),
+            0x14 => self.pending_transfer = Some(Transfer::BorderMap),
+            0x15 => self.pending_transfer = Some(Transfer::Attributes),
+            // ATTR_SET
+            0x16 => {
+                self.apply_attribute_file((data[1] & 0x3F) as usize);
+                if (data[1] & 0x40) != 0 { self.mask = MaskMode::None; }
+            }
+            // MASK_EN
+            0x17 => {
+                self.mask = match data[1] & 0x03 { 1 => MaskMode::Freeze, 2 => MaskMode::Black, 3 => MaskMode::Color0, _ => MaskMode::None };
+            }
+            // サウンド、SNESプログラム転送などは未対応
+            _ => {}
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(18);
+        for set in 0..count {
+            let offset = 2 + set * 6;
+            let control = data[offset];
+            let pals = data[offset + 1];
+            let (x1, y1) = (data[offset + 2] as usize, data[offset + 3] as usize);
+            let (x2, y2) = (data[offset + 4] as usize, data[offset + 5] as usize);
+            let inside = pals & 0x03;
+            let mut border = (pals >> 2) & 0x03;
+            let outside = (pals >> 4) & 0x03;
+            // 内側/外側の片方だけ指定された場合、境界線もその色になる
+            match control & 0x07 {
+                0x01 => border = inside,
+                0x04 => border = outside,
+                _ => {}
+            }
+            let change_inside = (control & 0x01) != 0;
+            let change_border = (control & 0x02) != 0 || matches!(control & 0x07, 0x01 | 0x04);
+            let change_outside = (control & 0x04) != 0;
+            for y in 0..TILES_Y {
+                for x in 0..TILES_X {
+                    let in_rect = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = in_rect && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = if on_border {
+                        if change_border { Some(border) } else { None }
+                    } else if in_rect {
+                        if change_inside { Some(inside) } else { None }
+                    } else if change_outside {
+                        Some(outside)
+                    } else {
+                        None
+                    };
+                    if let Some(p) = palette { self.attribute_map[y * TILES_X + x] = p; }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(PACKET_SIZE * MAX_PACKETS - 2);
+        for &line in &data[2..2 + count] {
+            let index = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if (line & 0x80) != 0 {
+                if index < TILES_Y { self.attribute_map[index * TILES_X..(index + 1) * TILES_X].fill(palette); }
+            } else if index < TILES_X {
+                for y in 0..TILES_Y { self.attribute_map[y * TILES_X + index] = palette; }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = (data[1] & 0x40) != 0;
+        let split = data[2] as usize;
+        for y in 0..TILES_Y {
+            for x in 0..TILES_X {
+                let pos = if horizontal { y } else { x };
+                self.attribute_map[y * TILES_X + x] = match pos.cmp(&split) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = (data[1] as usize).min(TILES_X - 1);
+        let mut y = (data[2] as usize).min(TILES_Y - 1);
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(TILES_X * TILES_Y);
+        let vertical = data[5] == 1;
+        for i in 0..count {
+            let byte = data.get(6 + i / 4).copied().unwrap_or(0);
+            let palette = (byte >> (6 - (i % 4) * 2)) & 0x03;
+            self.attribute_map[y * TILES_X + x] = palette;
+            if vertical {
+                y += 1;
+                if y == TILES_Y { y = 0; x = (x + 1) % TILES_X; }
+            } else {
+                x += 1;
+                if x == TILES_X { x = 0; y = (y + 1) % TILES_Y; }
+            }
+        }
+    }
+
+    fn apply_attribute_file(&mut self, index: usize) {
+        if index >= ATTRIBUTE_FILE_COUNT { return; }
+        let file = &self.attribute_files[index * ATTRIBUTE_FILE_SIZE..(index + 1) * ATTRIBUTE_FILE_SIZE];
+        for (i, palette) in self.attribute_map.iter_mut().enumerate() {
+            *palette = (file[i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+        }
+    }
+
+    /// VBlankごとに呼び出します。VRAM転送コマンドが保留中なら、表示中の画面からデータを取り込みます。
+    /// `shade_buffer` はPPUが出力した色番号 (0-3) の画面です。
+    pub fn on_vblank(&mut self, shade_buffer: &[u8]) {
+        if self.mask != MaskMode::Freeze {
+            self.frozen_screen.copy_from_slice(shade_buffer);
+        }
+        let Some(transfer) = self.pending_transfer.take() else { return; };
+        let data = Self::screen_to_transfer_data(shade_buffer);
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        match transfer {
+            Transfer::Palettes => {
+                for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                    for (c, color) in palette.iter_mut().enumerate() { *color = read_u16(i * 8 + c * 2); }
+                }
+            }
+            Transfer::BorderTiles(first_tile) => {
+                self.border_tiles[first_tile * 32..first_tile * 32 + TRANSFER_SIZE].copy_from_slice(&data);
+            }
+            Transfer::BorderMap => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() { *entry = read_u16(i * 2); }
+                for (p, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (c, color) in palette.iter_mut().enumerate() { *color = read_u16(0x800 + p * 32 + c * 2); }
+                }
+            }
+            Transfer::Attributes => {
+                self.attribute_files.copy_from_slice(&data[..ATTRIBUTE_FILE_SIZE * ATTRIBUTE_FILE_COUNT]);
+            }
+        }
+    }
+
+    // 画面に表示された先頭256タイル (1行20タイル) を2bppのタイルデータとして読み戻す
+    fn screen_to_transfer_data(shade_buffer: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; TRANSFER_SIZE];
+        for tile in 0..256 {
+            let (tile_x, tile_y) = (tile % TILES_X, tile / TILES_X);
+            for row in 0..8 {
+                let y = tile_y * 8 + row;
+                let (mut low, mut high) = (0u8, 0u8);
+                for bit in 0..8 {
+                    let shade = shade_buffer[y * SCREEN_WIDTH + tile_x * 8 + bit];
+                    low |= (shade & 1) << (7 - bit);
+                    high |= ((shade >> 1) & 1) << (7 - bit);
+                }
+                data[tile * 16 + row * 2] = low;
+                data[tile * 16 + row * 2 + 1] = high;
+            }
+        }
+        data
+    }
+
+    /// ボーダー付きの 256x224 画面を描画します。
+    pub fn render(&self, shade_buffer: &[u8], out: &mut [u32]) {
+        let backdrop = rgb555_to_u32(self.palettes[0][0]);
+        out.fill(backdrop);
+
+        let screen = if self.mask == MaskMode::Freeze { &self.frozen_screen[..] } else { shade_buffer };
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let color = match self.mask {
+                    MaskMode::Black => 0,
+                    MaskMode::Color0 => backdrop,
+                    _ => {
+                        let palette = self.attribute_map[(y / 8) * TILES_X + x / 8] as usize;
+                        rgb555_to_u32(self.palettes[palette][screen[y * SCREEN_WIDTH + x] as usize])
+                    }
+                };
+                out[(GB_SCREEN_Y + y) * SGB_SCREEN_WIDTH + GB_SCREEN_X + x] = color;
+            }
+        }
+
+        // ボーダーはゲーム画面より手前に描画され、色0は透明
+        for map_y in 0..BORDER_MAP_HEIGHT {
+            for map_x in 0..BORDER_MAP_WIDTH {
+                let entry = self.border_map[map_y * BORDER_MAP_WIDTH + map_x];
+                let tile = (entry & 0xFF) as usize;
+                let palette = &self.border_palettes[((entry >> 10) & 0x03) as usize];
+                let x_flip = (entry & 0x4000) != 0;
+                let y_flip = (entry & 0x8000) != 0;
+                for row in 0..8 {
+                    let src_row = if y_flip { 7 - row } else { row };
+                    let base = tile * 32 + src_row * 2;
+                    let planes = [self.border_tiles[base], self.border_tiles[base + 1], self.border_tiles[base + 16], self.border_tiles[base + 17]];
+                    for col in 0..8 {
+                        let bit = if x_flip { col } else { 7 - col };
+                        let color_index = planes.iter().enumerate()
+                            .fold(0usize, |acc, (plane, byte)| acc | ((((byte >> bit) & 1) as usize) << plane));
+                        if color_index == 0 { continue; }
+                        out[(map_y * 8 + row) * SGB_SCREEN_WIDTH + map_x * 8 + col] = rgb555_to_u32(palette[color_index]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // P1 のパルスで1パケット (16バイト, LSBから) とストップビットを送る
+    fn send_packet(sgb: &mut Sgb, packet: &[u8]) {
+        let mut bytes = [0u8; PACKET_SIZE];
+        bytes[..packet.len()].copy_from_slice(packet);
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        for i in 0..PACKET_SIZE * 8 {
+            let bit = (bytes[i / 8] >> (i % 8)) & 1;
+            sgb.write_p1(if bit == 1 { 0x10 } else { 0x20 });
+            sgb.write_p1(0x30);
+        }
+        sgb.write_p1(0x20);
+        sgb.write_p1(0x30);
+    }
+
+    fn command(code: u8) -> u8 { (code << 3) | 1 }
+
+    fn attribute(sgb: &Sgb, x: usize, y: usize) -> u8 { sgb.attribute_map[y * TILES_X + x] }
+
+    #[test]
+    fn receives_packets_through_p1_pulses() {
+        let mut sgb = Sgb::new();
+        send_packet(&mut sgb, &[command(0x17), 0x02]);
+        assert_eq!(sgb.mask_mode(), MaskMode::Black);
+
+        // ストップビットが 1 のパケットは捨てられる
+        let mut bytes = [0u8; PACKET_SIZE];
+        bytes[0] = command(0x17);
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        for i in 0..PACKET_SIZE * 8 {
+            sgb.write_p1(if (bytes[i / 8] >> (i % 8)) & 1 == 1 { 0x10 } else { 0x20 });
+            sgb.write_p1(0x30);
+        }
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.mask_mode(), MaskMode::Black);
+
+        // リセットパルスからやり直せば受信できる
+        send_packet(&mut sgb, &[command(0x17), 0x00]);
+        assert_eq!(sgb.mask_mode(), MaskMode::None);
+    }
+
+    #[test]
+    fn pal01_sets_palettes_zero_and_one() {
+        let mut sgb = Sgb::new();
+        let colors: [u16; 7] = [0x7FFF, 0x001F, 0x03E0, 0x7C00, 0x1111, 0x2222, 0x3333];
+        let mut packet = vec![command(0x00)];
+        packet.extend(colors.iter().flat_map(|c| c.to_le_bytes()));
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.palettes[0], [0x7FFF, 0x001F, 0x03E0, 0x7C00]);
+        assert_eq!(sgb.palettes[1], [0x7FFF, 0x1111, 0x2222, 0x3333]);
+        // 色0は全パレットで共有される
+        assert_eq!(sgb.palettes[2], [0x7FFF, DEFAULT_PALETTE[1], DEFAULT_PALETTE[2], DEFAULT_PALETTE[3]]);
+        assert_eq!(rgb555_to_u32(0x001F), 0xFF0000);
+        assert_eq!(rgb555_to_u32(0x7C00), 0x0000FF);
+    }
+
+    #[test]
+    fn attr_blk_colors_inside_border_and_outside() {
+        let mut sgb = Sgb::new();
+        // 内側=1, 境界=2, 外側=3 の (2,3)-(5,6) の矩形
+        send_packet(&mut sgb, &[command(0x04), 1, 0x07, 1 | (2 << 2) | (3 << 4), 2, 3, 5, 6]);
+        assert_eq!((attribute(&sgb, 3, 4), attribute(&sgb, 4, 5)), (1, 1));
+        assert_eq!((attribute(&sgb, 2, 3), attribute(&sgb, 5, 6), attribute(&sgb, 2, 5), attribute(&sgb, 4, 3)), (2, 2, 2, 2));
+        assert_eq!((attribute(&sgb, 0, 0), attribute(&sgb, 6, 3), attribute(&sgb, 3, 7), attribute(&sgb, 19, 17)), (3, 3, 3, 3));
+
+        // 内側だけを指定すると境界も内側の色になり、外側は変わらない
+        send_packet(&mut sgb, &[command(0x04), 1, 0x01, 0, 2, 3, 5, 6]);
+        assert_eq!((attribute(&sgb, 3, 4), attribute(&sgb, 2, 3), attribute(&sgb, 0, 0)), (0, 0, 3));
+    }
+
+    #[test]
+    fn mlt_req_cycles_the_controller_id() {
+        let mut sgb = Sgb::new();
+        assert_eq!(sgb.read_p1(0xFF), 0xFF);
+        send_packet(&mut sgb, &[command(0x11), 0x01]);
+        assert_eq!(sgb.player_count(), 2);
+        let mut ids = vec![sgb.read_p1(0xFF) & 0x0F];
+        for _ in 0..3 {
+            // P15 を下げてから上げるたびに次のコントローラへ
+            sgb.write_p1(0x10);
+            sgb.write_p1(0x30);
+            ids.push(sgb.read_p1(0xFF) & 0x0F);
+        }
+        // ストップビット後の P15 の立ち上がりで、もう 2P に切り替わっている
+        assert_eq!(ids, vec![0x0E, 0x0F, 0x0E, 0x0F]);
+        // 1P に戻ると P1 はジョイパッドの値のまま。2P はボタンが押されていない
+        assert_eq!(sgb.read_p1(0xE5), 0xE5);
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.read_p1(0xE5), 0xEF);
+    }
+
+    #[test]
+    fn mask_en_freezes_or_blanks_the_screen() {
+        let mut sgb = Sgb::new();
+        let mut out = vec![0; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT];
+        let game_pixel = GB_SCREEN_Y * SGB_SCREEN_WIDTH + GB_SCREEN_X;
+        let shade = |s: u8| vec![s; SCREEN_WIDTH * SCREEN_HEIGHT];
+
+        sgb.on_vblank(&shade(1));
+        send_packet(&mut sgb, &[command(0x17), 0x01]);
+        sgb.on_vblank(&shade(3));
+        sgb.render(&shade(3), &mut out);
+        assert_eq!(out[game_pixel], rgb555_to_u32(DEFAULT_PALETTE[1]));
+
+        send_packet(&mut sgb, &[command(0x17), 0x02]);
+        sgb.render(&shade(3), &mut out);
+        assert_eq!(out[game_pixel], 0);
+        assert_eq!(out[0], rgb555_to_u32(DEFAULT_PALETTE[0]));
+
+        send_packet(&mut sgb, &[command(0x17), 0x00]);
+        sgb.on_vblank(&shade(3));
+        sgb.render(&shade(3), &mut out);
+        assert_eq!(out[game_pixel], rgb555_to_u32(DEFAULT_PALETTE[3]));
+    }
+
+    #[test]
+    fn game_screen_is_placed_at_48_40() {
+        let sgb = Sgb::new();
+        let mut shades = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        shades[0] = 3;
+        shades[SCREEN_WIDTH * SCREEN_HEIGHT - 1] = 2;
+        let mut out = vec![0; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT];
+        sgb.render(&shades, &mut out);
+        let backdrop = rgb555_to_u32(DEFAULT_PALETTE[0]);
+        assert_eq!(out[40 * SGB_SCREEN_WIDTH + 48], rgb555_to_u32(DEFAULT_PALETTE[3]));
+        assert_eq!(out[(40 + 143) * SGB_SCREEN_WIDTH + 48 + 159], rgb555_to_u32(DEFAULT_PALETTE[2]));
+        assert_eq!(out[39 * SGB_SCREEN_WIDTH + 48], backdrop);
+        assert_eq!(out[40 * SGB_SCREEN_WIDTH + 47], backdrop);
+        assert_eq!(out[(40 + 144) * SGB_SCREEN_WIDTH + 48 + 159], backdrop);
+        assert_eq!(out[40 * SGB_SCREEN_WIDTH + 48 + 160], backdrop);
+    }
+}
@@ -0,0 +1,72 @@
+// src/stretch.rs
+// 音程を変えずに再生速度を変える時間伸縮 (窓付きオーバーラップ加算)
+//
+// 入力から固定長のグレインを切り出し、ハン窓を掛けて半分ずつ重ねて出力する。
+// 出力側のホップは常にグレイン長の半分で、入力側のホップを速度倍にすることで、
+// 速いときはグレインを飛ばし、遅いときは同じ部分を重ねて読む。
+
+use std::collections::VecDeque;
+
+// グレインの長さ (秒)。短いと音がざらつき、長いとエコーのように聞こえる
+const GRAIN_SECONDS: f64 = 0.04;
+
+pub struct TimeStretcher {
+    speed: f64,
+    grain: usize,
+    window: Vec<f32>,
+    // input[0] の入力全体での位置
+    input_start: u64,
+    input: VecDeque<(f32, f32)>,
+    // 次のグレインの開始位置 (入力全体での位置、端数あり)
+    next_grain: f64,
+    // 前のグレインの後半 (窓掛け済み)。次のグレインの前半に足す
+    overlap: Vec<(f32, f32)>,
+}
+
+impl TimeStretcher {
+    pub fn new(sample_rate: u32, speed: f64) -> Self {
+        // 半分ずつ重ねたときに窓の和が1になるよう、偶数長の周期的なハン窓を使う
+        let grain = (((sample_rate as f64 * GRAIN_SECONDS) as usize) & !1).max(2);
+        let window = (0..grain).map(|i| (0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / grain as f64).cos()) as f32).collect();
+        Self {
+            speed: speed.max(0.01),
+            grain,
+            window,
+            input_start: 0,
+            input: VecDeque::with_capacity(grain * 2),
+            next_grain: 0.0,
+            overlap: vec![(0.0, 0.0); grain / 2],
+        }
+    }
+
+    pub fn speed(&self) -> f64 { self.speed }
+
+    /// 入力サンプルを1つ追加し、出力できるようになったサンプルを `output` に渡します。
+    pub fn push(&mut self, sample: (f32, f32), mut output: impl FnMut((f32, f32))) {
+        // 次のグレインより前の入力は使わないので捨てる (速度が2倍を超えるとき)
+        if self.input.is_empty() && (self.input_start as f64) < self.next_grain.floor() {
+            self.input_start += 1;
+            return;
+        }
+        self.input.push_back(sample);
+
+        let hop = self.grain / 2;
+        while self.input_start + self.input.len() as u64 >= self.next_grain as u64 + self.grain as u64 {
+            let start = (self.next_grain as u64 - self.input_start) as usize;
+            for i in 0..hop {
+                let (l, r) = self.input[start + i];
+                let w = self.window[i];
+                output((self.overlap[i].0 + l * w, self.overlap[i].1 + r * w));
+            }
+            for i in 0..hop {
+                let (l, r) = self.input[start + hop + i];
+                let w = self.window[hop + i];
+                self.overlap[i] = (l * w, r * w);
+            }
+            self.next_grain += hop as f64 * self.speed;
+            let consumed = ((self.next_grain as u64).saturating_sub(self.input_start) as usize).min(self.input.len());
+            self.input.drain(..consumed);
+            self.input_start += consumed as u64;
+        }
+    }
+}
@@ -0,0 +1,147 @@
+// src/test_rom.rs
+// テストROM (Blargg, Mooneye) をヘッドレスで実行して合否を判定する
+//
+// - Mooneye: 終了時に `LD B,B` を実行し、合格ならB,C,D,E,H,Lがフィボナッチ数 (3,5,8,13,21,34)、
+//   不合格ならすべて $42 になる
+// - Blargg: 結果をシリアルに文字で出力する ("Passed" / "Failed")。新しいテストは $A000 にも
+//   状態 ($80 = 実行中、それ以外は結果コード、0で合格) と、$A001-$A003 に署名 DE B0 61、
+//   $A004 以降に0終端の文字列を書く
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::cartridge::Cartridge;
+use crate::cpu::Cpu;
+use crate::headless::{Headless, HeadlessOptions};
+use crate::ppu::CYCLES_PER_FRAME;
+use crate::serial::SerialDevice;
+
+pub const CPU_FREQ: f64 = 4_194_304.0;
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: u8 = 0x42;
+const LD_B_B: u8 = 0x40;
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+// $A004 以降の結果文字列の最大長
+const BLARGG_TEXT_LIMIT: u16 = 0x1000;
+// シリアルで結果を見つけた後、続きのメッセージを受け取るために実行を続ける時間 (Tサイクル、約0.5秒)
+const SERIAL_SETTLE_CYCLES: u64 = 2_097_152;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    /// 制限時間内に結果が出なかった
+    Timeout,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestReport {
+    pub status: TestStatus,
+    /// 結果を判定した方法と、テストが出力したメッセージ
+    pub message: String,
+    pub serial_output: String,
+    /// 判定までに実行したTサイクル数
+    pub cycles: u64,
+}
+
+/// シリアルに送られた文字を記録するだけの機器 (応答は未接続と同じ $FF)
+#[derive(Clone, Default)]
+pub struct SerialCapture {
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl SerialCapture {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.output.lock().unwrap()).into_owned()
+    }
+}
+
+impl SerialDevice for SerialCapture {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.output.lock().unwrap().push(byte);
+        0xFF
+    }
+}
+
+/// Mooneyeの結果レジスタを判定します。どちらの値でもなければ `None` です。
+pub fn mooneye_result(cpu: &Cpu) -> Option<TestStatus> {
+    let r = &cpu.registers;
+    let values = [r.b, r.c, r.d, r.e, r.h, r.l];
+    if values == MOONEYE_PASS {
+        Some(TestStatus::Passed)
+    } else if values.iter().all(|&v| v == MOONEYE_FAIL) {
+        Some(TestStatus::Failed)
+    } else {
+        None
+    }
+}
+
+/// Blarggの $A000 の結果を判定します。署名がないか実行中なら `None` です。
+pub fn blargg_memory_result(cpu: &Cpu) -> Option<(TestStatus, String)> {
+    let mmu = &cpu.mmu;
+    let signature = [mmu.read_byte(0xA001), mmu.read_byte(0xA002), mmu.read_byte(0xA003)];
+    let code = mmu.read_byte(0xA000);
+    if signature != BLARGG_SIGNATURE || code == BLARGG_RUNNING {
+        return None;
+    }
+    let text: Vec<u8> = (0xA004..0xA004 + BLARGG_TEXT_LIMIT).map(|addr| mmu.read_byte(addr)).take_while(|&b| b != 0).collect();
+    let status = if code == 0 { TestStatus::Passed } else { TestStatus::Failed };
+    Some((status, format!("result code {}: {}", code, String::from_utf8_lossy(&text).trim())))
+}
+
+/// Blarggのシリアル出力を判定します。
+pub fn blargg_serial_result(output: &str) -> Option<TestStatus> {
+    if output.contains("Passed") {
+        Some(TestStatus::Passed)
+    } else if output.contains("Failed") {
+        Some(TestStatus::Failed)
+    } else {
+        None
+    }
+}
+
+/// テストROMを最大 `timeout_seconds` 秒 (エミュレーション時間) 実行して合否を判定します。
+pub fn run_test_rom(cartridge: Cartridge, options: &HeadlessOptions, timeout_seconds: f64) -> io::Result<TestReport> {
+    run_test_rom_cycles(cartridge, options, (timeout_seconds * CPU_FREQ) as u64)
+}
+
+/// `run_test_rom` と同じですが、制限をTサイクル数で指定します。
+pub fn run_test_rom_cycles(cartridge: Cartridge, options: &HeadlessOptions, max_cycles: u64) -> io::Result<TestReport> {
+    let mut emulator = Headless::new(cartridge, options)?;
+    let serial = SerialCapture::new();
+    emulator.cpu.mmu.serial.connect(Box::new(serial.clone()));
+    let cpu = &mut emulator.cpu;
+
+    let mut cycles: u64 = 0;
+    let mut next_check = CYCLES_PER_FRAME as u64;
+    let mut serial_result: Option<(TestStatus, u64)> = None;
+    let report = |status, message: String, cycles| TestReport { status, message, serial_output: serial.text(), cycles };
+    while cycles < max_cycles {
+        let opcode = cpu.mmu.read_byte(cpu.registers.pc);
+        cycles += cpu.step() as u64;
+        if opcode == LD_B_B && let Some(status) = mooneye_result(cpu) {
+            return Ok(report(status, "Mooneye registers".to_string(), cycles));
+        }
+        // シリアルと $A000 の確認は1フレームごとで十分
+        if cycles >= next_check {
+            next_check += CYCLES_PER_FRAME as u64;
+            if let Some((status, message)) = blargg_memory_result(cpu) {
+                return Ok(report(status, format!("Blargg $A000 {}", message), cycles));
+            }
+            match serial_result {
+                Some((status, found_at)) if cycles >= found_at + SERIAL_SETTLE_CYCLES => {
+                    return Ok(report(status, "Blargg serial output".to_string(), found_at));
+                }
+                Some(_) => (),
+                None => serial_result = blargg_serial_result(&serial.text()).map(|status| (status, cycles)),
+            }
+        }
+    }
+    if let Some((status, found_at)) = serial_result {
+        return Ok(report(status, "Blargg serial output".to_string(), found_at));
+    }
+    Ok(report(TestStatus::Timeout, format!("no result after {:.1} s", max_cycles as f64 / CPU_FREQ), cycles))
+}
@@ -1,5 +1,12 @@
 // src/timer.rs
 
+use std::io;
+
+use crate::save_state::{StateReader, StateWriter};
+
+// APUのフレームシーケンサを駆動するDIVカウンタのビット (DIVレジスタのビット4、512Hz)
+const APU_DIV_BIT: u16 = 12;
+
 pub struct Timer {
     internal_div_counter: u16,
     pub tima: u8,
@@ -8,6 +15,13 @@ pub struct Timer {
     tima_reload_countdown: i8, // 0以下は遅延なし、>0でカウントダウン
     prev_timer_trigger_bit_state: bool, // 前回の relevant_bit の状態
     interrupt_request: bool,
+    apu_frame_clocks: u8, // 未処理の DIV-APU イベント数
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Timer {
@@ -21,9 +35,33 @@ impl Timer {
             tima_reload_countdown: -1, // 遅延なし
             prev_timer_trigger_bit_state: Self::get_timer_enable_and_bit_state(0, tac), // 初期状態
             interrupt_request: false,
+            apu_frame_clocks: 0,
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.internal_div_counter);
+        w.write(&self.tima);
+        w.write(&self.tma);
+        w.write(&self.tac);
+        w.write(&self.tima_reload_countdown);
+        w.write(&self.prev_timer_trigger_bit_state);
+        w.write(&self.interrupt_request);
+        w.write(&self.apu_frame_clocks);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.internal_div_counter)?;
+        r.read_into(&mut self.tima)?;
+        r.read_into(&mut self.tma)?;
+        r.read_into(&mut self.tac)?;
+        r.read_into(&mut self.tima_reload_countdown)?;
+        r.read_into(&mut self.prev_timer_trigger_bit_state)?;
+        r.read_into(&mut self.interrupt_request)?;
+        r.read_into(&mut self.apu_frame_clocks)?;
+        Ok(())
+    }
+
     // 現在のTACとDIVカウンタに基づいて、TIMAを駆動する最終的なANDゲートの出力がHighかどうか
     fn get_timer_enable_and_bit_state(div_counter: u16, tac_val: u8) -> bool {
         if (tac_val & 0b100) == 0 { // Timer Stop
@@ -40,7 +78,11 @@ impl Timer {
 
     pub fn tick(&mut self, t_cycles_elapsed: u8) {
         for _ in 0..t_cycles_elapsed {
+            let old_div_counter = self.internal_div_counter;
             self.internal_div_counter = self.internal_div_counter.wrapping_add(1);
+            if Self::apu_div_bit(old_div_counter) && !Self::apu_div_bit(self.internal_div_counter) {
+                self.apu_frame_clocks = self.apu_frame_clocks.saturating_add(1);
+            }
 
             if self.tima_reload_countdown > 0 {
                 self.tima_reload_countdown -= 1;
@@ -54,12 +96,10 @@ impl Timer {
             let current_timer_enabled_and_bit_state = Self::get_timer_enable_and_bit_state(self.internal_div_counter, self.tac);
 
             // 立ち下がりエッジ: (前の状態がHigh) AND (現在の状態がLow)
-            if self.prev_timer_trigger_bit_state && !current_timer_enabled_and_bit_state {
-                if self.tima_reload_countdown <= 0 { // リロード中でなければ
-                    self.tima = self.tima.wrapping_add(1);
-                    if self.tima == 0 {
-                        self.tima_reload_countdown = 4; // 4T後にTMAロード＆割り込み
-                    }
+            if self.prev_timer_trigger_bit_state && !current_timer_enabled_and_bit_state && self.tima_reload_countdown <= 0 { // リロード中でなければ
+                self.tima = self.tima.wrapping_add(1);
+                if self.tima == 0 {
+                    self.tima_reload_countdown = 4; // 4T後にTMAロード＆割り込み
                 }
             }
             self.prev_timer_trigger_bit_state = current_timer_enabled_and_bit_state;
@@ -68,6 +108,10 @@ impl Timer {
     pub fn read_div(&self) -> u8 { (self.internal_div_counter >> 8) as u8 }
     pub fn write_div(&mut self) {
         let old_trigger_state = Self::get_timer_enable_and_bit_state(self.internal_div_counter, self.tac);
+        // DIVリセットでビットが立ち下がった場合も DIV-APU イベントになる
+        if Self::apu_div_bit(self.internal_div_counter) {
+            self.apu_frame_clocks = self.apu_frame_clocks.saturating_add(1);
+        }
         self.internal_div_counter = 0;
         let new_trigger_state = Self::get_timer_enable_and_bit_state(self.internal_div_counter, self.tac);
         if old_trigger_state && !new_trigger_state && self.tima_reload_countdown <= 0 {
@@ -91,5 +135,44 @@ impl Timer {
         }
         self.prev_timer_trigger_bit_state = new_trigger_state;
     }
+    fn apu_div_bit(div_counter: u16) -> bool { ((div_counter >> APU_DIV_BIT) & 1) != 0 }
+    /// 前回の呼び出し以降に発生した DIV-APU イベントの数を返します。
+    pub fn take_apu_frame_clocks(&mut self) -> u8 { let clocks = self.apu_frame_clocks; self.apu_frame_clocks = 0; clocks }
     pub fn take_interrupt_request(&mut self) -> bool { let req = self.interrupt_request; self.interrupt_request = false; req }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick_cycles(timer: &mut Timer, cycles: u32) {
+        for _ in 0..cycles / 4 {
+            timer.tick(4);
+        }
+    }
+
+    #[test]
+    fn div_apu_event_every_8192_cycles() {
+        let mut timer = Timer::new();
+        tick_cycles(&mut timer, 8192 - 4);
+        assert_eq!(timer.take_apu_frame_clocks(), 0);
+        tick_cycles(&mut timer, 4);
+        assert_eq!(timer.take_apu_frame_clocks(), 1);
+        tick_cycles(&mut timer, 8192 * 3);
+        assert_eq!(timer.take_apu_frame_clocks(), 3);
+    }
+
+    #[test]
+    fn div_reset_with_bit_set_clocks_apu() {
+        let mut timer = Timer::new();
+        tick_cycles(&mut timer, 4096);
+        timer.write_div();
+        assert_eq!(timer.take_apu_frame_clocks(), 1);
+        // ビットが0のときのリセットではイベントは起きない
+        tick_cycles(&mut timer, 1024);
+        timer.write_div();
+        assert_eq!(timer.take_apu_frame_clocks(), 0);
+        // リセットで周期も最初からになる
+        tick_cycles(&mut timer, 8192);
+        assert_eq!(timer.take_apu_frame_clocks(), 1);
+    }
 }
\ No newline at end of file
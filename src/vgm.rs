@@ -0,0 +1,194 @@
+// src/vgm.rs
+// APUレジスタ書き込みのログをVGM (v1.61, Game Boy DMG) 形式で書き出す
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const CPU_FREQ: u64 = 4_194_304;
+const VGM_SAMPLE_RATE: u64 = 44_100;
+const VGM_VERSION: u32 = 0x0000_0161;
+const VGM_HEADER_SIZE: usize = 0x100;
+// ヘッダ内の各フィールドのオフセット
+const EOF_OFFSET: usize = 0x04;
+const VERSION_OFFSET: usize = 0x08;
+const TOTAL_SAMPLES_OFFSET: usize = 0x18;
+const DATA_OFFSET_OFFSET: usize = 0x34;
+const GB_DMG_CLOCK_OFFSET: usize = 0x80;
+
+const CMD_GB_DMG_WRITE: u8 = 0xB3;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_735: u8 = 0x62;
+const CMD_WAIT_882: u8 = 0x63;
+const CMD_END: u8 = 0x66;
+
+pub const APU_REGISTER_BASE: u16 = 0xFF10;
+pub const APU_REGISTER_COUNT: usize = 0x30;
+
+/// `Apu::write_reg` を通ったレジスタ書き込みをCPUサイクル付きで記録します。
+pub struct VgmLogger {
+    start_cycle: u64,
+    end_cycle: u64,
+    initial_state: Vec<(u8, u8)>,
+    writes: Vec<(u64, u8, u8)>,
+}
+
+impl VgmLogger {
+    /// 記録開始時点のレジスタ値 (最後に書き込まれた値) から初期状態を作ります。
+    /// 電源 (NR52) → 波形RAM → その他のレジスタの順に書き込み、トリガービットは立てません。
+    pub fn new(start_cycle: u64, registers: &[u8; APU_REGISTER_COUNT]) -> Self {
+        let mut initial_state = vec![(0x16, registers[0x16])];
+        initial_state.extend((0x20..0x30).map(|reg| (reg as u8, registers[reg])));
+        for (reg, &value) in registers.iter().enumerate().take(0x16) {
+            let value = match reg {
+                0x04 | 0x09 | 0x0E | 0x13 => value & 0x7F,
+                _ => value,
+            };
+            initial_state.push((reg as u8, value));
+        }
+        Self { start_cycle, end_cycle: start_cycle, initial_state, writes: Vec::new() }
+    }
+
+    pub fn log_write(&mut self, cycle: u64, address: u16, value: u8) {
+        if (APU_REGISTER_BASE..APU_REGISTER_BASE + APU_REGISTER_COUNT as u16).contains(&address) {
+            self.writes.push((cycle, (address - APU_REGISTER_BASE) as u8, value));
+        }
+    }
+
+    pub fn set_end_cycle(&mut self, cycle: u64) {
+        self.end_cycle = cycle.max(self.start_cycle);
+    }
+
+    pub fn write_count(&self) -> usize {
+        self.writes.len()
+    }
+
+    // CPUサイクルを記録開始からの44.1kHzサンプル位置に変換する (端数は切り捨て、誤差は蓄積しない)
+    fn sample_position(&self, cycle: u64) -> u64 {
+        (cycle - self.start_cycle) * VGM_SAMPLE_RATE / CPU_FREQ
+    }
+
+    fn push_wait(data: &mut Vec<u8>, mut samples: u64) {
+        while samples > 0 {
+            match samples {
+                735 => { data.push(CMD_WAIT_735); samples = 0; }
+                882 => { data.push(CMD_WAIT_882); samples = 0; }
+                1..=16 => { data.push(0x70 + (samples - 1) as u8); samples = 0; }
+                _ => {
+                    let chunk = samples.min(u16::MAX as u64);
+                    data.push(CMD_WAIT);
+                    data.extend_from_slice(&(chunk as u16).to_le_bytes());
+                    samples -= chunk;
+                }
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; VGM_HEADER_SIZE];
+        for (reg, value) in &self.initial_state {
+            data.extend_from_slice(&[CMD_GB_DMG_WRITE, *reg, *value]);
+        }
+
+        let mut current_sample = 0;
+        for (cycle, reg, value) in &self.writes {
+            let sample = self.sample_position(*cycle);
+            Self::push_wait(&mut data, sample - current_sample);
+            current_sample = sample;
+            data.extend_from_slice(&[CMD_GB_DMG_WRITE, *reg, *value]);
+        }
+        let total_samples = self.sample_position(self.end_cycle).max(current_sample);
+        Self::push_wait(&mut data, total_samples - current_sample);
+        data.push(CMD_END);
+
+        let write_u32 = |data: &mut Vec<u8>, offset: usize, value: u32| data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        data[0..4].copy_from_slice(b"Vgm ");
+        let eof = (data.len() - EOF_OFFSET) as u32;
+        write_u32(&mut data, EOF_OFFSET, eof);
+        write_u32(&mut data, VERSION_OFFSET, VGM_VERSION);
+        write_u32(&mut data, TOTAL_SAMPLES_OFFSET, total_samples as u32);
+        write_u32(&mut data, DATA_OFFSET_OFFSET, (VGM_HEADER_SIZE - DATA_OFFSET_OFFSET) as u32);
+        write_u32(&mut data, GB_DMG_CLOCK_OFFSET, CPU_FREQ as u32);
+        data
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn waits(samples: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        VgmLogger::push_wait(&mut data, samples);
+        data
+    }
+
+    #[test]
+    fn header_fields() {
+        let mut logger = VgmLogger::new(1000, &[0; APU_REGISTER_COUNT]);
+        logger.set_end_cycle(1000 + CPU_FREQ);
+        let data = logger.to_bytes();
+        assert_eq!(&data[0..4], b"Vgm ");
+        assert_eq!(read_u32(&data, EOF_OFFSET) as usize, data.len() - 4);
+        assert_eq!(read_u32(&data, VERSION_OFFSET), 0x161);
+        assert_eq!(read_u32(&data, TOTAL_SAMPLES_OFFSET), 44_100);
+        assert_eq!(read_u32(&data, DATA_OFFSET_OFFSET), 0x100 - 0x34);
+        assert_eq!(read_u32(&data, GB_DMG_CLOCK_OFFSET), 4_194_304);
+        assert_eq!(&data[data.len() - 4..], &[CMD_WAIT, 0x44, 0xAC, CMD_END]);
+    }
+
+    #[test]
+    fn initial_state_powers_on_first_without_triggers() {
+        let mut registers = [0; APU_REGISTER_COUNT];
+        for (i, value) in registers.iter_mut().enumerate() {
+            *value = 0x80 | i as u8;
+        }
+        let data = VgmLogger::new(0, &registers).to_bytes();
+        let writes: Vec<&[u8]> = data[VGM_HEADER_SIZE..data.len() - 1].chunks(3).collect();
+        assert_eq!(writes.len(), 1 + 16 + 0x16);
+        assert!(writes.iter().all(|write| write[0] == CMD_GB_DMG_WRITE));
+        assert_eq!(writes[0], &[0xB3, 0x16, 0x96]);
+        assert_eq!(writes[1], &[0xB3, 0x20, 0xA0]);
+        assert_eq!(writes[16], &[0xB3, 0x2F, 0xAF]);
+        assert_eq!(writes[17], &[0xB3, 0x00, 0x80]);
+        for reg in [0x04, 0x09, 0x0E, 0x13] {
+            assert_eq!(writes[17 + reg], &[0xB3, reg as u8, reg as u8], "NR{:02X}", reg);
+        }
+        assert_eq!(writes[17 + 0x15], &[0xB3, 0x15, 0x95]);
+    }
+
+    #[test]
+    fn writes_are_encoded_with_waits_between_them() {
+        let mut logger = VgmLogger::new(500, &[0; APU_REGISTER_COUNT]);
+        logger.log_write(500, 0xFF12, 0xF3);
+        logger.log_write(600, 0xFF00, 0x10); // APU 以外は記録しない
+        // 735サンプル (1/60秒) 目に入る最初のサイクル
+        logger.log_write(500 + (735 * CPU_FREQ).div_ceil(VGM_SAMPLE_RATE), 0xFF3F, 0x01);
+        assert_eq!(logger.write_count(), 2);
+        let data = logger.to_bytes();
+        let body = &data[VGM_HEADER_SIZE + 39 * 3..];
+        assert_eq!(body, &[0xB3, 0x02, 0xF3, CMD_WAIT_735, 0xB3, 0x2F, 0x01, CMD_END]);
+    }
+
+    #[test]
+    fn push_wait_picks_the_shortest_commands() {
+        assert_eq!(waits(0), Vec::<u8>::new());
+        assert_eq!(waits(1), vec![0x70]);
+        assert_eq!(waits(16), vec![0x7F]);
+        assert_eq!(waits(17), vec![0x61, 17, 0]);
+        assert_eq!(waits(735), vec![0x62]);
+        assert_eq!(waits(882), vec![0x63]);
+        assert_eq!(waits(65_535), vec![0x61, 0xFF, 0xFF]);
+        assert_eq!(waits(65_536), vec![0x61, 0xFF, 0xFF, 0x70]);
+        assert_eq!(waits(65_535 + 735), vec![0x61, 0xFF, 0xFF, 0x62]);
+        assert_eq!(waits(200_000), vec![0x61, 0xFF, 0xFF, 0x61, 0xFF, 0xFF, 0x61, 0xFF, 0xFF, 0x61, 0x43, 0x0D]);
+    }
+}
@@ -0,0 +1,170 @@
+// src/video.rs
+// 画面出力の動画録画 (アニメーションGIF / 連番PNG)
+//
+// エンコードは別スレッドで行い、エミュレーションループを止めないようにする。
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use chrono::Local;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, RgbaImage};
+
+/// LCDのフレームレート (4194304 Hz / 70224 サイクル ≒ 59.73 Hz)
+pub const FRAME_RATE: f64 = 4_194_304.0 / 70224.0;
+// GIFの遅延は1/100秒単位で、多くのビューアは2未満の値を遅く再生するため、
+// GIFでは2フレームに1枚だけ書き出す (約29.9fps)
+const GIF_FRAME_STEP: u64 = 2;
+// エンコードを待つフレームの上限。エンコードが追いつかない場合は、
+// メモリを使い続けずにエミュレーションループ側を待たせる
+const MAX_PENDING_FRAMES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    /// アニメーションGIF 1ファイル
+    Gif,
+    /// 連番PNGと、同じフォルダへのWAV録音 (外部ツールでの結合用)
+    PngSequence,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VideoOptions {
+    pub format: VideoFormat,
+    /// 整数倍の拡大率 (ニアレストネイバー)
+    pub scale: u32,
+    /// 色番号 (0-3) に割り当てる色。`None` なら画面表示と同じ色で録画します
+    pub palette: Option<[u32; 4]>,
+}
+
+impl Default for VideoOptions {
+    fn default() -> Self {
+        Self { format: VideoFormat::Gif, scale: 2, palette: None }
+    }
+}
+
+enum FrameWriter {
+    Gif { encoder: GifEncoder<BufWriter<File>>, written_centis: u64 },
+    Png { dir: PathBuf },
+}
+
+impl FrameWriter {
+    fn write(&mut self, index: u64, image: RgbaImage) -> io::Result<()> {
+        let to_io = |e: image::ImageError| io::Error::other(e.to_string());
+        match self {
+            FrameWriter::Gif { encoder, written_centis } => {
+                if !index.is_multiple_of(GIF_FRAME_STEP) { return Ok(()); }
+                // 累積時刻を丸めて遅延を決めるので、1/100秒単位でも再生速度がずれない
+                let end_centis = ((index + GIF_FRAME_STEP) as f64 * 100.0 / FRAME_RATE).round() as u64;
+                let delay = (end_centis - *written_centis) as u32;
+                *written_centis = end_centis;
+                encoder.encode_frame(Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(delay * 10, 1))).map_err(to_io)
+            }
+            FrameWriter::Png { dir } => image.save(dir.join(format!("frame-{:06}.png", index))).map_err(to_io),
+        }
+    }
+}
+
+fn to_rgba_image(frame: &[u32], width: usize, height: usize, scale: u32) -> RgbaImage {
+    let scale = scale.max(1);
+    RgbaImage::from_fn(width as u32 * scale, height as u32 * scale, |x, y| {
+        let pixel = frame[(y / scale) as usize * width + (x / scale) as usize];
+        image::Rgba([(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8, 255])
+    })
+}
+
+pub struct VideoRecorder {
+    options: VideoOptions,
+    width: usize,
+    height: usize,
+    path: PathBuf,
+    frame_count: u64,
+    sender: Option<SyncSender<(u64, Vec<u32>)>>,
+    worker: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl VideoRecorder {
+    /// `dir` 以下にタイムスタンプ付きの出力先を作成して録画を開始します。
+    /// 連番PNGの場合は `video-<日時>/` フォルダ、GIFの場合は `video-<日時>.gif` になります。
+    pub fn start<P: AsRef<Path>>(dir: P, options: VideoOptions, width: usize, height: usize) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let timestamp = Local::now().format("%Y%m%d-%H%M%S");
+        let (path, mut writer) = match options.format {
+            VideoFormat::Gif => {
+                let path = dir.join(format!("video-{}.gif", timestamp));
+                let mut encoder = GifEncoder::new(BufWriter::new(File::create(&path)?));
+                encoder.set_repeat(Repeat::Infinite).map_err(|e| io::Error::other(e.to_string()))?;
+                (path.clone(), FrameWriter::Gif { encoder, written_centis: 0 })
+            }
+            VideoFormat::PngSequence => {
+                let path = dir.join(format!("video-{}", timestamp));
+                fs::create_dir_all(&path)?;
+                (path.clone(), FrameWriter::Png { dir: path })
+            }
+        };
+
+        let (sender, receiver) = mpsc::sync_channel::<(u64, Vec<u32>)>(MAX_PENDING_FRAMES);
+        let scale = options.scale;
+        let worker = thread::spawn(move || {
+            for (index, frame) in receiver {
+                writer.write(index, to_rgba_image(&frame, width, height, scale))?;
+            }
+            Ok(())
+        });
+        Ok(Self { options, width, height, path, frame_count: 0, sender: Some(sender), worker: Some(worker) })
+    }
+
+    pub fn options(&self) -> &VideoOptions { &self.options }
+
+    /// 出力先 (GIFファイル、または連番PNGのフォルダ)
+    pub fn path(&self) -> &Path { &self.path }
+
+    pub fn frame_count(&self) -> u64 { self.frame_count }
+
+    /// 1フレーム分を追加します。`frame` は表示中の画面、`shades` はPPUの色番号バッファで、
+    /// パレットが指定されていて `shades` がある場合はそちらから色を付けます。
+    /// エンコード待ちのフレームが溜まっている間は、空きができるまでブロックします。
+    pub fn push_frame(&mut self, frame: &[u32], shades: Option<&[u8]>) {
+        let pixels = match (self.options.palette, shades) {
+            (Some(palette), Some(shades)) => shades.iter().map(|&shade| palette[(shade & 3) as usize]).collect(),
+            _ => frame[..self.width * self.height].to_vec(),
+        };
+        if let Some(sender) = &self.sender && sender.send((self.frame_count, pixels)).is_ok() {
+            self.frame_count += 1;
+        }
+    }
+
+    /// 残りのフレームを書き出して録画を終了します。
+    pub fn finish(mut self) -> io::Result<PathBuf> {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            worker.join().map_err(|_| io::Error::other("video encoder thread panicked"))??;
+        }
+        Ok(self.path.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_every_frame_beyond_queue_capacity() {
+        let dir = std::env::temp_dir().join(format!("video-test-{}", std::process::id()));
+        let options = VideoOptions { format: VideoFormat::PngSequence, scale: 1, palette: None };
+        let mut recorder = VideoRecorder::start(&dir, options, 4, 2).unwrap();
+        let frame = [0x00FF_FFFFu32; 8];
+        let total = MAX_PENDING_FRAMES as u64 * 2;
+        for _ in 0..total {
+            recorder.push_frame(&frame, None);
+        }
+        assert_eq!(recorder.frame_count(), total);
+        let path = recorder.finish().unwrap();
+        let written = fs::read_dir(&path).unwrap().count() as u64;
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written, total);
+    }
+}
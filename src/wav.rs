@@ -0,0 +1,214 @@
+// src/wav.rs
+// APU出力のWAV録音
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+
+const STEM_NAMES: [&str; 4] = ["pulse1", "pulse2", "wave", "noise"];
+// ステムのDC成分除去に使うハイパスフィルタのカットオフ周波数 (ミックスと同じ)
+const STEM_HPF_CUTOFF: f32 = 20.0;
+
+/// 16bit PCMのWAVファイルを書き出します。データ長はfinalize時にヘッダへ書き戻します。
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    /// 新しいファイルを作成します。既存のファイルは上書きせずにエラーを返します。
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create_new(path)?);
+        let block_align = channels * 2;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&16u16.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { writer, data_bytes: 0 })
+    }
+
+    pub fn write_sample(&mut self, value: f32) -> io::Result<()> {
+        let pcm = (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        self.writer.write_all(&pcm.to_le_bytes())?;
+        self.data_bytes = self.data_bytes.saturating_add(2);
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&36u32.saturating_add(self.data_bytes).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_bytes.to_le_bytes())?;
+        self.writer.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingMode {
+    /// 最終的なステレオミックスを1ファイルに録音
+    Mix,
+    /// 4チャンネルをそれぞれモノラルのステムとして別ファイルに録音
+    Stems,
+}
+
+pub struct AudioRecorder {
+    mode: RecordingMode,
+    writers: Vec<WavWriter>,
+    paths: Vec<PathBuf>,
+    hpf_alpha: f32,
+    hpf_cap: [f32; 4],
+    last_raw: [f32; 4],
+}
+
+impl AudioRecorder {
+    /// `dir` 以下にタイムスタンプ付きのファイルを作成して録音を開始します。
+    /// 同じ秒に始めた録音があれば、名前に連番を付けて別のファイルにします。
+    pub fn start<P: AsRef<Path>>(dir: P, mode: RecordingMode, sample_rate: u32) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let timestamp = Local::now().format("%Y%m%d-%H%M%S").to_string();
+        let channels = match mode { RecordingMode::Mix => 2, RecordingMode::Stems => 1 };
+        let mut number = 1;
+        let paths = loop {
+            let base = if number == 1 { format!("recording-{}", timestamp) } else { format!("recording-{}-{}", timestamp, number) };
+            let paths: Vec<PathBuf> = match mode {
+                RecordingMode::Mix => vec![dir.join(format!("{}.wav", base))],
+                RecordingMode::Stems => STEM_NAMES.iter().map(|name| dir.join(format!("{}-{}.wav", base, name))).collect(),
+            };
+            if !paths.iter().any(|path| path.exists()) { break paths; }
+            number += 1;
+        };
+        let writers = paths.iter().map(|path| WavWriter::create(path, channels, sample_rate)).collect::<io::Result<Vec<_>>>()?;
+
+        let dt = 1.0 / sample_rate as f32;
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * STEM_HPF_CUTOFF);
+        Ok(Self { mode, writers, paths, hpf_alpha: rc / (rc + dt), hpf_cap: [0.0; 4], last_raw: [0.0; 4] })
+    }
+
+    pub fn mode(&self) -> RecordingMode { self.mode }
+
+    // DACのDC成分を除去する (出力デバイスへのミックスと同じフィルタ)
+    fn high_pass(&mut self, index: usize, raw: f32) -> f32 {
+        let filtered = self.hpf_alpha * (self.hpf_cap[index] + raw - self.last_raw[index]);
+        self.hpf_cap[index] = filtered;
+        self.last_raw[index] = raw;
+        filtered
+    }
+
+    /// 1サンプル分を書き込みます。`mix` はDC成分を除去する前のステレオミックス、
+    /// `channels` はミックス前の各チャンネル出力です。
+    pub fn push(&mut self, mix: (f32, f32), channels: [f32; 4]) -> io::Result<()> {
+        match self.mode {
+            RecordingMode::Mix => {
+                let left = self.high_pass(0, mix.0);
+                let right = self.high_pass(1, mix.1);
+                self.writers[0].write_sample(left)?;
+                self.writers[0].write_sample(right)?;
+            }
+            RecordingMode::Stems => {
+                for (i, channel) in channels.into_iter().enumerate() {
+                    // ミックス時と同じく1/4にスケールする
+                    let filtered = self.high_pass(i, channel / 4.0);
+                    self.writers[i].write_sample(filtered)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 録音を終了してファイルを閉じ、書き出したファイルのパスを返します。
+    pub fn finish(self) -> io::Result<Vec<PathBuf>> {
+        for writer in self.writers {
+            writer.finalize()?;
+        }
+        Ok(self.paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wav-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 { u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) }
+    fn read_u16(data: &[u8], offset: usize) -> u16 { u16::from_le_bytes([data[offset], data[offset + 1]]) }
+
+    // ヘッダを読み、(チャンネル数, サンプルレート, データのバイト数) を返す
+    fn parse_header(data: &[u8]) -> (u16, u32, u32) {
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(read_u32(data, 4) as usize, data.len() - 8);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!((read_u32(data, 16), read_u16(data, 20)), (16, 1));
+        let (channels, sample_rate) = (read_u16(data, 22), read_u32(data, 24));
+        assert_eq!(read_u32(data, 28), sample_rate * channels as u32 * 2);
+        assert_eq!((read_u16(data, 32), read_u16(data, 34)), (channels * 2, 16));
+        assert_eq!(&data[36..40], b"data");
+        let data_bytes = read_u32(data, 40);
+        assert_eq!(data_bytes as usize, data.len() - 44);
+        (channels, sample_rate, data_bytes)
+    }
+
+    #[test]
+    fn mix_and_stem_headers_round_trip() {
+        let dir = temp_dir("headers");
+        let mut mix = AudioRecorder::start(&dir, RecordingMode::Mix, 48_000).unwrap();
+        let mut stems = AudioRecorder::start(&dir, RecordingMode::Stems, 32_000).unwrap();
+        for i in 0..100 {
+            let level = if i % 2 == 0 { 0.5 } else { -0.5 };
+            mix.push((level, -level), [level; 4]).unwrap();
+            stems.push((level, -level), [level; 4]).unwrap();
+        }
+        let mix_paths = mix.finish().unwrap();
+        let stem_paths = stems.finish().unwrap();
+        assert_eq!(mix_paths.len(), 1);
+        assert_eq!(parse_header(&fs::read(&mix_paths[0]).unwrap()), (2, 48_000, 100 * 2 * 2));
+        assert_eq!(stem_paths.len(), 4);
+        for (path, name) in stem_paths.iter().zip(STEM_NAMES) {
+            assert!(path.to_string_lossy().ends_with(&format!("-{}.wav", name)));
+            assert_eq!(parse_header(&fs::read(path).unwrap()), (1, 32_000, 100 * 2));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recordings_in_the_same_second_get_distinct_names() {
+        let dir = temp_dir("names");
+        let recorders: Vec<AudioRecorder> = (0..3).map(|_| AudioRecorder::start(&dir, RecordingMode::Mix, 44_100).unwrap()).collect();
+        let mut paths: Vec<PathBuf> = recorders.into_iter().flat_map(|recorder| recorder.finish().unwrap()).collect();
+        paths.sort();
+        paths.dedup();
+        assert_eq!(paths.len(), 3);
+        assert!(WavWriter::create(&paths[0], 2, 44_100).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finalize_saturates_the_riff_size() {
+        let dir = temp_dir("saturate");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("long.wav");
+        let mut writer = WavWriter::create(&path, 2, 44_100).unwrap();
+        writer.data_bytes = u32::MAX - 10;
+        writer.write_sample(0.0).unwrap();
+        writer.finalize().unwrap();
+        let data = fs::read(&path).unwrap();
+        assert_eq!((read_u32(&data, 4), read_u32(&data, 40)), (u32::MAX, u32::MAX - 8));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
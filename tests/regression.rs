@@ -0,0 +1,131 @@
+// tests/regression.rs
+// 画面の回帰テストとテストROMのスイート
+//
+// テストROMはリポジトリに含めないので、GB_TEST_ROMS (既定: tests/roms) の下に置いたものだけを実行する。
+// ディレクトリがなければそのスイートは何もせずに通る。
+//
+//   dmg-acid2/               dmg-acid2.gb と参照画像 (dmg-acid2.png か reference-dmg.png)
+//   mealybug-tearoom-tests/  *.gb と参照画像 (同じ名前の .png か expected/DMG-blob/<名前>.png)
+//   mooneye-test-suite/      *.gb (サブディレクトリも含む。DMGで動かないモデル指定のものは除く)
+//   dmg_sound/               Blarggの dmg_sound (rom_singles/*.gb。結果は $A000 とシリアルから読む)
+//
+// 一致しなかった画面は target/tmp/regression-diffs/ に差分画像と実際の画面を書き出す。
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use rust_gb_emulator::cartridge::Cartridge;
+use rust_gb_emulator::conformance::{self, CaseStatus, ConformanceOptions};
+use rust_gb_emulator::headless::{Headless, HeadlessOptions};
+use rust_gb_emulator::regression::{self, ScriptRun};
+use rust_gb_emulator::test_rom::{self, TestStatus};
+
+// ROM1本あたりの上限 (約10秒)
+const MAX_CYCLES: u64 = 41_943_040;
+
+fn rom_root() -> PathBuf {
+    env::var_os("GB_TEST_ROMS").map(PathBuf::from).unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms"))
+}
+
+/// スイートのROMをすべて実行し、合格しなかったものを一覧にして失敗させます。
+/// `screen_only` なら参照画像のないROMは飛ばします (MealybugにはCGB用の参照画像しかないテストがある)。
+fn run_suite(name: &str, screen_only: bool) {
+    let suite = rom_root().join(name);
+    if !suite.is_dir() {
+        eprintln!("skipping {}: {} not found", name, suite.display());
+        return;
+    }
+    let options = ConformanceOptions {
+        headless: HeadlessOptions::default(),
+        max_cycles: MAX_CYCLES,
+        jobs: 1,
+        diff_dir: Some(Path::new(env!("CARGO_TARGET_TMPDIR")).join("regression-diffs").join(name)),
+    };
+    let mut failures = Vec::new();
+    for rom in conformance::find_roms(&suite) {
+        if screen_only && conformance::find_reference(&suite, &rom).is_none() {
+            continue;
+        }
+        let result = conformance::run_case(&suite, &rom, &options);
+        if matches!(result.status, CaseStatus::Failed | CaseStatus::Timeout) {
+            failures.push(format!("{} {}: {}", result.status.label(), result.rom.display(), result.message));
+        }
+    }
+    assert!(failures.is_empty(), "{} failures in {}:\n{}", failures.len(), name, failures.join("\n"));
+}
+
+#[test]
+fn dmg_acid2() {
+    run_suite("dmg-acid2", true);
+}
+
+#[test]
+fn mealybug_tearoom() {
+    run_suite("mealybug-tearoom-tests", true);
+}
+
+#[test]
+fn mooneye() {
+    run_suite("mooneye-test-suite", false);
+}
+
+#[test]
+fn blargg_dmg_sound() {
+    run_suite("dmg_sound", false);
+}
+
+/// 結果レジスタにMooneyeの合格の値を入れて LD B,B を実行するだけのROM
+fn breakpoint_rom() -> Cartridge {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    let program = [
+        0x06, 3, 0x0E, 5, 0x16, 8, 0x1E, 13, 0x26, 21, 0x2E, 34, // LD B,3 ... LD L,34
+        0x40,       // LD B,B
+        0x18, 0xFE, // JR -2
+    ];
+    rom[0x150..0x150 + program.len()].copy_from_slice(&program);
+    Cartridge::from_bytes(rom).unwrap()
+}
+
+#[test]
+fn breakpoint_stops_script() {
+    let mut emulator = Headless::new(breakpoint_rom(), &HeadlessOptions::default()).unwrap();
+    let run = ScriptRun { frames: 60, input: Vec::new(), until_breakpoint: true };
+    assert_eq!(regression::run_script(&mut emulator, &run), (1, true));
+}
+
+#[test]
+fn breakpoint_reports_mooneye_pass() {
+    let report = test_rom::run_test_rom(breakpoint_rom(), &HeadlessOptions::default(), 1.0).unwrap();
+    assert_eq!(report.status, TestStatus::Passed);
+}
+
+#[test]
+fn same_input_gives_same_screen() {
+    let input = regression::parse_input_script("Start:2, :3 # wait\nA+B:4").unwrap();
+    assert_eq!(input.len(), 9);
+    let run = ScriptRun { frames: 20, input, until_breakpoint: false };
+    let hashes: Vec<u64> = (0..2).map(|_| {
+        let mut emulator = Headless::new(breakpoint_rom(), &HeadlessOptions::default()).unwrap();
+        regression::run_script(&mut emulator, &run);
+        regression::frame_hash(emulator.screen())
+    }).collect();
+    assert_eq!(hashes[0], hashes[1]);
+}
+
+#[test]
+fn compare_reports_differing_pixels() {
+    let emulator = Headless::new(breakpoint_rom(), &HeadlessOptions::default()).unwrap();
+    let actual = emulator.screen_image();
+    assert!(regression::compare_images(&actual, &actual).matches());
+
+    let mut expected = actual.clone();
+    expected.put_pixel(5, 7, image::Rgba([1, 2, 3, 255]));
+    let diff = regression::compare_images(&actual, &expected);
+    assert_eq!(diff.differing_pixels, 1);
+    assert_eq!(diff.first_difference, Some((5, 7)));
+    assert_eq!(diff.diff_image.get_pixel(5, 7), &image::Rgba([255, 0, 0, 255]));
+
+    let smaller = image::imageops::crop_imm(&actual, 0, 0, 10, 10).to_image();
+    assert!(!regression::compare_images(&actual, &smaller).matches());
+}